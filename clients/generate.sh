@@ -0,0 +1,29 @@
+#!/usr/bin/env bash
+# Generates Python and TypeScript Management API clients from the OpenAPI document
+# derived from the ya-http-proxy-model crate.
+#
+# Requires `openapi-generator-cli` (https://openapi-generator.tech), e.g.
+# `npm install -g @openapitools/openapi-generator-cli`.
+#
+# Usage: clients/generate.sh [output directory, `target/clients` by default]
+set -euo pipefail
+
+ROOT="$(cd "$(dirname "$0")/.." && pwd)"
+OUT="${1:-$ROOT/target/clients}"
+VERSION="$(sed -n 's/^version = "\(.*\)"/\1/p' "$ROOT/crates/ya-http-proxy-model/Cargo.toml" | head -n 1)"
+
+mkdir -p "$OUT"
+cargo run --quiet --manifest-path "$ROOT/Cargo.toml" -p ya-http-proxy-model \
+  --features schema --example openapi > "$OUT/openapi.json"
+
+openapi-generator-cli generate \
+  -i "$OUT/openapi.json" \
+  -g python \
+  -o "$OUT/python" \
+  --additional-properties "packageName=ya_http_proxy_client,projectName=ya-http-proxy-client,packageVersion=$VERSION"
+
+openapi-generator-cli generate \
+  -i "$OUT/openapi.json" \
+  -g typescript-fetch \
+  -o "$OUT/typescript" \
+  --additional-properties "npmName=ya-http-proxy-client,npmVersion=$VERSION,supportsES6=true"
@@ -105,6 +105,23 @@ impl ServiceCommands {
                         timeouts: None,
                         cpu_threads: None,
                         user: None,
+                        alerts: None,
+                        protocols: None,
+                        upstream_protocol: None,
+                        filters: None,
+                        request_tap: None,
+                        strip_response_headers: None,
+                        rewrite_redirects: None,
+                        rewrite_cookies: None,
+                        rewrite_html: None,
+                        deny: None,
+                        address_policy: None,
+                        max_users: None,
+                        server_tuning: None,
+                        answer_options: None,
+                        streaming: None,
+                        journal: None,
+                        rate_limit: None,
                     })
                     .await?;
                 print_service(&s);
@@ -122,13 +139,7 @@ impl UserCommands {
             Self::Delete { name } => api.delete_user(service, name).await?,
             Self::Add { user, pass } => {
                 let user = api
-                    .create_user(
-                        service,
-                        &CreateUser {
-                            username: user.to_string(),
-                            password: pass.to_string(),
-                        },
-                    )
+                    .create_user(service, &CreateUser::basic(user, pass))
                     .await?;
                 eprintln!("{user:?}");
             }
@@ -1,12 +1,29 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::stream::LocalBoxStream;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
 use crate::model::{
-    CreateService, CreateUser, GlobalStats, Service, User, UserEndpointStats, UserStats,
+    Alert, CreateService, CreateUser, Drain, GlobalStats, JournalEntry, LogQuery, ProxyRuntime,
+    RequestTiming, RotateCredentials, ServerCertInfo, Service, ServiceIsolation, ServiceStats,
+    ServiceUsage, Shutdown, Status, TimeRange, UpdateUser, User, UserAllowance, UserCredentials,
+    UserEndpointBytes, UserEndpointStats, UserLimitsStatus, UserStats, API_VERSION,
+    SHUTDOWN_CONFIRMATION,
 };
-use crate::{web::WebClient, Result};
+use crate::web::{WebClient, WireLog};
+use crate::{Error, MetricsObserver, Result};
+
+/// Request timeout when following logs
+const FOLLOW_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
 
 /// Handle to a proxy api.
 #[derive(Clone)]
 pub struct ManagementApi {
     client: WebClient,
+    verified: Rc<Cell<bool>>,
 }
 
 impl ManagementApi {
@@ -20,60 +37,235 @@ impl ManagementApi {
         Ok(Self::new(WebClient::new(url)?))
     }
 
+    /// Authenticates requests with a Management API bearer token.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.client = self.client.with_token(token.into());
+        self
+    }
+
+    /// Reports metrics of every Management API request to the observer.
+    pub fn with_metrics(mut self, observer: impl MetricsObserver + 'static) -> Self {
+        self.client = self.client.with_observer(Rc::new(observer));
+        self
+    }
+
+    /// Logs every Management API request and response.
+    pub fn with_wire_log(mut self, wire_log: WireLog) -> Self {
+        self.client = self.client.with_wire_log(wire_log);
+        self
+    }
+
+    /// Returns the wire logging settings, if enabled.
+    pub fn wire_log(&self) -> Option<&WireLog> {
+        self.client.wire_log()
+    }
+
+    /// Connects to a Management API at another url, keeping the token, metrics observer
+    /// and wire log settings.
+    pub fn with_url(&self, url: &str) -> Result<Self> {
+        Ok(Self::new(self.client.with_url(url)?))
+    }
+
+    /// Returns the Management API bearer token, if set.
+    pub fn token(&self) -> Option<&str> {
+        self.client.token()
+    }
+
+    /// Returns a handle tagging requests with the correlation ID, recorded by the proxy
+    /// in events caused by the requests.
+    pub fn with_correlation_id(&self, correlation_id: impl Into<String>) -> Self {
+        Self {
+            client: self.client.with_correlation_id(correlation_id.into()),
+            verified: self.verified.clone(),
+        }
+    }
+
     fn new(client: WebClient) -> Self {
-        Self { client }
+        Self {
+            client,
+            verified: Default::default(),
+        }
+    }
+
+    /// Retrieves the Management API status.
+    pub async fn get_status(&self) -> Result<Status> {
+        self.client.get("status").await
     }
 
     /// Lists available services.
     pub async fn get_services(&self) -> Result<Vec<Service>> {
-        self.client.get("services").await
+        self.get("services").await
     }
 
     /// Create new service from spec.
     pub async fn create_service(&self, cs: &CreateService) -> Result<Service> {
-        self.client.post("services", cs).await
+        self.post("services", cs).await
+    }
+
+    /// Create new service from spec. Retried requests with the same key
+    /// return the previously created service.
+    pub async fn create_service_idempotent(
+        &self,
+        cs: &CreateService,
+        key: &str,
+    ) -> Result<Service> {
+        self.handshake().await?;
+        self.client.post_idempotent("services", cs, key).await
     }
 
     /// Gets service by name.
     pub async fn get_service(&self, service_name: &str) -> Result<Service> {
         let url = format!("services/{}", service_name);
-        self.client.get(&url).await
+        self.get(&url).await
+    }
+
+    /// Replaces service spec, keeping its users and stats.
+    pub async fn update_service(&self, cs: &CreateService) -> Result<Service> {
+        let url = format!("services/{}", cs.name);
+        self.handshake().await?;
+        self.client.put(&url, cs).await
     }
 
     /// Drops service.
     pub async fn delete_service(&self, service_name: &str) -> Result<()> {
         let url = format!("services/{}", service_name);
-        self.client.delete(&url).await
+        self.delete(&url).await
+    }
+
+    /// Aggregated service statistics.
+    pub async fn get_service_stats(&self, service_name: &str) -> Result<ServiceStats> {
+        let url = format!("services/{}/stats", service_name);
+        self.get(&url).await
+    }
+
+    /// Timings of recent service requests, when the request tap is enabled.
+    pub async fn get_request_timings(&self, service_name: &str) -> Result<Vec<RequestTiming>> {
+        let url = format!("services/{}/debug/requests", service_name);
+        self.get(&url).await
+    }
+
+    /// Request journal entries from a time range, when the journal is enabled.
+    pub async fn get_journal(
+        &self,
+        service_name: &str,
+        query: &TimeRange,
+    ) -> Result<Vec<JournalEntry>> {
+        let query =
+            serde_urlencoded::to_string(query).map_err(|e| Error::PayloadError(e.to_string()))?;
+        let url = format!("services/{}/journal?{}", service_name, query);
+        self.get(&url).await
+    }
+
+    /// Resources the service shares with other services.
+    pub async fn get_service_isolation(&self, service_name: &str) -> Result<ServiceIsolation> {
+        let url = format!("services/{}/isolation", service_name);
+        self.get(&url).await
+    }
+
+    /// Usage aggregated per user over a time range.
+    pub async fn get_usage(&self, service_name: &str, range: &TimeRange) -> Result<ServiceUsage> {
+        let query =
+            serde_urlencoded::to_string(range).map_err(|e| Error::PayloadError(e.to_string()))?;
+        let url = format!("services/{}/usage?{}", service_name, query);
+        self.get(&url).await
     }
 
     /// User management per service
     pub async fn get_users(&self, service_name: &str) -> Result<Vec<User>> {
         let url = format!("services/{}/users", service_name);
-        self.client.get(&url).await
+        self.get(&url).await
     }
 
     /// Add user to service
     pub async fn create_user(&self, service_name: &str, cu: &CreateUser) -> Result<User> {
         let url = format!("services/{}/users", service_name);
-        self.client.post(&url, cu).await
+        self.post(&url, cu).await
+    }
+
+    /// Add user to service. Retried requests with the same key
+    /// return the previously created user.
+    pub async fn create_user_idempotent(
+        &self,
+        service_name: &str,
+        cu: &CreateUser,
+        key: &str,
+    ) -> Result<User> {
+        let url = format!("services/{}/users", service_name);
+        self.handshake().await?;
+        self.client.post_idempotent(&url, cu, key).await
     }
 
     /// Get user info for service.
     pub async fn get_user(&self, service_name: &str, username: &str) -> Result<User> {
         let url = format!("services/{}/users/{}", service_name, username);
-        self.client.get(&url).await
+        self.get(&url).await
+    }
+
+    /// Replaces user credentials, keeping the user stats.
+    pub async fn update_user(
+        &self,
+        service_name: &str,
+        username: &str,
+        uu: &UpdateUser,
+    ) -> Result<User> {
+        let url = format!("services/{}/users/{}", service_name, username);
+        self.handshake().await?;
+        self.client.put(&url, uu).await
+    }
+
+    /// Generates new user credentials, creating the user if missing.
+    /// Generated secrets are returned only once.
+    pub async fn rotate_credentials(
+        &self,
+        service_name: &str,
+        username: &str,
+        rc: &RotateCredentials,
+    ) -> Result<UserCredentials> {
+        let url = format!(
+            "services/{}/users/{}/credentials/rotate",
+            service_name, username
+        );
+        self.post(&url, rc).await
+    }
+
+    /// Reloads the certificate files of the proxy serving the service.
+    pub async fn reload_cert(&self, service_name: &str) -> Result<ServerCertInfo> {
+        let url = format!("services/{}/cert/reload", service_name);
+        self.post(&url, &()).await
     }
 
     /// Removes giver user from given server.
     pub async fn delete_user(&self, service_name: &str, username: &str) -> Result<()> {
         let url = format!("services/{}/users/{}", service_name, username);
-        self.client.delete(&url).await
+        self.delete(&url).await
+    }
+
+    /// User credentials usage limits and the current usage
+    pub async fn get_user_limits(
+        &self,
+        service_name: &str,
+        username: &str,
+    ) -> Result<UserLimitsStatus> {
+        let url = format!("services/{}/users/{}/limits", service_name, username);
+        self.get(&url).await
+    }
+
+    /// Sets the usage paid for by the requestor. `None` lifts the allowance.
+    pub async fn set_user_allowance(
+        &self,
+        service_name: &str,
+        username: &str,
+        allowance: Option<&UserAllowance>,
+    ) -> Result<UserLimitsStatus> {
+        let url = format!("services/{}/users/{}/allowance", service_name, username);
+        self.handshake().await?;
+        self.client.put(&url, &allowance).await
     }
 
     /// User statistics
     pub async fn get_user_stats(&self, service_name: &str, username: &str) -> Result<UserStats> {
         let url = format!("services/{}/users/{}/stats", service_name, username);
-        self.client.get(&url).await
+        self.get(&url).await
     }
 
     /// List user endpoints stats.
@@ -86,11 +278,137 @@ impl ManagementApi {
             "services/{}/users/{}/endpoints/stats",
             service_name, username
         );
-        self.client.get(&url).await
+        self.get(&url).await
+    }
+
+    /// List request and response body bytes transferred by a user per endpoint.
+    pub async fn get_endpoint_user_bytes(
+        &self,
+        service_name: &str,
+        username: &str,
+    ) -> Result<UserEndpointBytes> {
+        let url = format!(
+            "services/{}/users/{}/endpoints/bytes",
+            service_name, username
+        );
+        self.get(&url).await
+    }
+
+    /// Lists active service alerts.
+    pub async fn get_alerts(&self) -> Result<Vec<Alert>> {
+        self.get("alerts").await
+    }
+
+    /// Runtime diagnostics of proxy instances.
+    pub async fn get_proxy_runtimes(&self) -> Result<Vec<ProxyRuntime>> {
+        self.get("debug/proxies").await
+    }
+
+    /// Streams recent proxy log lines, optionally referring to a single service.
+    /// When following, lines logged afterwards are streamed until the proxy stops.
+    pub async fn get_logs(
+        &self,
+        service_name: Option<&str>,
+        tail: Option<usize>,
+        follow: bool,
+    ) -> Result<LocalBoxStream<'static, Result<String>>> {
+        let query = serde_urlencoded::to_string(&LogQuery {
+            service: service_name.map(ToString::to_string),
+            tail,
+            follow,
+        })
+        .map_err(|e| Error::PayloadError(e.to_string()))?;
+        let timeout = if follow { Some(FOLLOW_TIMEOUT) } else { None };
+
+        self.handshake().await?;
+        self.client
+            .get_lines(format!("logs?{}", query), timeout)
+            .await
     }
 
     /// Global statistics.
     pub async fn get_global_stats(&self) -> Result<GlobalStats> {
-        self.client.get("stats").await
+        self.get("stats").await
+    }
+
+    /// Stops the proxy, waiting up to `drain` for in-flight requests to complete.
+    pub async fn shutdown(&self, drain: Option<Duration>) -> Result<()> {
+        let nonce = self.shutdown_nonce().await?;
+        self.post("control/shutdown", &Shutdown::new(nonce, drain))
+            .await
+    }
+
+    /// Stops accepting connections and stops the proxy once in-flight requests are
+    /// complete, waiting up to `timeout` or the proxy drain timeout.
+    pub async fn drain(&self, timeout: Option<Duration>) -> Result<()> {
+        let nonce = self.shutdown_nonce().await?;
+        self.post("control/drain", &Drain::new(nonce, timeout))
+            .await
+    }
+
+    /// Confirmation of shutdown requests reported by the proxy. Older proxies expect
+    /// `SHUTDOWN_CONFIRMATION`
+    async fn shutdown_nonce(&self) -> Result<String> {
+        match self.get_status().await {
+            Ok(status) => Ok(status
+                .shutdown_nonce
+                .unwrap_or_else(|| SHUTDOWN_CONFIRMATION.to_string())),
+            // proxies predating the status endpoint
+            Err(Error::SendRequestError {
+                code: StatusCode::NOT_FOUND,
+                ..
+            }) => Ok(SHUTDOWN_CONFIRMATION.to_string()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get<R>(&self, uri: &str) -> Result<R>
+    where
+        R: for<'de> Deserialize<'de>,
+    {
+        self.handshake().await?;
+        self.client.get(uri).await
+    }
+
+    async fn post<P, R>(&self, uri: &str, payload: &P) -> Result<R>
+    where
+        P: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        self.handshake().await?;
+        self.client.post(uri, payload).await
+    }
+
+    async fn delete(&self, uri: &str) -> Result<()> {
+        self.handshake().await?;
+        self.client.delete(uri).await
+    }
+
+    /// Verifies the Management API version on first use
+    async fn handshake(&self) -> Result<()> {
+        if self.verified.get() {
+            return Ok(());
+        }
+
+        let actual = match self.get_status().await {
+            Ok(status) if status.is_compatible() => {
+                self.verified.set(true);
+                return Ok(());
+            }
+            Ok(status) => status.api_version,
+            // proxies predating the status endpoint
+            Err(Error::SendRequestError {
+                code: StatusCode::NOT_FOUND,
+                ..
+            })
+            | Err(Error::JsonError(_))
+            | Err(Error::InvalidResponse { .. }) => "unknown".to_string(),
+            Err(err) => return Err(err),
+        };
+
+        Err(Error::IncompatibleVersion {
+            expected: API_VERSION.to_string(),
+            actual,
+        })
     }
 }
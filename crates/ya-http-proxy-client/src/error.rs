@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
 use awc::error::{PayloadError, SendRequestError};
 use http::uri::InvalidUri;
 use http::{Method, StatusCode};
@@ -12,6 +15,25 @@ pub enum Error {
         msg: String,
         method: Method,
         url: String,
+        /// Machine readable error code
+        error_code: Option<String>,
+        /// Suggested delay before retrying the request
+        retry_after: Option<Duration>,
+        /// Properties of the resources involved
+        details: Option<Box<HashMap<String, String>>>,
+    },
+    #[error("Error sending request {method} {url}: {msg}")]
+    ConnectionError {
+        msg: String,
+        method: Method,
+        url: String,
+    },
+    #[error("Invalid response to {method} {url}: {reason}; body: '{body}'")]
+    InvalidResponse {
+        reason: String,
+        body: String,
+        method: Method,
+        url: String,
     },
     #[error("JSON error: {0}")]
     JsonError(#[from] serde_json::error::Error),
@@ -21,6 +43,8 @@ pub enum Error {
     PayloadError(String),
     #[error("Invalid URI string: {0}")]
     InvalidUriError(#[from] InvalidUri),
+    #[error("Incompatible proxy Management API version: {actual} (expected {expected})")]
+    IncompatibleVersion { expected: String, actual: String },
 }
 
 impl From<PayloadError> for Error {
@@ -31,13 +55,34 @@ impl From<PayloadError> for Error {
 
 impl Error {
     pub(crate) fn from_request(err: SendRequestError, method: Method, url: String) -> Self {
-        let msg = err.to_string();
-        let code = StatusCode::INTERNAL_SERVER_ERROR;
-        Error::SendRequestError {
-            code,
-            msg,
+        Error::ConnectionError {
+            msg: err.to_string(),
             method,
             url,
         }
     }
+
+    /// Returns the machine readable code of a failed Management API request.
+    pub fn error_code(&self) -> Option<&str> {
+        match self {
+            Self::SendRequestError { error_code, .. } => error_code.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the suggested delay before retrying a failed Management API request.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::SendRequestError { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Returns the HTTP status code of a failed Management API request.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::SendRequestError { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
 }
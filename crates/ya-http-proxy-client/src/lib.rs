@@ -15,6 +15,7 @@
 //!
 mod api;
 mod error;
+mod metrics;
 mod web;
 
 /// Management API communication objects.
@@ -22,8 +23,9 @@ pub mod model;
 
 pub use api::ManagementApi;
 pub use error::Error;
+pub use metrics::{MetricsObserver, RecentRequests, RequestMetric, RequestSummary};
 
 /// A specialized Result type for proxy client operations.
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub use web::{DEFAULT_MANAGEMENT_API_URL, ENV_MANAGEMENT_API_URL};
+pub use web::{WireLog, DEFAULT_MANAGEMENT_API_URL, ENV_MANAGEMENT_API_URL};
@@ -0,0 +1,149 @@
+use http::Method;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::Error;
+
+/// Outcome of a single Management API request.
+pub struct RequestMetric<'a> {
+    /// Request method.
+    pub method: &'a Method,
+    /// Requested path, relative to the Management API URL.
+    pub path: &'a str,
+    /// Time elapsed until the response was read.
+    pub duration: Duration,
+    /// Request result.
+    pub result: std::result::Result<(), &'a Error>,
+}
+
+impl<'a> RequestMetric<'a> {
+    /// Checks whether the request failed due to a Management API
+    /// or connection problem, rather than being rejected by the API.
+    pub fn is_failure(&self) -> bool {
+        match self.result {
+            Ok(_) => false,
+            Err(Error::SendRequestError { code, .. }) => code.is_server_error(),
+            Err(_) => true,
+        }
+    }
+}
+
+/// Receives metrics of every request sent to the Management API.
+pub trait MetricsObserver {
+    /// Invoked once a request is completed.
+    fn observe(&self, metric: &RequestMetric<'_>);
+}
+
+impl<F> MetricsObserver for F
+where
+    F: Fn(&RequestMetric<'_>),
+{
+    fn observe(&self, metric: &RequestMetric<'_>) {
+        self(metric)
+    }
+}
+
+/// Keeps metrics of a bounded number of most recent requests.
+#[derive(Clone)]
+pub struct RecentRequests {
+    capacity: usize,
+    requests: Rc<RefCell<VecDeque<(Duration, bool)>>>,
+}
+
+impl RecentRequests {
+    /// Creates a window of `capacity` most recent requests.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            requests: Rc::new(RefCell::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Summarizes requests currently in the window.
+    pub fn summary(&self) -> RequestSummary {
+        let requests = self.requests.borrow();
+        let total: Duration = requests.iter().map(|(d, _)| *d).sum();
+
+        RequestSummary {
+            requests: requests.len(),
+            failures: requests.iter().filter(|(_, failed)| *failed).count(),
+            mean_duration: match u32::try_from(requests.len()) {
+                Ok(0) | Err(_) => Duration::ZERO,
+                Ok(n) => total / n,
+            },
+            max_duration: requests.iter().map(|(d, _)| *d).max().unwrap_or_default(),
+        }
+    }
+}
+
+impl MetricsObserver for RecentRequests {
+    fn observe(&self, metric: &RequestMetric<'_>) {
+        let mut requests = self.requests.borrow_mut();
+        while !requests.is_empty() && requests.len() >= self.capacity {
+            requests.pop_front();
+        }
+        if self.capacity > 0 {
+            requests.push_back((metric.duration, metric.is_failure()));
+        }
+    }
+}
+
+/// Summary of recent Management API requests.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequestSummary {
+    /// Number of requests.
+    pub requests: usize,
+    /// Number of failed requests.
+    pub failures: usize,
+    /// Mean request duration.
+    pub mean_duration: Duration,
+    /// Maximum request duration.
+    pub max_duration: Duration,
+}
+
+impl RequestSummary {
+    /// Fraction of failed requests.
+    pub fn failure_rate(&self) -> f64 {
+        if self.requests == 0 {
+            0.
+        } else {
+            self.failures as f64 / self.requests as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MetricsObserver, RecentRequests, RequestMetric};
+    use crate::Error;
+    use http::Method;
+    use std::time::Duration;
+
+    #[test]
+    fn recent_requests() {
+        let recent = RecentRequests::new(2);
+        let err = Error::ConnectionError {
+            msg: "refused".to_string(),
+            method: Method::GET,
+            url: "http://127.0.0.1:6668/services".to_string(),
+        };
+
+        for (millis, result) in [(100, Ok(())), (200, Err(&err)), (400, Ok(()))] {
+            recent.observe(&RequestMetric {
+                method: &Method::GET,
+                path: "services",
+                duration: Duration::from_millis(millis),
+                result,
+            });
+        }
+
+        let summary = recent.summary();
+        assert_eq!(summary.requests, 2);
+        assert_eq!(summary.failures, 1);
+        assert_eq!(summary.failure_rate(), 0.5);
+        assert_eq!(summary.mean_duration, Duration::from_millis(300));
+        assert_eq!(summary.max_duration, Duration::from_millis(400));
+    }
+}
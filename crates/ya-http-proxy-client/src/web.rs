@@ -1,10 +1,16 @@
-use http::{Method, Uri};
+use futures::stream::{self, LocalBoxStream};
+use futures::StreamExt;
+use http::{Method, StatusCode, Uri};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+use crate::metrics::{MetricsObserver, RequestMetric};
 use crate::{Error, Result};
-use ya_http_proxy_model::ErrorResponse;
+use ya_http_proxy_model::{
+    redact_json, snippet, ErrorResponse, CORRELATION_ID_HEADER, SECRET_FIELDS,
+};
 
 /// Default management api url.
 pub const DEFAULT_MANAGEMENT_API_URL: &str = "http://127.0.0.1:6668";
@@ -13,11 +19,72 @@ pub const DEFAULT_MANAGEMENT_API_URL: &str = "http://127.0.0.1:6668";
 pub const ENV_MANAGEMENT_API_URL: &str = "MANAGEMENT_API_URL";
 
 const MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+/// Number of characters of a response body to include in logs and errors
+const SNIPPET_LEN: usize = 512;
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// Management API wire logging settings.
+#[derive(Clone, Debug)]
+pub struct WireLog {
+    /// Include request and response bodies.
+    pub bodies: bool,
+    /// Names of JSON fields redacted in logged bodies.
+    pub redact: Vec<String>,
+}
+
+impl Default for WireLog {
+    fn default() -> Self {
+        Self {
+            bodies: true,
+            redact: SECRET_FIELDS.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+impl WireLog {
+    fn log(
+        &self,
+        method: &Method,
+        url: &str,
+        status: StatusCode,
+        elapsed: Duration,
+        bodies: (Option<&str>, &str),
+    ) {
+        if !self.bodies {
+            log::info!(
+                "{} {} {} {} ms",
+                method,
+                url,
+                status.as_u16(),
+                elapsed.as_millis()
+            );
+            return;
+        }
+
+        let (request, response) = bodies;
+        log::info!(
+            "{} {} {} {} ms; request: '{}'; response: '{}'",
+            method,
+            url,
+            status.as_u16(),
+            elapsed.as_millis(),
+            snippet(
+                &redact_json(request.unwrap_or_default(), &self.redact),
+                SNIPPET_LEN
+            ),
+            snippet(&redact_json(response, &self.redact), SNIPPET_LEN),
+        );
+    }
+}
 
 /// REST api client abstraction
 #[derive(Clone)]
 pub struct WebClient {
     url: Rc<Uri>,
+    token: Option<Rc<str>>,
+    correlation_id: Option<Rc<str>>,
+    observer: Option<Rc<dyn MetricsObserver>>,
+    wire_log: Option<Rc<WireLog>>,
     inner: awc::Client,
 }
 
@@ -29,16 +96,58 @@ impl WebClient {
     pub fn new(url: &str) -> Result<Self> {
         Ok(Self {
             url: Rc::new(url.parse()?),
+            token: None,
+            correlation_id: None,
+            observer: None,
+            wire_log: None,
             inner: awc::Client::new(),
         })
     }
 
+    /// Connects to another url, keeping the token, metrics observer and wire log settings
+    pub fn with_url(&self, url: &str) -> Result<Self> {
+        Ok(Self {
+            url: Rc::new(url.parse()?),
+            ..self.clone()
+        })
+    }
+
+    pub fn with_token(mut self, token: String) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn with_correlation_id(&self, correlation_id: String) -> Self {
+        Self {
+            correlation_id: Some(correlation_id.into()),
+            ..self.clone()
+        }
+    }
+
+    pub fn with_observer(mut self, observer: Rc<dyn MetricsObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    pub fn with_wire_log(mut self, wire_log: WireLog) -> Self {
+        self.wire_log = Some(Rc::new(wire_log));
+        self
+    }
+
+    pub fn wire_log(&self) -> Option<&WireLog> {
+        self.wire_log.as_deref()
+    }
+
     pub async fn get<R, S>(&self, uri: S) -> Result<R>
     where
         R: for<'de> Deserialize<'de>,
         S: AsRef<str>,
     {
-        self.request::<(), R, S>(Method::GET, uri, None).await
+        self.request::<(), R, S>(Method::GET, uri, None, None).await
     }
 
     pub async fn post<P, R, S>(&self, uri: S, payload: &P) -> Result<R>
@@ -47,55 +156,241 @@ impl WebClient {
         R: for<'de> Deserialize<'de>,
         S: AsRef<str>,
     {
-        self.request(Method::POST, uri, Some(payload)).await
+        self.request(Method::POST, uri, Some(payload), None).await
+    }
+
+    pub async fn post_idempotent<P, R, S>(&self, uri: S, payload: &P, key: &str) -> Result<R>
+    where
+        P: Serialize,
+        R: for<'de> Deserialize<'de>,
+        S: AsRef<str>,
+    {
+        self.request(Method::POST, uri, Some(payload), Some(key))
+            .await
+    }
+
+    pub async fn put<P, R, S>(&self, uri: S, payload: &P) -> Result<R>
+    where
+        P: Serialize,
+        R: for<'de> Deserialize<'de>,
+        S: AsRef<str>,
+    {
+        self.request(Method::PUT, uri, Some(payload), None).await
     }
 
     pub async fn delete<S>(&self, uri: S) -> Result<()>
     where
         S: AsRef<str>,
     {
-        self.request::<(), (), S>(Method::DELETE, uri, None).await
+        self.request::<(), (), S>(Method::DELETE, uri, None, None)
+            .await
     }
 
-    async fn request<P, R, S>(&self, method: Method, uri: S, payload: Option<&P>) -> Result<R>
+    async fn request<P, R, S>(
+        &self,
+        method: Method,
+        uri: S,
+        payload: Option<&P>,
+        idempotency_key: Option<&str>,
+    ) -> Result<R>
     where
         P: Serialize,
         R: for<'de> Deserialize<'de>,
         S: AsRef<str>,
     {
         let uri = uri.as_ref();
+        let started = Instant::now();
+        let result = self
+            .send(method.clone(), uri, payload, idempotency_key, started)
+            .await;
+
+        if let Some(ref observer) = self.observer {
+            observer.observe(&RequestMetric {
+                method: &method,
+                path: uri,
+                duration: started.elapsed(),
+                result: result.as_ref().map(|_| ()),
+            });
+        }
+        result
+    }
+
+    async fn send<P, R>(
+        &self,
+        method: Method,
+        uri: &str,
+        payload: Option<&P>,
+        idempotency_key: Option<&str>,
+        started: Instant,
+    ) -> Result<R>
+    where
+        P: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
         let url = format!("{}{}", self.url, uri);
+        let request_body = match (&self.wire_log, payload) {
+            (Some(wire_log), Some(payload)) if wire_log.bodies => {
+                serde_json::to_string(payload).ok()
+            }
+            _ => None,
+        };
 
-        let req = self.inner.request(method.clone(), &url);
+        let mut req = self.inner.request(method.clone(), &url);
+        if let Some(ref token) = self.token {
+            req = req.bearer_auth(token);
+        }
+        if let Some(key) = idempotency_key {
+            req = req.insert_header((IDEMPOTENCY_KEY, key));
+        }
+        if let Some(ref correlation_id) = self.correlation_id {
+            req = req.insert_header((CORRELATION_ID_HEADER, correlation_id.as_ref()));
+        }
 
-        let mut res = match payload {
+        let sent = match payload {
             Some(payload) => req.send_json(payload),
             None => req.send(),
         }
-        .await
-        .map_err(|e| Error::from_request(e, method.clone(), url.clone()))?;
+        .await;
+        let mut res = match sent {
+            Ok(res) => res,
+            Err(e) => {
+                let e = Error::from_request(e, method.clone(), url.clone());
+                if self.wire_log.is_some() {
+                    let elapsed = started.elapsed().as_millis();
+                    log::info!("{} {} failed after {} ms: {}", method, url, elapsed, e);
+                }
+                return Err(e);
+            }
+        };
 
         let raw_body = res.body().limit(MAX_BODY_SIZE).await?;
-        let body = std::str::from_utf8(&raw_body)?;
+        let body = String::from_utf8_lossy(&raw_body);
+        let redacted = redact_json(&body, SECRET_FIELDS);
+        let status = res.status();
+
+        match self.wire_log {
+            Some(ref wire_log) => wire_log.log(
+                &method,
+                &url,
+                status,
+                started.elapsed(),
+                (request_body.as_deref(), &body),
+            ),
+            None => log::debug!(
+                "WebRequest: method={} url={}, status={}",
+                method,
+                url,
+                status
+            ),
+        }
+
+        if status.is_success() {
+            // `204 No Content` and other empty responses carry no value
+            let result = if status == StatusCode::NO_CONTENT || body.trim().is_empty() {
+                serde_json::from_value(serde_json::Value::Null)
+            } else {
+                serde_json::from_str(&body)
+            };
+            return result.map_err(|e| Error::InvalidResponse {
+                reason: e.to_string(),
+                body: snippet(&redacted, SNIPPET_LEN).to_string(),
+                method,
+                url,
+            });
+        }
+
+        Err(error_response(status, &body, method, url))
+    }
 
+    /// Streams lines of the response body
+    pub async fn get_lines<S>(
+        &self,
+        uri: S,
+        timeout: Option<Duration>,
+    ) -> Result<LocalBoxStream<'static, Result<String>>>
+    where
+        S: AsRef<str>,
+    {
+        let method = Method::GET;
+        let url = format!("{}{}", self.url, uri.as_ref());
+
+        let mut req = self.inner.request(method.clone(), &url);
+        if let Some(ref token) = self.token {
+            req = req.bearer_auth(token);
+        }
+        if let Some(timeout) = timeout {
+            req = req.timeout(timeout);
+        }
+
+        let mut res = req
+            .send()
+            .await
+            .map_err(|e| Error::from_request(e, method.clone(), url.clone()))?;
+        let status = res.status();
         log::debug!(
-            "WebRequest: method={} url={}, resp='{}'",
+            "WebRequest: method={} url={}, status={}",
             method,
             url,
-            body.split_at(512.min(body.len())).0,
+            status
         );
 
-        if res.status().is_success() {
-            return Ok(serde_json::from_str(body)?);
+        if !status.is_success() {
+            let raw_body = res.body().limit(MAX_BODY_SIZE).await?;
+            let body = String::from_utf8_lossy(&raw_body);
+            return Err(error_response(status, &body, method, url));
         }
 
-        let response: ErrorResponse = serde_json::from_str(body)?;
-        Err(Error::SendRequestError {
-            code: res.status(),
+        let lines = stream::unfold(
+            (Box::pin(res), Vec::new()),
+            |(mut res, mut buf)| async move {
+                loop {
+                    if let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                        let line: Vec<u8> = buf.drain(..=pos).collect();
+                        let line = String::from_utf8_lossy(&line[..pos]).into_owned();
+                        return Some((Ok(line), (res, buf)));
+                    }
+                    match res.next().await {
+                        Some(Ok(bytes)) => buf.extend_from_slice(&bytes),
+                        Some(Err(e)) => return Some((Err(e.into()), (res, Vec::new()))),
+                        // the last line may not be terminated
+                        None if buf.is_empty() => return None,
+                        None => {
+                            let line = String::from_utf8_lossy(&buf).into_owned();
+                            return Some((Ok(line), (res, Vec::new())));
+                        }
+                    }
+                }
+            },
+        );
+        Ok(lines.boxed_local())
+    }
+}
+
+fn error_response(status: StatusCode, body: &str, method: Method, url: String) -> Error {
+    match serde_json::from_str::<ErrorResponse>(body) {
+        Ok(response) => Error::SendRequestError {
+            code: status,
             url,
             method,
             msg: response.message,
-        })
+            error_code: response.code,
+            retry_after: response.retry_after,
+            details: response.details.map(Box::new),
+        },
+        // not a Management API response, e.g. an HTML error page
+        Err(_) => Error::SendRequestError {
+            code: status,
+            url,
+            method,
+            msg: if body.trim().is_empty() {
+                status.canonical_reason().unwrap_or_default().to_string()
+            } else {
+                snippet(&redact_json(body, SECRET_FIELDS), SNIPPET_LEN).to_string()
+            },
+            error_code: None,
+            retry_after: None,
+            details: None,
+        },
     }
 }
 
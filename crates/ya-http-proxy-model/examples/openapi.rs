@@ -0,0 +1,10 @@
+//! Prints the OpenAPI document of the Management API, which Python and TypeScript
+//! API clients are generated from:
+//!
+//! `cargo run -p ya-http-proxy-model --features schema --example openapi > openapi.json`
+use ya_http_proxy_model::openapi;
+
+fn main() {
+    let document = openapi::document();
+    println!("{}", serde_json::to_string_pretty(&document).unwrap());
+}
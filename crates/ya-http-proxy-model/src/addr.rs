@@ -36,6 +36,18 @@ impl Addresses {
         self.0.is_empty()
     }
 
+    /// Checks whether any address in this collection overlaps with `other`.
+    /// Addresses overlap when they share a port and either the same IP address
+    /// or an unspecified (wildcard) IP address.
+    pub fn overlaps(&self, other: &Addresses) -> bool {
+        self.0.iter().any(|l| {
+            other.0.iter().any(|r| {
+                l.port() == r.port()
+                    && (l.ip() == r.ip() || l.ip().is_unspecified() || r.ip().is_unspecified())
+            })
+        })
+    }
+
     /// Converts collection to `Vec`.
     #[inline]
     pub fn to_vec(&self) -> Vec<SocketAddr> {
@@ -58,6 +70,18 @@ impl<'de> Deserialize<'de> for Addresses {
     }
 }
 
+#[cfg(feature = "schema")]
+impl schemars::JsonSchema for Addresses {
+    fn schema_name() -> String {
+        "Addresses".to_string()
+    }
+
+    /// Addresses are serialized as a list, even though a single address is accepted as well
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        <Vec<SocketAddr>>::json_schema(gen)
+    }
+}
+
 impl<I: IntoIterator<Item = SocketAddr>> Add<I> for Addresses {
     type Output = Self;
 
@@ -5,6 +5,10 @@ mod addr;
 #[doc(hidden)]
 pub mod deser;
 mod model;
+#[cfg(feature = "schema")]
+pub mod openapi;
+mod secret;
 
 pub use addr::*;
 pub use model::*;
+pub use secret::*;
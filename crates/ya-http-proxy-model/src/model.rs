@@ -1,4 +1,5 @@
 use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::time::Duration;
 
@@ -7,14 +8,215 @@ use http::uri::Uri;
 use serde::{Deserialize, Serialize};
 use strum::{EnumString, EnumVariantNames, IntoStaticStr};
 
-use crate::{deser, Addresses};
+use crate::{deser, Addresses, Secret};
+
+/// Management API version, shared by the proxy and its clients.
+pub const API_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Value of [`Shutdown::confirm`] expected by proxies which do not report
+/// [`Status::shutdown_nonce`].
+pub const SHUTDOWN_CONFIRMATION: &str = "shutdown";
+
+/// Name of the [`Ready`] file written to the proxy status directory.
+pub const READY_FILE_NAME: &str = "ready.json";
+
+/// Name of the unix socket in the proxy status directory serving the [`Ready`] descriptor.
+pub const INSTANCE_SOCKET_NAME: &str = "instance.sock";
+
+/// Management API request header identifying the operation that issued the request,
+/// recorded in [`EventRecord::correlation_id`].
+pub const CORRELATION_ID_HEADER: &str = "X-Correlation-Id";
+
+/// Management API port of the unnamed proxy instance.
+pub const DEFAULT_MANAGEMENT_PORT: u16 = 6668;
+
+/// Number of ports named proxy instances derive their default Management API port from.
+const INSTANCE_PORT_RANGE: u16 = 1000;
+const MAX_INSTANCE_NAME_LEN: usize = 64;
+
+/// Request header carrying API keys of [`AuthMethod::ApiKey`] users, unless configured
+/// with [`Auth::api_key_header`].
+pub const DEFAULT_API_KEY_HEADER: &str = "X-Api-Key";
 
 /// Authorization configuration
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct Auth {
     /// Authorization method
     pub method: AuthMethod,
+    /// Authorization webhook (`External` method)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<AuthWebhook>,
+    /// External user store, complementing users created via the Management API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub users: Option<UserSource>,
+    /// JSON Web Token verification (`Jwt` method)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwt: Option<JwtConf>,
+    /// Request header carrying API keys. Defaults to [`DEFAULT_API_KEY_HEADER`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_header: Option<String>,
+}
+
+impl Auth {
+    /// Request header carrying API keys
+    pub fn api_key_header(&self) -> &str {
+        self.api_key_header
+            .as_deref()
+            .unwrap_or(DEFAULT_API_KEY_HEADER)
+    }
+}
+
+/// JSON Web Token verification configuration. Requests carry tokens as bearer credentials
+/// and are accounted to the user named by the `sub` claim
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct JwtConf {
+    /// Token signature verification key
+    pub key: JwtKey,
+    /// Accepted `aud` claim values. The audience is not verified when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audience: Vec<String>,
+    /// Clock skew tolerated when verifying token expiry
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub leeway: Option<Duration>,
+}
+
+/// JSON Web Token signature verification key
+#[non_exhaustive]
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum JwtKey {
+    /// HMAC SHA-256 (`HS256`) shared secret
+    Hs256 {
+        /// Secret key.
+        key: Secret<String>,
+    },
+    /// RSA SHA-256 (`RS256`) public key
+    #[serde(rename_all = "camelCase")]
+    Rs256 {
+        /// PEM-encoded public key.
+        public_key: String,
+    },
+    /// RSA SHA-256 (`RS256`) public keys published as a JSON Web Key Set
+    #[serde(rename_all = "camelCase")]
+    Jwks {
+        /// Key set URL (e.g. `https://auth.local/.well-known/jwks.json`)
+        #[serde(with = "deser::uri")]
+        #[cfg_attr(feature = "schema", schemars(with = "String"))]
+        url: Uri,
+        /// How often to fetch the key set. Unknown key ids trigger an earlier fetch.
+        #[serde(
+            default,
+            with = "deser::duration::opt_ms",
+            skip_serializing_if = "Option::is_none"
+        )]
+        #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+        refresh_interval: Option<Duration>,
+    },
+}
+
+/// External user store
+#[non_exhaustive]
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum UserSource {
+    /// Apache `.htpasswd` file with crypt(3) style, `$apr1$` or `{SHA}` password hashes
+    #[serde(rename_all = "camelCase")]
+    Htpasswd {
+        /// File path on disk.
+        path: PathBuf,
+        /// How often to check the file for modifications.
+        #[serde(
+            default,
+            with = "deser::duration::opt_ms",
+            skip_serializing_if = "Option::is_none"
+        )]
+        #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+        refresh_interval: Option<Duration>,
+    },
+    /// LDAP server, verified with a simple bind as the user
+    #[serde(rename_all = "camelCase")]
+    Ldap {
+        /// Server URL (e.g. `ldaps://ldap.local:636`).
+        url: String,
+        /// Bind DN template, e.g. `uid={username},ou=people,dc=example,dc=com`.
+        bind_dn: String,
+        /// Time to cache successful binds for.
+        #[serde(
+            default,
+            with = "deser::duration::opt_ms",
+            skip_serializing_if = "Option::is_none"
+        )]
+        #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+        cache_ttl: Option<Duration>,
+    },
+}
+
+/// Authorization webhook configuration
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct AuthWebhook {
+    /// Webhook URL (e.g. `https://auth.local/verify`)
+    #[serde(with = "deser::uri")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub url: Uri,
+    /// Time to cache the webhook verdict for
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub cache_ttl: Option<Duration>,
+    /// Webhook response timeout
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub timeout: Option<Duration>,
+}
+
+/// Request sent to the authorization webhook
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalAuthRequest {
+    /// Service name.
+    pub service: String,
+    /// Request method.
+    #[serde(default)]
+    pub method: String,
+    /// Requested path.
+    pub path: String,
+    /// Client address.
+    pub remote_addr: String,
+    /// Request headers, including credentials.
+    pub headers: HashMap<String, String>,
+}
+
+/// Authorization webhook verdict
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalAuthResponse {
+    /// Whether the request is authorized.
+    pub allow: bool,
+    /// User name to account the request to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
 }
 
 /// Authorization method
@@ -22,10 +224,21 @@ pub struct Auth {
 #[derive(
     Clone, Debug, Eq, PartialEq, Deserialize, Serialize, EnumString, EnumVariantNames, IntoStaticStr,
 )]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub enum AuthMethod {
     /// HTTP basic auth
     Basic,
+    /// HTTP bearer token auth
+    Bearer,
+    /// API key auth
+    ApiKey,
+    /// Client certificate auth
+    CertFingerprint,
+    /// Authorization webhook, called for requests with and without credentials
+    External,
+    /// JSON Web Token auth
+    Jwt,
 }
 
 impl Default for AuthMethod {
@@ -36,6 +249,7 @@ impl Default for AuthMethod {
 
 /// Service descriptor
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct Service {
     #[allow(missing_docs)]
@@ -43,16 +257,39 @@ pub struct Service {
     pub inner: CreateService,
     /// Creation date
     pub created_at: DateTime<Utc>,
+    /// Whether the upstream server accepted the last forwarded connection.
+    /// Not reported by older proxy versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_available: Option<bool>,
+    /// Number of service users.
+    /// Not reported by older proxy versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_count: Option<usize>,
+    /// Reason the proxy serving the service has stopped accepting connections.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_error: Option<String>,
+    /// Errors of proxy listeners being rebound. Meanwhile, the service may be unreachable
+    /// on some of the listening addresses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listener_error: Option<String>,
 }
 
 impl From<(CreateService, DateTime<Utc>)> for Service {
     fn from((inner, created_at): (CreateService, DateTime<Utc>)) -> Self {
-        Self { inner, created_at }
+        Self {
+            inner,
+            created_at,
+            upstream_available: None,
+            user_count: None,
+            proxy_error: None,
+            listener_error: None,
+        }
     }
 }
 
 /// Public service information
 #[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct PubService {
     /// Service name.
@@ -71,9 +308,15 @@ pub struct PubService {
     /// Service timeout rules.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub timeouts: Option<Timeouts>,
-    /// How many cpu threads should be started for given service.
+    /// Maximum number of proxy worker threads handling service requests at once.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cpu_threads: Option<usize>,
+    /// Maximum number of service users.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_users: Option<usize>,
+    /// Current number of service users.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_count: Option<usize>,
 }
 
 impl From<Service> for PubService {
@@ -90,12 +333,15 @@ impl From<Service> for PubService {
             cert_hash: service.inner.cert.as_ref().map(|c| c.hash.clone()),
             timeouts: service.inner.timeouts,
             cpu_threads: service.inner.cpu_threads,
+            max_users: service.inner.max_users,
+            user_count: service.user_count,
         }
     }
 }
 
 /// New service descriptor
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct CreateService {
     /// Unique Service name
@@ -104,10 +350,10 @@ pub struct CreateService {
     /// Domain names or public IP addresses
     #[serde(default)]
     pub server_name: Vec<String>,
-    /// HTTPS listening addresses
+    /// HTTPS listening addresses. Addresses with port 0 are reported with the ports bound
     #[serde(alias = "bind")]
     pub bind_https: Option<Addresses>,
-    /// HTTP listening addresses
+    /// HTTP listening addresses. Addresses with port 0 are reported with the ports bound
     pub bind_http: Option<Addresses>,
     /// Certificate configuration
     pub cert: Option<CreateServiceCert>,
@@ -115,17 +361,74 @@ pub struct CreateService {
     pub auth: Option<Auth>,
     /// Source endpoint (e.g. `/resource`)
     #[serde(with = "deser::uri")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
     pub from: Uri,
     /// Destination URL (e.g. `http://127.0.0.1:8080`)
     #[serde(with = "deser::uri")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
     pub to: Uri,
     /// Timeout configuration
     #[serde(flatten)]
     pub timeouts: Option<Timeouts>,
-    /// Number of CPU (worker) threads to use
+    /// Maximum number of proxy worker threads handling service requests at once
     pub cpu_threads: Option<usize>,
     /// Forwarding options
     pub user: Option<CreateServiceUser>,
+    /// Alert rules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alerts: Option<AlertRules>,
+    /// Accepted HTTP protocol versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocols: Option<HttpProtocols>,
+    /// Protocol used for connections to the destination URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_protocol: Option<UpstreamProtocol>,
+    /// WASM request filters, applied in order after authorization
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filters: Option<Vec<WasmFilter>>,
+    /// Number of recent requests to record timings of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_tap: Option<usize>,
+    /// Upstream response headers to remove before responding.
+    /// Names ending with `*` match a prefix, e.g. `X-Backend-*`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_response_headers: Option<Vec<String>>,
+    /// Rewrite `Location` headers of upstream redirects pointing at the destination URL,
+    /// absolute or root-relative, so that clients are redirected to the service endpoint instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite_redirects: Option<bool>,
+    /// Rewrite `Set-Cookie` paths within the destination URL path to the service endpoint,
+    /// and drop domains pointing at the destination host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite_cookies: Option<bool>,
+    /// Rewrite HTML responses, so that web UIs work when exposed under the service endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite_html: Option<RewriteHtml>,
+    /// Rules of rejecting requests before forwarding
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deny: Option<DenyRules>,
+    /// Client addresses allowed to access the service, checked before authorization
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_policy: Option<AddressPolicy>,
+    /// Maximum number of service users
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_users: Option<usize>,
+    /// HTTP server settings overriding the proxy defaults. Services sharing
+    /// listening addresses need to use the same settings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_tuning: Option<ServerTuning>,
+    /// Answer `OPTIONS` requests with the allowed methods instead of forwarding them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer_options: Option<bool>,
+    /// Passthrough of streamed responses, e.g. Server-Sent Events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub streaming: Option<Streaming>,
+    /// Journal of forwarded requests, kept as evidence of service usage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub journal: Option<RequestJournal>,
+    /// Rate limit of all service requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
 }
 
 impl CreateService {
@@ -152,8 +455,227 @@ impl CreateService {
     }
 }
 
+/// HTTP protocol versions accepted by a service.
+///
+/// Services sharing a listener are all offered both `h2` and `http/1.1`
+/// via ALPN; requests made with a protocol not accepted by the service are
+/// rejected with `505 HTTP Version Not Supported`.
+#[non_exhaustive]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    PartialEq,
+    Deserialize,
+    Serialize,
+    EnumString,
+    EnumVariantNames,
+    IntoStaticStr,
+)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum HttpProtocols {
+    /// HTTP/1.x and HTTP/2
+    Auto,
+    /// HTTP/1.0 and HTTP/1.1 only
+    Http1,
+    /// HTTP/2 only (e.g. gRPC)
+    Http2,
+}
+
+impl Default for HttpProtocols {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+impl HttpProtocols {
+    /// Whether requests made with the HTTP `version` are accepted.
+    pub fn accepts(&self, version: http::Version) -> bool {
+        match self {
+            Self::Auto => true,
+            Self::Http1 => version == http::Version::HTTP_10 || version == http::Version::HTTP_11,
+            Self::Http2 => version == http::Version::HTTP_2,
+        }
+    }
+}
+
+/// Sandboxed WASM module implementing a request policy.
+///
+/// The module exports `memory`, `alloc(len: i32) -> i32` and
+/// `on_request(ptr: i32, len: i32) -> i32`. `on_request` receives a JSON
+/// request description and returns `0` to let the request through or an HTTP
+/// status code to respond with. Request headers can be modified with the
+/// `env.set_header(name_ptr, name_len, value_ptr, value_len)` and
+/// `env.remove_header(name_ptr, name_len)` imports.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct WasmFilter {
+    /// Module file path on disk.
+    pub path: PathBuf,
+    /// Module configuration, passed verbatim with each request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<String>,
+    /// Maximum amount of fuel consumed per request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fuel: Option<u64>,
+    /// Maximum size of the module memory in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_memory: Option<u64>,
+}
+
+/// Client address restrictions. Denied addresses take precedence over allowed ones
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct AddressPolicy {
+    /// Allowed address ranges in the CIDR notation. All addresses are allowed when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow: Option<Vec<String>>,
+    /// Denied address ranges in the CIDR notation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deny: Option<Vec<String>>,
+    /// Allowed ISO 3166-1 country codes. Requires a GeoIP database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow_countries: Option<Vec<String>>,
+    /// Denied ISO 3166-1 country codes. Requires a GeoIP database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deny_countries: Option<Vec<String>>,
+}
+
+/// Streamed responses are sent without buffering and are not subject to the
+/// response body idle timeout. Responses with the `text/event-stream` content type
+/// are always streamed
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Streaming {
+    /// Regular expressions matched against the request path of other streamed responses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paths: Option<Vec<String>>,
+}
+
+/// Binary journal of forwarded requests, rotated by size
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RequestJournal {
+    /// Directory to write journal files to.
+    pub path: PathBuf,
+    /// Size of a journal file in bytes, after which the file is rotated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+    /// Number of rotated journal files to keep.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<usize>,
+}
+
+/// Requests matching any of the rules are rejected with `403 Forbidden`
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct DenyRules {
+    /// Regular expressions matched against the request path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paths: Option<Vec<String>>,
+    /// Allowed request methods. All methods are allowed when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub methods: Option<Vec<String>>,
+    /// Forbidden request header names.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<Vec<String>>,
+    /// Maximum total size of request header names and values, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_header_size: Option<usize>,
+}
+
+/// Rewriting of HTML responses of services exposed under a sub-path
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RewriteHtml {
+    /// Point `<base href>` at the service endpoint, inserting the element when missing.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub base_href: bool,
+    /// String substitutions, applied in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replace: Vec<Substitution>,
+    /// Maximum size of rewritten responses, in bytes. Larger responses are forwarded as is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_body_size: Option<usize>,
+}
+
+/// Replaces all occurrences of `from` with `to`
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Substitution {
+    /// Replaced string.
+    pub from: String,
+    /// Replacement.
+    pub to: String,
+}
+
+/// Request description passed to WASM filters
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct WasmFilterRequest {
+    /// Service name.
+    pub service: String,
+    /// Request method.
+    pub method: String,
+    /// Request path and query.
+    pub path: String,
+    /// Request headers with valid UTF-8 values.
+    pub headers: HashMap<String, String>,
+    /// Name of the authorized user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Client address.
+    pub remote_addr: String,
+    /// Filter configuration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<String>,
+}
+
+/// Protocol used for upstream connections
+#[non_exhaustive]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    PartialEq,
+    Deserialize,
+    Serialize,
+    EnumString,
+    EnumVariantNames,
+    IntoStaticStr,
+)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum UpstreamProtocol {
+    /// HTTP/1.1 over cleartext or TLS, depending on the destination URL scheme
+    Http1,
+    /// HTTP/2 over cleartext, with prior knowledge
+    H2c,
+    /// HTTP/2 over TLS
+    H2,
+    /// HTTP/2 when negotiated via ALPN, HTTP/1.1 otherwise
+    Auto,
+}
+
+impl Default for UpstreamProtocol {
+    fn default() -> Self {
+        Self::Http1
+    }
+}
+
 /// HTTP request forward options
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct CreateServiceUser {
     /// Authorization options
@@ -161,24 +683,122 @@ pub struct CreateServiceUser {
     /// Timeout configuration
     #[serde(flatten)]
     pub timeouts: Option<Timeouts>,
+    /// Rate limit of requests made by each user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Request rate limit. Requests exceeding the limit are answered with
+/// `429 Too Many Requests`
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimit {
+    /// Sustained number of requests per second.
+    pub requests_per_second: u32,
+    /// Number of requests allowed at once. Defaults to `requestsPerSecond`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+}
+
+impl RateLimit {
+    /// Number of requests allowed at once
+    pub fn burst(&self) -> u32 {
+        self.burst.unwrap_or(self.requests_per_second).max(1)
+    }
+}
+
+/// Service alert rules, evaluated over a sliding window of requests
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct AlertRules {
+    /// Maximum percentage of failed requests (upstream errors and 5xx responses).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_rate: Option<u32>,
+    /// Maximum 99th percentile of upstream response latency.
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub p99_latency: Option<Duration>,
+    /// Maximum time without any requests.
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub idle: Option<Duration>,
+    /// Evaluation window. Defaults to 1 minute.
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub window: Option<Duration>,
+}
+
+/// Alert raised for a service
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+    /// Service name.
+    pub service: String,
+    /// Violated rule.
+    pub kind: AlertKind,
+    /// Observed value: percentage for `errorRate`, milliseconds otherwise.
+    pub value: u64,
+    /// Rule threshold, in units of `value`.
+    pub threshold: u64,
+    /// Time when the alert was raised.
+    pub since: DateTime<Utc>,
+}
+
+/// Alert rule kind
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum AlertKind {
+    /// Failed request percentage exceeded
+    ErrorRate,
+    /// Response latency exceeded
+    P99Latency,
+    /// No traffic
+    Idle,
+    /// Rule kind introduced in a newer version
+    #[serde(other)]
+    Unknown,
 }
 
 /// Service certificate configuration
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct CreateServiceCert {
     /// Hash Sha3_256 of ssl certificate.
     #[serde(default)]
     pub hash: String,
     /// Certificate path on disk.
+    #[serde(default)]
     pub path: PathBuf,
     /// certificate key.
+    #[serde(default)]
     pub key_path: PathBuf,
+    /// Obtain and renew the certificate for `serverName` domains via ACME (e.g. Let's Encrypt),
+    /// instead of reading it from `path` and `keyPath`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub acme: bool,
 }
 
 impl PartialEq for CreateServiceCert {
     fn eq(&self, other: &Self) -> bool {
-        self.path == other.path && self.key_path == other.key_path
+        self.path == other.path && self.key_path == other.key_path && self.acme == other.acme
     }
 }
 
@@ -186,60 +806,1088 @@ impl Eq for CreateServiceCert {}
 
 /// New user descriptor
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase", from = "CreateUserRepr")]
 pub struct CreateUser {
     /// Http auth user name.
     pub username: String,
-    /// Password for new user.
-    pub password: String,
+    /// Credentials for new user.
+    pub credentials: Credentials,
+    /// Credentials usage limits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<UserLimits>,
+}
+
+impl CreateUser {
+    /// Creates a new user authorized with HTTP basic auth.
+    pub fn basic(username: impl ToString, password: impl ToString) -> Self {
+        Self {
+            username: username.to_string(),
+            credentials: Credentials::Basic {
+                password: password.to_string().into(),
+            },
+            limits: None,
+        }
+    }
+
+    /// Creates a new user authorized with an API key.
+    pub fn api_key(username: impl ToString, key: impl ToString) -> Self {
+        Self {
+            username: username.to_string(),
+            credentials: Credentials::ApiKey {
+                key: key.to_string().into(),
+            },
+            limits: None,
+        }
+    }
+}
+
+/// Accepts the current user descriptor, the API key shorthand (`username` & `apiKey`)
+/// and the legacy (`username` & `password`) descriptor
+#[derive(Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase", untagged)]
+enum CreateUserRepr {
+    Current {
+        username: String,
+        credentials: Credentials,
+        #[serde(default)]
+        limits: Option<UserLimits>,
+    },
+    ApiKey {
+        username: String,
+        #[serde(rename = "apiKey")]
+        api_key: Secret<String>,
+        #[serde(default)]
+        limits: Option<UserLimits>,
+    },
+    Legacy {
+        username: String,
+        password: String,
+    },
+}
+
+impl From<CreateUserRepr> for CreateUser {
+    fn from(repr: CreateUserRepr) -> Self {
+        match repr {
+            CreateUserRepr::Current {
+                username,
+                credentials,
+                limits,
+            } => Self {
+                username,
+                credentials,
+                limits,
+            },
+            CreateUserRepr::ApiKey {
+                username,
+                api_key,
+                limits,
+            } => Self {
+                username,
+                credentials: Credentials::ApiKey { key: api_key },
+                limits,
+            },
+            CreateUserRepr::Legacy { username, password } => Self::basic(username, password),
+        }
+    }
+}
+
+/// User credentials usage limits. Credentials are revoked once a limit is reached
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UserLimits {
+    /// Number of requests the credentials are valid for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests: Option<usize>,
+    /// Bind credentials to the IP address of the first client.
+    /// Requests from other addresses are rejected.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub single_session: bool,
+    /// Number of request and response body bytes the credentials are valid for.
+    /// Request and response bodies exceeding the limit are aborted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+/// Usage paid for by the requestor. Requests beyond the allowance are rejected with
+/// `402 Payment Required` until the allowance is raised; credentials are not revoked
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UserAllowance {
+    /// Number of requests allowed with the current credentials.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests: Option<usize>,
+    /// Number of request and response body bytes allowed with the current credentials.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+/// User credentials
+#[non_exhaustive]
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase", tag = "method")]
+pub enum Credentials {
+    /// HTTP basic auth password
+    Basic {
+        /// User password.
+        password: Secret<String>,
+    },
+    /// HTTP bearer token
+    Bearer {
+        /// Secret token.
+        token: Secret<String>,
+    },
+    /// API key
+    ApiKey {
+        /// Secret key.
+        key: Secret<String>,
+    },
+    /// Client certificate fingerprint
+    CertFingerprint {
+        /// Hex-encoded Sha3_256 hash of the DER-encoded client certificate.
+        fingerprint: String,
+    },
+}
+
+impl Credentials {
+    /// Creates credentials for the given auth method from a secret value.
+    /// Returns `None` for methods that do not use per-user credentials.
+    pub fn new(method: AuthMethod, secret: impl ToString) -> Option<Self> {
+        let secret = secret.to_string();
+        let credentials = match method {
+            AuthMethod::Basic => Self::Basic {
+                password: secret.into(),
+            },
+            AuthMethod::Bearer => Self::Bearer {
+                token: secret.into(),
+            },
+            AuthMethod::ApiKey => Self::ApiKey { key: secret.into() },
+            AuthMethod::CertFingerprint => Self::CertFingerprint {
+                fingerprint: secret,
+            },
+            AuthMethod::External | AuthMethod::Jwt => return None,
+        };
+        Some(credentials)
+    }
+
+    /// Auth method the credentials are used with.
+    pub fn method(&self) -> AuthMethod {
+        match self {
+            Self::Basic { .. } => AuthMethod::Basic,
+            Self::Bearer { .. } => AuthMethod::Bearer,
+            Self::ApiKey { .. } => AuthMethod::ApiKey,
+            Self::CertFingerprint { .. } => AuthMethod::CertFingerprint,
+        }
+    }
 }
 
 /// User descriptor
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct User {
     /// User name.
     pub username: String,
+    /// Auth method.
+    #[serde(default)]
+    pub method: AuthMethod,
+    /// Credentials usage limits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<UserLimits>,
+    /// Fingerprint of the credentials, reported in request records. Keyed with a secret of
+    /// the proxy instance, so it cannot be checked against guessed credentials.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Time when user was created.
+    pub created_at: DateTime<Utc>,
+}
+
+/// User credentials replacement. The user keeps its statistics
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateUser {
+    /// New user credentials.
+    pub credentials: Credentials,
+    /// Usage limits of the new credentials. Defaults to the current user limits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<UserLimits>,
+}
+
+impl UpdateUser {
+    /// Replaces user credentials with an HTTP basic auth password.
+    pub fn basic(password: impl ToString) -> Self {
+        Self {
+            credentials: Credentials::Basic {
+                password: password.to_string().into(),
+            },
+            limits: None,
+        }
+    }
+
+    /// Replaces user credentials with an API key.
+    pub fn api_key(key: impl ToString) -> Self {
+        Self {
+            credentials: Credentials::ApiKey {
+                key: key.to_string().into(),
+            },
+            limits: None,
+        }
+    }
+}
+
+/// Credential rotation request
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RotateCredentials {
+    /// Auth method of the new credentials. Defaults to the current user method
+    /// or HTTP basic auth for new users.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<AuthMethod>,
+    /// Usage limits of the new credentials. Defaults to the current user limits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<UserLimits>,
+}
+
+/// Generated user credentials. Returned only once, never stored by the proxy
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UserCredentials {
+    /// User name.
+    pub username: String,
+    /// Generated credentials.
+    pub credentials: Credentials,
+    /// Non-reversible fingerprint of the credentials.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
     /// Time when user was created.
     pub created_at: DateTime<Utc>,
 }
 
+impl From<UserCredentials> for User {
+    fn from(c: UserCredentials) -> Self {
+        Self {
+            username: c.username,
+            method: c.credentials.method(),
+            limits: None,
+            fingerprint: c.fingerprint,
+            created_at: c.created_at,
+        }
+    }
+}
+
+/// Proxy event
+#[non_exhaustive]
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize, IntoStaticStr)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase", tag = "event")]
+#[strum(serialize_all = "camelCase")]
+pub enum Event {
+    /// Service was created
+    ServiceCreated {
+        /// Service name.
+        service: String,
+    },
+    /// Service was removed
+    ServiceRemoved {
+        /// Service name.
+        service: String,
+    },
+    /// Service definition was updated
+    ServiceUpdated {
+        /// Service name.
+        service: String,
+    },
+    /// User was created
+    UserCreated {
+        /// Service name.
+        service: String,
+        /// User name.
+        username: String,
+    },
+    /// User was removed
+    UserRemoved {
+        /// Service name.
+        service: String,
+        /// User name.
+        username: String,
+    },
+    /// User credentials were replaced
+    UserUpdated {
+        /// Service name.
+        service: String,
+        /// User name.
+        username: String,
+    },
+    /// User credentials were revoked by the proxy
+    UserRevoked {
+        /// Service name.
+        service: String,
+        /// User name.
+        username: String,
+        /// Revocation reason.
+        reason: RevokeReason,
+    },
+    /// Service upstream cannot be reached
+    UpstreamUnavailable {
+        /// Service name.
+        service: String,
+        /// Error description.
+        reason: String,
+    },
+    /// Service upstream can be reached again
+    UpstreamRecovered {
+        /// Service name.
+        service: String,
+    },
+    /// Service alert rule was violated
+    AlertRaised(Alert),
+    /// Service alert rule is no longer violated
+    AlertResolved(Alert),
+    /// Event introduced in a newer version
+    #[serde(other)]
+    Unknown,
+}
+
+impl Event {
+    /// Event name, as serialized in the `event` field.
+    pub fn name(&self) -> &'static str {
+        self.into()
+    }
+
+    /// Name of the service the event refers to.
+    pub fn service(&self) -> Option<&str> {
+        let service = match self {
+            Self::ServiceCreated { service }
+            | Self::ServiceRemoved { service }
+            | Self::ServiceUpdated { service }
+            | Self::UserCreated { service, .. }
+            | Self::UserRemoved { service, .. }
+            | Self::UserUpdated { service, .. }
+            | Self::UserRevoked { service, .. }
+            | Self::UpstreamUnavailable { service, .. }
+            | Self::UpstreamRecovered { service } => service,
+            Self::AlertRaised(alert) | Self::AlertResolved(alert) => &alert.service,
+            Self::Unknown => return None,
+        };
+        Some(service)
+    }
+}
+
+/// Timestamped event notification
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct EventRecord {
+    #[allow(missing_docs)]
+    #[serde(flatten)]
+    pub event: Event,
+    /// Time when the event occurred.
+    pub created_at: DateTime<Utc>,
+    /// Correlation ID of the Management API request causing the event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl EventRecord {
+    /// Record of an event caused by a Management API request.
+    pub fn correlated(event: Event, correlation_id: Option<&str>) -> Self {
+        Self {
+            correlation_id: correlation_id.map(ToString::to_string),
+            ..Self::from(event)
+        }
+    }
+}
+
+impl From<Event> for EventRecord {
+    fn from(event: Event) -> Self {
+        Self {
+            event,
+            created_at: Utc::now(),
+            correlation_id: None,
+        }
+    }
+}
+
+/// Credentials revocation reason
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum RevokeReason {
+    /// Request limit has been reached
+    RequestLimit,
+    /// Credentials were used outside of the bound client session
+    SessionEnded,
+    /// Byte quota has been exhausted
+    ByteLimit,
+    /// Reason introduced in a newer version
+    #[serde(other)]
+    Unknown,
+}
+
 /// Aggregated user statistics
 #[derive(Clone, Default, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct UserStats {
     /// Number of user requests.
     pub requests: usize,
+    /// Number of request and response body bytes transferred by the user.
+    #[serde(default)]
+    pub bytes: u64,
+    /// Rate limiter state, when requests are rate limited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitStatus>,
+}
+
+/// Rate limiter state
+#[derive(Clone, Default, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitStatus {
+    /// Sustained number of requests per second.
+    pub requests_per_second: u32,
+    /// Number of requests allowed at once.
+    pub burst: u32,
+    /// Number of requests that can be made immediately.
+    pub available: u32,
+    /// Number of requests rejected for exceeding the limit.
+    pub limited: u64,
+}
+
+/// Credentials usage limits of a user, along with the current usage
+#[derive(Clone, Default, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UserLimitsStatus {
+    /// User name.
+    pub username: String,
+    /// Number of requests the credentials are valid for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests: Option<usize>,
+    /// Number of requests made with the current credentials.
+    pub requests: usize,
+    /// Number of requests left before the credentials are revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining_requests: Option<usize>,
+    /// Credentials are bound to the IP address of the first client.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub single_session: bool,
+    /// Credentials are already bound to a client address.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub session_bound: bool,
+    /// Number of bytes the credentials are valid for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    /// Number of bytes transferred with the current credentials.
+    #[serde(default)]
+    pub bytes: u64,
+    /// Number of bytes left before the credentials are revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining_bytes: Option<u64>,
+    /// Usage paid for by the requestor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowance: Option<UserAllowance>,
+}
+
+/// Aggregated service statistics
+#[derive(Clone, Default, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceStats {
+    /// Number of registered users.
+    pub users: usize,
+    /// Number of requests made to the service.
+    pub requests: usize,
+    /// Number of request and response body bytes transferred by service users.
+    #[serde(default)]
+    pub bytes: u64,
+    /// Number of requests per negotiated protocol (e.g. `h2`, `http/1.1`).
+    #[serde(default)]
+    pub protocols: HashMap<String, usize>,
+    /// Number of requests rejected by the service deny rules or address policy.
+    #[serde(default)]
+    pub blocked: usize,
+    /// Outcomes of requests forwarded to each upstream server.
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamStats>,
+    /// Number of TLS handshakes timed out on the service listening addresses,
+    /// shared with other services bound to the same addresses.
+    #[serde(default)]
+    pub tls_handshake_timeouts: usize,
+}
+
+/// Outcomes of requests forwarded to an upstream server
+#[derive(Clone, Default, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamStats {
+    /// Upstream server URL.
+    pub target: String,
+    /// Whether the upstream server accepted the last forwarded connection.
+    pub available: bool,
+    /// Number of forwarded requests.
+    pub requests: usize,
+    /// Number of requests failed to connect to the upstream server.
+    pub connect_errors: usize,
+    /// Number of requests timed out.
+    pub timeouts: usize,
+    /// Number of `5xx` responses.
+    pub server_errors: usize,
+    /// Number of requests failed for other reasons.
+    pub other_errors: usize,
+    /// Most recent request error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Time of the most recent request error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error_at: Option<DateTime<Utc>>,
+}
+
+/// Timing phases of a forwarded request
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RequestTiming {
+    /// Time the request was received at.
+    pub received_at: DateTime<Utc>,
+    /// Request method.
+    pub method: String,
+    /// Request path.
+    pub path: String,
+    /// Name of the authorized user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Fingerprint of the credentials the request was authorized with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Upstream response status code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Upstream request error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Time spent in the proxy before forwarding (authorization and filters).
+    #[serde(with = "deser::duration::ms")]
+    #[cfg_attr(feature = "schema", schemars(with = "u64"))]
+    pub queue: Duration,
+    /// Time spent establishing a new upstream connection.
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub connect: Option<Duration>,
+    /// Time to the first byte of the upstream response.
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub ttfb: Option<Duration>,
+    /// Time until the response was sent to the client.
+    #[serde(with = "deser::duration::ms")]
+    #[cfg_attr(feature = "schema", schemars(with = "u64"))]
+    pub total: Duration,
+}
+
+/// Runtime diagnostics of a proxy instance
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyRuntime {
+    /// Listening addresses.
+    pub addresses: Addresses,
+    /// Names of the services served by the proxy.
+    pub services: Vec<String>,
+    /// Name of the runtime worker threads.
+    pub thread_name: String,
+    /// Number of runtime worker threads, unless chosen by the runtime.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_threads: Option<usize>,
+    /// Number of open client connections.
+    pub connections: usize,
+    /// Number of client connections accepted since start.
+    pub connections_total: usize,
+    /// Number of requests being handled.
+    pub active_requests: usize,
+    /// Number of times request handling tasks were polled.
+    pub polls: u64,
+    /// Total time spent polling request handling tasks.
+    #[serde(with = "deser::duration::ms")]
+    #[cfg_attr(feature = "schema", schemars(with = "u64"))]
+    pub poll_time: Duration,
+    /// Longest single poll of a request handling task.
+    #[serde(with = "deser::duration::ms")]
+    #[cfg_attr(feature = "schema", schemars(with = "u64"))]
+    pub max_poll_time: Duration,
+    /// Worker threads available to services with a `cpuThreads` limit.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub concurrency: Vec<ServiceConcurrency>,
+}
+
+/// Share of proxy worker threads available to a service
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceConcurrency {
+    /// Service name.
+    pub service: String,
+    /// Number of threads requested by the service.
+    pub cpu_threads: usize,
+    /// Number of threads the service may occupy at once, up to the runtime worker threads.
+    pub limit: usize,
+    /// Number of threads handling service requests.
+    pub active: usize,
+    /// Number of times service requests waited for a thread.
+    pub throttled: u64,
+}
+
+/// Resources a service shares with other services, e.g. to verify the isolation
+/// advertised in offers
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceIsolation {
+    /// Service name.
+    pub service: String,
+    /// Listening addresses.
+    pub addresses: Addresses,
+    /// Services accepting connections on the same addresses, with the same TLS
+    /// certificate and HTTP server settings.
+    pub listener: Vec<String>,
+    /// Services handled by the same proxy worker threads.
+    pub runtime_threads: Vec<String>,
+    /// Number of runtime worker threads, unless chosen by the runtime.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_threads: Option<usize>,
+    /// Number of worker threads the service may occupy at once, when limited by `cpuThreads`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_threads: Option<usize>,
+    /// Services sharing connections to upstream servers.
+    pub client_pool: Vec<String>,
+    /// No resources are shared with other services.
+    pub isolated: bool,
 }
 
 /// User statistics per endpoint
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct UserEndpointStats(pub HashMap<String, usize>);
 
+/// Request and response body bytes transferred by a user, per endpoint
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UserEndpointBytes(pub HashMap<String, u64>);
+
 /// Timeout configuration
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct Timeouts {
-    /// Timeout for requests.
+    /// Max time from receiving a request until its response body is sent.
     #[serde(with = "deser::duration::opt_ms")]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
     pub request_timeout: Option<Duration>,
-    /// Max wait time for response.
+    /// Max wait time for the upstream response headers, once the request body is sent.
     #[serde(with = "deser::duration::opt_ms")]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
     pub response_timeout: Option<Duration>,
+    /// Max wait time for the next chunk of a response body.
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub response_idle_timeout: Option<Duration>,
+}
+
+/// HTTP server settings of the proxy instance hosting a service
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ServerTuning {
+    /// Whether HTTP/1 connections are kept alive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http1_keepalive: Option<bool>,
+    /// HTTP/2 stream-level flow control window size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http2_initial_stream_window_size: Option<u32>,
+    /// HTTP/2 connection-level flow control window size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http2_initial_connection_window_size: Option<u32>,
+    /// Whether HTTP/2 window sizes are adjusted to the connection bandwidth.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http2_adaptive_window: Option<bool>,
+    /// Maximum number of concurrent HTTP/2 streams per connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http2_max_concurrent_streams: Option<u32>,
+    /// Interval of HTTP/2 keep-alive pings.
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// Time to wait for a HTTP/2 keep-alive ping acknowledgement.
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub http2_keep_alive_timeout: Option<Duration>,
 }
 
 /// Error response
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct ErrorResponse {
     /// Human readable error message.
     pub message: String,
+    /// Machine readable error code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// Suggested delay before retrying the request.
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub retry_after: Option<Duration>,
+    /// Properties of the resources involved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<HashMap<String, String>>,
+}
+
+impl ErrorResponse {
+    /// Creates an error response with a message only.
+    pub fn new(message: impl ToString) -> Self {
+        Self {
+            message: message.to_string(),
+            code: None,
+            retry_after: None,
+            details: None,
+        }
+    }
+}
+
+/// Management API status
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    /// Proxy binary version.
+    pub version: String,
+    /// Management API version.
+    pub api_version: String,
+    /// Proxy process id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    /// Whether the proxy is draining and no longer accepts new services.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub draining: bool,
+    /// Confirmation of shutdown and drain requests, generated when the proxy starts.
+    /// Not reported by older proxy versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shutdown_nonce: Option<String>,
+}
+
+impl Status {
+    /// Checks whether the reported Management API version is compatible with `API_VERSION`.
+    pub fn is_compatible(&self) -> bool {
+        versions_compatible(&self.api_version, API_VERSION)
+    }
+}
+
+/// Payload tagged with the Management API version of its producer.
+///
+/// Payload fields are flattened, so that versions unaware of the envelope read
+/// enveloped payloads, and payloads of such versions are read without a version.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Versioned<T> {
+    /// Management API version of the producer. Missing in payloads of older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+    #[allow(missing_docs)]
+    #[serde(flatten)]
+    pub payload: T,
+}
+
+impl<T> Versioned<T> {
+    /// Tags the payload with `API_VERSION`.
+    pub fn new(payload: T) -> Self {
+        Self {
+            api_version: Some(API_VERSION.to_string()),
+            payload,
+        }
+    }
+
+    /// Checks whether the producer version is compatible with `API_VERSION`.
+    /// Payloads of versions preceding the envelope are assumed to be compatible.
+    pub fn is_compatible(&self) -> bool {
+        match self.api_version {
+            Some(ref version) => versions_compatible(version, API_VERSION),
+            None => true,
+        }
+    }
+
+    /// Unwraps the payload.
+    pub fn into_inner(self) -> T {
+        self.payload
+    }
+}
+
+/// Contents of the file published once the proxy is ready to accept Management API requests
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Ready {
+    /// Proxy process id.
+    pub pid: u32,
+    /// Proxy binary version.
+    pub version: String,
+    /// Management API version.
+    pub api_version: String,
+    /// Management API URL.
+    pub management_url: String,
+    /// Address the Management API is bound to.
+    pub management_addr: SocketAddr,
+}
+
+/// Verifies that a proxy instance name, given with `--instance-name`, can be used
+/// in file names and directory names.
+pub fn check_instance_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_INSTANCE_NAME_LEN {
+        return Err(format!(
+            "instance name must be 1 to {} characters long",
+            MAX_INSTANCE_NAME_LEN
+        ));
+    }
+    match name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_'))
+    {
+        Some(c) => Err(format!("invalid character '{}' in instance name", c)),
+        None => Ok(()),
+    }
+}
+
+/// Encodes a name, e.g. of a service, as a file name. ASCII letters, digits, `-` and `.`
+/// are kept, other bytes are written as `_` followed by their hex value, so that distinct
+/// names are stored in distinct files. A leading `.` is encoded as well.
+pub fn file_name(name: &str) -> String {
+    if name.is_empty() {
+        return "_".to_string();
+    }
+    let mut encoded = String::with_capacity(name.len());
+    for (i, b) in name.bytes().enumerate() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' => encoded.push(b as char),
+            b'.' if i > 0 => encoded.push('.'),
+            b => encoded.push_str(&format!("_{:02x}", b)),
+        }
+    }
+    encoded
+}
+
+/// Default Management API address of a proxy instance. Named instances use a port
+/// derived from the name, following [`DEFAULT_MANAGEMENT_PORT`].
+pub fn instance_management_addr(instance_name: Option<&str>) -> SocketAddr {
+    let port = match instance_name {
+        Some(name) => {
+            let offset = fnv1a(name) % INSTANCE_PORT_RANGE as u64;
+            DEFAULT_MANAGEMENT_PORT + 1 + offset as u16
+        }
+        None => DEFAULT_MANAGEMENT_PORT,
+    };
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/// FNV-1a, stable across builds
+fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Journaled request
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct JournalEntry {
+    /// Time the request was received at, with millisecond precision.
+    pub timestamp: DateTime<Utc>,
+    /// Name of the authorized user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Hex-encoded prefix of the SHA3-256 digest of the request path.
+    pub path_hash: String,
+    /// Number of response body bytes sent to the client.
+    pub bytes: u64,
+    /// Upstream response status code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+}
+
+/// Time range query parameters
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct TimeRange {
+    /// Only include requests received at or after the time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<DateTime<Utc>>,
+    /// Only include requests received before the time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Service usage aggregated per user
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceUsage {
+    /// Start of the time range.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<DateTime<Utc>>,
+    /// End of the time range.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<DateTime<Utc>>,
+    /// Whether the usage was read from the request journal. Otherwise, requests
+    /// are counted in hourly buckets overlapping the time range, within the proxy
+    /// stats retention period.
+    pub journaled: bool,
+    /// Usage of each user.
+    pub users: Vec<UserUsage>,
+}
+
+/// Service usage of a single user
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UserUsage {
+    /// Name of the user.
+    pub username: String,
+    /// Number of forwarded requests.
+    pub requests: usize,
+    /// Number of response body bytes sent, when read from the request journal.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+/// Log stream query parameters
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct LogQuery {
+    /// Only include lines referring to the service.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    /// Number of recent lines to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tail: Option<usize>,
+    /// Keep streaming lines logged afterwards.
+    #[serde(default)]
+    pub follow: bool,
+}
+
+/// Proxy shutdown request
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Shutdown {
+    /// Shutdown nonce reported by the proxy in [`Status::shutdown_nonce`].
+    pub confirm: String,
+    /// Time to wait for in-flight requests to complete.
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub drain: Option<Duration>,
+}
+
+impl Shutdown {
+    /// Shutdown request confirmed with the proxy shutdown nonce.
+    pub fn new(nonce: impl Into<String>, drain: Option<Duration>) -> Self {
+        Self {
+            confirm: nonce.into(),
+            drain,
+        }
+    }
+
+    /// Checks whether the request carries the shutdown nonce.
+    pub fn is_confirmed(&self, nonce: &str) -> bool {
+        self.confirm == nonce
+    }
+}
+
+/// Server certificate served by a proxy instance
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ServerCertInfo {
+    /// Hash Sha3_256 of the certificate file.
+    pub hash: String,
+    /// Time when the certificate was loaded.
+    pub loaded_at: DateTime<Utc>,
+}
+
+/// Proxy drain request
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Drain {
+    /// Shutdown nonce reported by the proxy in [`Status::shutdown_nonce`].
+    pub confirm: String,
+    /// Time to wait for in-flight requests to complete, the proxy drain timeout by default.
+    #[serde(
+        default,
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Option<u64>"))]
+    pub timeout: Option<Duration>,
+}
+
+impl Drain {
+    /// Drain request confirmed with the proxy shutdown nonce.
+    pub fn new(nonce: impl Into<String>, timeout: Option<Duration>) -> Self {
+        Self {
+            confirm: nonce.into(),
+            timeout,
+        }
+    }
+
+    /// Checks whether the request carries the shutdown nonce.
+    pub fn is_confirmed(&self, nonce: &str) -> bool {
+        self.confirm == nonce
+    }
 }
 
 /// Global statistics
 /// FIXME: introduce per-runtime instead of global statistics
 #[derive(Clone, Default, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct GlobalStats {
     /// Number of registered users.
@@ -250,9 +1898,141 @@ pub struct GlobalStats {
     pub requests: UserStats,
 }
 
+fn versions_compatible(left: &str, right: &str) -> bool {
+    fn parse(version: &str) -> Option<(u64, u64)> {
+        let mut split = version.trim().splitn(3, '.');
+        let major = split.next()?.parse().ok()?;
+        let minor = split.next()?.parse().ok()?;
+        Some((major, minor))
+    }
+
+    match (parse(left), parse(right)) {
+        (Some((0, l_minor)), Some((0, r_minor))) => l_minor == r_minor,
+        (Some((l_major, _)), Some((r_major, _))) => l_major == r_major,
+        _ => false,
+    }
+}
+
 fn next_service_name() -> String {
     use std::sync::atomic::{AtomicUsize, Ordering};
     static ATOMIC_ID: AtomicUsize = AtomicUsize::new(0);
     let id = ATOMIC_ID.fetch_add(1, Ordering::SeqCst);
     format!("service-{}", id)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_instance_name, file_name, instance_management_addr, versions_compatible, CreateUser,
+        Credentials, ErrorResponse, Event, EventRecord, HttpProtocols, RevokeReason,
+        DEFAULT_MANAGEMENT_PORT,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn version_compatibility() {
+        assert!(versions_compatible("0.3.0", "0.3.2"));
+        assert!(versions_compatible("1.2.0", "1.0.7"));
+        assert!(!versions_compatible("0.2.9", "0.3.0"));
+        assert!(!versions_compatible("1.0.0", "2.0.0"));
+        assert!(!versions_compatible("unknown", "0.3.0"));
+    }
+
+    #[test]
+    fn create_user_deser() -> serde_json::Result<()> {
+        let legacy: CreateUser =
+            serde_json::from_str(r#"{"username": "user", "password": "secret"}"#)?;
+        assert_eq!(legacy, CreateUser::basic("user", "secret"));
+
+        let current: CreateUser = serde_json::from_str(
+            r#"{"username": "user", "credentials": {"method": "bearer", "token": "secret"}}"#,
+        )?;
+        assert_eq!(
+            current.credentials,
+            Credentials::Bearer {
+                token: "secret".into()
+            }
+        );
+        assert!(!format!("{:?}", current).contains("secret"));
+
+        let serialized = serde_json::to_string(&legacy)?;
+        assert_eq!(serde_json::from_str::<CreateUser>(&serialized)?, legacy);
+        Ok(())
+    }
+
+    #[test]
+    fn event_name() -> serde_json::Result<()> {
+        let event = Event::UserRevoked {
+            service: "service".to_string(),
+            username: "user".to_string(),
+            reason: RevokeReason::RequestLimit,
+        };
+        let value = serde_json::to_value(EventRecord::from(event.clone()))?;
+
+        assert_eq!(value["event"].as_str(), Some(event.name()));
+        assert_eq!(value["reason"].as_str(), Some("requestLimit"));
+        assert!(value.get("correlationId").is_none());
+
+        let record = EventRecord::correlated(event, Some("activity/1"));
+        let value = serde_json::to_value(&record)?;
+        assert_eq!(value["correlationId"].as_str(), Some("activity/1"));
+        assert_eq!(record.event.service(), Some("service"));
+        Ok(())
+    }
+
+    #[test]
+    fn error_response_deser() -> serde_json::Result<()> {
+        let legacy: ErrorResponse = serde_json::from_str(r#"{"message":"error"}"#)?;
+        assert_eq!(legacy, ErrorResponse::new("error"));
+
+        let response: ErrorResponse = serde_json::from_str(
+            r#"{"message":"error","code":"userNotFound","retryAfter":1500,"details":{"user":"u"}}"#,
+        )?;
+        assert_eq!(response.code.as_deref(), Some("userNotFound"));
+        assert_eq!(response.retry_after, Some(Duration::from_millis(1500)));
+        assert_eq!(
+            response.details.unwrap().get("user").map(String::as_str),
+            Some("u")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn http_protocols() -> serde_json::Result<()> {
+        let protocols: HttpProtocols = serde_json::from_str(r#""http2""#)?;
+        assert_eq!(protocols, HttpProtocols::Http2);
+        assert!(protocols.accepts(http::Version::HTTP_2));
+        assert!(!protocols.accepts(http::Version::HTTP_11));
+
+        assert!(HttpProtocols::Http1.accepts(http::Version::HTTP_10));
+        assert!(!HttpProtocols::Http1.accepts(http::Version::HTTP_2));
+        assert!(HttpProtocols::default().accepts(http::Version::HTTP_2));
+        Ok(())
+    }
+
+    #[test]
+    fn instance_names() {
+        assert!(check_instance_name("node-1_test").is_ok());
+        assert!(check_instance_name("").is_err());
+        assert!(check_instance_name("../node").is_err());
+
+        assert_eq!(
+            instance_management_addr(None).port(),
+            DEFAULT_MANAGEMENT_PORT
+        );
+        let port = instance_management_addr(Some("node-1")).port();
+        assert!(port > DEFAULT_MANAGEMENT_PORT);
+        assert_eq!(instance_management_addr(Some("node-1")).port(), port);
+        assert_ne!(instance_management_addr(Some("node-2")).port(), port);
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(file_name("svc-1.v2"), "svc-1.v2");
+        assert_eq!(file_name("svc/1"), "svc_2f1");
+        assert_eq!(file_name("svc_1"), "svc_5f1");
+        assert_eq!(file_name(".."), "_2e.");
+        assert_eq!(file_name("ż"), "_c5_bc");
+        assert_eq!(file_name(""), "_");
+    }
+}
@@ -0,0 +1,343 @@
+//! OpenAPI document of the Management API, which Python and TypeScript API clients
+//! are generated from
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+use crate::*;
+
+/// OpenAPI 3 document describing all Management API routes
+pub fn document() -> Value {
+    let mut api = Api::new();
+
+    api.get::<Status>("/status", "getStatus", "Management API status");
+    let html = json!({"type": "string"});
+    api.get_content(
+        "/dashboard",
+        "getDashboard",
+        "Web dashboard",
+        "text/html",
+        html,
+    );
+    let event = api.schema::<EventRecord>();
+    api.get_content(
+        "/events",
+        "getEvents",
+        "Streams events of all proxy instances as Server-Sent Events",
+        "text/event-stream",
+        event,
+    );
+    api.get::<Vec<Service>>("/services", "getServices", "Lists services");
+    api.create::<CreateService, Service>("/services", "createService", "Creates a service");
+    api.get::<Service>("/services/{service}", "getService", "Retrieves a service");
+    api.put::<CreateService, Service>(
+        "/services/{service}",
+        "updateService",
+        "Replaces the service definition, keeping its users and stats",
+    );
+    api.delete("/services/{service}", "deleteService", "Removes a service");
+    let cert = api.schema::<ServerCertInfo>();
+    api.operation(
+        "post",
+        "/services/{service}/cert/reload",
+        "reloadCert",
+        "Reloads the certificate served by the proxy instance of the service",
+        None,
+        Some(("200", cert)),
+    );
+    api.get::<ServiceStats>(
+        "/services/{service}/stats",
+        "getServiceStats",
+        "Aggregated service statistics",
+    );
+    api.get::<ServiceIsolation>(
+        "/services/{service}/isolation",
+        "getServiceIsolation",
+        "Resources the service shares with other services",
+    );
+    api.get::<Vec<RequestTiming>>(
+        "/services/{service}/debug/requests",
+        "getRequestTimings",
+        "Timings of recent service requests",
+    );
+    let journal = api.get::<Vec<JournalEntry>>(
+        "/services/{service}/journal",
+        "getJournal",
+        "Exports request journal entries from a time range",
+    );
+    query(journal, &["from", "to"]);
+    let usage = api.get::<ServiceUsage>(
+        "/services/{service}/usage",
+        "getUsage",
+        "Service usage per user over a time range",
+    );
+    query(usage, &["from", "to"]);
+
+    api.get::<Vec<User>>(
+        "/services/{service}/users",
+        "getUsers",
+        "Lists service users",
+    );
+    api.create::<CreateUser, User>(
+        "/services/{service}/users",
+        "createUser",
+        "Creates a service user",
+    );
+    api.get::<User>(
+        "/services/{service}/users/{user}",
+        "getUser",
+        "Retrieves a service user",
+    );
+    api.put::<UpdateUser, User>(
+        "/services/{service}/users/{user}",
+        "updateUser",
+        "Replaces user credentials, keeping the user stats",
+    );
+    api.delete(
+        "/services/{service}/users/{user}",
+        "deleteUser",
+        "Removes a service user",
+    );
+    api.post::<RotateCredentials, UserCredentials>(
+        "/services/{service}/users/{user}/credentials/rotate",
+        "rotateCredentials",
+        "Replaces user credentials with generated ones",
+    );
+    api.get::<UserLimitsStatus>(
+        "/services/{service}/users/{user}/limits",
+        "getUserLimits",
+        "User limits and their current usage",
+    );
+    api.put::<Option<UserAllowance>, UserLimitsStatus>(
+        "/services/{service}/users/{user}/allowance",
+        "setUserAllowance",
+        "Sets the usage paid for by the requestor; null lifts the allowance",
+    );
+    api.get::<UserStats>(
+        "/services/{service}/users/{user}/stats",
+        "getUserStats",
+        "User statistics",
+    );
+    api.get::<UserEndpointStats>(
+        "/services/{service}/users/{user}/endpoints/stats",
+        "getUserEndpointStats",
+        "Number of user requests per endpoint",
+    );
+    api.get::<UserEndpointBytes>(
+        "/services/{service}/users/{user}/endpoints/bytes",
+        "getUserEndpointBytes",
+        "Number of bytes transferred by the user per endpoint",
+    );
+
+    api.get::<Vec<Alert>>("/alerts", "getAlerts", "Lists active alerts");
+    api.get::<Vec<ProxyRuntime>>(
+        "/debug/proxies",
+        "getProxyRuntimes",
+        "Runtime diagnostics of proxy instances",
+    );
+    api.get::<Value>(
+        "/proxies/{addr}/config",
+        "getProxyConfig",
+        "Redacted configuration of the proxy instance listening on the address",
+    );
+    let text = json!({"type": "string"});
+    let logs = api.get_content(
+        "/logs",
+        "getLogs",
+        "Recent proxy log lines, optionally followed by lines logged afterwards",
+        "text/plain",
+        text,
+    );
+    query(logs, &["service", "tail", "follow"]);
+    api.action::<Shutdown>("/control/shutdown", "shutdown", "Stops the proxy");
+    api.action::<Drain>(
+        "/control/drain",
+        "drain",
+        "Stops the proxy once in-flight requests are complete",
+    );
+
+    api.document()
+}
+
+struct Api {
+    gen: SchemaGenerator,
+    paths: Map<String, Value>,
+}
+
+impl Api {
+    fn new() -> Self {
+        Self {
+            gen: SchemaSettings::openapi3().into_generator(),
+            paths: Map::new(),
+        }
+    }
+
+    fn schema<T: JsonSchema>(&mut self) -> Value {
+        serde_json::to_value(self.gen.subschema_for::<T>()).unwrap()
+    }
+
+    fn get<R: JsonSchema>(&mut self, path: &str, id: &str, summary: &str) -> &mut Value {
+        let response = self.schema::<R>();
+        self.operation("get", path, id, summary, None, Some(("200", response)))
+    }
+
+    /// Creates a resource, responding with `201 Created`
+    fn create<B: JsonSchema, R: JsonSchema>(
+        &mut self,
+        path: &str,
+        id: &str,
+        summary: &str,
+    ) -> &mut Value {
+        let (body, response) = (self.schema::<B>(), self.schema::<R>());
+        self.operation(
+            "post",
+            path,
+            id,
+            summary,
+            Some(body),
+            Some(("201", response)),
+        )
+    }
+
+    fn post<B: JsonSchema, R: JsonSchema>(
+        &mut self,
+        path: &str,
+        id: &str,
+        summary: &str,
+    ) -> &mut Value {
+        let (body, response) = (self.schema::<B>(), self.schema::<R>());
+        self.operation(
+            "post",
+            path,
+            id,
+            summary,
+            Some(body),
+            Some(("200", response)),
+        )
+    }
+
+    /// Triggers an action, responding with `204 No Content`
+    fn action<B: JsonSchema>(&mut self, path: &str, id: &str, summary: &str) -> &mut Value {
+        let body = self.schema::<B>();
+        self.operation("post", path, id, summary, Some(body), None)
+    }
+
+    fn put<B: JsonSchema, R: JsonSchema>(
+        &mut self,
+        path: &str,
+        id: &str,
+        summary: &str,
+    ) -> &mut Value {
+        let (body, response) = (self.schema::<B>(), self.schema::<R>());
+        self.operation(
+            "put",
+            path,
+            id,
+            summary,
+            Some(body),
+            Some(("200", response)),
+        )
+    }
+
+    fn delete(&mut self, path: &str, id: &str, summary: &str) -> &mut Value {
+        self.operation("delete", path, id, summary, None, None)
+    }
+
+    /// Responds with a document or a stream of another content type than JSON
+    fn get_content(
+        &mut self,
+        path: &str,
+        id: &str,
+        summary: &str,
+        content_type: &str,
+        schema: Value,
+    ) -> &mut Value {
+        let operation = self.operation("get", path, id, summary, None, Some(("200", schema)));
+        let content = &mut operation["responses"]["200"]["content"];
+        let media = content["application/json"].take();
+        let mut media_types = Map::new();
+        media_types.insert(content_type.to_string(), media);
+        *content = Value::Object(media_types);
+        operation
+    }
+
+    fn operation(
+        &mut self,
+        method: &str,
+        path: &str,
+        id: &str,
+        summary: &str,
+        body: Option<Value>,
+        response: Option<(&str, Value)>,
+    ) -> &mut Value {
+        let error = self.schema::<ErrorResponse>();
+        let parameters: Vec<Value> = path
+            .split('/')
+            .filter_map(|s| s.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| json!({"name": name, "in": "path", "required": true, "schema": {"type": "string"}}))
+            .collect();
+
+        let mut responses = json!({
+            "default": {
+                "description": "Error",
+                "content": {"application/json": {"schema": error}},
+            }
+        });
+        match response {
+            Some((status, schema)) => {
+                responses[status] = json!({
+                    "description": "OK",
+                    "content": {"application/json": {"schema": schema}},
+                })
+            }
+            None => responses["204"] = json!({"description": "No Content"}),
+        }
+
+        let mut operation = json!({
+            "operationId": id,
+            "summary": summary,
+            "parameters": parameters,
+            "responses": responses,
+        });
+        if let Some(schema) = body {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": {"application/json": {"schema": schema}},
+            });
+        }
+
+        let item = self
+            .paths
+            .entry(path.to_string())
+            .or_insert_with(|| json!({}));
+        item[method] = operation;
+        &mut item[method]
+    }
+
+    fn document(self) -> Value {
+        let schemas = serde_json::to_value(self.gen.definitions()).unwrap();
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "ya-http-proxy Management API",
+                "version": API_VERSION,
+            },
+            "security": [{"bearer": []}],
+            "paths": self.paths,
+            "components": {
+                "schemas": schemas,
+                "securitySchemes": {"bearer": {"type": "http", "scheme": "bearer"}},
+            },
+        })
+    }
+}
+
+/// Adds optional string query parameters to the operation
+fn query(operation: &mut Value, names: &[&str]) {
+    let parameters = operation["parameters"].as_array_mut().unwrap();
+    parameters.extend(
+        names
+            .iter()
+            .map(|name| json!({"name": name, "in": "query", "schema": {"type": "string"}})),
+    );
+}
@@ -0,0 +1,181 @@
+use std::borrow::Cow;
+use std::fmt::{Debug, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Placeholder printed instead of secret values.
+pub const REDACTED: &str = "***";
+
+/// Names of object fields holding secret values in Management API messages.
+pub const SECRET_FIELDS: &[&str] = &["password", "token", "key", "apiKey"];
+
+/// Sensitive value wrapper, redacted when formatted with `Debug`.
+///
+/// Serialization is transparent, so that secrets can be exchanged with the Management API.
+///
+/// ### Example
+/// ```
+/// use ya_http_proxy_model::Secret;
+/// let secret = Secret::from("password");
+/// assert_eq!(format!("{:?}", secret), "***");
+/// assert_eq!(secret.expose(), "password");
+/// ```
+///
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    /// Wraps a sensitive value.
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// Returns a reference to the secret value.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    /// Unwraps the secret value.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Debug for Secret<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl From<String> for Secret<String> {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for Secret<String> {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+
+impl<T: FromStr> FromStr for Secret<T> {
+    type Err = T::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        T::from_str(s).map(Self)
+    }
+}
+
+impl<T: Serialize> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Secret<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self)
+    }
+}
+
+#[cfg(feature = "schema")]
+impl<T: schemars::JsonSchema> schemars::JsonSchema for Secret<T> {
+    fn schema_name() -> String {
+        T::schema_name()
+    }
+
+    fn is_referenceable() -> bool {
+        T::is_referenceable()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        T::json_schema(gen)
+    }
+}
+
+/// Replaces string values of `fields` in a JSON document with a placeholder.
+/// Documents that cannot be parsed are returned unchanged.
+///
+/// ### Example
+/// ```
+/// use ya_http_proxy_model::{redact_json, SECRET_FIELDS};
+/// let body = r#"{"password":"secret"}"#;
+/// assert_eq!(redact_json(body, SECRET_FIELDS), r#"{"password":"***"}"#);
+/// ```
+///
+pub fn redact_json<'a, S: AsRef<str>>(body: &'a str, fields: &[S]) -> Cow<'a, str> {
+    fn redact<S: AsRef<str>>(value: &mut serde_json::Value, fields: &[S]) -> bool {
+        let mut redacted = false;
+        match value {
+            serde_json::Value::Object(map) => {
+                for (k, v) in map.iter_mut() {
+                    if v.is_string() && fields.iter().any(|f| f.as_ref() == k) {
+                        *v = serde_json::Value::String(REDACTED.to_string());
+                        redacted = true;
+                    } else {
+                        redacted |= redact(v, fields);
+                    }
+                }
+            }
+            serde_json::Value::Array(vec) => {
+                for v in vec.iter_mut() {
+                    redacted |= redact(v, fields);
+                }
+            }
+            _ => {}
+        }
+        redacted
+    }
+
+    if fields.is_empty() {
+        return Cow::Borrowed(body);
+    }
+    let mut value: serde_json::Value = match serde_json::from_str(body) {
+        Ok(value) => value,
+        Err(_) => return Cow::Borrowed(body),
+    };
+    if redact(&mut value, fields) {
+        Cow::Owned(value.to_string())
+    } else {
+        Cow::Borrowed(body)
+    }
+}
+
+/// Truncates a logged body to at most `len` characters.
+pub fn snippet(body: &str, len: usize) -> &str {
+    match body.char_indices().nth(len) {
+        Some((idx, _)) => &body[..idx],
+        None => body,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{redact_json, snippet, SECRET_FIELDS};
+
+    #[test]
+    fn redact_secrets() {
+        let body = r#"{"username":"user","credentials":{"method":"basic","password":"secret"}}"#;
+        let redacted = redact_json(body, SECRET_FIELDS);
+        assert!(!redacted.contains("secret"));
+        assert!(redacted.contains("user"));
+
+        let body = r#"[{"username":"user","method":"basic"}]"#;
+        assert_eq!(redact_json(body, SECRET_FIELDS), body);
+        assert_eq!(
+            redact_json(r#"[{"username":"user"}]"#, &["username"]),
+            r#"[{"username":"***"}]"#
+        );
+        assert_eq!(redact_json("<html></html>", SECRET_FIELDS), "<html></html>");
+    }
+
+    #[test]
+    fn body_snippet() {
+        assert_eq!(snippet("<html></html>", 512), "<html></html>");
+
+        let body = "ż".repeat(513);
+        assert_eq!(snippet(&body, 512).chars().count(), 512);
+    }
+}
@@ -0,0 +1,154 @@
+//! Golden payloads of other Management API versions, read by the current model.
+//!
+//! `golden/0.3.0` holds payloads serialized by the first 0.3.0 release, `golden/newer`
+//! payloads a future version may produce.
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use ya_http_proxy_model::{
+    AlertKind, AuthMethod, CreateService, CreateUser, ErrorResponse, Event, EventRecord,
+    GlobalStats, RevokeReason, Service, User, Versioned, API_VERSION,
+};
+
+macro_rules! golden {
+    ($path:literal) => {
+        include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/", $path))
+    };
+}
+
+fn read<T: DeserializeOwned>(json: &str) -> T {
+    serde_json::from_str(json).unwrap_or_else(|e| panic!("{}\n{}", e, json))
+}
+
+/// Asserts that the current serialization keeps the fields set in an older payload,
+/// so that binaries of the older version can read it
+fn assert_readable_by_older<T: Serialize>(value: &T, json: &str) {
+    let older: Value = read(json);
+    let current = serde_json::to_value(value).unwrap();
+
+    for (key, value) in older.as_object().unwrap() {
+        if value.is_null() {
+            continue;
+        }
+        assert!(
+            current.get(key).is_some(),
+            "field '{}' is missing in {}",
+            key,
+            current
+        );
+    }
+}
+
+#[test]
+fn create_service_0_3_0() {
+    let json = golden!("0.3.0/create_service.json");
+    let create: CreateService = read(json);
+
+    assert_eq!(create.name, "acme-service");
+    assert_eq!(create.https_ports().into_iter().collect::<Vec<_>>(), [443]);
+    assert_eq!(create.auth.as_ref().unwrap().method, AuthMethod::Basic);
+    assert_eq!(
+        create.timeouts.as_ref().unwrap().request_timeout,
+        Some(Duration::from_secs(30))
+    );
+    assert!(create.max_users.is_none());
+    assert_readable_by_older(&create, json);
+}
+
+#[test]
+fn service_0_3_0() {
+    let json = golden!("0.3.0/service.json");
+    let service: Service = read(json);
+
+    assert_eq!(service.inner.cpu_threads, Some(2));
+    assert!(service.user_count.is_none());
+    assert!(service.upstream_available.is_none());
+    assert_readable_by_older(&service, json);
+}
+
+#[test]
+fn user_0_3_0() {
+    let json = golden!("0.3.0/user.json");
+    let user: User = read(json);
+
+    assert_eq!(user.method, AuthMethod::Basic);
+    assert!(user.fingerprint.is_none());
+    assert_readable_by_older(&user, json);
+
+    let create: CreateUser = read(golden!("0.3.0/create_user.json"));
+    assert_eq!(create, CreateUser::basic("user", "secret"));
+}
+
+#[test]
+fn create_user_api_key() {
+    let create: CreateUser = read(r#"{"username":"user","apiKey":"secret"}"#);
+    assert_eq!(create, CreateUser::api_key("user", "secret"));
+
+    let json = serde_json::to_string(&create).unwrap();
+    assert_eq!(read::<CreateUser>(&json), create);
+}
+
+#[test]
+fn responses_0_3_0() {
+    let json = golden!("0.3.0/error_response.json");
+    let response: ErrorResponse = read(json);
+    assert_eq!(response, ErrorResponse::new("User not found"));
+    assert_readable_by_older(&response, json);
+
+    let json = golden!("0.3.0/global_stats.json");
+    let stats: GlobalStats = read(json);
+    assert_eq!(stats.requests.requests, 7);
+    assert_readable_by_older(&stats, json);
+}
+
+#[test]
+fn unknown_event() {
+    let record: Versioned<EventRecord> = read(golden!("newer/event.json"));
+
+    assert_eq!(record.api_version.as_deref(), Some("0.4.0"));
+    assert_eq!(record.payload.event, Event::Unknown);
+    assert_eq!(record.payload.correlation_id.as_deref(), Some("activity/1"));
+    assert_eq!(record.payload.event.service(), None);
+}
+
+#[test]
+fn unknown_variants() {
+    let record: EventRecord = read(golden!("newer/alert.json"));
+    match record.event {
+        Event::AlertRaised(alert) => assert_eq!(alert.kind, AlertKind::Unknown),
+        event => panic!("unexpected event: {:?}", event),
+    }
+
+    let record: EventRecord = read(golden!("newer/user_revoked.json"));
+    match record.event {
+        Event::UserRevoked { reason, .. } => assert_eq!(reason, RevokeReason::Unknown),
+        event => panic!("unexpected event: {:?}", event),
+    }
+}
+
+#[test]
+fn unknown_fields() {
+    let service: Service = read(golden!("newer/service.json"));
+    assert_eq!(service.user_count, Some(3));
+}
+
+#[test]
+fn versioned_envelope() {
+    let record: Versioned<EventRecord> = read(golden!("newer/user_revoked.json"));
+    assert_eq!(record.is_compatible(), API_VERSION.starts_with("0.4."));
+
+    let record: Versioned<EventRecord> = read(golden!("newer/alert.json"));
+    let unversioned = Versioned {
+        api_version: None,
+        payload: record.into_inner(),
+    };
+    assert!(unversioned.is_compatible());
+
+    // readers unaware of the envelope
+    let current = serde_json::to_string(&Versioned::new(unversioned.payload.clone())).unwrap();
+    let record: EventRecord = read(&current);
+    assert_eq!(record, unversioned.payload);
+}
@@ -0,0 +1,18 @@
+//! The committed OpenAPI document has to match the one derived from the model. Regenerate it with
+//! `UPDATE_OPENAPI=1 cargo test -p ya-http-proxy-model --features schema --test openapi`
+use ya_http_proxy_model::openapi;
+
+const DOCUMENT_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+#[test]
+fn committed_document() {
+    let document = serde_json::to_string_pretty(&openapi::document()).unwrap() + "\n";
+    if std::env::var_os("UPDATE_OPENAPI").is_some() {
+        std::fs::write(DOCUMENT_PATH, &document).unwrap();
+    }
+    let committed = std::fs::read_to_string(DOCUMENT_PATH).unwrap_or_default();
+    assert!(
+        committed == document,
+        "openapi.json is outdated, regenerate it with UPDATE_OPENAPI=1"
+    );
+}
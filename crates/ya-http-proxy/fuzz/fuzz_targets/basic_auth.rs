@@ -0,0 +1,20 @@
+#![no_main]
+//! Input: `Authorization` header value
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::HeaderMap;
+use libfuzzer_sys::fuzz_target;
+
+use ya_http_proxy::fuzzing::{basic_credentials, extract_access};
+
+fuzz_target!(|data: &[u8]| {
+    let value = match HeaderValue::from_bytes(data) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, value);
+
+    if basic_credentials(&headers).is_some() {
+        assert!(extract_access(&headers));
+    }
+});
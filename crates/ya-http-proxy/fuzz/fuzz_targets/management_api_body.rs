@@ -0,0 +1,9 @@
+#![no_main]
+//! Input: Management API request body
+use libfuzzer_sys::fuzz_target;
+
+use ya_http_proxy::fuzzing::management_api_body;
+
+fuzz_target!(|data: &[u8]| {
+    management_api_body(data);
+});
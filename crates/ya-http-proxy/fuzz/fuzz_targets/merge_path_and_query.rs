@@ -0,0 +1,33 @@
+#![no_main]
+//! Input: request URI, service endpoint and upstream URL, separated by newlines
+use hyper::Uri;
+use libfuzzer_sys::fuzz_target;
+
+use ya_http_proxy::fuzzing::merge_path_and_query;
+
+fuzz_target!(|data: &[u8]| {
+    let data = match std::str::from_utf8(data) {
+        Ok(data) => data,
+        Err(_) => return,
+    };
+    let mut lines = data.splitn(3, '\n');
+    let (req, from, to) = match (lines.next(), lines.next(), lines.next()) {
+        (Some(req), Some(from), Some(to)) => (req, from, to),
+        _ => return,
+    };
+    let (mut req, from, to): (Uri, Uri, Uri) = match (req.parse(), from.parse(), to.parse()) {
+        (Ok(req), Ok(from), Ok(to)) => (req, from, to),
+        _ => return,
+    };
+
+    let query = req.query().map(ToString::to_string);
+    let keeps_query = from.query().is_none() && to.query().is_none();
+    if merge_path_and_query(&mut req, from, to.clone()).is_ok() {
+        // requests never redirect the proxy to a different upstream
+        assert_eq!(req.scheme(), to.scheme());
+        assert_eq!(req.authority(), to.authority());
+        if keeps_query {
+            assert_eq!(req.query().map(ToString::to_string), query);
+        }
+    }
+});
@@ -0,0 +1,226 @@
+//! Service alert rules evaluation
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use crate::proxy::ProxyManager;
+use ya_http_proxy_model as model;
+use ya_http_proxy_model::AlertKind;
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+const EVALUATION_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_SAMPLES: usize = 10_000;
+
+/// Request outcomes of a service within the alert evaluation window
+#[derive(Debug)]
+pub struct Metrics {
+    window: Duration,
+    samples: VecDeque<Sample>,
+    last_request: Instant,
+}
+
+#[derive(Debug)]
+struct Sample {
+    at: Instant,
+    latency: Duration,
+    failed: bool,
+}
+
+impl Metrics {
+    pub fn new(rules: Option<&model::AlertRules>) -> Self {
+        Self {
+            window: rules.and_then(|r| r.window).unwrap_or(DEFAULT_WINDOW),
+            samples: Default::default(),
+            last_request: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration, failed: bool) {
+        let now = Instant::now();
+        self.prune(now);
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(Sample {
+            at: now,
+            latency,
+            failed,
+        });
+        self.last_request = now;
+    }
+
+    /// Returns violated rules, along with the observed values and thresholds
+    fn violations(
+        &mut self,
+        rules: &model::AlertRules,
+        now: Instant,
+    ) -> Vec<(AlertKind, u64, u64)> {
+        self.prune(now);
+        let mut violations = Vec::new();
+
+        if let Some(threshold) = rules.idle {
+            let idle = now.saturating_duration_since(self.last_request);
+            if idle > threshold {
+                violations.push((AlertKind::Idle, millis(idle), millis(threshold)));
+            }
+        }
+
+        if self.samples.is_empty() {
+            return violations;
+        }
+
+        if let Some(threshold) = rules.error_rate {
+            let failed = self.samples.iter().filter(|s| s.failed).count();
+            let rate = (failed * 100 / self.samples.len()) as u64;
+            if rate > threshold as u64 {
+                violations.push((AlertKind::ErrorRate, rate, threshold as u64));
+            }
+        }
+
+        if let Some(threshold) = rules.p99_latency {
+            let mut latencies: Vec<_> = self.samples.iter().map(|s| s.latency).collect();
+            latencies.sort_unstable();
+            let idx = latencies.len() * 99 / 100;
+            let p99 = latencies[idx.min(latencies.len() - 1)];
+            if p99 > threshold {
+                violations.push((AlertKind::P99Latency, millis(p99), millis(threshold)));
+            }
+        }
+
+        violations
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(sample) = self.samples.front() {
+            if now.saturating_duration_since(sample.at) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+}
+
+/// Spawns a task periodically evaluating alert rules of all services
+pub fn spawn(manager: ProxyManager) {
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(EVALUATION_INTERVAL);
+        loop {
+            interval.tick().await;
+            evaluate(&manager).await;
+        }
+    });
+}
+
+async fn evaluate(manager: &ProxyManager) {
+    let now = Instant::now();
+    let mut violated = HashMap::new();
+
+    {
+        let proxies = manager.proxies.read().await;
+        for proxy in proxies.values() {
+            let state = proxy.state.read().await;
+            for service in state.by_route.values() {
+                let rules = match service.created_with.alerts {
+                    Some(ref rules) => rules,
+                    None => continue,
+                };
+                let name = &service.created_with.name;
+                let mut metrics = service.metrics.lock().unwrap();
+                for (kind, value, threshold) in metrics.violations(rules, now) {
+                    violated.insert((name.clone(), kind), (value, threshold));
+                }
+            }
+        }
+    }
+
+    let mut alerts = manager.alerts.write().await;
+
+    let resolved: Vec<_> = alerts
+        .keys()
+        .filter(|key| !violated.contains_key(*key))
+        .cloned()
+        .collect();
+    for key in resolved {
+        if let Some(alert) = alerts.remove(&key) {
+            log::info!("[{}] alert resolved: {:?}", alert.service, alert.kind);
+            manager.emit(model::Event::AlertResolved(alert));
+        }
+    }
+
+    for ((service, kind), (value, threshold)) in violated {
+        match alerts.entry((service.clone(), kind)) {
+            Entry::Occupied(mut entry) => entry.get_mut().value = value,
+            Entry::Vacant(entry) => {
+                let alert = model::Alert {
+                    service,
+                    kind,
+                    value,
+                    threshold,
+                    since: Utc::now(),
+                };
+                log::warn!(
+                    "[{}] alert raised: {:?} {} > {}",
+                    alert.service,
+                    kind,
+                    value,
+                    threshold
+                );
+                manager.emit(model::Event::AlertRaised(alert.clone()));
+                entry.insert(alert);
+            }
+        }
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use std::time::{Duration, Instant};
+    use ya_http_proxy_model::{AlertKind, AlertRules};
+
+    #[test]
+    fn alert_violations() {
+        let rules = AlertRules {
+            error_rate: Some(10),
+            p99_latency: Some(Duration::from_millis(100)),
+            idle: Some(Duration::from_secs(60)),
+            window: None,
+        };
+        let mut metrics = Metrics::new(Some(&rules));
+        let now = Instant::now();
+        assert!(metrics.violations(&rules, now).is_empty());
+
+        for _ in 0..98 {
+            metrics.record(Duration::from_millis(10), false);
+        }
+        metrics.record(Duration::from_millis(500), true);
+        metrics.record(Duration::from_millis(500), false);
+        let now = Instant::now();
+
+        let violations = metrics.violations(&rules, now);
+        assert_eq!(violations, vec![(AlertKind::P99Latency, 500, 100)]);
+
+        for _ in 0..20 {
+            metrics.record(Duration::from_millis(10), true);
+        }
+        let now = Instant::now();
+        let kinds: Vec<_> = metrics
+            .violations(&rules, now)
+            .into_iter()
+            .map(|(kind, _, _)| kind)
+            .collect();
+        assert_eq!(kinds, vec![AlertKind::ErrorRate, AlertKind::P99Latency]);
+
+        let later = now + Duration::from_secs(120);
+        let violations = metrics.violations(&rules, later);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].0, AlertKind::Idle);
+    }
+}
@@ -3,24 +3,39 @@ use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::header::{HeaderValue, AUTHORIZATION, RETRY_AFTER, WWW_AUTHENTICATE};
 use hyper::http::response::Builder;
 use hyper::server::conn::AddrIncoming;
-use hyper::{Body, Request, Response, Server, StatusCode};
-use routerify::prelude::*;
-use routerify::{Middleware, RouteError, Router, RouterService};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use routerify::{Middleware, RouteError, Router, RouterBuilder, RouterService};
 
 use crate::error::{Error, ProxyError, ServiceError, UserError};
-use crate::proxy::ProxyManager;
+use crate::logs::LogBuffer;
+use crate::proxy::{digest, ProxyManager};
 use crate::ManagementError;
+use crate::{alerts, hooks};
 use ya_http_proxy_model as model;
 
+mod cors;
 mod handler;
+mod idempotency;
+mod wire;
 
 pub type HandlerError = ApiErrorKind;
+
+/// Path of the web dashboard. Browsers prompt for the token when loading it
+const DASHBOARD_PATH: &str = "/dashboard";
 pub type ApiServer = Server<AddrIncoming, RouterService<Body, HandlerError>>;
 
 pub struct Management {
-    server: Option<ApiServer>,
+    server: Option<BoxFuture<'static, hyper::Result<()>>>,
+    local_addr: Option<SocketAddr>,
+    token: Option<String>,
+    logs: LogBuffer,
     pub(self) manager: ProxyManager,
 }
 
@@ -28,29 +43,50 @@ impl Management {
     pub fn new(manager: ProxyManager) -> Self {
         Self {
             server: None,
+            local_addr: None,
+            token: None,
+            logs: Default::default(),
             manager,
         }
     }
 
+    /// Requires API clients to present the token as a bearer credential
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token.filter(|t| !t.is_empty());
+        self
+    }
+
+    /// Serves log lines collected by the buffer
+    pub fn with_logs(mut self, logs: LogBuffer) -> Self {
+        self.logs = logs;
+        self
+    }
+
     pub fn bind(&mut self, address: SocketAddr) -> Result<(), Error> {
-        let router = router(self.manager.clone()).map_err(ProxyError::rt)?;
+        let router = router(self.manager.clone(), self.token.clone(), self.logs.clone())
+            .map_err(ProxyError::rt)?;
         let service = RouterService::new(router).unwrap();
 
-        let server = Server::try_bind(&address)
+        let server: ApiServer = Server::try_bind(&address)
             .map_err(|e| ManagementError::Bind {
                 address,
                 message: e.to_string(),
             })?
             .serve(service);
+        self.local_addr.replace(server.local_addr());
+
+        let stopped = self.manager.stopped();
+        let server = server.with_graceful_shutdown(stopped).boxed();
         self.server.replace(server);
 
+        hooks::spawn(&self.manager.default_conf, self.manager.events())?;
+        alerts::spawn(self.manager.clone());
+
         Ok(())
     }
 
     pub fn local_addr(&self) -> Result<SocketAddr, Error> {
-        self.server
-            .as_ref()
-            .map(|s| s.local_addr())
+        self.local_addr
             .ok_or_else(|| ManagementError::NotRunning.into())
     }
 }
@@ -61,48 +97,194 @@ impl Future for Management {
     #[inline]
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match self.server.as_mut() {
-            Some(server) => Pin::new(server).poll(cx).map_err(Error::other),
+            Some(server) => server.poll_unpin(cx).map_err(Error::other),
             None => Poll::Ready(Err(ManagementError::NotRunning.into())),
         }
     }
 }
 
-fn router(manager: ProxyManager) -> routerify::Result<Router<Body, HandlerError>> {
-    use handler::*;
+/// Declares Management API routes, along with a table of their methods and paths
+macro_rules! routes {
+    ($($method:ident $path:expr => $handler:ident,)*) => {
+        /// Methods and paths of Management API routes
+        #[cfg(test)]
+        const ROUTES: &[(&str, &str)] = &[$((stringify!($method), $path),)*];
+
+        fn routes(
+            builder: RouterBuilder<Body, HandlerError>,
+        ) -> RouterBuilder<Body, HandlerError> {
+            use handler::*;
+            builder$(.$method($path, $handler))*
+        }
+    };
+}
+
+routes! {
+    get "/status" => get_status,
+    get DASHBOARD_PATH => get_dashboard,
+    get "/events" => get_events,
+    get "/services" => get_services,
+    post "/services" => post_services,
+    get "/services/:service" => get_service,
+    put "/services/:service" => put_service,
+    delete "/services/:service" => delete_service,
+    post "/services/:service/cert/reload" => post_service_cert_reload,
+    get "/services/:service/stats" => get_service_stats,
+    get "/services/:service/isolation" => get_service_isolation,
+    get "/services/:service/debug/requests" => get_request_timings,
+    get "/services/:service/journal" => get_journal,
+    get "/services/:service/usage" => get_usage,
+    get "/services/:service/users" => get_users,
+    post "/services/:service/users" => post_users,
+    get "/services/:service/users/:user" => get_user,
+    put "/services/:service/users/:user" => put_user,
+    delete "/services/:service/users/:user" => delete_user,
+    post "/services/:service/users/:user/credentials/rotate" => post_user_credentials_rotate,
+    get "/services/:service/users/:user/limits" => get_user_limits,
+    put "/services/:service/users/:user/allowance" => put_user_allowance,
+    get "/services/:service/users/:user/stats" => get_user_stats,
+    get "/services/:service/users/:user/endpoints/stats" => get_user_endpoint_stats,
+    get "/services/:service/users/:user/endpoints/bytes" => get_user_endpoint_bytes,
+    get "/alerts" => get_alerts,
+    get "/debug/proxies" => get_proxy_runtimes,
+    get "/proxies/:addr/config" => get_proxy_config,
+    get "/logs" => get_logs,
+    post "/control/shutdown" => post_shutdown,
+    post "/control/drain" => post_drain,
+}
+
+fn router(
+    manager: ProxyManager,
+    token: Option<String>,
+    logs: LogBuffer,
+) -> routerify::Result<Router<Body, HandlerError>> {
+    let wire_log = Arc::new(manager.default_conf.wire_log.clone());
+    let wire_log_post = wire_log.clone();
+    let cors = match manager.default_conf.cors.enabled() {
+        true if token.is_none() => {
+            log::warn!("Management API CORS is only allowed with a token, ignoring");
+            None
+        }
+        true => Some(Arc::new(manager.default_conf.cors.clone())),
+        false => None,
+    };
 
     let mut builder = Router::builder()
         .data(manager)
-        .middleware(Middleware::pre(middleware_logger));
+        .data(logs)
+        .data(idempotency::Idempotency::default());
+
+    if let Some(token) = token {
+        let digest: Arc<str> = digest(&token).into();
+        let preflight = cors.is_some();
+        builder = builder.middleware(Middleware::pre(move |req| {
+            middleware_auth(req, digest.clone(), preflight)
+        }));
+    }
 
+    // unauthorized requests are not buffered
     builder = builder
-        .get("/services", get_services)
-        .post("/services", post_services)
-        .get("/services/:service", get_service)
-        .delete("/services/:service", delete_service)
-        .get("/services/:service/users", get_users)
-        .post("/services/:service/users", post_users)
-        .get("/services/:service/users/:user", get_user)
-        .delete("/services/:service/users/:user", delete_user)
-        .get("/services/:service/users/:user/stats", get_user_stats)
-        .get(
-            "/services/:service/users/:user/endpoints/stats",
-            get_user_endpoint_stats,
-        )
-        .post("/control/shutdown", post_shutdown);
-
-    builder.err_handler(err_handler).build()
-}
-
-async fn middleware_logger(req: Request<Body>) -> Result<Request<Body>, HandlerError> {
-    log::debug!(
-        "{} {} {}",
-        req.remote_addr(),
-        req.method(),
-        req.uri().path()
-    );
+        .middleware(Middleware::pre(move |req| {
+            wire::log_request(req, wire_log.clone())
+        }))
+        .middleware(Middleware::post_with_info(move |res, info| {
+            wire::log_response(res, info, wire_log_post.clone())
+        }));
+
+    if let Some(ref cors) = cors {
+        let (cors_post, cors_preflight) = (cors.clone(), cors.clone());
+        builder = builder
+            .middleware(Middleware::post_with_info(move |res, info| {
+                cors::allow_response(res, info, cors_post.clone())
+            }))
+            .options("/*", move |req| {
+                cors::preflight(req, cors_preflight.clone())
+            });
+    }
+
+    builder = routes(builder);
+
+    builder
+        .err_handler_with_info(move |err, info| {
+            let cors = cors.clone();
+            async move {
+                let mut res = err_handler(err).await;
+                if res.status() == StatusCode::UNAUTHORIZED && info.uri().path() == DASHBOARD_PATH {
+                    res.headers_mut().insert(
+                        WWW_AUTHENTICATE,
+                        HeaderValue::from_static("Basic realm=\"ya-http-proxy\""),
+                    );
+                }
+                if let Some(cors) = cors {
+                    cors::apply(&cors, info.headers(), &mut res);
+                }
+                res
+            }
+        })
+        .build()
+}
+
+async fn middleware_auth(
+    req: Request<Body>,
+    token_digest: Arc<str>,
+    preflight: bool,
+) -> Result<Request<Body>, HandlerError> {
+    // browsers do not send credentials with CORS preflight requests
+    if preflight && cors::is_preflight(&req) {
+        return Ok(req);
+    }
+
+    // compare digests, not the tokens themselves
+    let authorized = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .and_then(|(scheme, credentials)| {
+            if scheme.eq_ignore_ascii_case("basic") && !dashboard_request(&req) {
+                return None;
+            }
+            presented_token(scheme, credentials.trim())
+        })
+        .map(|token| digest(&token) == *token_digest)
+        .unwrap_or(false);
+
+    if !authorized {
+        return Err(ManagementError::Unauthorized.into());
+    }
     Ok(req)
 }
 
+/// Whether the request is sent by the dashboard. Browsers attach cached basic credentials
+/// to cross-site requests as well, so these are only accepted for reading the resources
+/// the dashboard displays
+fn dashboard_request(req: &Request<Body>) -> bool {
+    if req.method() != Method::GET {
+        return false;
+    }
+    let segments: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+    matches!(
+        segments.as_slice(),
+        ["dashboard"] | ["status"] | ["events"] | ["alerts"] | ["services"]
+    ) || matches!(segments.as_slice(), ["services", _, "stats" | "users"])
+}
+
+/// Reads the token from bearer credentials, or from the password of basic credentials
+/// sent by browsers loading the dashboard
+fn presented_token(scheme: &str, credentials: &str) -> Option<String> {
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Some(credentials.to_string());
+    }
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::decode(credentials).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    decoded
+        .split_once(':')
+        .map(|(_, password)| password.to_string())
+}
+
 async fn err_handler(err: RouteError) -> Response<Body> {
     let builder = Response::builder();
 
@@ -110,18 +292,39 @@ async fn err_handler(err: RouteError) -> Response<Body> {
         Ok(err) => match *err {
             ApiErrorKind::BadRequest(err) => err_response(builder, StatusCode::BAD_REQUEST, err),
             ApiErrorKind::Conflict(err) => err_response(builder, StatusCode::CONFLICT, err),
-            ApiErrorKind::InternalServerError(err) => {
-                err_response(builder, StatusCode::INTERNAL_SERVER_ERROR, err)
+            ApiErrorKind::Unauthorized(err) => err_response(builder, StatusCode::UNAUTHORIZED, err),
+            ApiErrorKind::PreconditionFailed(err) => {
+                err_response(builder, StatusCode::PRECONDITION_FAILED, err)
+            }
+            ApiErrorKind::Unavailable(err) => {
+                err_response(builder, StatusCode::SERVICE_UNAVAILABLE, err)
             }
+            ApiErrorKind::InternalServerError(err) => err_response(
+                builder,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                model::ErrorResponse::new(err),
+            ),
         },
-        Err(err) => err_response(builder, StatusCode::INTERNAL_SERVER_ERROR, err),
+        Err(err) => err_response(
+            builder,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            model::ErrorResponse::new(err),
+        ),
     }
 }
 
-fn err_response(builder: Builder, code: StatusCode, msg: impl ToString) -> Response<Body> {
-    match serde_json::to_string(&model::ErrorResponse {
-        message: msg.to_string(),
-    }) {
+fn err_response(
+    mut builder: Builder,
+    code: StatusCode,
+    response: impl Into<model::ErrorResponse>,
+) -> Response<Body> {
+    let response = response.into();
+    if let Some(retry_after) = response.retry_after {
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        builder = builder.header(RETRY_AFTER, secs);
+    }
+
+    match serde_json::to_string(&response) {
         Ok(ser) => builder.status(code).body(Body::from(ser)),
         Err(err) => builder
             .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -136,6 +339,12 @@ pub enum ApiErrorKind {
     BadRequest(Error),
     #[error("Conflict: {}", .0.to_string())]
     Conflict(Error),
+    #[error("Unauthorized: {}", .0.to_string())]
+    Unauthorized(Error),
+    #[error("Precondition failed: {}", .0.to_string())]
+    PreconditionFailed(Error),
+    #[error("Service unavailable: {}", .0.to_string())]
+    Unavailable(Error),
     #[error("Internal server error {0}")]
     InternalServerError(String),
 }
@@ -146,14 +355,38 @@ where
 {
     fn from(e: T) -> Self {
         match Error::from(e) {
+            e @ Error::Management(ManagementError::Unauthorized) => Self::Unauthorized(e),
+            e @ Error::Management(ManagementError::PreconditionFailed) => {
+                Self::PreconditionFailed(e)
+            }
             e @ Error::Proxy(ProxyError::AlreadyRunning(_)) => Self::Conflict(e),
+            e @ Error::Proxy(ProxyError::AddressOwned { .. }) => Self::Conflict(e),
+            e @ Error::Proxy(ProxyError::AddressInUse { .. }) => Self::Conflict(e),
             e @ Error::Service(ServiceError::AlreadyExists { .. }) => Self::Conflict(e),
+            e @ Error::Service(ServiceError::Conflict { .. }) => Self::Conflict(e),
+            e @ Error::Service(ServiceError::Immutable { .. }) => Self::Conflict(e),
             e @ Error::User(UserError::AlreadyExists(_)) => Self::Conflict(e),
+            e @ Error::User(UserError::DuplicateCredentials) => Self::Conflict(e),
+            e @ Error::User(UserError::LimitReached(_)) => Self::Conflict(e),
+            e @ Error::Management(ManagementError::IdempotencyKeyReused) => Self::Conflict(e),
+            e @ Error::Proxy(ProxyError::ResourceLimit(_)) => Self::Unavailable(e),
+            e @ Error::Management(ManagementError::Draining) => Self::Unavailable(e),
             e => Self::BadRequest(e),
         }
     }
 }
 
+impl From<Error> for model::ErrorResponse {
+    fn from(e: Error) -> Self {
+        Self {
+            code: Some(e.code().to_string()),
+            retry_after: e.retry_after(),
+            details: e.details(),
+            message: e.to_string(),
+        }
+    }
+}
+
 impl From<hyper::Error> for ApiErrorKind {
     fn from(e: hyper::Error) -> Self {
         Self::InternalServerError(e.to_string())
@@ -177,3 +410,41 @@ impl From<Box<serde_json::Error>> for ApiErrorKind {
         Self::BadRequest(Error::other(e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ROUTES;
+    use ya_http_proxy_model::openapi;
+
+    /// API clients are generated from the OpenAPI document, which has to describe all routes
+    #[test]
+    fn documented_routes() {
+        let document = openapi::document();
+        let mut documented: Vec<(String, String)> = document["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, item)| {
+                let methods = item.as_object().unwrap().keys();
+                methods.map(move |method| (method.clone(), path.clone()))
+            })
+            .collect();
+        let mut routed: Vec<(String, String)> = ROUTES
+            .iter()
+            .map(|(method, path)| {
+                let path: Vec<_> = path
+                    .split('/')
+                    .map(|s| match s.strip_prefix(':') {
+                        Some(param) => format!("{{{}}}", param),
+                        None => s.to_string(),
+                    })
+                    .collect();
+                (method.to_string(), path.join("/"))
+            })
+            .collect();
+
+        documented.sort();
+        routed.sort();
+        assert_eq!(documented, routed);
+    }
+}
@@ -0,0 +1,86 @@
+//! Cross-origin access to the Management API from browser-based dashboards
+use std::sync::Arc;
+
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use routerify::RequestInfo;
+
+use crate::api::HandlerError;
+use crate::conf::CorsConf;
+
+const ALLOWED_METHODS: &str = "GET, POST, PUT, DELETE";
+const EXPOSED_HEADERS: &str = "ETag, Location, Retry-After";
+
+/// Checks whether the request is a preflight request, sent by browsers without credentials
+pub(super) fn is_preflight(req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS
+        && req.headers().contains_key(ORIGIN)
+        && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Answers preflight requests for all routes
+pub(super) async fn preflight(
+    req: Request<Body>,
+    conf: Arc<CorsConf>,
+) -> Result<Response<Body>, HandlerError> {
+    let mut res = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())?;
+    if !allow_origin(&conf, req.headers(), res.headers_mut()) {
+        return Ok(res);
+    }
+
+    let headers = res.headers_mut();
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static(ALLOWED_METHODS),
+    );
+    if let Some(requested) = req.headers().get(ACCESS_CONTROL_REQUEST_HEADERS) {
+        headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+    }
+    if let Some(max_age) = conf.max_age {
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+    }
+    Ok(res)
+}
+
+/// Lets scripts of allowed origins read the response
+pub(super) async fn allow_response(
+    mut res: Response<Body>,
+    info: RequestInfo,
+    conf: Arc<CorsConf>,
+) -> Result<Response<Body>, HandlerError> {
+    apply(&conf, info.headers(), &mut res);
+    Ok(res)
+}
+
+/// Adds CORS headers to a response, including responses of the error handler
+pub(super) fn apply(conf: &CorsConf, req_headers: &HeaderMap, res: &mut Response<Body>) {
+    if allow_origin(conf, req_headers, res.headers_mut()) {
+        res.headers_mut().insert(
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::from_static(EXPOSED_HEADERS),
+        );
+    }
+}
+
+fn allow_origin(conf: &CorsConf, req_headers: &HeaderMap, res_headers: &mut HeaderMap) -> bool {
+    // responses differ between origins, regardless of whether the origin is allowed
+    res_headers.insert(VARY, HeaderValue::from_static("Origin"));
+
+    let origin = match req_headers.get(ORIGIN) {
+        Some(origin) => origin,
+        None => return false,
+    };
+    match origin.to_str() {
+        Ok(value) if conf.allows(value) => {
+            res_headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            true
+        }
+        _ => false,
+    }
+}
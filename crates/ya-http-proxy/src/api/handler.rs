@@ -1,14 +1,178 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+
 use futures::{stream, StreamExt};
-use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_MATCH};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
 use routerify::prelude::RequestExt;
+use tokio::sync::broadcast::error::RecvError;
 
+use crate::api::idempotency::Idempotency;
 use crate::api::ApiErrorKind;
-use crate::proxy::ProxyManager;
-use crate::UserError;
+use crate::logs::LogBuffer;
+use crate::proxy::{etag, ProxyManager};
+use crate::{Error, ManagementError, UserError};
 use ya_http_proxy_model as model;
 
 type HandlerResult = Result<Response<Body>, ApiErrorKind>;
 
+/// Number of log lines returned when not specified in the query
+const DEFAULT_LOG_TAIL: usize = 100;
+/// Single-page dashboard, calling the API with the credentials it was loaded with
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Retrieves the Management API status
+pub async fn get_status(req: Request<Body>) -> HandlerResult {
+    let manager: &ProxyManager = req.data().unwrap();
+    Response::object(&model::Status {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        api_version: model::API_VERSION.to_string(),
+        pid: Some(std::process::id()),
+        draining: manager.is_draining(),
+        shutdown_nonce: Some(manager.shutdown_nonce().to_string()),
+    })
+}
+
+/// Lists active alerts
+pub async fn get_alerts(req: Request<Body>) -> HandlerResult {
+    let manager: &ProxyManager = req.data().unwrap();
+    let alerts = manager.alerts.read().await;
+    let vec: Vec<model::Alert> = alerts.values().cloned().collect();
+
+    Response::object(&vec)
+}
+
+/// Serves the web dashboard
+pub async fn get_dashboard(_req: Request<Body>) -> HandlerResult {
+    let res = Response::builder()
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(CACHE_CONTROL, "no-store")
+        .status(StatusCode::OK)
+        .body(Body::from(DASHBOARD))?;
+    Ok(res)
+}
+
+/// Streams events emitted by all proxy instances as Server-Sent Events,
+/// until the client disconnects
+pub async fn get_events(req: Request<Body>) -> HandlerResult {
+    let manager: &ProxyManager = req.data().unwrap();
+
+    let events = stream::unfold(manager.events(), |mut rx| async move {
+        loop {
+            let chunk = match rx.recv().await {
+                Ok(record) => match serde_json::to_string(&record) {
+                    Ok(json) => format!("data: {}\n\n", json),
+                    Err(_) => continue,
+                },
+                Err(RecvError::Lagged(n)) => format!(": {} events skipped\n\n", n),
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((Ok::<_, Infallible>(chunk), rx));
+        }
+    });
+    // finish the response, so that the server can shut down gracefully
+    let body = Body::wrap_stream(events.take_until(manager.stopped()));
+
+    let res = Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .status(StatusCode::OK)
+        .body(body)?;
+    Ok(res)
+}
+
+/// Lists runtime diagnostics of proxy instances
+pub async fn get_proxy_runtimes(req: Request<Body>) -> HandlerResult {
+    let manager: &ProxyManager = req.data().unwrap();
+    let proxies = manager.proxies();
+
+    let vec: Vec<model::ProxyRuntime> = Default::default();
+    let vec = stream::iter(proxies.read().await.iter())
+        .fold(vec, |mut vec, (addrs, proxy)| async move {
+            let state = proxy.state.read().await;
+            let services = state.by_name.keys().cloned().collect();
+            let mut concurrency: Vec<_> = state
+                .by_route
+                .values()
+                .filter_map(|s| {
+                    let name = s.created_with.name.clone();
+                    s.concurrency.as_ref().map(|c| c.status(name))
+                })
+                .collect();
+            concurrency.sort_by(|a, b| a.service.cmp(&b.service));
+            vec.push(proxy.runtime.snapshot(addrs.clone(), services, concurrency));
+            vec
+        })
+        .await;
+
+    Response::object(&vec)
+}
+
+/// Retrieves the configuration in use by the proxy listening on the address
+pub async fn get_proxy_config(req: Request<Body>) -> HandlerResult {
+    let addr = req.param("addr").unwrap();
+    let manager: &ProxyManager = req.data().unwrap();
+
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|e| Error::other(format!("Invalid address '{}': {}", addr, e)))?;
+    let proxy = manager.proxy_by_addr(addr).await?;
+    let mut conf = (*proxy.conf).clone();
+    conf.server.server_cert = proxy.server_cert_conf();
+    let conf = conf
+        .to_redacted_json()
+        .map_err(|e| ApiErrorKind::InternalServerError(e.to_string()))?;
+
+    Response::object(&conf)
+}
+
+/// Streams recent log lines, one per line of a chunked response.
+/// Following streams lines logged afterwards, until the client disconnects
+pub async fn get_logs(req: Request<Body>) -> HandlerResult {
+    let query = req.uri().query().unwrap_or_default();
+    let query: model::LogQuery = serde_urlencoded::from_str(query)
+        .map_err(|e| Error::other(format!("Invalid query '{}': {}", query, e)))?;
+    let logs: &LogBuffer = req.data().unwrap();
+    let manager: &ProxyManager = req.data().unwrap();
+
+    let tail = query.tail.unwrap_or(DEFAULT_LOG_TAIL);
+    let service = query.service;
+    let text = |text: &str| Ok::<_, Infallible>(format!("{}\n", text));
+
+    let body = if query.follow {
+        let (lines, rx) = logs.follow(tail, service.as_deref());
+        let head = stream::iter(lines).map(move |l| text(&l.text));
+        let follow = stream::unfold(rx, move |mut rx| {
+            let service = service.clone();
+            async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(l) if l.matches(service.as_deref()) => return Some((text(&l.text), rx)),
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(n)) => {
+                            return Some((text(&format!("({} lines skipped)", n)), rx))
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        // finish the response, so that the server can shut down gracefully
+        let follow = follow.take_until(manager.stopped());
+        Body::wrap_stream(head.chain(follow))
+    } else {
+        let lines = logs.tail(tail, service.as_deref());
+        Body::wrap_stream(stream::iter(lines).map(move |l| text(&l.text)))
+    };
+
+    let res = Response::builder()
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .status(StatusCode::OK)
+        .body(body)?;
+    Ok(res)
+}
+
 /// Lists services
 pub async fn get_services(req: Request<Body>) -> HandlerResult {
     let manager: &ProxyManager = req.data().unwrap();
@@ -18,7 +182,7 @@ pub async fn get_services(req: Request<Body>) -> HandlerResult {
     let vec = stream::iter(proxies.read().await.values())
         .fold(vec, |mut vec, proxy| async move {
             let state = proxy.state.read().await;
-            vec.extend(state.by_endpoint.values().map(model::Service::from));
+            vec.extend(state.by_route.values().map(model::Service::from));
             vec
         })
         .await;
@@ -30,13 +194,22 @@ pub async fn get_services(req: Request<Body>) -> HandlerResult {
 pub async fn post_services(req: Request<Body>) -> HandlerResult {
     let (parts, body) = req.into_parts();
     let manager: &ProxyManager = parts.data().unwrap();
+    let idempotency: &Idempotency = parts.data().unwrap();
     let body = hyper::body::to_bytes(body).await?;
 
-    let mut create: model::CreateService = serde_json::from_slice(body.as_ref())?;
-    let proxy = manager.get_or_spawn(&mut create).await?;
-    let service: model::Service = proxy.add(create).await?;
+    let create: model::CreateService = serde_json::from_slice(body.as_ref())?;
+    let resource = format!("services/{}", create.name);
+    let location = format!("/{}", resource);
+    let correlation_id = correlation_id(&parts.headers);
+    let json = idempotency
+        .run(&parts.headers, resource, body.as_ref(), || async move {
+            let service: model::Service = manager.create_service(create, correlation_id).await?;
+            Ok(service)
+        })
+        .await?;
+    let etag = etag::of_json(&json);
 
-    Response::object(&service)
+    Response::created(&location, json).map(|res| with_etag(res, &etag))
 }
 
 /// Retrieves a single service
@@ -46,19 +219,148 @@ pub async fn get_service(req: Request<Body>) -> HandlerResult {
 
     let proxy = manager.proxy(service_name).await?;
     let service = proxy.get::<model::Service>(service_name).await?;
+    let etag = etag::service(&service);
+
+    Response::object(&service).map(|res| with_etag(res, &etag))
+}
+
+/// Replaces the definition of a service, keeping its users and stats
+pub async fn put_service(req: Request<Body>) -> HandlerResult {
+    let (parts, body) = req.into_parts();
+    let manager: &ProxyManager = parts.data().unwrap();
+    let body = hyper::body::to_bytes(body).await?;
+
+    let service_name = parts.param("service").unwrap();
+    let update: model::CreateService = serde_json::from_slice(body.as_ref())?;
+    let service: model::Service = manager
+        .update_service(
+            service_name,
+            update,
+            if_match(&parts.headers),
+            correlation_id(&parts.headers),
+        )
+        .await?;
+    let etag = etag::service(&service);
 
-    Response::object(&service)
+    Response::object(&service).map(|res| with_etag(res, &etag))
+}
+
+/// Reports the resources a service shares with other services
+pub async fn get_service_isolation(req: Request<Body>) -> HandlerResult {
+    let service_name = req.param("service").unwrap();
+    let manager: &ProxyManager = req.data().unwrap();
+
+    let isolation = manager.isolation(service_name).await?;
+
+    Response::object(&isolation)
+}
+
+/// Reloads the certificate served by the proxy instance of the service
+pub async fn post_service_cert_reload(req: Request<Body>) -> HandlerResult {
+    let service_name = req.param("service").unwrap();
+    let manager: &ProxyManager = req.data().unwrap();
+
+    let proxy = manager.proxy(service_name).await?;
+    let cert = proxy.reload_cert().await?;
+
+    Response::object(&cert)
+}
+
+/// Retrieves aggregated service stats
+pub async fn get_service_stats(req: Request<Body>) -> HandlerResult {
+    let service_name = req.param("service").unwrap();
+    let manager: &ProxyManager = req.data().unwrap();
+
+    let proxy = manager.proxy(service_name).await?;
+    let users = proxy.get_users(service_name).await?.len();
+    let upstreams = proxy.get_upstream_stats(service_name).await?;
+    let stats = proxy.stats.read().await;
+    let requests = stats.service.get(service_name).copied().unwrap_or_default();
+    let protocols = stats
+        .service_protocol
+        .get(service_name)
+        .map(|p| p.iter().map(|(k, v)| (k.to_string(), *v)).collect())
+        .unwrap_or_default();
+    let blocked = stats
+        .service_blocked
+        .get(service_name)
+        .copied()
+        .unwrap_or_default();
+    let bytes = stats
+        .service_bytes
+        .get(service_name)
+        .copied()
+        .unwrap_or_default();
+
+    Response::object(&model::ServiceStats {
+        users,
+        requests,
+        bytes,
+        protocols,
+        blocked,
+        upstreams,
+        tls_handshake_timeouts: proxy.handshake_timeouts.load(Ordering::Relaxed),
+    })
+}
+
+/// Lists timings of recent service requests
+pub async fn get_request_timings(req: Request<Body>) -> HandlerResult {
+    let service_name = req.param("service").unwrap();
+    let manager: &ProxyManager = req.data().unwrap();
+
+    let proxy = manager.proxy(service_name).await?;
+    let timings = proxy.get_request_timings(service_name).await?;
+
+    Response::object(&timings)
+}
+
+/// Exports service request journal entries from a time range
+pub async fn get_journal(req: Request<Body>) -> HandlerResult {
+    let service_name = req.param("service").unwrap();
+    let query = req.uri().query().unwrap_or_default();
+    let query: model::TimeRange = serde_urlencoded::from_str(query)
+        .map_err(|e| Error::other(format!("Invalid query '{}': {}", query, e)))?;
+    let manager: &ProxyManager = req.data().unwrap();
+
+    let proxy = manager.proxy(service_name).await?;
+    let entries = proxy.export_journal(service_name, query).await?;
+
+    Response::object(&entries)
+}
+
+/// Aggregates service usage per user over a time range
+pub async fn get_usage(req: Request<Body>) -> HandlerResult {
+    let service_name = req.param("service").unwrap();
+    let query = req.uri().query().unwrap_or_default();
+    let range: model::TimeRange = serde_urlencoded::from_str(query)
+        .map_err(|e| Error::other(format!("Invalid query '{}': {}", query, e)))?;
+    let manager: &ProxyManager = req.data().unwrap();
+
+    let proxy = manager.proxy(service_name).await?;
+    let usage = proxy.get_usage(service_name, range).await?;
+
+    Response::object(&usage)
 }
 
 /// Removes a service
 pub async fn delete_service(req: Request<Body>) -> HandlerResult {
     let service_name = req.param("service").unwrap();
     let manager: &ProxyManager = req.data().unwrap();
+    let idempotency: &Idempotency = req.data().unwrap();
 
     let proxy = manager.proxy(service_name).await?;
-    proxy.remove(service_name).await?;
+    proxy
+        .remove(
+            service_name,
+            if_match(req.headers()),
+            correlation_id(req.headers()),
+        )
+        .await?;
+    idempotency
+        .remove(&format!("services/{}", service_name))
+        .await;
 
-    Response::object(&())
+    Response::no_content()
 }
 
 /// Lists service users
@@ -71,10 +373,7 @@ pub async fn get_users(req: Request<Body>) -> HandlerResult {
         .get_users(service_name)
         .await?
         .into_iter()
-        .map(|u| model::User {
-            username: u.username,
-            created_at: u.created_at,
-        })
+        .map(|u| model::User::from(&u))
         .collect::<Vec<_>>();
 
     Response::object(&vec)
@@ -84,20 +383,55 @@ pub async fn get_users(req: Request<Body>) -> HandlerResult {
 pub async fn post_users(req: Request<Body>) -> HandlerResult {
     let (parts, body) = req.into_parts();
     let manager: &ProxyManager = parts.data().unwrap();
+    let idempotency: &Idempotency = parts.data().unwrap();
     let body = hyper::body::to_bytes(body).await?;
 
     let service_name = parts.param("service").unwrap();
     let create: model::CreateUser = serde_json::from_slice(body.as_ref())?;
+    let resource = format!("services/{}/users/{}", service_name, create.username);
+    let location = format!("/{}", resource);
+    let correlation_id = correlation_id(&parts.headers);
+    let json = idempotency
+        .run(&parts.headers, resource, body.as_ref(), || async move {
+            let proxy = manager.proxy(service_name).await?;
+            let user = proxy.add_user(service_name, create, correlation_id).await?;
+            Ok(model::User::from(&user))
+        })
+        .await?;
+    let etag = etag::of_json(&json);
+
+    Response::created(&location, json).map(|res| with_etag(res, &etag))
+}
+
+/// Generates new credentials for a service user
+pub async fn post_user_credentials_rotate(req: Request<Body>) -> HandlerResult {
+    let (parts, body) = req.into_parts();
+    let manager: &ProxyManager = parts.data().unwrap();
+    let body = hyper::body::to_bytes(body).await?;
+
+    let service_name = parts.param("service").unwrap();
+    let username = parts.param("user").unwrap();
+    let rotate: model::RotateCredentials = if body.is_empty() {
+        Default::default()
+    } else {
+        serde_json::from_slice(body.as_ref())?
+    };
 
     let proxy = manager.proxy(service_name).await?;
-    let user = proxy
-        .add_user(service_name, create.username, create.password)
+    let if_match = if_match(&parts.headers);
+    let correlation_id = correlation_id(&parts.headers);
+    let (user, credentials) = proxy
+        .rotate_user(service_name, username, rotate, if_match, correlation_id)
         .await?;
+    let etag = user.etag();
 
-    Response::object(&model::User {
+    Response::object(&model::UserCredentials {
+        fingerprint: Some(user.fingerprint.clone()),
         username: user.username,
+        credentials,
         created_at: user.created_at,
     })
+    .map(|res| with_etag(res, &etag))
 }
 
 /// Retrieves a single service user
@@ -109,10 +443,31 @@ pub async fn get_user(req: Request<Body>) -> HandlerResult {
     let proxy = manager.proxy(service_name).await?;
     let user = proxy.get_user(service_name, username).await?;
 
-    Response::object(&model::User {
-        username: user.username,
-        created_at: user.created_at,
-    })
+    Response::object(&model::User::from(&user)).map(|res| with_etag(res, &user.etag()))
+}
+
+/// Replaces credentials of a service user, keeping its stats
+pub async fn put_user(req: Request<Body>) -> HandlerResult {
+    let (parts, body) = req.into_parts();
+    let manager: &ProxyManager = parts.data().unwrap();
+    let body = hyper::body::to_bytes(body).await?;
+
+    let service_name = parts.param("service").unwrap();
+    let username = parts.param("user").unwrap();
+    let update: model::UpdateUser = serde_json::from_slice(body.as_ref())?;
+
+    let proxy = manager.proxy(service_name).await?;
+    let user = proxy
+        .update_user(
+            service_name,
+            username,
+            update,
+            if_match(&parts.headers),
+            correlation_id(&parts.headers),
+        )
+        .await?;
+
+    Response::object(&model::User::from(&user)).map(|res| with_etag(res, &user.etag()))
 }
 
 /// Removes a service user
@@ -120,11 +475,57 @@ pub async fn delete_user(req: Request<Body>) -> HandlerResult {
     let service_name = req.param("service").unwrap();
     let username = req.param("user").unwrap();
     let manager: &ProxyManager = req.data().unwrap();
+    let idempotency: &Idempotency = req.data().unwrap();
+
+    let proxy = manager.proxy(service_name).await?;
+    proxy
+        .remove_user(
+            service_name,
+            username,
+            if_match(req.headers()),
+            correlation_id(req.headers()),
+        )
+        .await?;
+    idempotency
+        .remove(&format!("services/{}/users/{}", service_name, username))
+        .await;
+
+    Response::no_content()
+}
+
+/// Retrieves credentials usage limits of a service user
+pub async fn get_user_limits(req: Request<Body>) -> HandlerResult {
+    let service_name = req.param("service").unwrap();
+    let username = req.param("user").unwrap();
+    let manager: &ProxyManager = req.data().unwrap();
 
     let proxy = manager.proxy(service_name).await?;
-    proxy.remove_user(service_name, username).await?;
+    let user = proxy.get_user(service_name, username).await?;
+
+    Response::object(&model::UserLimitsStatus::from(&user))
+}
+
+/// Sets the usage allowance of a service user
+pub async fn put_user_allowance(req: Request<Body>) -> HandlerResult {
+    let (parts, body) = req.into_parts();
+    let manager: &ProxyManager = parts.data().unwrap();
+    let body = hyper::body::to_bytes(body).await?;
+
+    let service_name = parts.param("service").unwrap();
+    let username = parts.param("user").unwrap();
+    let allowance: Option<model::UserAllowance> = serde_json::from_slice(body.as_ref())?;
+
+    let proxy = manager.proxy(service_name).await?;
+    let user = proxy
+        .set_user_allowance(
+            service_name,
+            username,
+            allowance,
+            correlation_id(&parts.headers),
+        )
+        .await?;
 
-    Response::object(&())
+    Response::object(&model::UserLimitsStatus::from(&user))
 }
 
 /// Retrieves service user stats
@@ -140,8 +541,15 @@ pub async fn get_user_stats(req: Request<Body>) -> HandlerResult {
         .get(username)
         .copied()
         .ok_or_else(|| UserError::NotFound(username.to_string()))?;
-
-    Response::object(&model::UserStats { requests })
+    let bytes = stats.user_bytes.get(username).copied().unwrap_or_default();
+    drop(stats);
+    let rate_limit = proxy.get_user_rate_limit(service_name, username).await?;
+
+    Response::object(&model::UserStats {
+        requests,
+        bytes,
+        rate_limit,
+    })
 }
 
 /// Retrieves service user stats per endpoint called
@@ -153,25 +561,88 @@ pub async fn get_user_endpoint_stats(req: Request<Body>) -> HandlerResult {
     let proxy = manager.proxy(service_name).await?;
     let stats = proxy.stats.read().await;
     let endpoint_requests = stats
-        .user_endpoint
-        .get(username)
+        .user_endpoint(username)
+        .ok_or_else(|| UserError::NotFound(username.to_string()))?;
+
+    Response::object(&model::UserEndpointStats(endpoint_requests))
+}
+
+/// Retrieves body bytes transferred by a service user per endpoint called
+pub async fn get_user_endpoint_bytes(req: Request<Body>) -> HandlerResult {
+    let service_name = req.param("service").unwrap();
+    let username = req.param("user").unwrap();
+    let manager: &ProxyManager = req.data().unwrap();
+
+    let proxy = manager.proxy(service_name).await?;
+    let stats = proxy.stats.read().await;
+    let endpoint_bytes = stats
+        .user_endpoint_bytes(username)
         .ok_or_else(|| UserError::NotFound(username.to_string()))?;
 
-    Response::object(&model::UserEndpointStats(endpoint_requests.clone()))
+    Response::object(&model::UserEndpointBytes(endpoint_bytes))
 }
 
 /// Shuts down the proxy
 pub async fn post_shutdown(req: Request<Body>) -> HandlerResult {
-    let manager: &ProxyManager = req.data().unwrap();
-    manager.stop().await;
+    let (parts, body) = req.into_parts();
+    let manager: &ProxyManager = parts.data().unwrap();
+    let body = hyper::body::to_bytes(body).await?;
 
-    Response::object(&())
+    let shutdown: model::Shutdown = serde_json::from_slice(body.as_ref())?;
+    if !shutdown.is_confirmed(manager.shutdown_nonce()) {
+        return Err(ManagementError::ShutdownNotConfirmed.into());
+    }
+    manager.stop(shutdown.drain).await;
+
+    Response::no_content()
+}
+
+/// Stops the proxy once in-flight requests are complete
+pub async fn post_drain(req: Request<Body>) -> HandlerResult {
+    let (parts, body) = req.into_parts();
+    let manager: &ProxyManager = parts.data().unwrap();
+    let body = hyper::body::to_bytes(body).await?;
+
+    let drain: model::Drain = serde_json::from_slice(body.as_ref())?;
+    if !drain.is_confirmed(manager.shutdown_nonce()) {
+        return Err(ManagementError::ShutdownNotConfirmed.into());
+    }
+    manager.drain(drain.timeout).await;
+
+    Response::no_content()
+}
+
+/// Reads the `If-Match` header. Values which are not valid strings never match
+fn if_match(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(IF_MATCH)
+        .map(|v| v.to_str().unwrap_or_default())
+}
+
+fn correlation_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(model::CORRELATION_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+}
+
+/// Sets the entity tag of the returned resource
+fn with_etag<B>(mut res: Response<B>, etag: &str) -> Response<B> {
+    if let Ok(value) = HeaderValue::from_str(etag) {
+        res.headers_mut().insert(ETAG, value);
+    }
+    res
 }
 
 trait ResponseExt<B, E> {
     fn object<T>(t: &T) -> Result<Response<B>, E>
     where
         T: serde::Serialize;
+
+    /// Responds with a resource created at `location`
+    fn created(location: &str, ser: String) -> Result<Response<B>, E>;
+
+    fn no_content() -> Result<Response<B>, E>;
 }
 
 impl<B, E> ResponseExt<B, E> for Response<B>
@@ -191,4 +662,20 @@ where
             .body(B::from(ser))?;
         Ok(res)
     }
+
+    fn created(location: &str, ser: String) -> Result<Response<B>, E> {
+        let res = Response::builder()
+            .header("Content-Type", "application/json")
+            .header("Location", location)
+            .status(StatusCode::CREATED)
+            .body(B::from(ser))?;
+        Ok(res)
+    }
+
+    fn no_content() -> Result<Response<B>, E> {
+        let res = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(B::from(String::new()))?;
+        Ok(res)
+    }
 }
@@ -0,0 +1,202 @@
+//! Replays of resource creation requests carrying an `Idempotency-Key` header
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::HeaderMap;
+use serde::Serialize;
+
+use crate::api::ApiErrorKind;
+use crate::proxy::digest;
+use crate::ManagementError;
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const PRUNE_SIZE: usize = 4096;
+
+/// Responses to creation requests, by idempotency key
+#[derive(Default)]
+pub struct Idempotency {
+    slots: Mutex<HashMap<String, Arc<Slot>>>,
+}
+
+/// Response to requests with a single key, locked while a request is processed
+struct Slot {
+    resource: String,
+    entry: tokio::sync::Mutex<Option<Entry>>,
+}
+
+struct Entry {
+    request: String,
+    response: String,
+    created_at: Instant,
+}
+
+impl Idempotency {
+    /// Runs `f` unless a request with the same key has already succeeded,
+    /// in which case the previous response is returned.
+    /// Requests with the same key are processed sequentially.
+    pub async fn run<F, Fut, T>(
+        &self,
+        headers: &HeaderMap,
+        resource: String,
+        body: &[u8],
+        f: F,
+    ) -> Result<String, ApiErrorKind>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, ApiErrorKind>>,
+        T: Serialize,
+    {
+        let key = match headers.get(IDEMPOTENCY_KEY) {
+            Some(value) => {
+                let value = value
+                    .to_str()
+                    .map_err(|_| ManagementError::InvalidIdempotencyKey)?;
+                // scope keys by resource
+                format!("{}\n{}", resource, value)
+            }
+            None => return serialize(&f().await?),
+        };
+        let request = digest(&String::from_utf8_lossy(body));
+
+        let slot = {
+            let mut slots = self.slots.lock().unwrap();
+            if slots.len() >= PRUNE_SIZE {
+                slots.retain(|_, slot| !slot.is_expired());
+            }
+            slots
+                .entry(key)
+                .or_insert_with(|| {
+                    Arc::new(Slot {
+                        resource,
+                        entry: Default::default(),
+                    })
+                })
+                .clone()
+        };
+
+        // requests with other keys are not held up
+        let mut entry = slot.entry.lock().await;
+        if let Some(ref entry) = *entry {
+            if entry.created_at.elapsed() < TTL {
+                if entry.request != request {
+                    return Err(ManagementError::IdempotencyKeyReused.into());
+                }
+                return Ok(entry.response.clone());
+            }
+        }
+
+        let response = serialize(&f().await?)?;
+        entry.replace(Entry {
+            request,
+            response: response.clone(),
+            created_at: Instant::now(),
+        });
+
+        Ok(response)
+    }
+
+    /// Forgets responses for a removed resource and resources nested within
+    pub async fn remove(&self, resource: &str) {
+        let nested = format!("{}/", resource);
+        let mut slots = self.slots.lock().unwrap();
+        slots.retain(|_, s| s.resource != resource && !s.resource.starts_with(&nested));
+    }
+}
+
+impl Slot {
+    /// Whether the slot holds no response to replay and no request is being processed
+    fn is_expired(&self) -> bool {
+        match self.entry.try_lock() {
+            Ok(entry) => match *entry {
+                Some(ref entry) => entry.created_at.elapsed() >= TTL,
+                None => true,
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+fn serialize<T: Serialize>(t: &T) -> Result<String, ApiErrorKind> {
+    serde_json::to_string(t).map_err(|e| ApiErrorKind::InternalServerError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{Idempotency, IDEMPOTENCY_KEY};
+    use crate::api::ApiErrorKind;
+    use futures::channel::oneshot;
+    use hyper::header::HeaderValue;
+    use hyper::HeaderMap;
+
+    #[tokio::test]
+    async fn replay_response() {
+        let idempotency = Idempotency::default();
+        let mut headers = HeaderMap::new();
+        headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static("key"));
+
+        let run = |body: &'static str, value: u32| {
+            idempotency.run(
+                &headers,
+                "services/s".into(),
+                body.as_bytes(),
+                move || async move { Ok::<_, ApiErrorKind>(value) },
+            )
+        };
+
+        assert_eq!(run("body", 1).await.unwrap(), "1");
+        assert_eq!(run("body", 2).await.unwrap(), "1");
+        assert!(run("other", 3).await.is_err());
+
+        idempotency.remove("services/s").await;
+        assert_eq!(run("body", 4).await.unwrap(), "4");
+    }
+
+    #[tokio::test]
+    async fn concurrent_keys() {
+        let idempotency = Arc::new(Idempotency::default());
+        let headers = |key: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static(key));
+            headers
+        };
+        let (tx, rx) = oneshot::channel::<()>();
+
+        // a request waiting for its creation to finish
+        let pending = tokio::spawn({
+            let idempotency = idempotency.clone();
+            let first = headers("first");
+            async move {
+                let run = idempotency.run(&first, "services/a".into(), b"", || async {
+                    let _ = rx.await;
+                    Ok::<_, ApiErrorKind>(1)
+                });
+                run.await
+            }
+        });
+        tokio::task::yield_now().await;
+
+        // does not block requests with other keys
+        let second = headers("second");
+        let other = idempotency.run(&second, "services/b".into(), b"", || async {
+            Ok::<_, ApiErrorKind>(2)
+        });
+        let other = tokio::time::timeout(Duration::from_secs(5), other).await;
+        assert_eq!(other.unwrap().unwrap(), "2");
+
+        // while requests with the same key wait for the response
+        let first = headers("first");
+        let same = idempotency.run(&first, "services/a".into(), b"", || async {
+            Ok::<_, ApiErrorKind>(3)
+        });
+        tx.send(()).unwrap();
+        assert_eq!(same.await.unwrap(), "1");
+        assert_eq!(pending.await.unwrap().unwrap(), "1");
+    }
+}
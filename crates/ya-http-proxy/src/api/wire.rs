@@ -0,0 +1,107 @@
+//! Management API request and response logging
+use std::sync::Arc;
+use std::time::Instant;
+
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Response};
+use routerify::prelude::*;
+use routerify::RequestInfo;
+
+use crate::api::HandlerError;
+use crate::conf::WireLogConf;
+use ya_http_proxy_model::{redact_json, snippet};
+
+/// Number of characters of a body to include in logs
+const SNIPPET_LEN: usize = 4096;
+/// Bodies of unknown or larger size are not buffered for logging
+const MAX_CAPTURED_LEN: u64 = 64 * 1024;
+const NOT_CAPTURED: &str = "<not captured>";
+/// Routes responding with streams that may never end
+const STREAMING_PATHS: &[&str] = &["/events", "/logs"];
+
+#[derive(Clone)]
+struct RequestBody(Arc<str>);
+
+pub(super) async fn log_request(
+    req: Request<Body>,
+    conf: Arc<WireLogConf>,
+) -> Result<Request<Body>, HandlerError> {
+    log::debug!(
+        "{} {} {}",
+        req.remote_addr(),
+        req.method(),
+        req.uri().path()
+    );
+    if !conf.enabled {
+        return Ok(req);
+    }
+
+    let req = if conf.bodies && capturable(req.body()) {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        let req = Request::from_parts(parts, Body::from(body.clone()));
+        req.set_context(RequestBody(String::from_utf8_lossy(&body).into()));
+        req
+    } else {
+        req
+    };
+    req.set_context(Instant::now());
+    Ok(req)
+}
+
+pub(super) async fn log_response(
+    res: Response<Body>,
+    info: RequestInfo,
+    conf: Arc<WireLogConf>,
+) -> Result<Response<Body>, HandlerError> {
+    if !conf.enabled {
+        return Ok(res);
+    }
+
+    let elapsed = info
+        .context::<Instant>()
+        .map(|started| started.elapsed().as_millis())
+        .unwrap_or_default();
+    let (method, path, status) = (info.method(), info.uri().path(), res.status().as_u16());
+
+    // streams are not buffered and the dashboard page is not worth logging
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let skip_body = STREAMING_PATHS.contains(&path)
+        || ["text/event-stream", "text/html"]
+            .iter()
+            .any(|t| content_type.starts_with(t));
+
+    if !conf.bodies || skip_body || !capturable(res.body()) {
+        log::info!("{} {} {} {} ms", method, path, status, elapsed);
+        return Ok(res);
+    }
+
+    let (parts, body) = res.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let request = info
+        .context::<RequestBody>()
+        .map(|b| b.0)
+        .unwrap_or_else(|| NOT_CAPTURED.into());
+    log::info!(
+        "{} {} {} {} ms; request: '{}'; response: '{}'",
+        method,
+        path,
+        status,
+        elapsed,
+        snippet(&redact_json(&request, &conf.redact), SNIPPET_LEN),
+        snippet(
+            &redact_json(&String::from_utf8_lossy(&body), &conf.redact),
+            SNIPPET_LEN
+        ),
+    );
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+fn capturable(body: &Body) -> bool {
+    matches!(body.size_hint().upper(), Some(len) if len <= MAX_CAPTURED_LEN)
+}
@@ -7,6 +7,7 @@ use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::{env, fs, io};
 
+use flexi_logger::writers::LogWriter;
 use flexi_logger::*;
 use futures::future::{select, Either};
 use structopt::StructOpt;
@@ -14,19 +15,38 @@ use tokio::runtime;
 use tokio::signal::ctrl_c;
 use tokio::task;
 
-use ya_http_proxy::{Management, ProxyConf, ProxyManager};
+use ya_http_proxy::{
+    IssueLevel, LogBuffer, LogLine, Management, ProxyConf, ProxyManager, WebhookConf,
+};
+use ya_http_proxy_model::{self as model, Secret};
+
+mod hardening;
+mod instance;
 
 #[derive(StructOpt, Debug)]
 struct Cli {
     /// Path to a custom configuration file
     #[structopt(long, short)]
     pub config: Option<PathBuf>,
+    /// Name of the proxy instance. Named instances default to a distinct Management API port
+    /// and log file name, so that independent proxies run side by side
+    #[structopt(long, env = "YA_HTTP_PROXY_INSTANCE")]
+    pub instance_name: Option<String>,
     /// Path to write logs to
     #[structopt(long, short)]
     pub log_dir: Option<PathBuf>,
-    /// Management API address
-    #[structopt(long, short, default_value = "127.0.0.1:6668")]
-    pub management_addr: SocketAddr,
+    /// Management API address [default: 127.0.0.1:6668, or a port derived from the instance name]
+    #[structopt(long, short)]
+    pub management_addr: Option<SocketAddr>,
+    /// File to write the bound Management API address to
+    #[structopt(long)]
+    pub management_addr_file: Option<PathBuf>,
+    /// Directory to write the readiness file to. A single proxy instance runs per directory
+    #[structopt(long)]
+    pub status_dir: Option<PathBuf>,
+    /// Bearer token required by the Management API
+    #[structopt(long, env = "YA_HTTP_PROXY_MANAGEMENT_TOKEN", hide_env_values = true)]
+    pub management_token: Option<Secret<String>>,
     /// Default proxy address
     #[structopt(long, short)]
     pub default_addr: Option<SocketAddr>,
@@ -36,6 +56,39 @@ struct Cli {
     /// Default proxy certificate key path
     #[structopt(long)]
     pub default_key: Option<PathBuf>,
+    /// URL to send event notifications to
+    #[structopt(long)]
+    pub webhook: Vec<String>,
+    /// Log Management API requests and responses, with credentials redacted
+    #[structopt(long)]
+    pub wire_log: bool,
+    /// User to switch to after binding the Management API
+    #[structopt(long)]
+    pub user: Option<String>,
+    /// Group to switch to after binding the Management API
+    #[structopt(long)]
+    pub group: Option<String>,
+    /// Directory to change the root directory to after binding the Management API
+    #[structopt(long)]
+    pub chroot: Option<PathBuf>,
+    /// Working directory
+    #[structopt(long)]
+    pub working_dir: Option<PathBuf>,
+    /// Allow only the system calls needed by the proxy (Linux only)
+    #[structopt(long)]
+    pub seccomp: bool,
+    /// Restrict filesystem access to configuration, certificate and log paths (Linux only)
+    #[structopt(long)]
+    pub landlock: bool,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(rename_all = "kebab-case")]
+enum Command {
+    /// Reports contradictory configuration settings and exits
+    Check,
 }
 
 impl Cli {
@@ -49,15 +102,122 @@ impl Cli {
         if let Some(ref path) = self.default_key {
             conf.server.server_cert.server_key_path = Some(path.clone());
         }
+        conf.hooks
+            .webhooks
+            .extend(self.webhook.iter().map(WebhookConf::new));
+
+        if self.wire_log {
+            conf.wire_log.enabled = true;
+        }
+
+        let security = &mut conf.security;
+        if let Some(ref user) = self.user {
+            security.user = Some(user.clone());
+        }
+        if let Some(ref group) = self.group {
+            security.group = Some(group.clone());
+        }
+        if let Some(ref path) = self.chroot {
+            security.chroot = Some(path.clone());
+        }
+        if let Some(ref path) = self.working_dir {
+            security.working_dir = Some(path.clone());
+        }
+        if self.seccomp {
+            security.seccomp = Some(true);
+        }
+        if self.landlock {
+            security.landlock = Some(true);
+        }
+
+        if security.landlock.unwrap_or(false) {
+            let cert = &conf.server.server_cert;
+            let read = [
+                self.config.as_ref(),
+                cert.server_cert_store_path.as_ref(),
+                cert.server_key_path.as_ref(),
+                conf.client.client_cert.client_ca_cert_store_path.as_ref(),
+                conf.server.geoip_database.as_ref(),
+            ];
+            let write = [
+                self.log_dir.clone(),
+                self.management_addr_file
+                    .as_ref()
+                    .and_then(|p| p.parent())
+                    .map(Path::to_path_buf),
+                self.status_dir.clone(),
+                conf.access_log
+                    .path
+                    .as_ref()
+                    .and_then(|p| p.parent())
+                    .map(Path::to_path_buf),
+            ];
+            security
+                .read_paths
+                .extend(read.into_iter().flatten().cloned());
+            security.write_paths.extend(write.into_iter().flatten());
+        }
     }
 }
 
-async fn run(addr: SocketAddr, conf: ProxyConf) -> anyhow::Result<()> {
-    let mut server = Management::new(ProxyManager::new(conf));
+async fn run(
+    addr: SocketAddr,
+    addr_file: Option<PathBuf>,
+    status_dir: Option<PathBuf>,
+    token: Option<String>,
+    conf: ProxyConf,
+    logs: LogBuffer,
+) -> anyhow::Result<()> {
+    let instance = match status_dir {
+        Some(ref dir) => match instance::Instance::claim(dir)? {
+            Some(instance) => Some(instance),
+            None => {
+                log::info!(
+                    "Another proxy instance is running for '{}', exiting",
+                    dir.display()
+                );
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    let security = conf.security.clone();
+    let mut server = Management::new(ProxyManager::new(conf))
+        .with_token(token)
+        .with_logs(logs);
 
     server.bind(addr)?;
+    let addr = server.local_addr()?;
     log::info!("Management API server is listening on {}", addr);
 
+    if let Some(ref path) = addr_file {
+        write_atomic(path, addr.to_string())?;
+    }
+
+    let ready = model::Ready {
+        pid: std::process::id(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        api_version: model::API_VERSION.to_string(),
+        management_url: format!("http://{}", addr),
+        management_addr: addr,
+    };
+    let ready_file = status_dir.map(|dir| dir.join(model::READY_FILE_NAME));
+    if let Some(ref path) = ready_file {
+        write_atomic(path, serde_json::to_string(&ready)?)?;
+    }
+    if let Some(instance) = instance {
+        instance.publish(&ready)?;
+    }
+    hardening::apply(&security)?;
+
+    log::info!(
+        "ya-http-proxy v{} ready [pid {}], Management API: {}",
+        ready.version,
+        ready.pid,
+        ready.management_url
+    );
+
     let ctrl_c = ctrl_c();
     futures::pin_mut!(ctrl_c);
     futures::pin_mut!(server);
@@ -67,15 +227,35 @@ async fn run(addr: SocketAddr, conf: ProxyConf) -> anyhow::Result<()> {
         Either::Right(_) => log::info!("Management API server has terminated"),
     }
 
+    for path in addr_file.iter().chain(ready_file.iter()) {
+        let _ = fs::remove_file(path);
+    }
+
     log::info!("Server stopped");
     Ok(())
 }
 
-fn setup_logging(log_dir: Option<impl AsRef<Path>>) -> anyhow::Result<()> {
+/// Publishes a status file; readers never observe a partially written file
+fn write_atomic(path: &Path, contents: String) -> anyhow::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn setup_logging(
+    log_dir: Option<impl AsRef<Path>>,
+    instance_name: Option<&str>,
+    logs: LogBuffer,
+) -> anyhow::Result<()> {
     let log_level = env::var("PROXY_LOG").unwrap_or_else(|_| "info".into());
     env::set_var("PROXY_LOG", &log_level);
 
-    let mut logger = Logger::try_with_str(&log_level)?;
+    let mut logger = Logger::try_with_str(&log_level)?
+        .log_to_writer(Box::new(BufferWriter(logs.clone())))
+        .duplicate_to_stderr(Duplicate::All);
 
     if let Some(log_dir) = log_dir {
         let log_dir = log_dir.as_ref();
@@ -86,9 +266,12 @@ fn setup_logging(log_dir: Option<impl AsRef<Path>>) -> anyhow::Result<()> {
             Err(e) => anyhow::bail!(format!("invalid log path: {}", e)),
         }
 
+        let mut file_spec = FileSpec::default().directory(log_dir);
+        if let Some(name) = instance_name {
+            file_spec = file_spec.discriminant(name);
+        }
         logger = logger
-            .log_to_file(FileSpec::default().directory(log_dir))
-            .duplicate_to_stderr(Duplicate::All)
+            .log_to_file_and_writer(file_spec, Box::new(BufferWriter(logs)))
             .rotate(
                 Criterion::Size(2 * 1024 * 1024),
                 Naming::Timestamps,
@@ -105,6 +288,23 @@ fn setup_logging(log_dir: Option<impl AsRef<Path>>) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Keeps recent log lines for the Management API
+struct BufferWriter(LogBuffer);
+
+impl LogWriter for BufferWriter {
+    fn write(&self, now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+        let mut text = Vec::new();
+        log_format(&mut text, now, record)?;
+        let text = String::from_utf8_lossy(&text).into_owned();
+        self.0.push(LogLine::new(&record.args().to_string(), text));
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 fn log_format(
     w: &mut dyn std::io::Write,
     now: &mut DeferredNow,
@@ -130,8 +330,16 @@ fn log_format(
 fn main() -> anyhow::Result<()> {
     let _ = dotenv::dotenv();
     let cli: Cli = Cli::from_args();
+    if let Some(ref name) = cli.instance_name {
+        model::check_instance_name(name).map_err(anyhow::Error::msg)?;
+    }
 
-    setup_logging(cli.log_dir.as_ref())?;
+    let logs = LogBuffer::default();
+    setup_logging(
+        cli.log_dir.as_ref(),
+        cli.instance_name.as_deref(),
+        logs.clone(),
+    )?;
 
     let mut conf = match cli.config {
         Some(ref path) => ProxyConf::from_path(path)?,
@@ -140,7 +348,28 @@ fn main() -> anyhow::Result<()> {
 
     cli.update_conf(&mut conf);
 
-    if !cli.management_addr.ip().is_loopback() {
+    let issues = conf.check();
+    if let Some(Command::Check) = cli.command {
+        println!("{}", serde_json::to_string_pretty(&issues)?);
+    } else {
+        for issue in issues.iter() {
+            match issue.level {
+                IssueLevel::Warning => log::warn!("Setting {}: {}", issue.setting, issue.message),
+                IssueLevel::Error => log::error!("Setting {}: {}", issue.setting, issue.message),
+            }
+        }
+    }
+    if issues.iter().any(|i| i.level == IssueLevel::Error) {
+        anyhow::bail!("Invalid configuration");
+    }
+    if cli.command.is_some() {
+        return Ok(());
+    }
+
+    let management_addr = cli
+        .management_addr
+        .unwrap_or_else(|| model::instance_management_addr(cli.instance_name.as_deref()));
+    if !management_addr.ip().is_loopback() {
         log::warn!("!!! Management API server will NOT be bound to a loopback address !!!");
         log::warn!("This is a dangerous action and should be taken with care");
     }
@@ -158,7 +387,13 @@ fn main() -> anyhow::Result<()> {
         .build()?;
 
     let task_set = task::LocalSet::new();
-    task_set.block_on(&rt, run(cli.management_addr, conf))?;
+    let addr_file = cli.management_addr_file.clone();
+    let status_dir = cli.status_dir.clone();
+    let token = cli.management_token.clone().map(Secret::into_inner);
+    task_set.block_on(
+        &rt,
+        run(management_addr, addr_file, status_dir, token, conf, logs),
+    )?;
 
     Ok(())
 }
@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Log of requests received by all proxy instances, rotated by size
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessLogConf {
+    /// Log file. Requests are not logged when not set
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// File size in bytes after which the log is rotated
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    /// Number of rotated files to keep
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+impl Default for AccessLogConf {
+    fn default() -> Self {
+        Self {
+            path: Default::default(),
+            format: Default::default(),
+            max_file_size: default_max_file_size(),
+            max_files: default_max_files(),
+        }
+    }
+}
+
+/// Access log line format
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// JSON object per line
+    Json,
+    /// NCSA combined log format, followed by the request latency in milliseconds
+    Combined,
+}
+
+impl Default for AccessLogFormat {
+    fn default() -> Self {
+        Self::Json
+    }
+}
+
+fn default_max_file_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    8
+}
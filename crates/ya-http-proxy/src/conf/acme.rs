@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use ya_http_proxy_model::deser;
+
+/// Let's Encrypt production directory
+pub const LETS_ENCRYPT_DIRECTORY_URL: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Certificates obtained via the ACME protocol, for services configured with `cert: { acme: true }`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AcmeConf {
+    /// ACME server directory URL
+    #[serde(default = "default_directory_url")]
+    pub directory_url: String,
+    /// Account contact URLs, e.g. `mailto:admin@example.com`
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Directory of the account key and the obtained certificates
+    #[serde(default)]
+    pub storage_dir: Option<PathBuf>,
+    /// Challenge proving the control of domains
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    /// Time before expiry when certificates are renewed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, with = "deser::duration::opt_ms")]
+    pub renew_before: Option<Duration>,
+}
+
+impl Default for AcmeConf {
+    fn default() -> Self {
+        Self {
+            directory_url: default_directory_url(),
+            contact: Default::default(),
+            storage_dir: Default::default(),
+            challenge: Default::default(),
+            renew_before: Default::default(),
+        }
+    }
+}
+
+/// ACME challenge type
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AcmeChallenge {
+    /// Responses served over HTTP on port 80
+    #[serde(rename = "http-01")]
+    Http01,
+    /// Certificates served over TLS on port 443
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+impl Default for AcmeChallenge {
+    fn default() -> Self {
+        Self::Http01
+    }
+}
+
+fn default_directory_url() -> String {
+    LETS_ENCRYPT_DIRECTORY_URL.to_string()
+}
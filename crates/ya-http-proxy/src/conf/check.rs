@@ -0,0 +1,190 @@
+//! Detection of contradictory configuration settings
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::conf::{ClientConf, CommonConf, ProxyConf};
+
+/// Configuration issue severity
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum IssueLevel {
+    /// The setting has no effect or may not work as intended
+    Warning,
+    /// The proxy cannot work with the setting
+    Error,
+}
+
+/// Contradictory or ineffective setting
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfIssue {
+    pub level: IssueLevel,
+    pub setting: &'static str,
+    pub message: String,
+}
+
+impl ConfIssue {
+    fn warning(setting: &'static str, message: impl ToString) -> Self {
+        Self {
+            level: IssueLevel::Warning,
+            setting,
+            message: message.to_string(),
+        }
+    }
+
+    fn error(setting: &'static str, message: impl ToString) -> Self {
+        Self {
+            level: IssueLevel::Error,
+            setting,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl ProxyConf {
+    /// Checks the configuration for settings contradicting each other
+    pub fn check(&self) -> Vec<ConfIssue> {
+        let mut issues = Vec::new();
+        let server = &self.server;
+
+        if let (Some(https), Some(http)) = (&server.bind_https, &server.bind_http) {
+            if https.overlaps(http) {
+                issues.push(ConfIssue::error(
+                    "bind_http",
+                    format!("HTTP and HTTPS addresses overlap: {} and {}", http, https),
+                ));
+            }
+        }
+        if server.bind_https.is_some() {
+            let cert = &server.server_cert;
+            if cert.server_cert_store_path.is_none() || cert.server_key_path.is_none() {
+                issues.push(ConfIssue::warning(
+                    "bind_https",
+                    "certificate paths are not set, services need to provide their own",
+                ));
+            }
+        }
+        if server.max_pending_connections == Some(0) {
+            issues.push(ConfIssue::warning(
+                "max_pending_connections",
+                "a limit of 0 is raised to 1",
+            ));
+        }
+        if server.http1_only.is_some() {
+            issues.push(ConfIssue::warning(
+                "http1_only",
+                "deprecated, sets accepted protocols of services which do not set them",
+            ));
+        }
+        if server.server_common.http2_only.is_some() {
+            issues.push(ConfIssue::warning(
+                "http2_only",
+                "has no effect on the server, accepted protocols are set per service",
+            ));
+        }
+
+        check_keep_alive(&server.server_common, &mut issues);
+        check_keep_alive(&self.client.client_common, &mut issues);
+        check_client(&self.client, &mut issues);
+        issues
+    }
+}
+
+fn keep_alive_disabled(interval: &Option<Option<Duration>>) -> bool {
+    !matches!(interval, Some(Some(_)))
+}
+
+fn check_keep_alive(common: &CommonConf, issues: &mut Vec<ConfIssue>) {
+    if common.http2_keep_alive_timeout.is_some()
+        && keep_alive_disabled(&common.http2_keep_alive_interval)
+    {
+        issues.push(ConfIssue::warning(
+            "http2_keep_alive_timeout",
+            "has no effect without http2_keep_alive_interval",
+        ));
+    }
+}
+
+fn check_client(client: &ClientConf, issues: &mut Vec<ConfIssue>) {
+    let common = &client.client_common;
+
+    if client.http2_keep_alive_while_idle == Some(true)
+        && keep_alive_disabled(&common.http2_keep_alive_interval)
+    {
+        issues.push(ConfIssue::warning(
+            "http2_keep_alive_while_idle",
+            "has no effect without http2_keep_alive_interval",
+        ));
+    }
+
+    if common.http2_only == Some(true) {
+        let http1 = [
+            ("http09_responses", client.http09_responses.is_some()),
+            (
+                "http1_read_buf_exact_size",
+                client.http1_read_buf_exact_size.is_some(),
+            ),
+            (
+                "http1_allow_spaces_after_header_name_in_responses",
+                client
+                    .http1_allow_spaces_after_header_name_in_responses
+                    .is_some(),
+            ),
+            ("http1_writev", common.http1_writev.is_some()),
+            ("http1_max_buf_size", common.http1_max_buf_size.is_some()),
+            (
+                "http1_title_case_headers",
+                common.http1_title_case_headers.is_some(),
+            ),
+            (
+                "http1_preserve_header_case",
+                common.http1_preserve_header_case.is_some(),
+            ),
+        ];
+        for (setting, _) in http1.iter().filter(|(_, set)| *set) {
+            issues.push(ConfIssue::warning(
+                setting,
+                "HTTP/1 option has no effect with http2_only",
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::IssueLevel;
+    use crate::conf::ProxyConf;
+
+    #[test]
+    fn contradictory_settings() {
+        let mut conf = ProxyConf::default();
+        assert!(conf.check().is_empty());
+
+        let https: SocketAddr = "0.0.0.0:443".parse().unwrap();
+        let http: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        conf.server.bind_https = Some(https.into());
+        conf.server.bind_http = Some(http.into());
+        conf.client.client_common.http2_only = Some(true);
+        conf.client.client_common.http1_title_case_headers = Some(true);
+        conf.client.client_common.http2_keep_alive_timeout = Some(Duration::from_secs(1));
+        conf.server.http1_only = Some(true);
+
+        let issues = conf.check();
+        let setting = |name: &str| issues.iter().find(|i| i.setting == name).map(|i| i.level);
+        assert_eq!(setting("bind_http"), Some(IssueLevel::Error));
+        assert_eq!(setting("bind_https"), Some(IssueLevel::Warning));
+        assert_eq!(
+            setting("http1_title_case_headers"),
+            Some(IssueLevel::Warning)
+        );
+        assert_eq!(
+            setting("http2_keep_alive_timeout"),
+            Some(IssueLevel::Warning)
+        );
+        assert_eq!(setting("http1_only"), Some(IssueLevel::Warning));
+        assert_eq!(issues.len(), 5);
+    }
+}
@@ -62,6 +62,7 @@ macro_rules! conf_builder_client {
         );
 
         let common = &$src.client_common;
+        $crate::conf_builder!($dst, common, [http2_only]);
         $crate::conf_builder_common!($dst, common);
     }};
 }
@@ -15,6 +15,7 @@ pub struct CommonConf {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub http1_preserve_header_case: Option<bool>,
 
+    /// Applies to upstream connections only; see `CreateService::protocols`
     #[serde(skip_serializing_if = "Option::is_none")]
     pub http2_only: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -61,7 +62,6 @@ macro_rules! conf_builder_common {
                 http1_max_buf_size,
                 http1_title_case_headers,
                 http1_preserve_header_case,
-                http2_only,
                 http2_initial_stream_window_size,
                 http2_initial_connection_window_size,
                 http2_adaptive_window,
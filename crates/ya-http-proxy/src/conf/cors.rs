@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use ya_http_proxy_model::deser;
+
+/// Management API access from browser-based dashboards. Only allowed when a Management API
+/// token is required
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorsConf {
+    /// Origins allowed to call the API, e.g. `https://dashboard.example.com`, or `*` for any.
+    /// Cross-origin requests are not allowed when empty
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Time browsers may cache preflight responses for
+    #[serde(default, with = "deser::duration::opt_ms")]
+    pub max_age: Option<Duration>,
+}
+
+impl CorsConf {
+    pub fn enabled(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+
+    /// Checks whether the origin is allowed
+    pub fn allows(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|allowed| {
+            allowed == "*" || allowed.trim_end_matches('/').eq_ignore_ascii_case(origin)
+        })
+    }
+}
@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use ya_http_proxy_model::deser;
+
+/// Management event notification configuration
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HooksConf {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<WebhookConf>,
+}
+
+/// Webhook receiving JSON event notifications
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookConf {
+    pub url: String,
+    /// Names of events to notify about. All events are sent when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, with = "deser::duration::opt_ms")]
+    pub timeout: Option<Duration>,
+}
+
+impl WebhookConf {
+    pub fn new(url: impl ToString) -> Self {
+        Self {
+            url: url.to_string(),
+            events: Default::default(),
+            timeout: None,
+        }
+    }
+
+    pub fn accepts(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event)
+    }
+}
@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 use serde_default::DefaultFromSerde;
 
 use crate::conf::common::CommonConf;
-use ya_http_proxy_model::{deser, Addresses};
+use ya_http_proxy_model::{deser, Addresses, HttpProtocols, ServerTuning};
 
 /// Configuration for the HTTP proxy server
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, DefaultFromSerde)]
@@ -23,6 +23,9 @@ pub struct ServerConf {
     /// Number of CPU (worker) threads to use
     #[serde(default)]
     pub cpu_threads: Option<usize>,
+    /// MaxMind GeoIP2 / GeoLite2 country database path
+    #[serde(default)]
+    pub geoip_database: Option<PathBuf>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(with = "deser::duration::double_opt_ms")]
@@ -34,6 +37,24 @@ pub struct ServerConf {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default = "default::tcp_sleep_on_accept_errors")]
     pub tcp_sleep_on_accept_errors: Option<bool>,
+    /// Maximum number of accepted connections awaiting a TLS handshake or a server worker.
+    /// New connections are not accepted while the limit is reached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub max_pending_connections: Option<usize>,
+    /// Time limit for completing a TLS handshake
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, with = "deser::duration::opt_ms")]
+    pub tls_handshake_timeout: Option<Duration>,
+    /// Time to wait for in-flight requests when draining the proxy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, with = "deser::duration::opt_ms")]
+    pub drain_timeout: Option<Duration>,
+    /// Interval of checking whether the server certificate files were modified,
+    /// reloading the certificate when they were
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, with = "deser::duration::opt_ms")]
+    pub cert_watch_interval: Option<Duration>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default = "default::http1_keepalive")]
@@ -47,8 +68,10 @@ pub struct ServerConf {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default, with = "deser::duration::opt_ms")]
     pub http1_header_read_timeout: Option<Duration>,
+    /// Deprecated, superseded by `CreateService::protocols`. Services which do not set
+    /// accepted protocols accept HTTP/1.x only
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(default = "default::http1_only")]
+    #[serde(default)]
     pub http1_only: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
@@ -64,6 +87,57 @@ impl ServerConf {
     pub fn addresses(&self) -> Addresses {
         self.bind_https.clone().unwrap_or_default() + self.bind_http.clone().unwrap_or_default()
     }
+
+    /// Protocols accepted by services which do not set them
+    pub fn default_protocols(&self) -> Option<HttpProtocols> {
+        match self.http1_only {
+            Some(true) => Some(HttpProtocols::Http1),
+            _ => None,
+        }
+    }
+
+    /// Overrides server settings with the ones set in `tuning`
+    pub fn apply_tuning(&mut self, tuning: &ServerTuning) {
+        let common = &mut self.server_common;
+
+        if let Some(v) = tuning.http1_keepalive {
+            self.http1_keepalive = Some(v);
+        }
+        if let Some(v) = tuning.http2_initial_stream_window_size {
+            common.http2_initial_stream_window_size = Some(Some(v));
+        }
+        if let Some(v) = tuning.http2_initial_connection_window_size {
+            common.http2_initial_connection_window_size = Some(Some(v));
+        }
+        if let Some(v) = tuning.http2_adaptive_window {
+            common.http2_adaptive_window = Some(v);
+        }
+        if let Some(v) = tuning.http2_max_concurrent_streams {
+            self.http2_max_concurrent_streams = Some(v);
+        }
+        if let Some(v) = tuning.http2_keep_alive_interval {
+            common.http2_keep_alive_interval = Some(Some(v));
+        }
+        if let Some(v) = tuning.http2_keep_alive_timeout {
+            common.http2_keep_alive_timeout = Some(v);
+        }
+    }
+
+    /// Server settings which can be tuned per service
+    pub fn tuning(&self) -> ServerTuning {
+        let common = &self.server_common;
+        ServerTuning {
+            http1_keepalive: self.http1_keepalive,
+            http2_initial_stream_window_size: common.http2_initial_stream_window_size.flatten(),
+            http2_initial_connection_window_size: common
+                .http2_initial_connection_window_size
+                .flatten(),
+            http2_adaptive_window: common.http2_adaptive_window,
+            http2_max_concurrent_streams: self.http2_max_concurrent_streams,
+            http2_keep_alive_interval: common.http2_keep_alive_interval.flatten(),
+            http2_keep_alive_timeout: common.http2_keep_alive_timeout,
+        }
+    }
 }
 
 /// Client CA certificate configuration for the HTTPS client used by a Proxy
@@ -91,10 +165,6 @@ mod default {
     pub const fn http1_keepalive() -> Option<bool> {
         Some(true)
     }
-
-    pub const fn http1_only() -> Option<bool> {
-        Some(false)
-    }
 }
 
 #[macro_export]
@@ -108,7 +178,6 @@ macro_rules! conf_builder_server {
                 http1_half_close,
                 http1_pipeline_flush,
                 http1_header_read_timeout,
-                http1_only,
                 http2_max_concurrent_streams
             ]
         );
@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+/// Request statistics retention
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsConf {
+    /// Number of hourly buckets of per-endpoint and per-user request counters to keep
+    #[serde(default = "default_retention_hours")]
+    pub retention_hours: usize,
+}
+
+impl Default for StatsConf {
+    fn default() -> Self {
+        Self {
+            retention_hours: default_retention_hours(),
+        }
+    }
+}
+
+fn default_retention_hours() -> usize {
+    7 * 24
+}
@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+
+use ya_http_proxy_model::SECRET_FIELDS;
+
+/// Management API request and response logging
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WireLogConf {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Log request and response bodies
+    #[serde(default = "default_bodies")]
+    pub bodies: bool,
+    /// Names of JSON fields redacted in logged bodies. Defaults to credential fields
+    #[serde(default = "default_redact")]
+    pub redact: Vec<String>,
+}
+
+impl Default for WireLogConf {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bodies: default_bodies(),
+            redact: default_redact(),
+        }
+    }
+}
+
+fn default_bodies() -> bool {
+    true
+}
+
+fn default_redact() -> Vec<String> {
+    SECRET_FIELDS.iter().map(ToString::to_string).collect()
+}
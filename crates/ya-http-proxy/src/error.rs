@@ -1,7 +1,12 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::time::Duration;
 use ya_http_proxy_model::Addresses;
 
+/// Suggested retry delay for transient errors
+const RETRY_AFTER: Duration = Duration::from_secs(1);
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("I/O error: {0}")]
@@ -26,6 +31,105 @@ impl Error {
     pub fn other(msg: impl ToString) -> Self {
         Self::Other(msg.to_string())
     }
+
+    /// Machine readable error code
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "io",
+            Self::Url(_) => "invalidUrl",
+            Self::Tls(TlsError::ClientCertStore(_)) => "tlsClientCert",
+            Self::Tls(TlsError::ServerCertStore(_)) => "tlsServerCert",
+            Self::Tls(TlsError::ServerCertKey(_)) => "tlsServerKey",
+            Self::Tls(TlsError::Other(_)) => "tls",
+            Self::Management(ManagementError::NotRunning) => "managementNotRunning",
+            Self::Management(ManagementError::Unauthorized) => "unauthorized",
+            Self::Management(ManagementError::Bind { .. }) => "managementBind",
+            Self::Management(ManagementError::InvalidIdempotencyKey) => "invalidIdempotencyKey",
+            Self::Management(ManagementError::IdempotencyKeyReused) => "idempotencyKeyReused",
+            Self::Management(ManagementError::ShutdownNotConfirmed) => "shutdownNotConfirmed",
+            Self::Management(ManagementError::Draining) => "draining",
+            Self::Management(ManagementError::PreconditionFailed) => "preconditionFailed",
+            Self::Proxy(ProxyError::AlreadyRunning(_)) => "proxyAlreadyRunning",
+            Self::Proxy(ProxyError::AddressOwned { .. }) => "addressOwned",
+            Self::Proxy(ProxyError::AddressInUse { .. }) => "addressInUse",
+            Self::Proxy(ProxyError::Bind { .. }) => "bind",
+            Self::Proxy(ProxyError::Runtime(_)) => "proxyRuntime",
+            Self::Proxy(ProxyError::Conf(_)) => "proxyConf",
+            Self::Proxy(ProxyError::NotFound(_)) => "proxyNotFound",
+            Self::Proxy(ProxyError::ResourceLimit(_)) => "resourceLimit",
+            Self::Service(ServiceError::AlreadyExists { .. }) => "serviceAlreadyExists",
+            Self::Service(ServiceError::Conflict { .. }) => "serviceConflict",
+            Self::Service(ServiceError::NotFound(_)) => "serviceNotFound",
+            Self::Service(ServiceError::InvalidAuth(_)) => "invalidAuth",
+            Self::Service(ServiceError::InvalidUpstream(_)) => "invalidUpstream",
+            Self::Service(ServiceError::InvalidFilter(_)) => "invalidFilter",
+            Self::Service(ServiceError::InvalidPolicy(_)) => "invalidPolicy",
+            Self::Service(ServiceError::InvalidJournal(_)) => "invalidJournal",
+            Self::Service(ServiceError::InvalidRateLimit(_)) => "invalidRateLimit",
+            Self::Service(ServiceError::Immutable { .. }) => "serviceImmutable",
+            Self::User(UserError::AlreadyExists(_)) => "userAlreadyExists",
+            Self::User(UserError::NotFound(_)) => "userNotFound",
+            Self::User(UserError::DuplicateCredentials) => "duplicateCredentials",
+            Self::User(UserError::UnsupportedMethod(_)) => "unsupportedMethod",
+            Self::User(UserError::LimitReached(_)) => "userLimitReached",
+            Self::Other(_) => "other",
+        }
+    }
+
+    /// Suggested delay before retrying a failed request
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Io(_)
+            | Self::Management(ManagementError::NotRunning)
+            | Self::Proxy(ProxyError::Runtime(_))
+            | Self::Proxy(ProxyError::ResourceLimit(_)) => Some(RETRY_AFTER),
+            _ => None,
+        }
+    }
+
+    /// Properties of the resources involved
+    pub fn details(&self) -> Option<HashMap<String, String>> {
+        let details: Vec<(&str, String)> = match self {
+            Self::Proxy(ProxyError::AlreadyRunning(addrs)) => vec![("addrs", addrs.to_string())],
+            Self::Proxy(ProxyError::AddressOwned {
+                addr,
+                addrs,
+                services,
+            }) => vec![
+                ("addr", addr.to_string()),
+                ("addrs", addrs.to_string()),
+                ("services", services.join(",")),
+            ],
+            Self::Proxy(ProxyError::AddressInUse { addr })
+            | Self::Proxy(ProxyError::Bind { addr, .. })
+            | Self::Proxy(ProxyError::NotFound(addr)) => vec![("addr", addr.to_string())],
+            Self::Service(ServiceError::AlreadyExists { name, endpoint }) => {
+                vec![("service", name.clone()), ("endpoint", endpoint.clone())]
+            }
+            Self::Service(ServiceError::Conflict {
+                name,
+                endpoint,
+                other,
+            }) => vec![
+                ("service", name.clone()),
+                ("endpoint", endpoint.clone()),
+                ("other", other.clone()),
+            ],
+            Self::Service(ServiceError::NotFound(name)) => vec![("service", name.clone())],
+            Self::User(UserError::AlreadyExists(name)) | Self::User(UserError::NotFound(name)) => {
+                vec![("user", name.clone())]
+            }
+            Self::User(UserError::LimitReached(max)) => vec![("maxUsers", max.to_string())],
+            _ => return None,
+        };
+
+        Some(
+            details
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -44,6 +148,18 @@ pub enum TlsError {
 pub enum ManagementError {
     #[error("Management API server is not running")]
     NotRunning,
+    #[error("Missing or invalid Management API token")]
+    Unauthorized,
+    #[error("Invalid idempotency key")]
+    InvalidIdempotencyKey,
+    #[error("Idempotency key was already used with a different request")]
+    IdempotencyKeyReused,
+    #[error("Shutdown request is not confirmed")]
+    ShutdownNotConfirmed,
+    #[error("Proxy is draining and no longer accepts new services")]
+    Draining,
+    #[error("Resource has been modified since it was retrieved")]
+    PreconditionFailed,
     #[error("Management API server cannot bind to {address}: {message}")]
     Bind {
         address: SocketAddr,
@@ -55,18 +171,52 @@ pub enum ManagementError {
 pub enum ProxyError {
     #[error("Proxy is already running on addresses: {0}")]
     AlreadyRunning(Addresses),
+    #[error("Address {addr} is already bound by the proxy serving {services:?} on {addrs}; use the same set of addresses to share the listener")]
+    AddressOwned {
+        addr: SocketAddr,
+        addrs: Addresses,
+        services: Vec<String>,
+    },
+    #[error("Address {addr} is already in use by another process")]
+    AddressInUse { addr: SocketAddr },
+    #[error("Cannot bind to {addr}: {reason}")]
+    Bind { addr: SocketAddr, reason: String },
     #[error("Proxy runtime error: {0}")]
     Runtime(String),
     #[error("Proxy configuration error: {0}")]
     Conf(String),
+    #[error("No proxy is listening on {0}")]
+    NotFound(SocketAddr),
+    #[error("Insufficient system resources: {0}")]
+    ResourceLimit(String),
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum ServiceError {
     #[error("Service '{name}' is already bound to '{endpoint}'")]
     AlreadyExists { name: String, endpoint: String },
+    #[error("Service '{name}' endpoint '{endpoint}' conflicts with service '{other}'")]
+    Conflict {
+        name: String,
+        endpoint: String,
+        other: String,
+    },
     #[error("Service '{0}' not found")]
     NotFound(String),
+    #[error("Invalid service authorization: {0}")]
+    InvalidAuth(String),
+    #[error("Invalid service upstream: {0}")]
+    InvalidUpstream(String),
+    #[error("Invalid service filter: {0}")]
+    InvalidFilter(String),
+    #[error("Invalid service address policy: {0}")]
+    InvalidPolicy(String),
+    #[error("Invalid service request journal: {0}")]
+    InvalidJournal(String),
+    #[error("Invalid service rate limit: {0}")]
+    InvalidRateLimit(String),
+    #[error("Service '{name}' cannot be updated: {reason}")]
+    Immutable { name: String, reason: String },
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -75,6 +225,35 @@ pub enum UserError {
     AlreadyExists(String),
     #[error("User '{0}' not found")]
     NotFound(String),
+    // the owner of the credentials is not disclosed
+    #[error("Credentials are already in use")]
+    DuplicateCredentials,
+    #[error("Unsupported auth method: {0}")]
+    UnsupportedMethod(String),
+    #[error("Service user limit of {0} reached")]
+    LimitReached(usize),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AcmeError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("ACME request error: {0}")]
+    Request(String),
+    #[error("ACME server error: {0}")]
+    Server(String),
+    #[error("ACME validation of '{domain}' failed with status '{status}'")]
+    Validation { domain: String, status: String },
+    #[error("ACME certificate error: {0}")]
+    Certificate(String),
+    #[error("ACME request timed out: {0}")]
+    Timeout(String),
+}
+
+impl From<hyper::Error> for AcmeError {
+    fn from(e: hyper::Error) -> Self {
+        Self::Request(e.to_string())
+    }
 }
 
 impl ProxyError {
@@ -89,6 +268,21 @@ impl ProxyError {
     pub fn rt(m: impl ToString) -> Self {
         Self::Runtime(m.to_string())
     }
+
+    /// Failure to start a thread or a runtime
+    pub fn resource(e: std::io::Error) -> Self {
+        Self::ResourceLimit(e.to_string())
+    }
+
+    pub fn bind(addr: SocketAddr, e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::AddrInUse => Self::AddressInUse { addr },
+            _ => Self::Bind {
+                addr,
+                reason: e.to_string(),
+            },
+        }
+    }
 }
 
 impl From<hyper::Error> for ProxyError {
@@ -0,0 +1,667 @@
+//! Privilege dropping and system call filtering for the proxy process
+use ya_http_proxy::SecurityConf;
+
+/// Applies the configured hardening options to the current process
+#[cfg(unix)]
+pub fn apply(conf: &SecurityConf) -> anyhow::Result<()> {
+    use std::env::set_current_dir;
+
+    // resolve names before the password database becomes unreachable
+    let (uid, mut gid) = match conf.user {
+        Some(ref user) => {
+            let (uid, gid) = unix::user(user)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    if let Some(ref group) = conf.group {
+        gid = Some(unix::group(group)?);
+    }
+
+    if let Some(ref path) = conf.chroot {
+        unix::chroot(path)?;
+        set_current_dir("/")?;
+        log::info!("Changed root directory to '{}'", path.display());
+    }
+    if let Some(ref path) = conf.working_dir {
+        set_current_dir(path).map_err(|e| {
+            anyhow::anyhow!("cannot change directory to '{}': {}", path.display(), e)
+        })?;
+    }
+
+    if uid.is_some() || gid.is_some() {
+        unix::drop_privileges(uid, gid)?;
+        log::info!("Switched to uid {:?}, gid {:?}", uid, gid);
+    }
+
+    if conf.landlock.unwrap_or(false) {
+        #[cfg(target_os = "linux")]
+        if landlock::apply(&conf.read_paths, &conf.write_paths)? {
+            log::info!("Filesystem access restricted");
+        } else {
+            log::warn!("Landlock is not supported, filesystem access is not restricted");
+        }
+        #[cfg(not(target_os = "linux"))]
+        log::warn!("Landlock is not supported, filesystem access is not restricted");
+    }
+
+    if conf.seccomp.unwrap_or(false) {
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        {
+            seccomp::apply()?;
+            log::info!("System call filter enabled");
+        }
+        #[cfg(not(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        )))]
+        anyhow::bail!("system call filtering is not supported on this platform");
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn apply(conf: &SecurityConf) -> anyhow::Result<()> {
+    if conf != &SecurityConf::default() {
+        anyhow::bail!("process hardening is not supported on this platform");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    #[cfg(target_os = "linux")]
+    const OFF: libc::c_ulong = 0;
+    #[cfg(target_os = "linux")]
+    const ON: libc::c_ulong = 1;
+
+    pub fn user(name: &str) -> anyhow::Result<(libc::uid_t, libc::gid_t)> {
+        let c_name = CString::new(name)?;
+        let passwd = unsafe {
+            match name.parse::<libc::uid_t>() {
+                Ok(uid) => libc::getpwuid(uid),
+                Err(_) => libc::getpwnam(c_name.as_ptr()),
+            }
+        };
+        if passwd.is_null() {
+            anyhow::bail!("unknown user '{}'", name);
+        }
+        Ok(unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) })
+    }
+
+    pub fn group(name: &str) -> anyhow::Result<libc::gid_t> {
+        if let Ok(gid) = name.parse::<libc::gid_t>() {
+            return Ok(gid);
+        }
+        let c_name = CString::new(name)?;
+        let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+        if group.is_null() {
+            anyhow::bail!("unknown group '{}'", name);
+        }
+        Ok(unsafe { (*group).gr_gid })
+    }
+
+    pub fn chroot(path: &Path) -> anyhow::Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        check(unsafe { libc::chroot(c_path.as_ptr()) }, "chroot")
+    }
+
+    pub fn drop_privileges(
+        uid: Option<libc::uid_t>,
+        gid: Option<libc::gid_t>,
+    ) -> anyhow::Result<()> {
+        #[cfg(target_os = "linux")]
+        check(
+            unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, ON, OFF, OFF, OFF) },
+            "prctl",
+        )?;
+
+        if let Some(gid) = gid {
+            check(unsafe { libc::setgroups(1, &gid) }, "setgroups")?;
+            check(unsafe { libc::setgid(gid) }, "setgid")?;
+        }
+        if let Some(uid) = uid {
+            check(unsafe { libc::setuid(uid) }, "setuid")?;
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            // services may still need to bind privileged ports
+            if let Err(e) = linux::retain_bind_capability() {
+                log::warn!("Unable to retain CAP_NET_BIND_SERVICE: {}", e);
+            }
+            check(
+                unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, OFF, OFF, OFF, OFF) },
+                "prctl",
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn check(ret: libc::c_int, call: &str) -> anyhow::Result<()> {
+        match ret {
+            -1 => anyhow::bail!("{} failed: {}", call, io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    mod linux {
+        use std::io;
+
+        const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+        const CAP_NET_BIND_SERVICE: u32 = 10;
+
+        #[repr(C)]
+        struct CapHeader {
+            version: u32,
+            pid: libc::c_int,
+        }
+
+        #[repr(C)]
+        #[derive(Clone, Copy, Default)]
+        struct CapData {
+            effective: u32,
+            permitted: u32,
+            inheritable: u32,
+        }
+
+        pub fn retain_bind_capability() -> io::Result<()> {
+            let mut header = CapHeader {
+                version: CAPABILITY_VERSION_3,
+                pid: 0,
+            };
+            let mut data = [CapData::default(); 2];
+            data[0].effective = 1 << CAP_NET_BIND_SERVICE;
+            data[0].permitted = 1 << CAP_NET_BIND_SERVICE;
+
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_capset,
+                    &mut header as *mut CapHeader,
+                    data.as_mut_ptr(),
+                )
+            };
+            match ret {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::PathBuf;
+
+    const SYS_CREATE_RULESET: libc::c_long = 444;
+    const SYS_ADD_RULE: libc::c_long = 445;
+    const SYS_RESTRICT_SELF: libc::c_long = 446;
+
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    // ABI v1 access rights
+    const EXECUTE: u64 = 1 << 0;
+    const WRITE_FILE: u64 = 1 << 1;
+    const READ_FILE: u64 = 1 << 2;
+    const READ_DIR: u64 = 1 << 3;
+    const REMOVE_DIR: u64 = 1 << 4;
+    const REMOVE_FILE: u64 = 1 << 5;
+    const MAKE_DIR: u64 = 1 << 7;
+    const MAKE_REG: u64 = 1 << 8;
+    const MAKE_SOCK: u64 = 1 << 9;
+    const MAKE_FIFO: u64 = 1 << 10;
+    const MAKE_SYM: u64 = 1 << 12;
+
+    /// All ABI v1 access rights
+    const HANDLED: u64 = (1 << 13) - 1;
+    const FILE_ACCESS: u64 = EXECUTE | WRITE_FILE | READ_FILE;
+    const READ_ACCESS: u64 = READ_FILE | READ_DIR;
+    const WRITE_ACCESS: u64 = READ_ACCESS
+        | WRITE_FILE
+        | REMOVE_DIR
+        | REMOVE_FILE
+        | MAKE_DIR
+        | MAKE_REG
+        | MAKE_SOCK
+        | MAKE_FIFO
+        | MAKE_SYM;
+
+    /// Read access required for name resolution and TLS root certificates
+    const SYSTEM_READ_PATHS: &[&str] = &[
+        "/etc/hosts",
+        "/etc/host.conf",
+        "/etc/gai.conf",
+        "/etc/nsswitch.conf",
+        "/etc/resolv.conf",
+        "/etc/pki",
+        "/etc/ssl",
+        "/usr/share/ca-certificates",
+    ];
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Restricts filesystem access of the current thread and threads spawned afterwards.
+    /// Returns `false` when Landlock is not supported by the kernel
+    pub fn apply(read_paths: &[PathBuf], write_paths: &[PathBuf]) -> anyhow::Result<bool> {
+        let attr = RulesetAttr {
+            handled_access_fs: HANDLED,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                SYS_CREATE_RULESET,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => Ok(false),
+                _ => anyhow::bail!("landlock_create_ruleset failed: {}", err),
+            };
+        }
+        let ruleset = unsafe { File::from_raw_fd(fd as libc::c_int) };
+
+        let system = SYSTEM_READ_PATHS.iter().map(PathBuf::from);
+        let rules = system
+            .chain(read_paths.iter().cloned())
+            .map(|p| (p, READ_ACCESS))
+            .chain(write_paths.iter().map(|p| (p.clone(), WRITE_ACCESS)));
+
+        for (path, access) in rules {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => anyhow::bail!("cannot open '{}': {}", path.display(), e),
+            };
+            // directory rights cannot be granted on files
+            let access = if file.metadata()?.is_dir() {
+                access
+            } else {
+                access & FILE_ACCESS
+            };
+            let rule = PathBeneathAttr {
+                allowed_access: access,
+                parent_fd: file.as_raw_fd(),
+            };
+            let ret = unsafe {
+                libc::syscall(
+                    SYS_ADD_RULE,
+                    ruleset.as_raw_fd(),
+                    RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0u32,
+                )
+            };
+            if ret < 0 {
+                anyhow::bail!(
+                    "cannot allow access to '{}': {}",
+                    path.display(),
+                    io::Error::last_os_error()
+                );
+            }
+        }
+
+        let on: libc::c_ulong = 1;
+        let off: libc::c_ulong = 0;
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, on, off, off, off) } == -1 {
+            anyhow::bail!("prctl failed: {}", io::Error::last_os_error());
+        }
+        let ret = unsafe { libc::syscall(SYS_RESTRICT_SELF, ruleset.as_raw_fd(), 0u32) };
+        if ret < 0 {
+            anyhow::bail!(
+                "landlock_restrict_self failed: {}",
+                io::Error::last_os_error()
+            );
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp {
+    use std::io;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    const OFF: libc::c_ulong = 0;
+    const ON: libc::c_ulong = 1;
+    const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+    const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+
+    /// `struct seccomp_data` field offsets
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    /// x32 ABI system calls share the x86_64 audit architecture
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// System calls made by the proxy, its runtime and its dependencies.
+    /// Other calls fail with `EPERM`
+    const ALLOWED: &[libc::c_long] = &[
+        // memory
+        libc::SYS_brk,
+        libc::SYS_madvise,
+        libc::SYS_membarrier,
+        libc::SYS_memfd_create,
+        libc::SYS_mlock,
+        libc::SYS_mmap,
+        libc::SYS_mprotect,
+        libc::SYS_mremap,
+        libc::SYS_munlock,
+        libc::SYS_munmap,
+        // threads, signals and time
+        libc::SYS_clock_getres,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_clone,
+        libc::SYS_clone3,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_futex,
+        libc::SYS_get_robust_list,
+        libc::SYS_getrandom,
+        libc::SYS_gettimeofday,
+        libc::SYS_kill,
+        libc::SYS_nanosleep,
+        libc::SYS_restart_syscall,
+        libc::SYS_rseq,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_sched_yield,
+        libc::SYS_set_robust_list,
+        libc::SYS_set_tid_address,
+        libc::SYS_sigaltstack,
+        libc::SYS_tgkill,
+        libc::SYS_tkill,
+        libc::SYS_wait4,
+        libc::SYS_waitid,
+        // process information
+        libc::SYS_capget,
+        libc::SYS_getegid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getpid,
+        libc::SYS_getppid,
+        libc::SYS_getresgid,
+        libc::SYS_getresuid,
+        libc::SYS_getrusage,
+        libc::SYS_gettid,
+        libc::SYS_getuid,
+        libc::SYS_prctl,
+        libc::SYS_prlimit64,
+        libc::SYS_sysinfo,
+        libc::SYS_umask,
+        libc::SYS_uname,
+        // files
+        libc::SYS_chdir,
+        libc::SYS_close,
+        libc::SYS_copy_file_range,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_faccessat,
+        libc::SYS_fallocate,
+        libc::SYS_fchdir,
+        libc::SYS_fchmod,
+        libc::SYS_fchmodat,
+        libc::SYS_fchown,
+        libc::SYS_fcntl,
+        libc::SYS_fdatasync,
+        libc::SYS_flock,
+        libc::SYS_fstat,
+        libc::SYS_fstatfs,
+        libc::SYS_fsync,
+        libc::SYS_ftruncate,
+        libc::SYS_getcwd,
+        libc::SYS_getdents64,
+        libc::SYS_ioctl,
+        libc::SYS_linkat,
+        libc::SYS_lseek,
+        libc::SYS_mkdirat,
+        libc::SYS_newfstatat,
+        libc::SYS_openat,
+        libc::SYS_pipe2,
+        libc::SYS_pread64,
+        libc::SYS_preadv,
+        libc::SYS_pwrite64,
+        libc::SYS_pwritev,
+        libc::SYS_read,
+        libc::SYS_readlinkat,
+        libc::SYS_readv,
+        libc::SYS_renameat2,
+        libc::SYS_statfs,
+        libc::SYS_statx,
+        libc::SYS_symlinkat,
+        libc::SYS_unlinkat,
+        libc::SYS_utimensat,
+        libc::SYS_write,
+        libc::SYS_writev,
+        // polling
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_eventfd2,
+        libc::SYS_ppoll,
+        libc::SYS_pselect6,
+        libc::SYS_timerfd_create,
+        libc::SYS_timerfd_gettime,
+        libc::SYS_timerfd_settime,
+        // network
+        libc::SYS_accept4,
+        libc::SYS_bind,
+        libc::SYS_connect,
+        libc::SYS_getpeername,
+        libc::SYS_getsockname,
+        libc::SYS_getsockopt,
+        libc::SYS_listen,
+        libc::SYS_recvfrom,
+        libc::SYS_recvmmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_sendmsg,
+        libc::SYS_sendto,
+        libc::SYS_setsockopt,
+        libc::SYS_shutdown,
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+    ];
+
+    /// Legacy system calls without a generic counterpart on aarch64
+    #[cfg(target_arch = "x86_64")]
+    const ALLOWED_LEGACY: &[libc::c_long] = &[
+        libc::SYS_access,
+        libc::SYS_arch_prctl,
+        libc::SYS_chmod,
+        libc::SYS_dup2,
+        libc::SYS_epoll_create,
+        libc::SYS_epoll_wait,
+        libc::SYS_eventfd,
+        libc::SYS_getdents,
+        libc::SYS_getrlimit,
+        libc::SYS_lstat,
+        libc::SYS_mkdir,
+        libc::SYS_open,
+        libc::SYS_pipe,
+        libc::SYS_poll,
+        libc::SYS_readlink,
+        libc::SYS_rename,
+        libc::SYS_renameat,
+        libc::SYS_rmdir,
+        libc::SYS_select,
+        libc::SYS_sendfile,
+        libc::SYS_stat,
+        libc::SYS_time,
+        libc::SYS_unlink,
+    ];
+    #[cfg(not(target_arch = "x86_64"))]
+    const ALLOWED_LEGACY: &[libc::c_long] = &[];
+
+    /// Installs the filter for all threads of the process
+    pub fn apply() -> anyhow::Result<()> {
+        install(&mut filter(), SECCOMP_FILTER_FLAG_TSYNC)
+    }
+
+    fn filter() -> Vec<libc::sock_filter> {
+        let mut filter = vec![
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH_OFFSET),
+            jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                AUDIT_ARCH,
+                1,
+                0,
+            ),
+            stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR_OFFSET),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        filter.extend([
+            jump(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                X32_SYSCALL_BIT,
+                0,
+                1,
+            ),
+            stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        ]);
+        for nr in ALLOWED.iter().chain(ALLOWED_LEGACY) {
+            filter.push(jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                *nr as u32,
+                0,
+                1,
+            ));
+            filter.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+        }
+        filter.push(stmt(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+        ));
+        filter
+    }
+
+    fn install(filter: &mut [libc::sock_filter], flags: libc::c_ulong) -> anyhow::Result<()> {
+        let prog = libc::sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_mut_ptr(),
+        };
+
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, ON, OFF, OFF, OFF) } == -1 {
+            anyhow::bail!("prctl failed: {}", io::Error::last_os_error());
+        }
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                flags,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            -1 => anyhow::bail!("seccomp failed: {}", io::Error::last_os_error()),
+            tid => anyhow::bail!("seccomp failed: thread {} cannot be synchronized", tid),
+        }
+    }
+
+    fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    #[cfg(all(test, target_arch = "x86_64"))]
+    mod tests {
+        use super::{filter, install};
+
+        /// Runs `f` in a forked child with the system call filter installed and
+        /// returns its wait status
+        fn filtered(f: fn() -> libc::c_int) -> libc::c_int {
+            // the filter is allocated before forking a multi-threaded process
+            let mut filter = filter();
+            match unsafe { libc::fork() } {
+                -1 => panic!("fork failed: {}", std::io::Error::last_os_error()),
+                0 => unsafe {
+                    let code = match install(&mut filter, 0) {
+                        Ok(_) => f(),
+                        Err(_) => 100,
+                    };
+                    libc::_exit(code)
+                },
+                pid => {
+                    let mut status = 0;
+                    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                    status
+                }
+            }
+        }
+
+        fn errno() -> libc::c_int {
+            unsafe { *libc::__errno_location() }
+        }
+
+        #[test]
+        fn seccomp_filter() {
+            // allowed calls succeed
+            let status = filtered(|| unsafe { (libc::getpid() <= 0) as libc::c_int });
+            assert!(libc::WIFEXITED(status));
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+
+            // calls outside of the allowlist fail
+            let status = filtered(|| unsafe {
+                match libc::syscall(libc::SYS_ptrace, libc::PTRACE_TRACEME, 0, 0, 0) {
+                    -1 if errno() == libc::EPERM => 0,
+                    _ => 1,
+                }
+            });
+            assert!(libc::WIFEXITED(status));
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+
+            // x32 calls bypassing the allowlist kill the process
+            let status = filtered(|| unsafe {
+                libc::syscall(0x4000_0000 | libc::SYS_getpid);
+                0
+            });
+            assert!(libc::WIFSIGNALED(status));
+            assert_eq!(libc::WTERMSIG(status), libc::SIGSYS);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Event notifications delivered to configured webhooks
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::conf::{ProxyConf, WebhookConf};
+use crate::error::Error;
+use crate::proxy::client;
+use ya_http_proxy_model as model;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Spawns a task forwarding events to webhooks. Does nothing when none are configured
+pub fn spawn(
+    conf: &ProxyConf,
+    mut rx: broadcast::Receiver<model::EventRecord>,
+) -> Result<(), Error> {
+    if conf.hooks.webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let client = client::build_tls(&conf.client)?;
+    let webhooks: Arc<[WebhookConf]> = conf.hooks.webhooks.clone().into();
+
+    tokio::task::spawn(async move {
+        loop {
+            let record = match rx.recv().await {
+                Ok(record) => record,
+                Err(RecvError::Lagged(n)) => {
+                    log::warn!("Webhooks: {} event(s) dropped", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let name = record.event.name();
+            let body = match serde_json::to_vec(&model::Versioned::new(record)) {
+                Ok(body) => body,
+                Err(e) => {
+                    log::error!("Webhooks: unable to serialize event '{}': {}", name, e);
+                    continue;
+                }
+            };
+
+            for webhook in webhooks.iter().filter(|w| w.accepts(name)) {
+                let client = client.clone();
+                let webhook = webhook.clone();
+                let body = body.clone();
+
+                tokio::task::spawn(async move {
+                    if let Err(e) = notify(&client, &webhook, body).await {
+                        log::warn!("Webhook '{}' error ({}): {}", webhook.url, name, e);
+                    }
+                });
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn notify(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    webhook: &WebhookConf,
+    body: Vec<u8>,
+) -> Result<(), String> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(webhook.url.as_str())
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        )
+        .body(Body::from(body))
+        .map_err(|e| e.to_string())?;
+
+    let timeout = webhook.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let res = tokio::time::timeout(timeout, client.request(req))
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    match res.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(format!("status {}", status)),
+    }
+}
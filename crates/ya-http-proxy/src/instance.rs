@@ -0,0 +1,201 @@
+//! Single proxy instance per status directory.
+//!
+//! On Linux, the instance holds a lock on a file in the status directory and serves the
+//! [`model::Ready`] descriptor to later runtimes over a unix socket next to it. Unlike
+//! abstract sockets, these are subject to filesystem permissions. On Windows, the instance
+//! owns a named mutex and the descriptor is read from the ready file. Elsewhere, runtimes
+//! serialize proxy starts with a lock file.
+use std::path::Path;
+
+pub use platform::Instance;
+
+impl Instance {
+    /// Claims the instance for a status directory. Returns `None` when the directory
+    /// is already owned by a running proxy
+    pub fn claim(status_dir: &Path) -> anyhow::Result<Option<Self>> {
+        platform::claim(status_dir)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs::{self, File, OpenOptions, Permissions};
+    use std::io::{self, Write};
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+
+    use ya_http_proxy_model as model;
+
+    const LOCK_FILE_NAME: &str = "instance.lock";
+
+    pub struct Instance {
+        listener: UnixListener,
+        /// Released by the system when the process exits
+        _lock: File,
+    }
+
+    pub fn claim(status_dir: &Path) -> anyhow::Result<Option<Instance>> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(status_dir.join(LOCK_FILE_NAME))?;
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(e.into()),
+            };
+        }
+
+        let path = status_dir.join(model::INSTANCE_SOCKET_NAME);
+        // left by an instance which did not stop cleanly
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+        Ok(Some(Instance {
+            listener,
+            _lock: lock,
+        }))
+    }
+
+    impl Instance {
+        /// Serves the ready descriptor to every connecting process
+        pub fn publish(self, ready: &model::Ready) -> anyhow::Result<()> {
+            let ready = serde_json::to_vec(ready)?;
+            std::thread::Builder::new()
+                .name("instance".to_string())
+                .spawn(move || {
+                    for mut stream in self.listener.incoming().flatten() {
+                        let _ = stream.write_all(&ready);
+                    }
+                })?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::os::windows::ffi::OsStrExt;
+
+    use winapi::shared::winerror::ERROR_ALREADY_EXISTS;
+    use winapi::um::errhandlingapi::GetLastError;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::synchapi::CreateMutexW;
+    use winapi::um::winnt::HANDLE;
+
+    use std::path::Path;
+
+    use ya_http_proxy_model as model;
+
+    pub struct Instance {
+        handle: HANDLE,
+    }
+
+    /// Name of the mutex owned by the instance using a status directory. Processes
+    /// referring to the same directory by different paths share the name
+    fn mutex_name(status_dir: &Path) -> String {
+        let dir = std::fs::canonicalize(status_dir).unwrap_or_else(|_| status_dir.to_path_buf());
+        let hash = dir
+            .to_string_lossy()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+                (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+            });
+        format!("Local\\ya-http-proxy-{:016x}", hash)
+    }
+
+    pub fn claim(status_dir: &Path) -> anyhow::Result<Option<Instance>> {
+        let name: Vec<u16> = std::ffi::OsStr::new(&mutex_name(status_dir))
+            .encode_wide()
+            .chain(Some(0))
+            .collect();
+
+        let handle = unsafe { CreateMutexW(std::ptr::null_mut(), 1, name.as_ptr()) };
+        if handle.is_null() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+            unsafe { CloseHandle(handle) };
+            return Ok(None);
+        }
+        Ok(Some(Instance { handle }))
+    }
+
+    impl Instance {
+        /// Keeps the mutex for the lifetime of the process. The ready descriptor
+        /// is published in the ready file
+        pub fn publish(self, _ready: &model::Ready) -> anyhow::Result<()> {
+            std::mem::forget(self);
+            Ok(())
+        }
+    }
+
+    impl Drop for Instance {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.handle) };
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod platform {
+    use ya_http_proxy_model as model;
+
+    use std::path::Path;
+
+    pub struct Instance;
+
+    pub fn claim(_status_dir: &Path) -> anyhow::Result<Option<Instance>> {
+        Ok(Some(Instance))
+    }
+
+    impl Instance {
+        pub fn publish(self, _ready: &model::Ready) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    use super::Instance;
+    use ya_http_proxy_model as model;
+
+    #[test]
+    fn single_instance() {
+        let dir =
+            std::env::temp_dir().join(format!("ya-http-proxy-instance-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let instance = Instance::claim(&dir).unwrap().unwrap();
+        assert!(Instance::claim(&dir).unwrap().is_none());
+
+        let ready = model::Ready {
+            pid: std::process::id(),
+            version: "0.0.0".to_string(),
+            api_version: "0.0.0".to_string(),
+            management_url: "http://127.0.0.1:6668".to_string(),
+            management_addr: "127.0.0.1:6668".parse().unwrap(),
+        };
+        instance.publish(&ready).unwrap();
+
+        let mut buf = Vec::new();
+        UnixStream::connect(dir.join(model::INSTANCE_SOCKET_NAME))
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(serde_json::from_slice::<model::Ready>(&buf).unwrap(), ready);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
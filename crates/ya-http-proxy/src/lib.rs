@@ -1,10 +1,19 @@
+mod alerts;
 mod api;
 #[macro_use]
 mod conf;
 mod error;
+mod hooks;
+mod logs;
 mod proxy;
 
 pub use api::Management;
 pub use conf::*;
 pub use error::*;
-pub use proxy::{Proxy, ProxyManager};
+pub use logs::{LogBuffer, LogLine, DEFAULT_LOG_CAPACITY};
+#[cfg(feature = "fuzzing")]
+pub use proxy::fuzzing;
+pub use proxy::{
+    Authorization, FilterContext, ForwardedHeaders, Proxy, ProxyManager, RequestFilter,
+    ResponseFilter, RewriteCookies, RewriteHtml, RewriteRedirects, StripHeaders,
+};
@@ -0,0 +1,147 @@
+//! Recent log lines kept in memory for the Management API
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+
+/// Default number of log lines kept in memory
+pub const DEFAULT_LOG_CAPACITY: usize = 1000;
+const FOLLOW_CHANNEL_CAPACITY: usize = 256;
+
+/// Formatted log line
+#[derive(Clone, Debug)]
+pub struct LogLine {
+    /// Service the message refers to, read from the `[service] ...` message prefix
+    pub service: Option<String>,
+    pub text: String,
+}
+
+impl LogLine {
+    pub fn new(message: &str, text: String) -> Self {
+        let service = message
+            .strip_prefix('[')
+            .and_then(|s| s.split_once(']'))
+            .map(|(service, _)| service.to_string());
+        Self { service, text }
+    }
+
+    pub fn matches(&self, service: Option<&str>) -> bool {
+        match service {
+            Some(service) => self.service.as_deref() == Some(service),
+            None => true,
+        }
+    }
+}
+
+/// Ring buffer of recent log lines, broadcasting new lines to followers
+#[derive(Clone)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<Arc<LogLine>>>>,
+    capacity: usize,
+    tx: broadcast::Sender<Arc<LogLine>>,
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOG_CAPACITY)
+    }
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(FOLLOW_CHANNEL_CAPACITY);
+        Self {
+            lines: Default::default(),
+            capacity: capacity.max(1),
+            tx,
+        }
+    }
+
+    pub fn push(&self, line: LogLine) {
+        let line = Arc::new(line);
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        if lines.len() >= self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line.clone());
+        // no followers
+        let _ = self.tx.send(line);
+    }
+
+    /// Returns up to `n` most recent lines referring to the service
+    pub fn tail(&self, n: usize, service: Option<&str>) -> Vec<Arc<LogLine>> {
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        Self::tail_of(&lines, n, service)
+    }
+
+    /// Returns up to `n` most recent lines and a receiver of lines pushed afterwards
+    pub fn follow(
+        &self,
+        n: usize,
+        service: Option<&str>,
+    ) -> (Vec<Arc<LogLine>>, broadcast::Receiver<Arc<LogLine>>) {
+        // subscribe under the lock, so that no line is skipped or repeated
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        (Self::tail_of(&lines, n, service), self.tx.subscribe())
+    }
+
+    fn tail_of(
+        lines: &VecDeque<Arc<LogLine>>,
+        n: usize,
+        service: Option<&str>,
+    ) -> Vec<Arc<LogLine>> {
+        let mut tail: Vec<_> = lines
+            .iter()
+            .rev()
+            .filter(|l| l.matches(service))
+            .take(n)
+            .cloned()
+            .collect();
+        tail.reverse();
+        tail
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogBuffer, LogLine};
+
+    fn push(logs: &LogBuffer, message: &str) {
+        logs.push(LogLine::new(message, format!("[INFO] {}", message)));
+    }
+
+    #[tokio::test]
+    async fn tail_and_follow() {
+        let logs = LogBuffer::new(3);
+        push(&logs, "started");
+        push(&logs, "[svc] upstream unavailable");
+        push(&logs, "[other] upstream unavailable");
+        push(&logs, "[svc] upstream recovered");
+
+        let texts = |lines: Vec<_>| {
+            lines
+                .into_iter()
+                .map(|l: std::sync::Arc<LogLine>| l.text.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(logs.tail(10, None).len(), 3);
+        assert_eq!(
+            texts(logs.tail(10, Some("svc"))),
+            vec![
+                "[INFO] [svc] upstream unavailable",
+                "[INFO] [svc] upstream recovered"
+            ]
+        );
+        assert_eq!(
+            texts(logs.tail(1, Some("svc"))),
+            vec!["[INFO] [svc] upstream recovered"]
+        );
+
+        let (tail, mut rx) = logs.follow(0, Some("svc"));
+        assert!(tail.is_empty());
+        push(&logs, "[svc] stopped");
+        let line = rx.recv().await.unwrap();
+        assert_eq!(line.service.as_deref(), Some("svc"));
+        assert_eq!(line.text, "[INFO] [svc] stopped");
+    }
+}
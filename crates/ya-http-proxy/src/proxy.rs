@@ -1,74 +1,386 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::future::Future;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use chrono::{DateTime, Utc};
 use futures::channel::oneshot;
+use futures::future::Either;
 use futures::FutureExt;
 use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Version};
 use sha3::{Digest, Sha3_256};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, watch, Mutex as AsyncMutex, RwLock};
 use tokio::task::LocalSet;
 
-use crate::conf::ProxyConf;
-use crate::error::{Error, ProxyError, ServiceError, UserError};
+use crate::alerts::Metrics;
+use crate::conf::{ProxyConf, ServerCertConf, StatsConf};
+use crate::error::{Error, ManagementError, ProxyError, ServiceError, TlsError, UserError};
+use crate::proxy::access_log::AccessLog;
+use crate::proxy::challenge::Challenges;
+use crate::proxy::client::UpstreamClient;
+use crate::proxy::concurrency::{Concurrency, Gate};
+use crate::proxy::endpoint::Route;
+use crate::proxy::filter::Filters;
 use crate::proxy::handler::forward_req;
+use crate::proxy::journal::Journal;
+use crate::proxy::policy::Policy;
+use crate::proxy::provider::CredentialProvider;
+use crate::proxy::ratelimit::RateLimiter;
+use crate::proxy::runtime::RuntimeStats;
+use crate::proxy::server::ProxyHealth;
 use crate::proxy::stream::HttpStream;
+use crate::proxy::streaming::Streaming;
+use crate::proxy::tap::Tap;
+use crate::proxy::tls::ServerCert;
 use ya_http_proxy_model as model;
 use ya_http_proxy_model::Addresses;
 
-mod client;
+mod access_log;
+#[cfg(feature = "acme")]
+mod acme;
+mod challenge;
+pub(crate) mod client;
+mod concurrency;
+mod endpoint;
+pub(crate) mod etag;
+mod filter;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
 mod handler;
+mod journal;
+mod policy;
+mod provider;
+mod ratelimit;
+mod rotation;
+mod runtime;
 mod server;
 mod stream;
+mod streaming;
+mod tap;
+mod tls;
+mod transfer;
+
+pub use filter::{
+    Authorization, FilterContext, ForwardedHeaders, RequestFilter, ResponseFilter, RewriteCookies,
+    RewriteHtml, RewriteRedirects, StripHeaders,
+};
+
+const EVENT_QUEUE_SIZE: usize = 64;
+const MAX_REQUEST_TAP: usize = 1000;
+const FINGERPRINT_LEN: usize = 16;
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Clone)]
 pub struct ProxyManager {
     pub default_conf: Arc<ProxyConf>,
     pub(crate) proxies: Arc<RwLock<HashMap<Addresses, Proxy>>>,
+    pub(crate) alerts: Arc<RwLock<HashMap<(String, model::AlertKind), model::Alert>>>,
+    events: broadcast::Sender<model::EventRecord>,
+    stop: Arc<watch::Sender<bool>>,
+    /// Names of services being created or updated, unique across proxy instances
+    creating: Arc<Mutex<HashSet<String>>>,
+    /// Locks held while spawning a proxy instance on the addresses
+    spawning: Arc<Mutex<HashMap<Addresses, Arc<AsyncMutex<()>>>>>,
+    draining: Arc<AtomicBool>,
+    /// Confirms shutdown requests made for this process
+    shutdown_nonce: Arc<str>,
+    /// Pending ACME challenges, answered by all proxy instances
+    challenges: Arc<Challenges>,
+    /// Access log written by all proxy instances
+    access_log: Option<Arc<AccessLog>>,
+    /// Locations of ACME certificates being kept renewed
+    #[cfg(feature = "acme")]
+    renewing: Arc<Mutex<HashSet<PathBuf>>>,
 }
 
 impl ProxyManager {
     pub fn new(conf: ProxyConf) -> Self {
+        let (events, _) = broadcast::channel(EVENT_QUEUE_SIZE);
+        let (stop, _) = watch::channel(false);
+        let access_log = conf.access_log.path.as_ref().and_then(|path| {
+            match AccessLog::open(path, &conf.access_log) {
+                Ok(access_log) => Some(Arc::new(access_log)),
+                Err(e) => {
+                    log::error!("Unable to open the access log '{}': {}", path.display(), e);
+                    None
+                }
+            }
+        });
         Self {
             default_conf: Arc::new(conf),
             proxies: Default::default(),
+            alerts: Default::default(),
+            events,
+            stop: Arc::new(stop),
+            creating: Default::default(),
+            spawning: Default::default(),
+            draining: Default::default(),
+            shutdown_nonce: shutdown_nonce().into(),
+            challenges: Default::default(),
+            access_log,
+            #[cfg(feature = "acme")]
+            renewing: Default::default(),
+        }
+    }
+
+    /// Subscribes to events emitted by all proxy instances
+    pub fn events(&self) -> broadcast::Receiver<model::EventRecord> {
+        self.events.subscribe()
+    }
+
+    /// Notifies event subscribers, if any
+    pub(crate) fn emit(&self, event: model::Event) {
+        let _ = self.events.send(event.into());
+    }
+
+    /// Adds a service to the proxy instance listening on the service addresses,
+    /// spawning the instance when needed
+    pub async fn create_service<S>(
+        &self,
+        mut create: model::CreateService,
+        correlation_id: Option<&str>,
+    ) -> Result<S, Error>
+    where
+        S: From<(model::CreateService, DateTime<Utc>)>,
+    {
+        if self.is_draining() {
+            return Err(ManagementError::Draining.into());
+        }
+        let _name = NameReservation::new(&self.creating, &create)?;
+        let proxy = self.get_or_spawn(&mut create).await?;
+        #[cfg(feature = "acme")]
+        let acme = create.cert.as_ref().map(|c| c.acme).unwrap_or(false);
+        let service = proxy.add(create, correlation_id).await?;
+        // certificates are renewed only for services which were created
+        #[cfg(feature = "acme")]
+        if acme {
+            acme::watch(proxy, self.renewing.clone());
+        }
+        Ok(service)
+    }
+
+    /// Replaces the definition of a service in place, keeping its users and stats.
+    /// Services cannot be moved to other listening addresses
+    pub async fn update_service<S>(
+        &self,
+        service_name: &str,
+        mut update: model::CreateService,
+        if_match: Option<&str>,
+        correlation_id: Option<&str>,
+    ) -> Result<S, Error>
+    where
+        S: for<'a> From<&'a ProxyService>,
+    {
+        if self.is_draining() {
+            return Err(ManagementError::Draining.into());
+        }
+        let immutable = |reason: &str| ServiceError::Immutable {
+            name: service_name.to_string(),
+            reason: reason.to_string(),
+        };
+        if update.name != service_name {
+            return Err(immutable("the name cannot be changed").into());
+        }
+        let _name = NameReservation::new(&self.creating, &update)
+            .map_err(|_| immutable("the service is being created or updated"))?;
+
+        let proxy = self.proxy(service_name).await?;
+        let conf = self.conf_update(&mut update)?;
+        let addrs = conf.server.addresses();
+        if addrs != proxy.conf.server.addresses() {
+            return Err(immutable("listening addresses cannot be changed").into());
         }
+        if update.server_tuning.is_some() && proxy.conf.server.tuning() != conf.server.tuning() {
+            return Err(immutable("server tuning of a running proxy cannot be changed").into());
+        }
+        self.check_conflicts(&update, true).await?;
+
+        let cert = &conf.server.server_cert;
+        let replace_cert = match (&cert.server_cert_store_path, &cert.server_key_path) {
+            (Some(path), Some(key_path))
+                if conf.server.bind_https.is_some() && *cert != proxy.server_cert_conf() =>
+            {
+                Some((path.clone(), key_path.clone()))
+            }
+            _ => None,
+        };
+
+        proxy
+            .update(update, replace_cert, if_match, correlation_id)
+            .await
     }
 
     #[inline]
     pub async fn get_or_spawn(&self, create: &mut model::CreateService) -> Result<Proxy, Error> {
-        let instances = self.proxies.write().await;
-        let addrs = create.addresses();
+        let conf = self.conf_update(create)?;
+        self.check_conflicts(create, false).await?;
 
-        match instances.get(&addrs) {
-            Some(proxy) => Ok(proxy.clone()),
-            None => {
-                drop(instances);
-                self.spawn(create).await
+        let addrs = conf.server.addresses();
+        if let Some(proxy) = self.running(&addrs).await {
+            Self::check_running(&proxy, create, &conf)?;
+            return Ok(proxy);
+        }
+
+        // concurrent requests wait for the instance spawned by the first one
+        let lock = self
+            .spawning
+            .lock()
+            .unwrap()
+            .entry(addrs.clone())
+            .or_default()
+            .clone();
+        let guard = lock.lock().await;
+
+        let result = match self.running(&addrs).await {
+            Some(proxy) => Self::check_running(&proxy, create, &conf).map(|_| proxy),
+            None => self.spawn(create, conf).await.inspect(|proxy| {
+                // the service reports ports bound in place of port 0
+                create.bind_https = proxy.conf.server.bind_https.clone();
+                create.bind_http = proxy.conf.server.bind_http.clone();
+            }),
+        };
+
+        drop(guard);
+        let mut spawning = self.spawning.lock().unwrap();
+        // the lock is only referenced by the map and by waiting requests
+        if Arc::strong_count(&lock) == 2 {
+            spawning.remove(&addrs);
+        }
+        result
+    }
+
+    async fn running(&self, addrs: &Addresses) -> Option<Proxy> {
+        self.proxies.read().await.get(addrs).cloned()
+    }
+
+    /// Checks whether a running proxy instance can serve the service
+    fn check_running(
+        proxy: &Proxy,
+        create: &model::CreateService,
+        conf: &ProxyConf,
+    ) -> Result<(), Error> {
+        if let Some(reason) = proxy.health.failure() {
+            return Err(ProxyError::rt(format!(
+                "proxy running on {} has stopped: {}",
+                conf.server.addresses(),
+                reason
+            ))
+            .into());
+        }
+        if create.server_tuning.is_some() && proxy.conf.server.tuning() != conf.server.tuning() {
+            return Err(ProxyError::Conf(format!(
+                "Proxy running on {} uses different server tuning",
+                conf.server.addresses()
+            ))
+            .into());
+        }
+        if create.bind_https.is_some() && proxy.server_cert_conf() != conf.server.server_cert {
+            return Err(ProxyError::Conf(format!(
+                "Proxy running on {} uses a different certificate",
+                conf.server.addresses()
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Checks whether any of the addresses is already bound by a running proxy instance
+    async fn check_owned(
+        instances: &HashMap<Addresses, Proxy>,
+        addrs: &Addresses,
+    ) -> Result<(), ProxyError> {
+        for (proxy_addrs, proxy) in instances.iter() {
+            let addr = addrs
+                .to_vec()
+                .into_iter()
+                .find(|a| proxy_addrs.overlaps(&Addresses::from(*a)));
+
+            if let Some(addr) = addr {
+                let state = proxy.state.read().await;
+                return Err(ProxyError::AddressOwned {
+                    addr,
+                    addrs: proxy_addrs.clone(),
+                    services: state.by_name.keys().cloned().collect(),
+                });
             }
         }
+        Ok(())
     }
 
-    async fn spawn(&self, create: &mut model::CreateService) -> Result<Proxy, Error> {
-        let mut services = self.proxies.write().await;
+    /// Checks whether the service would be routed ambiguously with any other service
+    /// reachable on the same listening addresses
+    async fn check_conflicts(
+        &self,
+        create: &model::CreateService,
+        updating: bool,
+    ) -> Result<(), ServiceError> {
         let addrs = create.addresses();
+        let route = Route::new(create);
+        let proxies = self.proxies.read().await;
 
-        if services.contains_key(&addrs) {
-            return Err(ProxyError::AlreadyRunning(addrs).into());
+        for (proxy_addrs, proxy) in proxies.iter() {
+            let state = proxy.state.read().await;
+            if !updating && state.by_name.contains_key(&create.name) {
+                return Err(ServiceError::AlreadyExists {
+                    name: create.name.clone(),
+                    endpoint: route.endpoint,
+                });
+            }
+            if !proxy_addrs.overlaps(&addrs) {
+                continue;
+            }
+
+            // listeners route requests by the host first, then by the path
+            for (other, service) in state.by_route.iter() {
+                if service.created_with.name == create.name {
+                    continue;
+                }
+                if route.overlaps(other) {
+                    return Err(ServiceError::Conflict {
+                        name: create.name.clone(),
+                        endpoint: route.endpoint,
+                        other: service.created_with.name.clone(),
+                    });
+                }
+            }
         }
 
-        let conf = self.conf_update(create)?;
-        let name = create.name.clone();
+        Ok(())
+    }
+
+    /// Spawns a proxy instance. Other requests are served while the instance starts
+    async fn spawn(&self, create: &model::CreateService, conf: ProxyConf) -> Result<Proxy, Error> {
         let addrs = conf.server.addresses();
-        let proxy_addrs = addrs.clone();
-        let cpu_threads = create.cpu_threads;
+        {
+            let services = self.proxies.read().await;
+            if services.contains_key(&addrs) {
+                return Err(ProxyError::AlreadyRunning(addrs).into());
+            }
+            Self::check_owned(&services, &addrs).await?;
+        }
+        // fail before setting up the runtime
+        server::check_bind(&addrs.to_vec())?;
 
-        let (tx, rx) = oneshot::channel();
-        std::thread::spawn(move || {
+        let name = create.name.clone();
+        // services limit their share of worker threads on their own
+        let cpu_threads = conf.server.cpu_threads.map(|n| 1.max(n));
+        let events = self.events.clone();
+        let challenges = self.challenges.clone();
+        let access_log = self.access_log.clone();
+
+        let thread_name = format!("{}@{}", name, addrs);
+        let runtime = Arc::new(RuntimeStats::new(thread_name.clone(), cpu_threads));
+
+        let (tx, rx) = oneshot::channel::<Result<Proxy, Error>>();
+        let thread = std::thread::Builder::new().name(thread_name.clone());
+        let spawned = thread.spawn(move || {
             let mut rt_builder = tokio::runtime::Builder::new_multi_thread();
-            rt_builder.enable_all().thread_name(&name);
+            rt_builder.enable_all().thread_name(thread_name);
 
             if let Some(n) = cpu_threads {
                 rt_builder.worker_threads(n);
@@ -76,25 +388,33 @@ impl ProxyManager {
             let rt = match rt_builder.build() {
                 Ok(rt) => rt,
                 Err(e) => {
-                    let _ = tx.send(Err(e.into()));
+                    let _ = tx.send(Err(ProxyError::resource(e).into()));
                     return;
                 }
             };
 
             let fut = async move {
-                let mut proxy = Proxy::new(conf);
+                let mut proxy = Proxy::new(conf, events);
+                proxy.runtime = runtime;
+                proxy.challenges = challenges;
+                proxy.access_log = access_log;
                 let finished = proxy.start().await?;
                 Ok((proxy, finished))
             }
             .then(|result| async move {
                 match result {
                     Ok((proxy, finished)) => {
+                        let health = proxy.health.clone();
+                        let addrs = proxy.conf.server.addresses();
                         let _ = tx.send(Ok(proxy));
 
                         log::info!("Proxy '{}' is listening on {}", name, addrs);
                         match finished.await {
                             Ok(_) => log::info!("Proxy '{}' stopped [{}]", name, addrs),
-                            Err(e) => log::error!("Proxy '{}' [{}] error: {}", name, addrs, e),
+                            Err(e) => {
+                                log::error!("Proxy '{}' [{}] error: {}", name, addrs, e);
+                                health.fail(e);
+                            }
                         }
                     }
                     Err(err) => {
@@ -106,16 +426,28 @@ impl ProxyManager {
             let task_set = LocalSet::new();
             task_set.block_on(&rt, fut);
         });
+        if let Err(e) = spawned {
+            return Err(ProxyError::resource(e).into());
+        }
 
-        match rx.await {
-            Ok(result) => {
-                if let Ok(ref proxy) = result {
-                    services.insert(proxy_addrs, proxy.clone());
-                }
-                result
-            }
-            Err(_) => Err(ProxyError::rt("Proxy canceled").into()),
+        let mut proxy = match rx.await {
+            Ok(result) => result?,
+            Err(_) => return Err(ProxyError::rt("Proxy canceled").into()),
+        };
+        let addrs = proxy.conf.server.addresses();
+        let mut services = self.proxies.write().await;
+        // instances on overlapping addresses may have been spawned in the meantime
+        let owned = match services.contains_key(&addrs) {
+            true => Err(ProxyError::AlreadyRunning(addrs.clone())),
+            false => Self::check_owned(&services, &addrs).await,
+        };
+        if let Err(e) = owned {
+            drop(services);
+            proxy.stop();
+            return Err(e.into());
         }
+        services.insert(addrs, proxy.clone());
+        Ok(proxy)
     }
 
     fn conf_update(&self, create: &mut model::CreateService) -> Result<ProxyConf, ProxyError> {
@@ -152,7 +484,27 @@ impl ProxyManager {
             .or(conf.server.cpu_threads)
             .map(|n| 1.max(n));
 
+        if let Some(ref tuning) = create.server_tuning {
+            conf.server.apply_tuning(tuning);
+        }
+        if create.protocols.is_none() {
+            create.protocols = conf.server.default_protocols();
+        }
+
         match create.cert {
+            #[cfg(feature = "acme")]
+            Some(ref mut cert) if cert.acme => {
+                let (path, key_path) = acme::cert_paths(&conf.acme, &create.server_name)?;
+                conf.server.server_cert.server_cert_store_path = Some(path.clone());
+                conf.server.server_cert.server_key_path = Some(key_path.clone());
+                cert.hash = cert_hash(&path)?;
+                cert.path = path;
+                cert.key_path = key_path;
+            }
+            #[cfg(not(feature = "acme"))]
+            Some(ref cert) if cert.acme => {
+                return Err(ProxyError::Conf("ACME support is not enabled".into()));
+            }
             Some(ref mut cert) => {
                 conf.server.server_cert.server_cert_store_path = Some(cert.path.clone());
                 conf.server.server_cert.server_key_path = Some(cert.key_path.clone());
@@ -173,6 +525,7 @@ impl ProxyManager {
                     hash,
                     path,
                     key_path,
+                    acme: false,
                 });
             }
         }
@@ -194,10 +547,130 @@ impl ProxyManager {
         Err(ServiceError::NotFound(service_name.to_string()).into())
     }
 
-    pub(crate) async fn stop(&self) {
+    /// Finds the proxy instance listening on the address
+    pub(crate) async fn proxy_by_addr(&self, addr: SocketAddr) -> Result<Proxy, Error> {
+        let proxies = self.proxies.read().await;
+        proxies
+            .iter()
+            .find(|(addrs, _)| addrs.to_vec().contains(&addr))
+            .map(|(_, proxy)| proxy.clone())
+            .ok_or_else(|| ProxyError::NotFound(addr).into())
+    }
+
+    /// Reports the resources the service shares with services of all proxy instances
+    pub(crate) async fn isolation(
+        &self,
+        service_name: &str,
+    ) -> Result<model::ServiceIsolation, Error> {
+        let proxies = self.proxies.read().await;
+        let mut others = Vec::new();
+        let mut found = None;
+
+        for (addrs, proxy) in proxies.iter() {
+            let state = proxy.state.read().await;
+            for service in state.by_route.values() {
+                let name = &service.created_with.name;
+                let resources = (
+                    addrs.clone(),
+                    service.runtime.clone(),
+                    service.client.clone(),
+                );
+                if name == service_name {
+                    let cpu_threads = service.concurrency.as_ref().map(|c| c.limit());
+                    found = Some((resources, cpu_threads));
+                } else {
+                    others.push((name.clone(), resources));
+                }
+            }
+        }
+        others.sort_by(|l, r| l.0.cmp(&r.0));
+
+        let ((addresses, runtime, client), cpu_threads) =
+            found.ok_or_else(|| ServiceError::NotFound(service_name.to_string()))?;
+        let mut listener = Vec::new();
+        let mut runtime_threads = Vec::new();
+        let mut client_pool = Vec::new();
+        for (name, (other_addrs, other_runtime, other_client)) in others {
+            if other_addrs == addresses {
+                listener.push(name.clone());
+            }
+            if Arc::ptr_eq(&other_runtime, &runtime) {
+                runtime_threads.push(name.clone());
+            }
+            if Arc::ptr_eq(&other_client, &client) {
+                client_pool.push(name);
+            }
+        }
+
+        Ok(model::ServiceIsolation {
+            service: service_name.to_string(),
+            isolated: listener.is_empty() && runtime_threads.is_empty() && client_pool.is_empty(),
+            addresses,
+            listener,
+            runtime_threads,
+            worker_threads: runtime.worker_threads,
+            cpu_threads,
+            client_pool,
+        })
+    }
+
+    /// Stops accepting connections on all proxy instances, waits up to `timeout`
+    /// (or the configured drain timeout) for in-flight requests to complete
+    /// and stops the Management API server
+    pub async fn drain(&self, timeout: Option<Duration>) {
+        let timeout = timeout
+            .or(self.default_conf.server.drain_timeout)
+            .unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+        self.stop(Some(timeout)).await
+    }
+
+    /// Stops all proxy instances and the Management API server.
+    /// With `drain` set, waits up to that long for in-flight requests to complete.
+    pub async fn stop(&self, drain: Option<Duration>) {
+        self.draining.store(true, Ordering::SeqCst);
+        let proxies = self.stop_proxies().await;
+
+        if let Some(drain) = drain {
+            log::info!("Draining {} proxy instance(s)", proxies.len());
+            let stopped = futures::future::join_all(proxies.values().map(|p| p.stopped()));
+            if tokio::time::timeout(drain, stopped).await.is_err() {
+                log::warn!("Proxies not drained within {:?}", drain);
+            }
+        }
+        // spawned by requests made before draining started
+        self.stop_proxies().await;
+
+        self.stop.send_replace(true);
+    }
+
+    /// Checks whether the manager is stopping and no longer accepts new services
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Confirmation of shutdown and drain requests, reported by the Management API status
+    pub fn shutdown_nonce(&self) -> &str {
+        &self.shutdown_nonce
+    }
+
+    async fn stop_proxies(&self) -> HashMap<Addresses, Proxy> {
         let mut proxies = { std::mem::take(&mut *self.proxies.write().await) };
         proxies.values_mut().for_each(|p| p.stop());
-        std::process::exit(0);
+        proxies
+    }
+
+    /// Resolves once the manager is stopped
+    pub fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        signaled(self.stop.subscribe())
+    }
+}
+
+/// Resolves once the watched flag is set or the sender is dropped
+async fn signaled(mut rx: watch::Receiver<bool>) {
+    while !*rx.borrow() {
+        if rx.changed().await.is_err() {
+            break;
+        }
     }
 }
 
@@ -207,19 +680,59 @@ pub struct Proxy {
     pub conf: Arc<ProxyConf>,
     pub(crate) state: Arc<RwLock<ProxyState>>,
     pub(crate) stats: Arc<RwLock<ProxyStats>>,
+    pub(crate) events: broadcast::Sender<model::EventRecord>,
+    /// Number of TLS handshakes that did not complete in time
+    pub(crate) handshake_timeouts: Arc<AtomicUsize>,
+    pub(crate) health: ProxyHealth,
+    pub(crate) runtime: Arc<RuntimeStats>,
+    /// Pending ACME challenges, shared by all proxy instances
+    pub(crate) challenges: Arc<Challenges>,
+    /// Access log, shared by all proxy instances
+    pub(crate) access_log: Option<Arc<AccessLog>>,
+    /// Server certificate, loaded once the proxy serves HTTPS
+    cert: Option<Arc<ServerCert>>,
+    filters: Arc<Mutex<Filters>>,
     stop_tx: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    finished: Arc<watch::Sender<bool>>,
 }
 
 impl Proxy {
-    pub fn new(conf: ProxyConf) -> Self {
+    pub fn new(conf: ProxyConf, events: broadcast::Sender<model::EventRecord>) -> Self {
+        let stats = ProxyStats::new(conf.stats.retention_hours);
         Self {
             conf: Arc::new(conf),
             state: Default::default(),
-            stats: Default::default(),
+            stats: Arc::new(RwLock::new(stats)),
+            events,
+            handshake_timeouts: Default::default(),
+            health: Default::default(),
+            runtime: Default::default(),
+            challenges: Default::default(),
+            access_log: None,
+            cert: None,
+            filters: Default::default(),
             stop_tx: Default::default(),
+            finished: Arc::new(watch::channel(false).0),
         }
     }
 
+    /// Appends a filter applied to requests before forwarding,
+    /// following the built-in authorization and header filters
+    pub fn add_request_filter(&self, filter: impl RequestFilter + 'static) {
+        let mut filters = self.filters.lock().unwrap();
+        filters.request.push(Arc::new(filter));
+    }
+
+    /// Appends a filter applied to upstream responses
+    pub fn add_response_filter(&self, filter: impl ResponseFilter + 'static) {
+        let mut filters = self.filters.lock().unwrap();
+        filters.response.push(Arc::new(filter));
+    }
+
+    pub(crate) fn filters(&self) -> Filters {
+        self.filters.lock().unwrap().clone()
+    }
+
     pub async fn start(
         &mut self,
     ) -> Result<impl Future<Output = hyper::Result<()>> + 'static, Error> {
@@ -233,67 +746,92 @@ impl Proxy {
                 return Err(ProxyError::AlreadyRunning(self.conf.server.addresses()).into());
             }
         }
+        if self.conf.server.bind_https.is_some() {
+            let cert = ServerCert::load(&self.conf.server, self.challenges.clone())?;
+            self.cert = Some(Arc::new(cert));
+        }
+
+        let mut server_conf = self.conf.server.clone();
+        let https = server::listen_https(
+            &mut server_conf,
+            self.cert.as_ref(),
+            self.health.clone(),
+            self.handshake_timeouts.clone(),
+        )
+        .await?;
+        let http = server::listen_http(&mut server_conf, self.health.clone()).await?;
+        // ports bound in place of port 0 are reported in the configuration
+        let mut conf = (*self.conf).clone();
+        conf.server.bind_https = server_conf.bind_https;
+        conf.server.bind_http = server_conf.bind_http;
+        self.conf = Arc::new(conf);
 
-        let client = client::build(&self.conf.client);
         let (tx, rx) = oneshot::channel();
         let rx = rx.shared();
 
         let handler = || {
-            let client = client.clone();
-            let state = self.state.clone();
-            let stats = self.stats.clone();
+            let proxy = self.clone();
 
             move |stream: &HttpStream| {
-                let client = client.clone();
-                let state = state.clone();
-                let stats = stats.clone();
+                let proxy = proxy.clone();
                 let address = stream.remote_addr();
+                let client_cert = stream.client_cert();
+                // dropped along with the connection service
+                let connection = proxy.runtime.connection();
 
                 async move {
-                    Ok::<_, Error>(service_fn(move |req| {
-                        forward_req(req, state.clone(), stats.clone(), client.clone(), address)
+                    Ok::<_, Error>(service_fn(move |mut req: Request<Body>| {
+                        let _ = &connection;
+                        if let Some(ref client_cert) = client_cert {
+                            req.extensions_mut().insert(client_cert.clone());
+                        }
+                        let runtime = proxy.runtime.clone();
+                        let gate = Arc::new(Gate::default());
+                        let forward = forward_req(req, proxy.clone(), address, gate.clone());
+                        runtime.instrument(gate.limit(forward))
                     }))
                 }
             }
         };
 
         let rx_ = rx.clone();
-        let https = server::listen_https(&self.conf.server)
-            .await?
-            .map(|builder| {
-                builder
-                    .serve(make_service_fn(handler()))
-                    .with_graceful_shutdown(rx_.map(|_| ()))
-                    .boxed()
-            });
+        let https = https.map(|builder| {
+            builder
+                .serve(make_service_fn(handler()))
+                .with_graceful_shutdown(rx_.map(|_| ()))
+                .boxed()
+        });
 
         let rx_ = rx;
-        let http = server::listen_http(&self.conf.server)
-            .await?
-            .map(|builder| {
-                builder
-                    .serve(make_service_fn(handler()))
-                    .with_graceful_shutdown(rx_.map(|_| ()))
-                    .boxed()
-            });
+        let http = http.map(|builder| {
+            builder
+                .serve(make_service_fn(handler()))
+                .with_graceful_shutdown(rx_.map(|_| ()))
+                .boxed()
+        });
 
         {
             let mut stop_tx = self.stop_tx.lock().unwrap();
             stop_tx.replace(tx);
         }
+        if let Some(interval) = self.conf.server.cert_watch_interval {
+            self.watch_cert(interval);
+        }
 
+        let finished = self.finished.clone();
         Ok(async move {
-            match (http, https) {
+            let result = match (http, https) {
                 (Some(http), Some(https)) => {
-                    futures::future::try_join(http, https).await?;
-                    Ok(())
+                    futures::future::try_join(http, https).await.map(|_| ())
                 }
                 (http, https) => {
                     http.or(https)
                         .unwrap_or_else(|| futures::future::ok(()).boxed())
                         .await
                 }
-            }
+            };
+            finished.send_replace(true);
+            result
         })
     }
 
@@ -304,9 +842,83 @@ impl Proxy {
                 let _ = tx.send(());
             });
     }
+
+    /// Resolves once the proxy has stopped serving connections
+    pub fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        signaled(self.finished.subscribe())
+    }
 }
 
 impl Proxy {
+    /// Certificate files served by the proxy, replaced by service updates
+    pub fn server_cert_conf(&self) -> ServerCertConf {
+        match self.cert {
+            Some(ref cert) => {
+                let (cert_path, key_path) = cert.paths();
+                ServerCertConf {
+                    server_cert_store_path: Some(cert_path),
+                    server_key_path: Some(key_path),
+                }
+            }
+            None => self.conf.server.server_cert.clone(),
+        }
+    }
+
+    /// Reloads the server certificate from its files, updating the certificate hash of services
+    pub async fn reload_cert(&self) -> Result<model::ServerCertInfo, Error> {
+        let cert = match self.cert {
+            Some(ref cert) => cert,
+            None => return Err(TlsError::Other("HTTPS is not enabled".to_string()).into()),
+        };
+        let model::ServerCertInfo { hash, loaded_at } = cert.reload()?;
+
+        let mut state = self.state.write().await;
+        for service in state.by_route.values_mut() {
+            if let Some(ref mut cert) = service.created_with.cert {
+                cert.hash = hash.clone();
+            }
+        }
+        drop(state);
+
+        log::info!(
+            "Server certificate of proxy on {} reloaded: {}",
+            self.conf.server.addresses(),
+            hash
+        );
+        Ok(model::ServerCertInfo { hash, loaded_at })
+    }
+
+    /// Reloads the server certificate once its files are modified, until the proxy stops
+    fn watch_cert(&self, interval: Duration) {
+        let cert = match self.cert {
+            Some(ref cert) => cert.clone(),
+            None => return,
+        };
+        let proxy = self.clone();
+
+        tokio::spawn(async move {
+            let stopped = proxy.stopped();
+            futures::pin_mut!(stopped);
+            loop {
+                let tick = tokio::time::sleep(interval);
+                futures::pin_mut!(tick);
+                if let Either::Right(_) = futures::future::select(tick, &mut stopped).await {
+                    break;
+                }
+                if !cert.is_modified() {
+                    continue;
+                }
+                if let Err(e) = proxy.reload_cert().await {
+                    log::warn!(
+                        "Unable to reload the server certificate of proxy on {}: {}",
+                        proxy.conf.server.addresses(),
+                        e
+                    );
+                }
+            }
+        });
+    }
+
     pub async fn contains(&self, service_name: &str) -> bool {
         let state = self.state.read().await;
         state.get_service(service_name).is_ok()
@@ -314,32 +926,271 @@ impl Proxy {
 
     pub async fn get<S>(&self, service_name: &str) -> Result<S, Error>
     where
-        S: From<(model::CreateService, DateTime<Utc>)> + 'static,
+        S: for<'a> From<&'a ProxyService> + 'static,
     {
         let state_lock = self.state.clone();
         let state = state_lock.read().await;
         let service = state.get_service(service_name)?;
-        Ok(S::from((service.created_with.clone(), service.created_at)))
+        Ok(S::from(service))
     }
 
-    pub async fn add<S>(&self, create: model::CreateService) -> Result<S, Error>
+    pub async fn add<S>(
+        &self,
+        create: model::CreateService,
+        correlation_id: Option<&str>,
+    ) -> Result<S, Error>
     where
         S: From<(model::CreateService, DateTime<Utc>)>,
     {
+        let parts = self.service_parts(&create).await?;
         let mut state = self.state.write().await;
-        let service = state.add_service(create)?;
+        // the certificate may have been replaced since the service was checked
+        if let (Some(cert), true) = (create.cert.as_ref(), create.bind_https.is_some()) {
+            let paths = (Some(cert.path.clone()), Some(cert.key_path.clone()));
+            let served = self.server_cert_conf();
+            if paths != (served.server_cert_store_path, served.server_key_path) {
+                return Err(ProxyError::Conf(format!(
+                    "Proxy running on {} uses a different certificate",
+                    self.conf.server.addresses()
+                ))
+                .into());
+            }
+        }
+        let service = state.add_service(create, parts)?;
+        service.health = self.health.clone();
         let model = S::from((service.created_with.clone(), service.created_at));
         let endpoint = service.created_with.from.path().to_string();
+        let service = service.created_with.name.clone();
         drop(state);
 
         let mut stats = self.stats.write().await;
+        stats.reset_service(&service);
         stats.reset_endpoint(&endpoint);
+        drop(stats);
+
+        self.emit_requested(model::Event::ServiceCreated { service }, correlation_id);
+        Ok(model)
+    }
+
+    /// Replaces the service definition, along with the server certificate files when given.
+    /// Fails if `if_match` does not match the service entity tag
+    pub async fn update<S>(
+        &self,
+        update: model::CreateService,
+        replace_cert: Option<(PathBuf, PathBuf)>,
+        if_match: Option<&str>,
+        correlation_id: Option<&str>,
+    ) -> Result<S, Error>
+    where
+        S: for<'a> From<&'a ProxyService>,
+    {
+        let parts = self.service_parts(&update).await?;
+        // served once the service is updated
+        let pending_cert = match replace_cert {
+            Some((path, key_path)) if self.cert.is_some() => {
+                Some(ServerCert::read(path, key_path)?)
+            }
+            Some(_) => return Err(TlsError::Other("HTTPS is not enabled".to_string()).into()),
+            None => None,
+        };
+        let service_name = update.name.clone();
+        let mut state = self.state.write().await;
+        let service = state.get_service(&service_name)?;
+        etag::check(if_match, Some(&service.etag()))?;
+
+        if pending_cert.is_some() {
+            let acme = |cert: Option<&model::CreateServiceCert>| cert.map(|c| c.acme);
+            let reason = if state.by_name.len() > 1 {
+                Some("the certificate is shared with other services")
+            } else if acme(service.created_with.cert.as_ref()) == Some(true)
+                || acme(update.cert.as_ref()) == Some(true)
+            {
+                Some("ACME certificates cannot be replaced")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                return Err(ServiceError::Immutable {
+                    name: service_name,
+                    reason: reason.to_string(),
+                }
+                .into());
+            }
+        }
+
+        let service = state.update_service(update, parts)?;
+        let model = S::from(service);
+        if let (Some(cert), Some(pending)) = (self.cert.as_ref(), pending_cert) {
+            cert.install(pending);
+        }
+        drop(state);
+
+        self.emit_requested(
+            model::Event::ServiceUpdated {
+                service: service_name,
+            },
+            correlation_id,
+        );
         Ok(model)
     }
 
-    pub async fn remove(&self, service_name: &str) -> Result<(), Error> {
+    /// Builds service components from the service definition
+    async fn service_parts(&self, create: &model::CreateService) -> Result<ServiceParts, Error> {
+        let provider = provider::from_service(create, &self.conf.client)?;
+        let client = Arc::new(client::build_upstream(
+            &self.conf.client,
+            create.upstream_protocol.unwrap_or_default(),
+            &create.to,
+        )?);
+        let filters = filter::from_service(create).await?;
+        let policy = match create.address_policy {
+            Some(ref policy) => {
+                let geoip = self.conf.server.geoip_database.as_deref();
+                Some(Arc::new(Policy::new(policy, geoip)?))
+            }
+            None => None,
+        };
+        let streaming = match create.streaming {
+            Some(ref streaming) => Some(Arc::new(Streaming::new(streaming)?)),
+            None => None,
+        };
+        let journal = match create.journal {
+            Some(ref journal) => Some(Arc::new(Journal::open(&create.name, journal)?)),
+            None => None,
+        };
+        let rate_limiter = RateLimiter::from_service(create)?.map(Arc::new);
+        let concurrency = create
+            .cpu_threads
+            .map(|n| Arc::new(Concurrency::new(n, self.runtime.worker_threads)));
+
+        Ok(ServiceParts {
+            provider,
+            runtime: self.runtime.clone(),
+            client,
+            filters,
+            policy,
+            streaming,
+            journal,
+            rate_limiter,
+            concurrency,
+        })
+    }
+
+    /// Removes the service. Fails if `if_match` does not match the service entity tag
+    pub async fn remove(
+        &self,
+        service_name: &str,
+        if_match: Option<&str>,
+        correlation_id: Option<&str>,
+    ) -> Result<(), Error> {
         let mut state = self.state.write().await;
-        Ok(state.remove_service(service_name)?)
+        let service = state.get_service(service_name)?;
+        etag::check(if_match, Some(&service.etag()))?;
+        state.remove_service(service_name)?;
+        drop(state);
+
+        self.emit_requested(
+            model::Event::ServiceRemoved {
+                service: service_name.to_string(),
+            },
+            correlation_id,
+        );
+        Ok(())
+    }
+
+    pub async fn get_request_timings(
+        &self,
+        service_name: &str,
+    ) -> Result<Vec<model::RequestTiming>, Error> {
+        let state = self.state.read().await;
+        let service = state.get_service(service_name)?;
+        Ok(service
+            .tap
+            .as_ref()
+            .map(|tap| tap.requests())
+            .unwrap_or_default())
+    }
+
+    /// Exports request journal entries of the service, when the journal is enabled
+    pub async fn export_journal(
+        &self,
+        service_name: &str,
+        query: model::TimeRange,
+    ) -> Result<Vec<model::JournalEntry>, Error> {
+        let journal = {
+            let state = self.state.read().await;
+            state.get_service(service_name)?.journal.clone()
+        };
+        match journal {
+            Some(journal) => Ok(journal.export(query.from, query.to).await?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Aggregates service usage per user, from the request journal when enabled
+    pub async fn get_usage(
+        &self,
+        service_name: &str,
+        range: model::TimeRange,
+    ) -> Result<model::ServiceUsage, Error> {
+        let journal = {
+            let state = self.state.read().await;
+            state.get_service(service_name)?.journal.clone()
+        };
+
+        let users = match journal {
+            Some(ref journal) => {
+                let mut users: BTreeMap<String, model::UserUsage> = BTreeMap::new();
+                for entry in journal.export(range.from, range.to).await? {
+                    let username = match entry.username {
+                        Some(username) => username,
+                        None => continue,
+                    };
+                    let usage = users
+                        .entry(username.clone())
+                        .or_insert_with(|| model::UserUsage {
+                            username,
+                            requests: 0,
+                            bytes: Some(0),
+                        });
+                    usage.requests += 1;
+                    usage.bytes = usage.bytes.map(|b| b + entry.bytes);
+                }
+                users.into_values().collect()
+            }
+            None => {
+                let stats = self.stats.read().await;
+                let mut users: Vec<_> = stats
+                    .service_users(service_name, range.from, range.to)
+                    .into_iter()
+                    .map(|(username, requests)| model::UserUsage {
+                        username,
+                        requests,
+                        bytes: None,
+                    })
+                    .collect();
+                users.sort_by(|l, r| l.username.cmp(&r.username));
+                users
+            }
+        };
+
+        Ok(model::ServiceUsage {
+            from: range.from,
+            to: range.to,
+            journaled: journal.is_some(),
+            users,
+        })
+    }
+
+    pub async fn get_upstream_stats(
+        &self,
+        service_name: &str,
+    ) -> Result<Vec<model::UpstreamStats>, Error> {
+        let state = self.state.read().await;
+        let service = state.get_service(service_name)?;
+        let mut stats = service.upstream.lock().unwrap().clone();
+        stats.available = !service.upstream_down.load(Ordering::SeqCst);
+        Ok(vec![stats])
     }
 
     pub async fn get_users(&self, service_name: &str) -> Result<Vec<ProxyUser>, Error> {
@@ -354,83 +1205,291 @@ impl Proxy {
         Ok(service.get_user(username)?)
     }
 
+    /// State of the rate limit applied to the user, if configured
+    pub async fn get_user_rate_limit(
+        &self,
+        service_name: &str,
+        username: &str,
+    ) -> Result<Option<model::RateLimitStatus>, Error> {
+        let state = self.state.read().await;
+        let service = state.get_service(service_name)?;
+        Ok(service
+            .rate_limiter
+            .as_ref()
+            .and_then(|l| l.user_status(username)))
+    }
+
     pub async fn add_user(
         &self,
         service_name: &str,
-        username: impl ToString,
-        password: impl ToString,
+        create: model::CreateUser,
+        correlation_id: Option<&str>,
     ) -> Result<ProxyUser, Error> {
         let mut state = self.state.write().await;
         let service = state.get_service_mut(service_name)?;
-        let user = service.add_user(username, password)?;
+        let user = service.add_user(create)?;
         drop(state);
 
         let mut stats = self.stats.write().await;
         stats.reset_user(&user.username);
+        drop(stats);
+
+        self.emit_requested(
+            model::Event::UserCreated {
+                service: service_name.to_string(),
+                username: user.username.clone(),
+            },
+            correlation_id,
+        );
         Ok(user)
     }
 
-    pub async fn remove_user(&self, service_name: &str, username: &str) -> Result<(), Error> {
+    pub async fn rotate_user(
+        &self,
+        service_name: &str,
+        username: &str,
+        rotate: model::RotateCredentials,
+        if_match: Option<&str>,
+        correlation_id: Option<&str>,
+    ) -> Result<(ProxyUser, model::Credentials), Error> {
         let mut state = self.state.write().await;
         let service = state.get_service_mut(service_name)?;
-        Ok(service.remove_user(username)?)
+        let current = service.users.get(username).map(ProxyUser::etag);
+        etag::check(if_match, current.as_deref())?;
+        let created = current.is_none();
+        let (user, credentials) = service.rotate_user(username, rotate)?;
+        drop(state);
+
+        let event = if created {
+            let mut stats = self.stats.write().await;
+            stats.reset_user(&user.username);
+            drop(stats);
+
+            model::Event::UserCreated {
+                service: service_name.to_string(),
+                username: user.username.clone(),
+            }
+        } else {
+            model::Event::UserUpdated {
+                service: service_name.to_string(),
+                username: user.username.clone(),
+            }
+        };
+        self.emit_requested(event, correlation_id);
+        Ok((user, credentials))
     }
-}
 
-/// Proxy service state
-#[derive(Default)]
-pub struct ProxyState {
-    pub(crate) by_endpoint: HashMap<String, ProxyService>,
-    pub(crate) by_name: HashMap<String, String>,
-}
+    /// Replaces user credentials, keeping the user statistics and usage.
+    /// Fails if `if_match` does not match the user entity tag
+    pub async fn update_user(
+        &self,
+        service_name: &str,
+        username: &str,
+        update: model::UpdateUser,
+        if_match: Option<&str>,
+        correlation_id: Option<&str>,
+    ) -> Result<ProxyUser, Error> {
+        let mut state = self.state.write().await;
+        let service = state.get_service_mut(service_name)?;
+        etag::check(if_match, Some(&service.get_user(username)?.etag()))?;
+        let user = service.update_user(username, update)?;
+        drop(state);
 
-impl ProxyState {
-    fn get_service(&self, service_name: &str) -> Result<&ProxyService, ServiceError> {
-        self.by_name
-            .get(service_name)
-            .and_then(|s| self.by_endpoint.get(s))
-            .ok_or_else(|| ServiceError::NotFound(service_name.to_string()))
+        self.emit_requested(
+            model::Event::UserUpdated {
+                service: service_name.to_string(),
+                username: username.to_string(),
+            },
+            correlation_id,
+        );
+        Ok(user)
+    }
+
+    /// Sets the usage allowance of the user, e.g. after a payment
+    pub async fn set_user_allowance(
+        &self,
+        service_name: &str,
+        username: &str,
+        allowance: Option<model::UserAllowance>,
+        correlation_id: Option<&str>,
+    ) -> Result<ProxyUser, Error> {
+        let mut state = self.state.write().await;
+        let service = state.get_service_mut(service_name)?;
+        let user = service.set_user_allowance(username, allowance.clone())?;
+        drop(state);
+
+        log::debug!(
+            "[{}] allowance of user '{}' set to {:?} (correlation id: {})",
+            service_name,
+            username,
+            allowance,
+            correlation_id.unwrap_or("none")
+        );
+        Ok(user)
+    }
+
+    pub async fn remove_user(
+        &self,
+        service_name: &str,
+        username: &str,
+        if_match: Option<&str>,
+        correlation_id: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut state = self.state.write().await;
+        let service = state.get_service_mut(service_name)?;
+        etag::check(if_match, Some(&service.get_user(username)?.etag()))?;
+        service.remove_user(username)?;
+        drop(state);
+
+        self.emit_requested(
+            model::Event::UserRemoved {
+                service: service_name.to_string(),
+                username: username.to_string(),
+            },
+            correlation_id,
+        );
+        Ok(())
+    }
+
+    /// Notifies event subscribers, if any
+    pub(crate) fn emit(&self, event: model::Event) {
+        let _ = self.events.send(event.into());
+    }
+
+    /// Records a change requested via the Management API and notifies event subscribers
+    fn emit_requested(&self, event: model::Event, correlation_id: Option<&str>) {
+        log::info!(
+            "[{}] {} (correlation id: {})",
+            event.service().unwrap_or_default(),
+            event.name(),
+            correlation_id.unwrap_or("none")
+        );
+        let _ = self
+            .events
+            .send(model::EventRecord::correlated(event, correlation_id));
+    }
+}
+
+/// Proxy service state
+#[derive(Default)]
+pub struct ProxyState {
+    pub(crate) by_route: HashMap<Route, ProxyService>,
+    pub(crate) by_name: HashMap<String, Route>,
+    /// Secret of credential fingerprints, generated for the proxy instance
+    fingerprint_key: FingerprintKey,
+}
+
+impl ProxyState {
+    fn get_service(&self, service_name: &str) -> Result<&ProxyService, ServiceError> {
+        self.by_name
+            .get(service_name)
+            .and_then(|s| self.by_route.get(s))
+            .ok_or_else(|| ServiceError::NotFound(service_name.to_string()))
     }
 
     fn get_service_mut(&mut self, service_name: &str) -> Result<&mut ProxyService, ServiceError> {
         self.by_name
             .get(service_name)
-            .and_then(|s| self.by_endpoint.get_mut(s))
+            .and_then(|s| self.by_route.get_mut(s))
             .ok_or_else(|| ServiceError::NotFound(service_name.to_string()))
     }
 
     fn add_service(
         &mut self,
         create: model::CreateService,
+        parts: ServiceParts,
     ) -> Result<&mut ProxyService, ServiceError> {
         let name = create.name.clone();
-        let mut endpoint = create.from.path().to_string();
-
-        if !endpoint.starts_with('/') {
-            endpoint = ["/", endpoint.as_str()].concat();
-        }
+        let route = Route::new(&create);
 
         if self.by_name.contains_key(&name) {
+            let endpoint = route.endpoint;
             return Err(ServiceError::AlreadyExists { name, endpoint });
         }
 
-        for by_endpoint in self.by_endpoint.keys() {
-            if by_endpoint.starts_with(&endpoint) || endpoint.starts_with(by_endpoint) {
-                return Err(ServiceError::AlreadyExists { name, endpoint });
+        for (other, service) in self.by_route.iter() {
+            if route.overlaps(other) {
+                return Err(ServiceError::Conflict {
+                    name,
+                    endpoint: route.endpoint,
+                    other: service.created_with.name.clone(),
+                });
             }
         }
 
-        let service = ProxyService::new(create);
-        self.by_name.insert(name, endpoint.clone());
-        self.by_endpoint.insert(endpoint.clone(), service);
+        let mut service = ProxyService::new(create, parts.client.clone());
+        service.fingerprint_key = self.fingerprint_key.clone();
+        parts.apply(&mut service);
+        self.by_name.insert(name, route.clone());
+        self.by_route.insert(route.clone(), service);
+
+        Ok(self.by_route.get_mut(&route).unwrap())
+    }
+
+    /// Replaces the service definition, routing the service anew when its route changes
+    fn update_service(
+        &mut self,
+        update: model::CreateService,
+        parts: ServiceParts,
+    ) -> Result<&mut ProxyService, ServiceError> {
+        let name = update.name.clone();
+        let previous = match self.by_name.get(&name) {
+            Some(route) => route.clone(),
+            None => return Err(ServiceError::NotFound(name)),
+        };
+        let route = Route::new(&update);
+
+        if route != previous {
+            for (other, service) in self.by_route.iter() {
+                if *other != previous && route.overlaps(other) {
+                    return Err(ServiceError::Conflict {
+                        name,
+                        endpoint: route.endpoint,
+                        other: service.created_with.name.clone(),
+                    });
+                }
+            }
+            let service = self.by_route.remove(&previous).unwrap();
+            self.by_route.insert(route.clone(), service);
+            self.by_name.insert(name, route.clone());
+        }
+
+        let service = self.by_route.get_mut(&route).unwrap();
+        service.update(update, parts);
+        Ok(service)
+    }
+
+    /// Finds the service serving the request path. Services reachable at the requested host
+    /// take precedence, otherwise the path has to match a single service
+    pub(crate) fn route(&self, host: Option<&str>, path: &str) -> Option<(&Route, &ProxyService)> {
+        let matching: Vec<_> = self
+            .by_route
+            .iter()
+            .filter(|(route, _)| endpoint::matches(path, &route.endpoint))
+            .collect();
+
+        if let Some(host) = host {
+            if let Some(found) = matching.iter().find(|(route, _)| route.serves(host)) {
+                return Some(*found);
+            }
+        }
+        match matching.as_slice() {
+            [found] => Some(*found),
+            _ => None,
+        }
+    }
 
-        Ok(self.by_endpoint.get_mut(&endpoint).unwrap())
+    /// Removes user credentials. Returns the name of the service the user was removed from
+    pub(crate) fn revoke_user(&mut self, route: &Route, username: &str) -> Option<String> {
+        let service = self.by_route.get_mut(route)?;
+        service.remove_user(username).ok()?;
+        Some(service.created_with.name.clone())
     }
 
     fn remove_service(&mut self, service_name: &str) -> Result<(), ServiceError> {
         match self.by_name.remove(service_name) {
-            Some(endpoint) => {
-                self.by_endpoint.remove(&endpoint);
+            Some(route) => {
+                self.by_route.remove(&route);
                 Ok(())
             }
             None => Err(ServiceError::NotFound(service_name.to_string())),
@@ -443,24 +1502,105 @@ impl ProxyState {
 pub struct ProxyService {
     pub created_at: DateTime<Utc>,
     pub created_with: model::CreateService,
-    pub(crate) access: HashSet<String>,
+    pub(crate) access: HashMap<Access, String>,
     pub(crate) users: HashMap<String, ProxyUser>,
+    pub(crate) provider: Option<Arc<dyn CredentialProvider>>,
+    /// Worker threads handling the service requests
+    pub(crate) runtime: Arc<RuntimeStats>,
+    /// Upstream client, sharing its connection pool with services holding the same client
+    pub(crate) client: Arc<UpstreamClient>,
+    pub(crate) filters: Filters,
+    pub(crate) policy: Option<Arc<Policy>>,
+    pub(crate) streaming: Option<Arc<Streaming>>,
+    pub(crate) journal: Option<Arc<Journal>>,
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+    pub(crate) concurrency: Option<Arc<Concurrency>>,
+    pub(crate) tap: Option<Arc<Tap>>,
+    pub(crate) health: ProxyHealth,
+    pub(crate) upstream_down: Arc<AtomicBool>,
+    pub(crate) upstream: Arc<Mutex<model::UpstreamStats>>,
+    pub(crate) metrics: Arc<Mutex<Metrics>>,
+    fingerprint_key: FingerprintKey,
 }
 
 impl ProxyService {
-    pub fn new(create: model::CreateService) -> Self {
+    pub fn new(create: model::CreateService, client: Arc<UpstreamClient>) -> Self {
+        let metrics = Metrics::new(create.alerts.as_ref());
+        let upstream = model::UpstreamStats {
+            target: create.to.to_string(),
+            ..Default::default()
+        };
+        let tap = create
+            .request_tap
+            .filter(|n| *n > 0)
+            .map(|n| Arc::new(Tap::new(n.min(MAX_REQUEST_TAP))));
         Self {
             created_at: Utc::now(),
             created_with: create,
             access: Default::default(),
             users: Default::default(),
+            provider: None,
+            runtime: Default::default(),
+            client,
+            filters: Filters {
+                request: Vec::new(),
+                response: Vec::new(),
+            },
+            policy: None,
+            streaming: None,
+            journal: None,
+            rate_limiter: None,
+            concurrency: None,
+            tap,
+            health: Default::default(),
+            upstream_down: Default::default(),
+            upstream: Arc::new(Mutex::new(upstream)),
+            metrics: Arc::new(Mutex::new(metrics)),
+            fingerprint_key: Default::default(),
+        }
+    }
+
+    /// Replaces the service definition. Components configured as before keep their state
+    fn update(&mut self, update: model::CreateService, mut parts: ServiceParts) {
+        let previous = std::mem::replace(&mut self.created_with, update);
+        let current = &self.created_with;
+
+        if previous.journal == current.journal {
+            parts.journal = self.journal.take();
+        }
+        let user_rate_limit =
+            |c: &model::CreateService| c.user.as_ref().map(|u| u.rate_limit.clone());
+        if previous.rate_limit == current.rate_limit
+            && user_rate_limit(&previous) == user_rate_limit(current)
+        {
+            parts.rate_limiter = self.rate_limiter.take();
+        }
+        if previous.cpu_threads == current.cpu_threads {
+            parts.concurrency = self.concurrency.take();
+        }
+        if previous.request_tap != current.request_tap {
+            self.tap = current
+                .request_tap
+                .filter(|n| *n > 0)
+                .map(|n| Arc::new(Tap::new(n.min(MAX_REQUEST_TAP))));
+        }
+        if previous.alerts != current.alerts {
+            *self.metrics.lock().unwrap() = Metrics::new(current.alerts.as_ref());
         }
+        self.upstream.lock().unwrap().target = current.to.to_string();
+
+        parts.apply(self);
     }
 
     fn get_users(&self) -> Vec<ProxyUser> {
         self.users.values().cloned().collect()
     }
 
+    /// Entity tag of the service definition
+    pub(crate) fn etag(&self) -> String {
+        etag::service(&model::Service::from(self))
+    }
+
     fn get_user(&self, username: &str) -> Result<ProxyUser, UserError> {
         self.users
             .get(username)
@@ -468,35 +1608,131 @@ impl ProxyService {
             .ok_or_else(|| UserError::NotFound(username.to_string()))
     }
 
-    fn add_user(
-        &mut self,
-        username: impl ToString,
-        password: impl ToString,
-    ) -> Result<ProxyUser, UserError> {
-        let username = username.to_string();
-        let password = password.to_string();
+    fn add_user(&mut self, create: model::CreateUser) -> Result<ProxyUser, UserError> {
+        let username = create.username;
 
         if self.users.contains_key(&username) {
             return Err(UserError::AlreadyExists(username));
         }
+        self.check_user_limit()?;
+
+        let access = Access::new(&username, &create.credentials)?;
+        if self.access.contains_key(&access) {
+            return Err(UserError::DuplicateCredentials);
+        }
 
-        let credentials = base64::encode(format!("{}:{}", username, password));
         let user = ProxyUser {
             created_at: Utc::now(),
             username: username.clone(),
-            credentials: credentials.clone(),
+            fingerprint: access.fingerprint(&self.fingerprint_key),
+            access: access.clone(),
+            usage: Arc::new(Usage::new(create.limits)),
         };
 
-        self.access.insert(credentials);
+        self.access.insert(access, username.clone());
         self.users.insert(username, user.clone());
 
         Ok(user)
     }
 
+    /// Replaces user credentials with randomly generated ones. Creates the user if missing
+    fn rotate_user(
+        &mut self,
+        username: &str,
+        rotate: model::RotateCredentials,
+    ) -> Result<(ProxyUser, model::Credentials), UserError> {
+        let previous = self.users.get(username);
+        if previous.is_none() {
+            self.check_user_limit()?;
+        }
+        let method = rotate
+            .method
+            .or_else(|| previous.map(|u| u.access.method()))
+            .unwrap_or_default();
+
+        let credentials = generate_credentials(method)?;
+        let access = Access::new(username, &credentials)?;
+        let created_at = previous.map(|u| u.created_at).unwrap_or_else(Utc::now);
+        let limits = rotate
+            .limits
+            .or_else(|| previous.and_then(|u| u.usage.limits.clone()));
+
+        if let Some(previous) = self.users.get(username) {
+            self.access.remove(&previous.access);
+        }
+
+        let usage = match previous {
+            Some(previous) => Usage::replacing(limits, &previous.usage),
+            None => Usage::new(limits),
+        };
+
+        let user = ProxyUser {
+            created_at,
+            username: username.to_string(),
+            fingerprint: access.fingerprint(&self.fingerprint_key),
+            access: access.clone(),
+            usage: Arc::new(usage),
+        };
+
+        self.access.insert(access, user.username.clone());
+        self.users.insert(user.username.clone(), user.clone());
+
+        Ok((user, credentials))
+    }
+
+    /// Replaces credentials of an existing user
+    fn update_user(
+        &mut self,
+        username: &str,
+        update: model::UpdateUser,
+    ) -> Result<ProxyUser, UserError> {
+        let previous = self.get_user(username)?;
+        let access = Access::new(username, &update.credentials)?;
+        match self.access.get(&access) {
+            Some(owner) if owner != username => return Err(UserError::DuplicateCredentials),
+            _ => {}
+        }
+        let limits = update.limits.or_else(|| previous.usage.limits.clone());
+
+        let usage = Usage::replacing(limits, &previous.usage);
+
+        let user = ProxyUser {
+            created_at: previous.created_at,
+            username: username.to_string(),
+            fingerprint: access.fingerprint(&self.fingerprint_key),
+            access: access.clone(),
+            usage: Arc::new(usage),
+        };
+
+        self.access.remove(&previous.access);
+        self.access.insert(access, user.username.clone());
+        self.users.insert(user.username.clone(), user.clone());
+
+        Ok(user)
+    }
+
+    /// Sets the usage allowance of the current user credentials, keeping their usage
+    fn set_user_allowance(
+        &mut self,
+        username: &str,
+        allowance: Option<model::UserAllowance>,
+    ) -> Result<ProxyUser, UserError> {
+        let user = self.get_user(username)?;
+        user.usage.set_allowance(allowance);
+        Ok(user)
+    }
+
+    fn check_user_limit(&self) -> Result<(), UserError> {
+        match self.created_with.max_users {
+            Some(max) if self.users.len() >= max => Err(UserError::LimitReached(max)),
+            _ => Ok(()),
+        }
+    }
+
     fn remove_user(&mut self, username: &str) -> Result<(), UserError> {
         match self.users.remove(username) {
             Some(user) => {
-                self.access.remove(&user.credentials);
+                self.access.remove(&user.access);
                 Ok(())
             }
             None => Err(UserError::NotFound(username.to_string())),
@@ -509,7 +1745,68 @@ impl<'a> From<&'a ProxyService> for model::Service {
         model::Service {
             created_at: s.created_at,
             inner: s.created_with.clone(),
+            upstream_available: Some(!s.upstream_down.load(Ordering::SeqCst)),
+            user_count: Some(s.users.len()),
+            proxy_error: s.health.failure(),
+            listener_error: s.health.listener_error(),
+        }
+    }
+}
+
+/// Service components built from the service definition
+struct ServiceParts {
+    provider: Option<Arc<dyn CredentialProvider>>,
+    runtime: Arc<RuntimeStats>,
+    client: Arc<UpstreamClient>,
+    filters: Filters,
+    policy: Option<Arc<Policy>>,
+    streaming: Option<Arc<Streaming>>,
+    journal: Option<Arc<Journal>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    concurrency: Option<Arc<Concurrency>>,
+}
+
+impl ServiceParts {
+    fn apply(self, service: &mut ProxyService) {
+        service.provider = self.provider;
+        service.runtime = self.runtime;
+        service.client = self.client;
+        service.filters = self.filters;
+        service.policy = self.policy;
+        service.streaming = self.streaming;
+        service.journal = self.journal;
+        service.rate_limiter = self.rate_limiter;
+        service.concurrency = self.concurrency;
+    }
+}
+
+/// Service name reserved while the service is created
+struct NameReservation {
+    creating: Arc<Mutex<HashSet<String>>>,
+    name: String,
+}
+
+impl NameReservation {
+    fn new(
+        creating: &Arc<Mutex<HashSet<String>>>,
+        create: &model::CreateService,
+    ) -> Result<Self, ServiceError> {
+        if !creating.lock().unwrap().insert(create.name.clone()) {
+            return Err(ServiceError::AlreadyExists {
+                name: create.name.clone(),
+                endpoint: endpoint::from_path(create.from.path()),
+            });
         }
+        Ok(Self {
+            creating: creating.clone(),
+            name: create.name.clone(),
+        })
+    }
+}
+
+impl Drop for NameReservation {
+    fn drop(&mut self) {
+        self.creating.lock().unwrap().remove(&self.name);
     }
 }
 
@@ -518,72 +1815,589 @@ impl<'a> From<&'a ProxyService> for model::Service {
 pub struct ProxyUser {
     pub created_at: DateTime<Utc>,
     pub username: String,
-    credentials: String,
+    /// Identifies the credentials in request records without disclosing them
+    pub(crate) fingerprint: String,
+    pub(crate) access: Access,
+    pub(crate) usage: Arc<Usage>,
 }
 
-/// Proxy server stats
-#[derive(Default)]
+impl ProxyUser {
+    /// Entity tag of the user descriptor
+    pub(crate) fn etag(&self) -> String {
+        etag::of(&model::User::from(self))
+    }
+}
+
+impl<'a> From<&'a ProxyUser> for model::User {
+    fn from(u: &'a ProxyUser) -> Self {
+        model::User {
+            username: u.username.clone(),
+            method: u.access.method(),
+            limits: u.usage.limits.clone(),
+            fingerprint: Some(u.fingerprint.clone()),
+            created_at: u.created_at,
+        }
+    }
+}
+
+/// Credentials usage tracker
+#[derive(Debug, Default)]
+pub(crate) struct Usage {
+    limits: Option<model::UserLimits>,
+    allowance: Arc<Allowance>,
+    requests: AtomicUsize,
+    bytes: AtomicU64,
+    session: Mutex<Option<IpAddr>>,
+}
+
+/// Credentials usage verdict
+pub(crate) enum Grant {
+    Allow,
+    /// Allow the request and revoke the credentials afterwards
+    Last(model::RevokeReason),
+    Deny(model::RevokeReason),
+    /// Reject the request without revoking the credentials
+    Throttle,
+    /// Reject the request made from another client than the credentials are bound to
+    Foreign,
+}
+
+impl Usage {
+    fn new(limits: Option<model::UserLimits>) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Tracks replacement credentials of a user, which keep the allowance and the usage
+    /// limits are measured against
+    fn replacing(limits: Option<model::UserLimits>, previous: &Usage) -> Self {
+        Self {
+            limits,
+            allowance: previous.allowance.clone(),
+            requests: AtomicUsize::new(previous.requests.load(Ordering::SeqCst)),
+            bytes: AtomicU64::new(previous.bytes.load(Ordering::SeqCst)),
+            ..Default::default()
+        }
+    }
+
+    /// Registers a credentials use by a client connection
+    pub(crate) fn acquire(&self, address: SocketAddr) -> Grant {
+        if !self.allowance.reserve() {
+            return Grant::Throttle;
+        }
+        let grant = self.acquire_limits(address);
+        if let Grant::Deny(_) | Grant::Foreign = grant {
+            self.allowance.release();
+        }
+        grant
+    }
+
+    fn acquire_limits(&self, address: SocketAddr) -> Grant {
+        let limits = match self.limits {
+            Some(ref limits) => limits,
+            None => {
+                self.requests.fetch_add(1, Ordering::SeqCst);
+                return Grant::Allow;
+            }
+        };
+
+        if limits.single_session {
+            // clients may open multiple connections or reconnect
+            let mut session = self.session.lock().unwrap();
+            match *session {
+                Some(bound) if bound != address.ip() => return Grant::Foreign,
+                Some(_) => (),
+                None => {
+                    session.replace(address.ip());
+                }
+            }
+        }
+
+        if let Some(max) = limits.max_bytes {
+            if self.bytes.load(Ordering::SeqCst) >= max {
+                return Grant::Deny(model::RevokeReason::ByteLimit);
+            }
+        }
+
+        let requests = self.requests.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Some(max) = limits.max_requests {
+            if requests > max {
+                return Grant::Deny(model::RevokeReason::RequestLimit);
+            } else if requests == max {
+                return Grant::Last(model::RevokeReason::RequestLimit);
+            }
+        }
+
+        Grant::Allow
+    }
+
+    /// Registers body bytes transferred with the credentials. Returns `false` once
+    /// the byte quota is exceeded
+    pub(crate) fn add_bytes(&self, bytes: u64) -> bool {
+        let total = self.bytes.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        self.allowance.bytes.fetch_add(bytes, Ordering::SeqCst);
+        match self.limits.as_ref().and_then(|limits| limits.max_bytes) {
+            Some(max) => total <= max,
+            None => true,
+        }
+    }
+
+    fn allowance(&self) -> Option<model::UserAllowance> {
+        self.allowance.limit.lock().unwrap().clone()
+    }
+
+    fn set_allowance(&self, allowance: Option<model::UserAllowance>) {
+        *self.allowance.limit.lock().unwrap() = allowance;
+    }
+}
+
+/// Usage allowance of a user and the usage measured against it, shared by the
+/// credentials replacing each other
+#[derive(Debug, Default)]
+struct Allowance {
+    limit: Mutex<Option<model::UserAllowance>>,
+    requests: AtomicUsize,
+    bytes: AtomicU64,
+}
+
+impl Allowance {
+    /// Counts a request, unless the allowance has been used up
+    fn reserve(&self) -> bool {
+        let limit = self.limit.lock().unwrap();
+        if let Some(ref limit) = *limit {
+            let requests = limit
+                .max_requests
+                .map(|max| self.requests.load(Ordering::SeqCst) >= max);
+            let bytes = limit
+                .max_bytes
+                .map(|max| self.bytes.load(Ordering::SeqCst) >= max);
+            if requests.unwrap_or(false) || bytes.unwrap_or(false) {
+                return false;
+            }
+        }
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    /// Returns a request denied after reservation
+    fn release(&self) {
+        self.requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<'a> From<&'a ProxyUser> for model::UserLimitsStatus {
+    fn from(u: &'a ProxyUser) -> Self {
+        let limits = u.usage.limits.clone().unwrap_or_default();
+        let requests = u.usage.requests.load(Ordering::SeqCst);
+        let bytes = u.usage.bytes.load(Ordering::SeqCst);
+        model::UserLimitsStatus {
+            username: u.username.clone(),
+            max_requests: limits.max_requests,
+            requests,
+            remaining_requests: limits.max_requests.map(|max| max.saturating_sub(requests)),
+            single_session: limits.single_session,
+            session_bound: u.usage.session.lock().unwrap().is_some(),
+            max_bytes: limits.max_bytes,
+            bytes,
+            remaining_bytes: limits.max_bytes.map(|max| max.saturating_sub(bytes)),
+            allowance: u.usage.allowance(),
+        }
+    }
+}
+
+/// User credentials lookup key. Secrets are only kept as Sha3_256 digests
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub(crate) enum Access {
+    Basic(String),
+    Bearer(String),
+    ApiKey(String),
+    CertFingerprint(String),
+}
+
+impl Access {
+    fn new(username: &str, credentials: &model::Credentials) -> Result<Self, UserError> {
+        use model::Credentials;
+
+        Ok(match credentials {
+            Credentials::Basic { password } => {
+                let pair = format!("{}:{}", username, password.expose());
+                Self::basic(&base64::encode(pair))
+            }
+            Credentials::Bearer { token } => Self::bearer(token.expose()),
+            Credentials::ApiKey { key } => Self::ApiKey(digest(key.expose())),
+            Credentials::CertFingerprint { fingerprint } => {
+                Self::CertFingerprint(fingerprint.to_lowercase())
+            }
+            credentials => {
+                let method: &'static str = credentials.method().into();
+                return Err(UserError::UnsupportedMethod(method.to_string()));
+            }
+        })
+    }
+
+    /// Creates a lookup key from base64 encoded `username:password` pair
+    pub(crate) fn basic(encoded: &str) -> Self {
+        Self::Basic(digest(encoded))
+    }
+
+    /// Creates a lookup key from a bearer token
+    pub(crate) fn bearer(token: &str) -> Self {
+        Self::Bearer(digest(token))
+    }
+
+    /// Creates a lookup key from an API key
+    pub(crate) fn api_key(key: &str) -> Self {
+        Self::ApiKey(digest(key))
+    }
+
+    /// Shortened SHA3-256 digest of the lookup key, keyed by the proxy secret. Fingerprints
+    /// cannot be checked against guessed credentials without the secret
+    pub(crate) fn fingerprint(&self, secret: &FingerprintKey) -> String {
+        let key = match self {
+            Self::Basic(key)
+            | Self::Bearer(key)
+            | Self::ApiKey(key)
+            | Self::CertFingerprint(key) => key,
+        };
+        let method: &'static str = self.method().into();
+        let message = format!("{}:{}", method, key);
+        // SHA-3 is not subject to length extension, a fixed-length key prefix authenticates
+        // the message
+        let mac = Sha3_256::new()
+            .chain_update(secret.0)
+            .chain_update(message)
+            .finalize();
+        format!("{:x}", mac)[..FINGERPRINT_LEN].to_string()
+    }
+
+    pub(crate) fn method(&self) -> model::AuthMethod {
+        match self {
+            Self::Basic(_) => model::AuthMethod::Basic,
+            Self::Bearer(_) => model::AuthMethod::Bearer,
+            Self::ApiKey(_) => model::AuthMethod::ApiKey,
+            Self::CertFingerprint(_) => model::AuthMethod::CertFingerprint,
+        }
+    }
+}
+
+/// Generates the shutdown nonce of a proxy process
+fn shutdown_nonce() -> String {
+    use rand::distributions::{Alphanumeric, DistString};
+
+    const NONCE_LEN: usize = 32;
+    Alphanumeric.sample_string(&mut rand::thread_rng(), NONCE_LEN)
+}
+
+/// Generates random credentials for the given auth method
+fn generate_credentials(method: model::AuthMethod) -> Result<model::Credentials, UserError> {
+    use rand::distributions::{Alphanumeric, DistString};
+
+    const PASSWORD_LEN: usize = 24;
+    const TOKEN_LEN: usize = 48;
+
+    let len = match method {
+        model::AuthMethod::Basic => PASSWORD_LEN,
+        _ => TOKEN_LEN,
+    };
+    let credentials = match method {
+        // fingerprints are derived from client certificates
+        model::AuthMethod::CertFingerprint => None,
+        ref method => {
+            let secret = Alphanumeric.sample_string(&mut rand::rngs::OsRng, len);
+            model::Credentials::new(method.clone(), secret)
+        }
+    };
+
+    credentials.ok_or_else(|| {
+        let method: &'static str = method.into();
+        UserError::UnsupportedMethod(method.to_string())
+    })
+}
+
+pub(crate) fn digest(secret: &str) -> String {
+    format!("{:x}", Sha3_256::digest(secret.as_bytes()))
+}
+
+/// Random secret of credential fingerprints
+#[derive(Clone)]
+pub(crate) struct FingerprintKey([u8; 32]);
+
+impl Default for FingerprintKey {
+    fn default() -> Self {
+        let mut key = [0u8; 32];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut key);
+        Self(key)
+    }
+}
+
+impl std::fmt::Debug for FingerprintKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FingerprintKey")
+    }
+}
+
+/// Proxy server stats. Service and user request and byte counters are kept for the lifetime
+/// of the service or user, while counters of endpoints are kept in hourly buckets
 pub struct ProxyStats {
     pub(crate) total: usize,
-    pub(crate) endpoint: HashMap<String, usize>,
+    pub(crate) service: HashMap<String, usize>,
+    pub(crate) service_protocol: HashMap<String, HashMap<&'static str, usize>>,
+    pub(crate) service_blocked: HashMap<String, usize>,
+    pub(crate) service_bytes: HashMap<String, u64>,
     pub(crate) user: HashMap<String, usize>,
-    pub(crate) user_endpoint: HashMap<String, HashMap<String, usize>>,
+    pub(crate) user_bytes: HashMap<String, u64>,
+    buckets: VecDeque<StatsBucket>,
+    retention_hours: usize,
 }
 
-impl ProxyStats {
-    pub fn reset_endpoint(&mut self, endpoint: &str) {
-        self.endpoint.insert(endpoint.to_string(), 0);
+/// Request and byte counters of a single hour
+#[derive(Debug, Default)]
+struct StatsBucket {
+    /// Number of hours since the UNIX epoch
+    hour: i64,
+    endpoint: HashMap<String, usize>,
+    endpoint_bytes: HashMap<String, u64>,
+    service_user: HashMap<String, HashMap<String, usize>>,
+    user_endpoint: HashMap<String, HashMap<String, usize>>,
+    user_endpoint_bytes: HashMap<String, HashMap<String, u64>>,
+}
+
+impl StatsBucket {
+    fn overlaps(&self, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> bool {
+        let start = self.hour * SECS_PER_HOUR;
+        from.map(|t| t.timestamp() < start + SECS_PER_HOUR)
+            .unwrap_or(true)
+            && to.map(|t| t.timestamp() > start).unwrap_or(true)
     }
+}
 
-    pub fn reset_user(&mut self, username: &str) {
-        let username = username.to_string();
-        self.user.insert(username.clone(), 0);
-        self.user_endpoint.insert(username, Default::default());
+const SECS_PER_HOUR: i64 = 3600;
+
+impl Default for ProxyStats {
+    fn default() -> Self {
+        Self::new(StatsConf::default().retention_hours)
     }
+}
 
-    pub fn inc(&mut self, endpoint: &str, username: &str) {
-        self.total += 1;
+impl ProxyStats {
+    pub fn new(retention_hours: usize) -> Self {
+        Self {
+            total: 0,
+            service: Default::default(),
+            service_protocol: Default::default(),
+            service_blocked: Default::default(),
+            service_bytes: Default::default(),
+            user: Default::default(),
+            user_bytes: Default::default(),
+            buckets: Default::default(),
+            retention_hours: retention_hours.max(1),
+        }
+    }
 
-        // `HashMap::raw_entry_mut` is unstable;
-        // use lookups before converting the key
+    pub fn reset_service(&mut self, service: &str) {
+        self.service.insert(service.to_string(), 0);
+        self.service_protocol.remove(service);
+        self.service_blocked.remove(service);
+        self.service_bytes.remove(service);
+        for bucket in self.buckets.iter_mut() {
+            bucket.service_user.remove(service);
+        }
+    }
 
-        if let Some(counter) = self.endpoint.get_mut(endpoint) {
-            *counter += 1;
-        } else {
-            self.endpoint.insert(endpoint.to_string(), 1);
+    pub fn reset_endpoint(&mut self, endpoint: &str) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.endpoint.remove(endpoint);
+            bucket.endpoint_bytes.remove(endpoint);
         }
+    }
 
-        if let Some(stats) = self.user.get_mut(username) {
-            *stats += 1;
-        } else {
-            self.user.insert(username.to_string(), 1);
+    pub fn reset_user(&mut self, username: &str) {
+        self.user.insert(username.to_string(), 0);
+        self.user_bytes.remove(username);
+        for bucket in self.buckets.iter_mut() {
+            bucket.user_endpoint.remove(username);
+            bucket.user_endpoint_bytes.remove(username);
+            for users in bucket.service_user.values_mut() {
+                users.remove(username);
+            }
         }
+    }
 
-        let user_stats = if let Some(stats) = self.user_endpoint.get_mut(username) {
+    pub fn inc_protocol(&mut self, service: &str, version: Version) {
+        let stats = if let Some(stats) = self.service_protocol.get_mut(service) {
             stats
         } else {
-            self.user_endpoint.entry(username.to_string()).or_default()
+            self.service_protocol
+                .entry(service.to_string())
+                .or_default()
         };
+        *stats.entry(protocol_name(version)).or_default() += 1;
+    }
 
-        if let Some(stats) = user_stats.get_mut(endpoint) {
-            *stats += 1;
+    pub fn inc_blocked(&mut self, service: &str) {
+        if let Some(blocked) = self.service_blocked.get_mut(service) {
+            *blocked += 1;
         } else {
-            user_stats.insert(endpoint.to_string(), 1);
-        };
+            self.service_blocked.insert(service.to_string(), 1);
+        }
     }
-}
 
-pub(crate) fn cert_hash(path: impl AsRef<Path>) -> Result<String, ProxyError> {
-    match std::fs::read(&path) {
-        Ok(vec) => {
-            let mut digest = Sha3_256::default();
-            digest.update(&vec);
+    pub fn inc(&mut self, service: &str, endpoint: &str, username: &str) {
+        self.inc_at(Utc::now(), service, endpoint, username)
+    }
+
+    fn inc_at(&mut self, now: DateTime<Utc>, service: &str, endpoint: &str, username: &str) {
+        self.total += 1;
+        inc(&mut self.service, service);
+        inc(&mut self.user, username);
+
+        let bucket = self.bucket(now);
+        inc(&mut bucket.endpoint, endpoint);
+        inc(nested(&mut bucket.service_user, service), username);
+        inc(nested(&mut bucket.user_endpoint, username), endpoint);
+    }
+
+    /// Registers request and response body bytes transferred by the user
+    pub fn add_bytes(&mut self, service: &str, endpoint: &str, username: &str, bytes: u64) {
+        self.add_bytes_at(Utc::now(), service, endpoint, username, bytes)
+    }
+
+    fn add_bytes_at(
+        &mut self,
+        now: DateTime<Utc>,
+        service: &str,
+        endpoint: &str,
+        username: &str,
+        bytes: u64,
+    ) {
+        add(&mut self.service_bytes, service, bytes);
+        add(&mut self.user_bytes, username, bytes);
+
+        let bucket = self.bucket(now);
+        add(&mut bucket.endpoint_bytes, endpoint, bytes);
+        add(
+            nested(&mut bucket.user_endpoint_bytes, username),
+            endpoint,
+            bytes,
+        );
+    }
+
+    /// Returns the bucket of the current hour, dropping buckets past the retention period
+    fn bucket(&mut self, now: DateTime<Utc>) -> &mut StatsBucket {
+        let hour = now.timestamp().div_euclid(SECS_PER_HOUR);
+        let oldest = hour - self.retention_hours as i64;
+        while matches!(self.buckets.front(), Some(b) if b.hour <= oldest) {
+            self.buckets.pop_front();
+        }
+        if !matches!(self.buckets.back(), Some(b) if b.hour >= hour) {
+            self.buckets.push_back(StatsBucket {
+                hour,
+                ..Default::default()
+            });
+        }
+        self.buckets.back_mut().unwrap()
+    }
+
+    /// Requests per endpoint made by the user within the retention period
+    pub fn user_endpoint(&self, username: &str) -> Option<HashMap<String, usize>> {
+        if !self.user.contains_key(username) {
+            return None;
+        }
+        let mut requests = HashMap::new();
+        for endpoints in self
+            .buckets
+            .iter()
+            .filter_map(|b| b.user_endpoint.get(username))
+        {
+            for (endpoint, count) in endpoints {
+                *requests.entry(endpoint.clone()).or_default() += count;
+            }
+        }
+        Some(requests)
+    }
 
-            let digest_str = format!("{:x}", digest.finalize());
-            let prefix = if digest_str.len() % 2 == 1 { "0" } else { "" };
+    /// Bytes per endpoint transferred by the user within the retention period
+    pub fn user_endpoint_bytes(&self, username: &str) -> Option<HashMap<String, u64>> {
+        if !self.user.contains_key(username) {
+            return None;
+        }
+        let mut bytes = HashMap::new();
+        for endpoints in self
+            .buckets
+            .iter()
+            .filter_map(|b| b.user_endpoint_bytes.get(username))
+        {
+            for (endpoint, count) in endpoints {
+                *bytes.entry(endpoint.clone()).or_default() += count;
+            }
+        }
+        Some(bytes)
+    }
 
-            Ok(format!("sha3:{}{}", prefix, digest_str))
+    /// Requests per service user, made within hours overlapping the time range
+    pub fn service_users(
+        &self,
+        service: &str,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> HashMap<String, usize> {
+        let mut requests = HashMap::new();
+        let buckets = self.buckets.iter().filter(|b| b.overlaps(from, to));
+        for users in buckets.filter_map(|b| b.service_user.get(service)) {
+            for (username, count) in users {
+                *requests.entry(username.clone()).or_default() += count;
+            }
         }
+        requests
+    }
+}
+
+// `HashMap::raw_entry_mut` is unstable;
+// use lookups before converting the key
+
+fn inc(counters: &mut HashMap<String, usize>, key: &str) {
+    if let Some(counter) = counters.get_mut(key) {
+        *counter += 1;
+    } else {
+        counters.insert(key.to_string(), 1);
+    }
+}
+
+fn add(counters: &mut HashMap<String, u64>, key: &str, value: u64) {
+    if let Some(counter) = counters.get_mut(key) {
+        *counter += value;
+    } else {
+        counters.insert(key.to_string(), value);
+    }
+}
+
+fn nested<'a, T: Default>(
+    counters: &'a mut HashMap<String, HashMap<String, T>>,
+    key: &str,
+) -> &'a mut HashMap<String, T> {
+    if !counters.contains_key(key) {
+        counters.insert(key.to_string(), Default::default());
+    }
+    counters.get_mut(key).unwrap()
+}
+
+/// Protocol name, as used in ALPN
+pub(crate) fn protocol_name(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "http/0.9",
+        Version::HTTP_10 => "http/1.0",
+        Version::HTTP_11 => "http/1.1",
+        Version::HTTP_2 => "h2",
+        Version::HTTP_3 => "h3",
+        _ => "unknown",
+    }
+}
+
+pub(crate) fn cert_hash(path: impl AsRef<Path>) -> Result<String, ProxyError> {
+    match std::fs::read(&path) {
+        Ok(vec) => Ok(cert_digest(&vec)),
         Err(err) => Err(ProxyError::Conf(format!(
             "Unable to read the certificate file '{}': {}",
             path.as_ref().display(),
@@ -591,3 +2405,982 @@ pub(crate) fn cert_hash(path: impl AsRef<Path>) -> Result<String, ProxyError> {
         ))),
     }
 }
+
+/// Hash of certificate file contents
+pub(crate) fn cert_digest(contents: &[u8]) -> String {
+    let mut digest = Sha3_256::default();
+    digest.update(contents);
+
+    let digest_str = format!("{:x}", digest.finalize());
+    let prefix = if digest_str.len() % 2 == 1 { "0" } else { "" };
+
+    format!("sha3:{}{}", prefix, digest_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use super::{Access, FingerprintKey, Grant, ProxyManager, ProxyStats, ProxyUser, Usage};
+    use crate::conf::ProxyConf;
+    use ya_http_proxy_model::{
+        self as model, Credentials, RevokeReason, UserAllowance, UserLimits, UserLimitsStatus,
+    };
+
+    /// Manager without default listening addresses, set by each service instead
+    pub(crate) fn manager() -> ProxyManager {
+        let mut conf = ProxyConf::default();
+        conf.server.bind_https = None;
+        conf.server.bind_http = None;
+        ProxyManager::new(conf)
+    }
+
+    /// Services bound to port 0 are assigned a free port by the system
+    pub(crate) fn any_port() -> SocketAddr {
+        ([127, 0, 0, 1], 0).into()
+    }
+
+    /// HTTP address the service or the proxy instance is bound to
+    pub(crate) fn bound(addrs: Option<&model::Addresses>) -> SocketAddr {
+        addrs.unwrap().to_vec()[0]
+    }
+
+    /// Service listening for HTTP on `addr`, forwarding to a closed port
+    pub(crate) fn create_service(name: &str, path: &str, addr: SocketAddr) -> model::CreateService {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "serverName": ["localhost"],
+            "bindHttp": addr.to_string(),
+            "from": path,
+            "to": "http://127.0.0.1:1",
+            "cpuThreads": 1,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn usage_limits() {
+        let first = "127.0.0.1:10000".parse().unwrap();
+        let second = "127.0.0.1:10001".parse().unwrap();
+
+        let usage = Usage::new(Some(UserLimits {
+            max_requests: Some(2),
+            single_session: false,
+            max_bytes: None,
+        }));
+        assert!(matches!(usage.acquire(first), Grant::Allow));
+        assert!(matches!(
+            usage.acquire(second),
+            Grant::Last(RevokeReason::RequestLimit)
+        ));
+        assert!(matches!(
+            usage.acquire(first),
+            Grant::Deny(RevokeReason::RequestLimit)
+        ));
+        let user = ProxyUser {
+            created_at: Utc::now(),
+            username: "user".to_string(),
+            fingerprint: Default::default(),
+            access: Access::ApiKey(Default::default()),
+            usage: Arc::new(usage),
+        };
+        let status = UserLimitsStatus::from(&user);
+        assert_eq!(status.requests, 3);
+        assert_eq!(status.remaining_requests, Some(0));
+        assert!(!status.session_bound);
+
+        let usage = Usage::new(Some(UserLimits {
+            max_requests: None,
+            single_session: true,
+            max_bytes: None,
+        }));
+        assert!(matches!(usage.acquire(first), Grant::Allow));
+        assert!(matches!(usage.acquire(second), Grant::Allow));
+        let other = "127.0.0.2:10000".parse().unwrap();
+        assert!(matches!(usage.acquire(other), Grant::Foreign));
+        assert!(matches!(usage.acquire(first), Grant::Allow));
+
+        let usage = Usage::new(Some(UserLimits {
+            max_requests: None,
+            single_session: false,
+            max_bytes: Some(100),
+        }));
+        assert!(matches!(usage.acquire(first), Grant::Allow));
+        assert!(usage.add_bytes(60));
+        // the transfer exceeding the quota is aborted
+        assert!(!usage.add_bytes(90));
+        assert!(matches!(
+            usage.acquire(first),
+            Grant::Deny(RevokeReason::ByteLimit)
+        ));
+        let user = ProxyUser {
+            created_at: Utc::now(),
+            username: "user".to_string(),
+            fingerprint: Default::default(),
+            access: Access::ApiKey(Default::default()),
+            usage: Arc::new(usage),
+        };
+        let status = UserLimitsStatus::from(&user);
+        assert_eq!(status.requests, 1);
+        assert_eq!(status.bytes, 150);
+        assert_eq!(status.remaining_bytes, Some(0));
+    }
+
+    #[test]
+    fn usage_allowance() {
+        let address = "127.0.0.1:10000".parse().unwrap();
+        let usage = Usage::new(Some(UserLimits {
+            max_requests: Some(3),
+            single_session: false,
+            max_bytes: None,
+        }));
+        usage.set_allowance(Some(UserAllowance {
+            max_requests: Some(1),
+            max_bytes: None,
+        }));
+        assert!(matches!(usage.acquire(address), Grant::Allow));
+        // throttled requests are not counted
+        assert!(matches!(usage.acquire(address), Grant::Throttle));
+        assert!(matches!(usage.acquire(address), Grant::Throttle));
+
+        usage.set_allowance(Some(UserAllowance {
+            max_requests: None,
+            max_bytes: Some(100),
+        }));
+        assert!(matches!(usage.acquire(address), Grant::Allow));
+        assert!(usage.add_bytes(100));
+        assert!(matches!(usage.acquire(address), Grant::Throttle));
+
+        usage.set_allowance(None);
+        assert!(matches!(
+            usage.acquire(address),
+            Grant::Last(RevokeReason::RequestLimit)
+        ));
+
+        // replacement credentials are measured against the same allowance
+        usage.set_allowance(Some(UserAllowance {
+            max_requests: Some(4),
+            max_bytes: None,
+        }));
+        let replaced = Usage::replacing(None, &usage);
+        assert!(matches!(replaced.acquire(address), Grant::Allow));
+        assert!(matches!(replaced.acquire(address), Grant::Throttle));
+        assert!(matches!(usage.acquire(address), Grant::Throttle));
+        replaced.set_allowance(None);
+        assert!(matches!(
+            usage.acquire(address),
+            Grant::Deny(RevokeReason::RequestLimit)
+        ));
+    }
+
+    #[test]
+    fn usage_allowance_concurrent() {
+        let address = "127.0.0.1:10000".parse().unwrap();
+        let usage = Arc::new(Usage::new(None));
+        usage.set_allowance(Some(UserAllowance {
+            max_requests: Some(10),
+            max_bytes: None,
+        }));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let usage = usage.clone();
+                std::thread::spawn(move || {
+                    (0..100)
+                        .filter(|_| matches!(usage.acquire(address), Grant::Allow))
+                        .count()
+                })
+            })
+            .collect();
+        let allowed: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(allowed, 10);
+    }
+
+    #[test]
+    fn credential_fingerprint() {
+        let credentials = Credentials::Bearer {
+            token: "token".into(),
+        };
+        let key = FingerprintKey::default();
+        let access = Access::new("user", &credentials).unwrap();
+        let fingerprint = access.fingerprint(&key);
+
+        assert_eq!(fingerprint.len(), 16);
+        assert!(!fingerprint.contains("token"));
+        assert_eq!(fingerprint, Access::bearer("token").fingerprint(&key));
+        assert_ne!(fingerprint, Access::bearer("other").fingerprint(&key));
+        assert_ne!(
+            fingerprint,
+            Access::ApiKey(super::digest("token")).fingerprint(&key)
+        );
+        // fingerprints depend on the proxy secret
+        assert_ne!(fingerprint, access.fingerprint(&FingerprintKey::default()));
+
+        assert_eq!(
+            Access::bearer("token").fingerprint(&FingerprintKey([0; 32])),
+            "ecf882401463c96a"
+        );
+    }
+
+    #[test]
+    fn hourly_stats() {
+        let mut stats = ProxyStats::new(2);
+        let start = Utc.timestamp_opt(1_650_000_000 / 3600 * 3600, 0).unwrap();
+        let hour = |h: i64| start + Duration::hours(h);
+
+        stats.inc_at(hour(0), "svc", "/a", "user");
+        stats.add_bytes_at(hour(0), "svc", "/a", "user", 10);
+        stats.inc_at(hour(1), "svc", "/a", "user");
+        stats.inc_at(hour(1), "svc", "/b", "other");
+        stats.inc_at(hour(2), "svc", "/b", "user");
+        stats.add_bytes_at(hour(2), "svc", "/b", "user", 20);
+        stats.add_bytes_at(hour(2), "svc", "/b", "user", 5);
+
+        // the first hour is past the retention period
+        assert_eq!(stats.total, 4);
+        assert_eq!(stats.user["user"], 3);
+        let endpoints = stats.user_endpoint("user").unwrap();
+        assert_eq!(endpoints.get("/a"), Some(&1));
+        assert_eq!(endpoints.get("/b"), Some(&1));
+        assert!(stats.user_endpoint("unknown").is_none());
+        assert_eq!(stats.user_bytes["user"], 35);
+        assert_eq!(stats.service_bytes["svc"], 35);
+        let bytes = stats.user_endpoint_bytes("user").unwrap();
+        assert_eq!(bytes.get("/a"), None);
+        assert_eq!(bytes.get("/b"), Some(&25));
+
+        let users = stats.service_users("svc", Some(hour(1)), Some(hour(2)));
+        assert_eq!(users.get("user"), Some(&1));
+        assert_eq!(users.get("other"), Some(&1));
+        assert_eq!(stats.service_users("svc", None, None)["user"], 2);
+
+        stats.reset_user("user");
+        assert!(stats.user_endpoint("user").unwrap().is_empty());
+        assert!(stats.user_endpoint_bytes("user").unwrap().is_empty());
+        assert!(!stats.user_bytes.contains_key("user"));
+    }
+
+    #[test]
+    fn shutdown_nonce() {
+        let nonce = ProxyManager::new(ProxyConf::default())
+            .shutdown_nonce()
+            .to_string();
+        assert_eq!(nonce.len(), 32);
+        assert_ne!(nonce, model::SHUTDOWN_CONFIRMATION);
+
+        let restarted = ProxyManager::new(ProxyConf::default());
+        assert_ne!(restarted.shutdown_nonce(), nonce);
+    }
+
+    /// Configurations setting the deprecated `http1_only` accept HTTP/1.x in services
+    /// which do not set accepted protocols
+    #[test]
+    fn http1_only_alias() {
+        let server = serde_json::from_value(serde_json::json!({"http1_only": true})).unwrap();
+        let manager = ProxyManager::new(ProxyConf {
+            server,
+            ..Default::default()
+        });
+        let mut legacy = create_service("svc", "/", any_port());
+        manager.conf_update(&mut legacy).unwrap();
+        assert_eq!(legacy.protocols, Some(model::HttpProtocols::Http1));
+
+        let mut explicit = create_service("svc", "/", any_port());
+        explicit.protocols = Some(model::HttpProtocols::Auto);
+        manager.conf_update(&mut explicit).unwrap();
+        assert_eq!(explicit.protocols, Some(model::HttpProtocols::Auto));
+    }
+
+    /// Usage is aggregated per user from the request journal when enabled,
+    /// from hourly request stats otherwise
+    #[tokio::test]
+    async fn usage_aggregation() {
+        use super::journal::JournalRecord;
+
+        let dir = std::env::temp_dir().join(format!("ya-http-proxy-usage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let manager = manager();
+
+        for (name, journal) in [("journaled", Some(&dir)), ("counted", None)] {
+            let mut create = create_service(name, &format!("/{}", name), any_port());
+            create.journal = journal.map(|path| model::RequestJournal {
+                path: path.clone(),
+                max_file_size: None,
+                max_files: None,
+            });
+            let _: model::Service = manager.create_service(create, None).await.unwrap();
+        }
+
+        let proxy = manager.proxy("journaled").await.unwrap();
+        let journal = {
+            let state = proxy.state.read().await;
+            state
+                .get_service("journaled")
+                .unwrap()
+                .journal
+                .clone()
+                .unwrap()
+        };
+        let start = Utc.timestamp_millis_opt(1_650_000_000_000).unwrap();
+        let requests = [
+            (0, Some("b"), 7),
+            (1, Some("a"), 10),
+            (2, Some("a"), 5),
+            (3, None, 1),
+        ];
+        for (secs, username, bytes) in requests {
+            let received_at = start + Duration::seconds(secs);
+            let mut record = JournalRecord::new(journal.clone(), received_at, "/", username);
+            record.add_bytes(bytes);
+        }
+
+        let usage = |from: Option<i64>, to: Option<i64>| {
+            let range = model::TimeRange {
+                from: from.map(|secs| start + Duration::seconds(secs)),
+                to: to.map(|secs| start + Duration::seconds(secs)),
+            };
+            let proxy = proxy.clone();
+            async move { proxy.get_usage("journaled", range).await.unwrap() }
+        };
+        let summary = |usage: model::ServiceUsage| -> Vec<_> {
+            let users = usage.users.into_iter();
+            users.map(|u| (u.username, u.requests, u.bytes)).collect()
+        };
+
+        // requests of anonymous users are not accounted
+        let all = usage(None, None).await;
+        assert!(all.journaled);
+        assert_eq!(
+            summary(all),
+            vec![
+                ("a".to_string(), 2, Some(15)),
+                ("b".to_string(), 1, Some(7))
+            ]
+        );
+        let later = summary(usage(Some(1), None).await);
+        assert_eq!(later, vec![("a".to_string(), 2, Some(15))]);
+        let earlier = summary(usage(None, Some(1)).await);
+        assert_eq!(earlier, vec![("b".to_string(), 1, Some(7))]);
+
+        let proxy = manager.proxy("counted").await.unwrap();
+        {
+            let mut stats = proxy.stats.write().await;
+            for username in ["b", "a", "b"] {
+                stats.inc("counted", "/counted", username);
+            }
+        }
+        let usage = proxy
+            .get_usage("counted", Default::default())
+            .await
+            .unwrap();
+        assert!(!usage.journaled);
+        assert_eq!(
+            summary(usage),
+            vec![("a".to_string(), 1, None), ("b".to_string(), 2, None)]
+        );
+
+        manager.stop(None).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Users are not added beyond the service limit, neither directly
+    /// nor by rotating credentials of missing users
+    #[tokio::test]
+    async fn user_limit() {
+        use crate::error::{Error, UserError};
+
+        let manager = manager();
+        let mut create = create_service("svc", "/", any_port());
+        create.max_users = Some(2);
+        let _: model::Service = manager.create_service(create, None).await.unwrap();
+        let proxy = manager.proxy("svc").await.unwrap();
+
+        let add = |username: &str| {
+            let proxy = proxy.clone();
+            let user = model::CreateUser::basic(username, "password");
+            async move { proxy.add_user("svc", user, None).await }
+        };
+        let rotate = |username: &str| {
+            let proxy = proxy.clone();
+            let username = username.to_string();
+            async move {
+                let rotate = model::RotateCredentials::default();
+                proxy
+                    .rotate_user("svc", &username, rotate, None, None)
+                    .await
+            }
+        };
+        let limit_reached = |e: Error| matches!(e, Error::User(UserError::LimitReached(2)));
+
+        add("a").await.unwrap();
+        add("b").await.unwrap();
+        assert!(limit_reached(add("c").await.unwrap_err()));
+        assert!(limit_reached(rotate("c").await.unwrap_err()));
+        // existing users are rotated at the limit
+        rotate("a").await.unwrap();
+
+        proxy.remove_user("svc", "b", None, None).await.unwrap();
+        rotate("c").await.unwrap();
+        assert!(limit_reached(add("d").await.unwrap_err()));
+        assert_eq!(proxy.get_users("svc").await.unwrap().len(), 2);
+
+        manager.stop(None).await;
+    }
+
+    /// Server tuning of a service overrides the proxy configuration of the instance
+    /// spawned for it. Services sharing the instance need to request the same tuning
+    #[tokio::test]
+    async fn server_tuning_overrides() {
+        use crate::error::{Error, ProxyError, ServiceError};
+
+        let manager = manager();
+        let tuning = model::ServerTuning {
+            http1_keepalive: Some(false),
+            http2_max_concurrent_streams: Some(10),
+            ..Default::default()
+        };
+
+        let mut first = create_service("svc-a", "/svc-a", any_port());
+        first.server_tuning = Some(tuning.clone());
+        let service: model::Service = manager.create_service(first, None).await.unwrap();
+        let addr = bound(service.inner.bind_http.as_ref());
+
+        let proxy = manager.proxy("svc-a").await.unwrap();
+        let server = &proxy.conf.server;
+        assert_eq!(server.http1_keepalive, Some(false));
+        assert_eq!(server.http2_max_concurrent_streams, Some(10));
+        // settings missing from the tuning are kept
+        let defaults = ProxyConf::default().server.server_common;
+        assert_eq!(
+            server.server_common.http2_adaptive_window,
+            defaults.http2_adaptive_window
+        );
+
+        let mut same = create_service("svc-b", "/svc-b", addr);
+        same.server_tuning = Some(tuning);
+        let _: model::Service = manager.create_service(same, None).await.unwrap();
+        let untuned = create_service("svc-c", "/svc-c", addr);
+        let _: model::Service = manager.create_service(untuned, None).await.unwrap();
+
+        let other = model::ServerTuning {
+            http2_max_concurrent_streams: Some(20),
+            ..Default::default()
+        };
+        let mut different = create_service("svc-d", "/svc-d", addr);
+        different.server_tuning = Some(other.clone());
+        let result = manager
+            .create_service::<model::Service>(different, None)
+            .await;
+        assert!(matches!(result, Err(Error::Proxy(ProxyError::Conf(_)))));
+
+        let mut update = create_service("svc-a", "/svc-a", addr);
+        update.server_tuning = Some(other);
+        let result = manager
+            .update_service::<model::Service>("svc-a", update, None, None)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::Service(ServiceError::Immutable { .. }))
+        ));
+
+        manager.stop(None).await;
+    }
+
+    /// Concurrent Management API requests. Service creation takes several locks,
+    /// so each scenario is bounded by a timeout
+    mod races {
+        use std::net::SocketAddr;
+        use std::sync::atomic::Ordering;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use futures::future::join_all;
+
+        use super::{any_port, bound, create_service, manager};
+        use crate::error::{Error, ManagementError, ProxyError, ServiceError, UserError};
+        use crate::proxy::{NameReservation, Proxy, ProxyManager};
+        use ya_http_proxy_model as model;
+
+        const TIMEOUT: Duration = Duration::from_secs(30);
+
+        async fn create_all(
+            manager: &ProxyManager,
+            creates: Vec<model::CreateService>,
+        ) -> Vec<Result<model::Service, Error>> {
+            let tasks = creates.into_iter().map(|create| {
+                let manager = manager.clone();
+                tokio::spawn(async move { manager.create_service(create, None).await })
+            });
+            let results = tokio::time::timeout(TIMEOUT, join_all(tasks))
+                .await
+                .expect("service creation deadlocked");
+            results.into_iter().map(Result::unwrap).collect()
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn concurrent_service_creation() {
+            let manager = manager();
+            let create = create_service("svc-0", "/svc-0", any_port());
+            let service: model::Service = manager.create_service(create, None).await.unwrap();
+            let shared = bound(service.inner.bind_http.as_ref());
+            assert_ne!(shared.port(), 0);
+
+            // distinct services sharing a proxy instance
+            let creates = (1..8)
+                .map(|i| create_service(&format!("svc-{}", i), &format!("/svc-{}", i), shared))
+                .collect();
+            let results = create_all(&manager, creates).await;
+            assert!(
+                results.iter().all(Result::is_ok),
+                "{:?}",
+                results.iter().find(|r| r.is_err())
+            );
+            assert_eq!(manager.proxies.read().await.len(), 1);
+
+            // the same service, on the shared and on separate addresses
+            let creates = (0..8)
+                .map(|i| {
+                    let addr = if i % 2 == 0 { shared } else { any_port() };
+                    create_service("same", "/same", addr)
+                })
+                .collect();
+            let results = create_all(&manager, creates).await;
+            assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+            assert!(results
+                .iter()
+                .filter_map(|r| r.as_ref().err())
+                .all(|e| matches!(e, Error::Service(ServiceError::AlreadyExists { .. }))));
+
+            let mut names = Vec::new();
+            for proxy in manager.proxies.read().await.values() {
+                names.extend(proxy.state.read().await.by_name.keys().cloned());
+            }
+            names.sort();
+            let mut expected: Vec<_> = (0..8).map(|i| format!("svc-{}", i)).collect();
+            expected.push("same".to_string());
+            expected.sort();
+            assert_eq!(names, expected);
+
+            manager.stop(Some(TIMEOUT)).await;
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn concurrent_spawn() {
+            let manager = manager();
+            let get_or_spawn = |addrs: Vec<SocketAddr>| {
+                let tasks = addrs.into_iter().enumerate().map(|(i, addr)| {
+                    let manager = manager.clone();
+                    let mut create = create_service(&format!("svc-{}", i), "/", addr);
+                    tokio::spawn(async move { manager.get_or_spawn(&mut create).await })
+                });
+                async move {
+                    let results = tokio::time::timeout(TIMEOUT, join_all(tasks))
+                        .await
+                        .expect("proxy spawn deadlocked");
+                    let proxies = results.into_iter().map(|r| r.unwrap().unwrap());
+                    proxies.collect::<Vec<_>>()
+                }
+            };
+
+            // requests for port 0 wait for one another, each spawning an instance
+            let spawned = get_or_spawn(vec![any_port(); 4]).await;
+            let addrs: Vec<_> = spawned
+                .iter()
+                .map(|proxy| bound(proxy.conf.server.bind_http.as_ref()))
+                .collect();
+            assert_eq!(manager.proxies.read().await.len(), 4);
+            assert!(manager.spawning.lock().unwrap().is_empty());
+
+            // a single instance per addresses, returned to every request
+            let proxies = get_or_spawn((0..16).map(|i| addrs[i % 4]).collect()).await;
+            assert_eq!(manager.proxies.read().await.len(), 4);
+            for (i, proxy) in proxies.iter().enumerate() {
+                assert!(Arc::ptr_eq(&proxy.state, &spawned[i % 4].state));
+            }
+            assert!(manager.spawning.lock().unwrap().is_empty());
+
+            manager.stop(Some(TIMEOUT)).await;
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn concurrent_user_creation() {
+            let manager = manager();
+            let create = create_service("svc", "/", any_port());
+            let _: model::Service = manager.create_service(create, None).await.unwrap();
+            let proxy = manager.proxy("svc").await.unwrap();
+
+            let tasks = (0..32).map(|i| {
+                let proxy = proxy.clone();
+                let username = match i % 2 {
+                    0 => "same".to_string(),
+                    _ => format!("user-{}", i),
+                };
+                let create = model::CreateUser::basic(&username, &format!("password-{}", i));
+                tokio::spawn(async move { proxy.add_user("svc", create, None).await })
+            });
+            let results: Vec<_> = tokio::time::timeout(TIMEOUT, join_all(tasks))
+                .await
+                .expect("user creation deadlocked")
+                .into_iter()
+                .map(Result::unwrap)
+                .collect();
+
+            let (created, failed): (Vec<_>, Vec<_>) = results.into_iter().partition(Result::is_ok);
+            assert_eq!(created.len(), 17);
+            assert!(failed.iter().all(|r| matches!(
+                r,
+                Err(Error::User(UserError::AlreadyExists(name))) if name == "same"
+            )));
+            assert_eq!(proxy.get_users("svc").await.unwrap().len(), 17);
+
+            manager.stop(Some(TIMEOUT)).await;
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn service_creation_while_draining() {
+            let manager = manager();
+            let create = create_service("svc", "/", any_port());
+            let _: model::Service = manager.create_service(create, None).await.unwrap();
+
+            let creates = (0..8)
+                .map(|i| create_service(&format!("svc-{}", i), "/", any_port()))
+                .collect();
+            let drain = {
+                let manager = manager.clone();
+                tokio::spawn(async move { manager.drain(Some(TIMEOUT)).await })
+            };
+            let results = create_all(&manager, creates).await;
+            tokio::time::timeout(TIMEOUT, drain)
+                .await
+                .expect("drain deadlocked")
+                .unwrap();
+
+            assert!(results.iter().all(|r| match r {
+                Ok(_) => true,
+                Err(Error::Management(ManagementError::Draining)) => true,
+                Err(e) => panic!("unexpected error: {}", e),
+            }));
+            // instances spawned while draining are stopped as well
+            assert!(manager.proxies.read().await.is_empty());
+            assert!(manager.is_draining());
+            tokio::time::timeout(TIMEOUT, manager.stopped())
+                .await
+                .expect("manager not stopped");
+
+            let create = create_service("late", "/", any_port());
+            let result = manager.create_service::<model::Service>(create, None).await;
+            assert!(matches!(
+                result,
+                Err(Error::Management(ManagementError::Draining))
+            ));
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn virtual_hosts() {
+            let manager = manager();
+            let virtual_host = |name: &str, addr: SocketAddr, server_name: &[&str]| {
+                let mut create = create_service(name, "/", addr);
+                create.server_name = server_name.iter().map(|s| s.to_string()).collect();
+                create
+            };
+
+            let create = virtual_host("a", any_port(), &["a.example.com", "127.0.0.1"]);
+            let service: model::Service = manager.create_service(create, None).await.unwrap();
+            let addr = bound(service.inner.bind_http.as_ref());
+            let create = virtual_host("b", addr, &["B.example.com"]);
+            let result = manager.create_service::<model::Service>(create, None).await;
+            assert!(result.is_ok(), "{:?}", result);
+
+            let create = virtual_host("c", addr, &["b.example.com", "c.example.com"]);
+            let result = manager.create_service::<model::Service>(create, None).await;
+            assert!(matches!(
+                result,
+                Err(Error::Service(ServiceError::Conflict { .. }))
+            ));
+
+            let proxy = manager
+                .running(&create_service("", "/", addr).addresses())
+                .await;
+            let state = proxy.as_ref().unwrap().state.read().await;
+            let routed = |host: Option<&str>| {
+                let (_, service) = state.route(host, "/index.html")?;
+                Some(service.created_with.name.clone())
+            };
+            assert_eq!(routed(Some("a.example.com")).as_deref(), Some("a"));
+            assert_eq!(routed(Some("127.0.0.1")).as_deref(), Some("a"));
+            assert_eq!(routed(Some("b.example.com")).as_deref(), Some("b"));
+            // ambiguous without a matching host
+            assert_eq!(routed(Some("localhost")), None);
+            assert_eq!(routed(None), None);
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn update_service() {
+            let manager = manager();
+            let create = create_service("a", "/a", any_port());
+            let service: model::Service = manager.create_service(create, None).await.unwrap();
+            let addr = bound(service.inner.bind_http.as_ref());
+            let create = create_service("b", "/b", addr);
+            let result = manager.create_service::<model::Service>(create, None).await;
+            assert!(result.is_ok(), "{:?}", result);
+
+            let proxy = manager.proxy("a").await.unwrap();
+            let user = model::CreateUser::basic("user", "password");
+            proxy.add_user("a", user, None).await.unwrap();
+
+            let immutable = |result: Result<model::Service, Error>| {
+                matches!(result, Err(Error::Service(ServiceError::Immutable { .. })))
+            };
+            let update = create_service("b", "/c", addr);
+            assert!(immutable(
+                manager.update_service("a", update, None, None).await
+            ));
+            let update = create_service("a", "/c", any_port());
+            assert!(immutable(
+                manager.update_service("a", update, None, None).await
+            ));
+
+            let update = create_service("a", "/b", addr);
+            let result = manager.update_service::<model::Service>("a", update, None, None);
+            assert!(matches!(
+                result.await,
+                Err(Error::Service(ServiceError::Conflict { .. }))
+            ));
+
+            let mut update = create_service("a", "/c", addr);
+            update.to = "http://127.0.0.1:2".parse().unwrap();
+            let result = manager.update_service::<model::Service>(
+                "a",
+                update.clone(),
+                Some("\"stale\""),
+                None,
+            );
+            assert!(matches!(
+                result.await,
+                Err(Error::Management(ManagementError::PreconditionFailed))
+            ));
+            let service: model::Service = manager
+                .update_service("a", update, None, None)
+                .await
+                .unwrap();
+            assert_eq!(service.inner.to, "http://127.0.0.1:2");
+            assert_eq!(service.user_count, Some(1));
+
+            let state = proxy.state.read().await;
+            let (_, routed) = state.route(None, "/c/index.html").unwrap();
+            assert_eq!(routed.created_with.name, "a");
+            assert!(state.route(None, "/a/index.html").is_none());
+            drop(state);
+
+            manager.stop(Some(TIMEOUT)).await;
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn isolation() {
+            let manager = manager();
+            let create = create_service("a", "/a", any_port());
+            let service: model::Service = manager.create_service(create, None).await.unwrap();
+            let shared = bound(service.inner.bind_http.as_ref());
+            let mut limited = create_service("b", "/b", shared);
+            limited.cpu_threads = Some(1);
+            let creates = vec![limited, create_service("c", "/", any_port())];
+            let results = create_all(&manager, creates).await;
+            assert!(results.iter().all(Result::is_ok), "{:?}", results);
+
+            let report = manager.isolation("a").await.unwrap();
+            assert_eq!(report.listener, vec!["b".to_string()]);
+            assert_eq!(report.runtime_threads, vec!["b".to_string()]);
+            assert!(report.client_pool.is_empty());
+            assert!(!report.isolated);
+            assert_eq!(manager.isolation("b").await.unwrap().cpu_threads, Some(1));
+
+            let report = manager.isolation("c").await.unwrap();
+            assert!(report.listener.is_empty());
+            assert!(report.isolated);
+
+            // resources recorded for the service are compared, not the listening addresses
+            let (runtime, client) = {
+                let proxy = manager.proxy("a").await.unwrap();
+                let state = proxy.state.read().await;
+                let service = state.get_service("a").unwrap();
+                (service.runtime.clone(), service.client.clone())
+            };
+            {
+                let proxy = manager.proxy("c").await.unwrap();
+                let mut state = proxy.state.write().await;
+                let service = state.get_service_mut("c").unwrap();
+                service.runtime = runtime;
+                service.client = client;
+            }
+            let report = manager.isolation("c").await.unwrap();
+            assert!(report.listener.is_empty());
+            assert_eq!(
+                report.runtime_threads,
+                vec!["a".to_string(), "b".to_string()]
+            );
+            assert_eq!(report.client_pool, vec!["a".to_string()]);
+            assert!(!report.isolated);
+            let report = manager.isolation("a").await.unwrap();
+            assert_eq!(
+                report.runtime_threads,
+                vec!["b".to_string(), "c".to_string()]
+            );
+            assert_eq!(report.client_pool, vec!["c".to_string()]);
+            assert!(matches!(
+                manager.isolation("missing").await,
+                Err(Error::Service(ServiceError::NotFound(_)))
+            ));
+
+            manager.stop(Some(TIMEOUT)).await;
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn replace_cert() {
+            use std::path::{Path, PathBuf};
+
+            let manager = manager();
+            let resources = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/resources");
+            let dir = std::env::temp_dir()
+                .join(format!("ya-http-proxy-replace-cert-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            for name in ["server.cert", "server.key"] {
+                std::fs::copy(resources.join(name), dir.join(name)).unwrap();
+            }
+            let https = |name: &str, addr: SocketAddr, dir: &Path| {
+                let mut create = create_service(name, &format!("/{}", name), addr);
+                create.bind_http = None;
+                create.bind_https = Some(addr.into());
+                create.cert = Some(model::CreateServiceCert {
+                    hash: Default::default(),
+                    path: dir.join("server.cert"),
+                    key_path: dir.join("server.key"),
+                    acme: false,
+                });
+                create
+            };
+            let served = |proxy: &Proxy| -> Option<PathBuf> {
+                proxy.server_cert_conf().server_cert_store_path
+            };
+
+            let create = https("a", any_port(), &resources);
+            let service: model::Service = manager.create_service(create, None).await.unwrap();
+            let addr = bound(service.inner.bind_https.as_ref());
+            let proxy = manager.proxy("a").await.unwrap();
+
+            // the certificate is replaced only once the service is updated
+            let replace = Some((dir.join("server.cert"), dir.join("server.key")));
+            let update = https("a", addr, &dir);
+            let result = proxy.update::<model::Service>(update, replace, Some("\"stale\""), None);
+            assert!(matches!(
+                result.await,
+                Err(Error::Management(ManagementError::PreconditionFailed))
+            ));
+            assert_eq!(served(&proxy), Some(resources.join("server.cert")));
+
+            let update = https("a", addr, &dir);
+            let result = manager.update_service::<model::Service>("a", update, None, None);
+            assert!(result.await.is_ok());
+            // seen by all clones of the proxy instance
+            assert_eq!(served(&proxy), Some(dir.join("server.cert")));
+            let running = manager.proxy("a").await.unwrap();
+            assert_eq!(served(&running), Some(dir.join("server.cert")));
+
+            let create = https("b", addr, &resources);
+            let result = manager.create_service::<model::Service>(create, None).await;
+            assert!(matches!(result, Err(Error::Proxy(ProxyError::Conf(_)))));
+            let create = https("b", addr, &dir);
+            let result = manager.create_service::<model::Service>(create, None).await;
+            assert!(result.is_ok(), "{:?}", result);
+
+            manager.stop(Some(TIMEOUT)).await;
+            let _ = std::fs::remove_dir_all(dir);
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn concurrent_updates() {
+            let manager = manager();
+            let create = create_service("svc", "/", any_port());
+            let _: model::Service = manager.create_service(create, None).await.unwrap();
+
+            // the service is reserved while it is updated
+            let _name =
+                NameReservation::new(&manager.creating, &create_service("svc", "/", any_port()))
+                    .unwrap();
+            let update = create_service("svc", "/", any_port());
+            let result = manager.update_service::<model::Service>("svc", update, None, None);
+            assert!(matches!(
+                result.await,
+                Err(Error::Service(ServiceError::Immutable { .. }))
+            ));
+
+            manager.stop(Some(TIMEOUT)).await;
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn update_user() {
+            let manager = manager();
+            let create = create_service("svc", "/", any_port());
+            let _: model::Service = manager.create_service(create, None).await.unwrap();
+            let proxy = manager.proxy("svc").await.unwrap();
+            for create in [
+                model::CreateUser::basic("user", "password"),
+                model::CreateUser::api_key("other", "taken"),
+            ] {
+                proxy.add_user("svc", create, None).await.unwrap();
+            }
+            proxy.stats.write().await.inc("svc", "/", "user");
+            let previous = proxy.get_user("svc", "user").await.unwrap();
+            previous.usage.requests.store(3, Ordering::SeqCst);
+            previous.usage.bytes.store(100, Ordering::SeqCst);
+            let mut events = manager.events();
+
+            let update = model::UpdateUser::api_key("taken");
+            let result = proxy.update_user("svc", "user", update, None, None);
+            assert!(matches!(
+                result.await,
+                Err(Error::User(UserError::DuplicateCredentials))
+            ));
+            let update = model::UpdateUser::api_key("key");
+            let result = proxy.update_user("svc", "user", update.clone(), Some("\"stale\""), None);
+            assert!(matches!(
+                result.await,
+                Err(Error::Management(ManagementError::PreconditionFailed))
+            ));
+            let result = proxy.update_user("svc", "missing", update.clone(), None, None);
+            assert!(matches!(
+                result.await,
+                Err(Error::User(UserError::NotFound(_)))
+            ));
+
+            let user = proxy
+                .update_user("svc", "user", update, None, None)
+                .await
+                .unwrap();
+            assert_eq!(user.access.method(), model::AuthMethod::ApiKey);
+            assert_eq!(proxy.stats.read().await.user.get("user"), Some(&1));
+            // the usage is not reset with the credentials
+            assert_eq!(user.usage.requests.load(Ordering::SeqCst), 3);
+            assert_eq!(user.usage.bytes.load(Ordering::SeqCst), 100);
+            assert_eq!(
+                events.recv().await.unwrap().event,
+                model::Event::UserUpdated {
+                    service: "svc".to_string(),
+                    username: "user".to_string(),
+                }
+            );
+
+            let state = proxy.state.read().await;
+            let service = state.get_service("svc").unwrap();
+            assert_eq!(service.access.len(), 2);
+            assert_eq!(
+                service.access.get(&user.access).map(String::as_str),
+                Some("user")
+            );
+            drop(state);
+
+            manager.stop(Some(TIMEOUT)).await;
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! Access log of requests received by all proxy instances, written as JSON lines
+//! or in the combined log format
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use hyper::header::{HeaderName, REFERER, USER_AGENT};
+use hyper::{Body, Request, StatusCode};
+use serde::Serialize;
+
+use crate::conf::{AccessLogConf, AccessLogFormat};
+use crate::proxy::rotation::RotatedFile;
+
+/// Number of log lines waiting to be written, after which new entries are dropped
+const QUEUE_LEN: usize = 4096;
+
+/// Access log entry. Query strings are omitted, as they may carry credentials
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    time: DateTime<Utc>,
+    service: Option<String>,
+    remote_ip: IpAddr,
+    method: String,
+    path: String,
+    protocol: String,
+    status: Option<u16>,
+    bytes: u64,
+    username: Option<String>,
+    latency_ms: u64,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    fn new(req: &Request<Body>, address: SocketAddr) -> Self {
+        let header = |name: HeaderName| {
+            let value = req.headers().get(name)?;
+            Some(String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+        Self {
+            time: Utc::now(),
+            service: None,
+            remote_ip: address.ip(),
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            protocol: format!("{:?}", req.version()),
+            status: None,
+            bytes: 0,
+            username: None,
+            latency_ms: 0,
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+        }
+    }
+
+    fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+            AccessLogFormat::Combined => self.combined(),
+        }
+    }
+
+    /// `host ident user [time] "request" status bytes "referer" "user-agent" latency`
+    fn combined(&self) -> String {
+        let quoted = |value: &Option<String>| match value {
+            Some(value) => escape(value),
+            None => "-".to_string(),
+        };
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {}",
+            self.remote_ip,
+            quoted(&self.username),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&self.method),
+            escape(&self.path),
+            self.protocol,
+            self.status
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            match self.bytes {
+                0 => "-".to_string(),
+                bytes => bytes.to_string(),
+            },
+            quoted(&self.referer),
+            quoted(&self.user_agent),
+            self.latency_ms,
+        )
+    }
+}
+
+/// Escapes quotes, backslashes, whitespace and control characters
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' ' => escaped.push_str("\\x20"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Access log file, rotated by size. Entries are written by a dedicated thread,
+/// which writes the pending ones when the log is dropped
+#[derive(Debug)]
+pub(crate) struct AccessLog {
+    path: PathBuf,
+    format: AccessLogFormat,
+    lines: Option<SyncSender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AccessLog {
+    pub fn open(path: &Path, conf: &AccessLogConf) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file =
+            RotatedFile::open(path.to_path_buf(), b"", conf.max_file_size, conf.max_files)?;
+
+        let (tx, rx) = mpsc::sync_channel::<String>(QUEUE_LEN);
+        let display = path.display().to_string();
+        let writer = std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                for line in rx {
+                    if let Err(e) = file.write(line.as_bytes()) {
+                        log::warn!("Unable to write the access log '{}': {}", display, e);
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            format: conf.format,
+            lines: Some(tx),
+            writer: Some(writer),
+        })
+    }
+
+    fn write(&self, entry: &Entry) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+
+        let lines = match self.lines {
+            Some(ref lines) => lines,
+            None => return,
+        };
+        match lines.try_send(line) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => log::warn!(
+                "Access log '{}' is not written in time, entry dropped",
+                self.path.display()
+            ),
+            Err(TrySendError::Disconnected(_)) => log::warn!(
+                "Access log '{}' writer has stopped, entry dropped",
+                self.path.display()
+            ),
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // the writer stops once the queue is closed and emptied
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Access log entry of a request, written when dropped
+pub(crate) struct AccessRecord {
+    log: Arc<AccessLog>,
+    received: Instant,
+    entry: Entry,
+}
+
+impl AccessRecord {
+    pub fn new(log: Arc<AccessLog>, req: &Request<Body>, address: SocketAddr) -> Self {
+        Self {
+            log,
+            received: Instant::now(),
+            entry: Entry::new(req, address),
+        }
+    }
+
+    pub fn set_service(&mut self, service: &str) {
+        self.entry.service = Some(service.to_string());
+    }
+
+    pub fn set_username(&mut self, username: Option<&str>) {
+        self.entry.username = username.map(ToString::to_string);
+    }
+
+    pub fn set_status(&mut self, status: StatusCode) {
+        self.entry.status = Some(status.as_u16());
+    }
+
+    pub fn add_bytes(&mut self, bytes: usize) {
+        self.entry.bytes += bytes as u64;
+    }
+}
+
+impl Drop for AccessRecord {
+    fn drop(&mut self) {
+        self.entry.latency_ms = self.received.elapsed().as_millis() as u64;
+        self.log.write(&self.entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{TimeZone, Utc};
+    use hyper::{Body, Request, StatusCode};
+
+    use super::{AccessLog, AccessRecord, Entry};
+    use crate::conf::{AccessLogConf, AccessLogFormat};
+    use crate::proxy::rotation::rotated_path;
+
+    fn request() -> Request<Body> {
+        Request::get("/api/some%20thing?key=secret")
+            .header("user-agent", "curl/7.1 \"x\"")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn entry_formats() {
+        let mut entry = Entry::new(&request(), "10.0.0.1:4000".parse().unwrap());
+        entry.time = Utc.timestamp_opt(1_650_000_000, 0).unwrap();
+        entry.service = Some("svc".to_string());
+        entry.username = Some("alice".to_string());
+        entry.status = Some(200);
+        entry.bytes = 512;
+        entry.latency_ms = 12;
+
+        assert_eq!(
+            entry.format(AccessLogFormat::Combined),
+            r#"10.0.0.1 - alice [15/Apr/2022:05:20:00 +0000] "GET /api/some%20thing HTTP/1.1" 200 512 "-" "curl/7.1\x20\"x\"" 12"#
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&entry.format(AccessLogFormat::Json)).unwrap();
+        assert_eq!(json["remoteIp"], "10.0.0.1");
+        assert_eq!(json["path"], "/api/some%20thing");
+        assert_eq!(json["username"], "alice");
+        assert_eq!(json["latencyMs"], 12);
+
+        entry.status = None;
+        entry.bytes = 0;
+        entry.username = None;
+        assert!(entry.format(AccessLogFormat::Combined).contains(
+            r#" - - [15/Apr/2022:05:20:00 +0000] "GET /api/some%20thing HTTP/1.1" - - "#
+        ));
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = std::env::temp_dir().join(format!("ya-http-proxy-access-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("access.log");
+        let conf = AccessLogConf {
+            max_file_size: 300,
+            max_files: 2,
+            ..Default::default()
+        };
+        let log = Arc::new(AccessLog::open(&path, &conf).unwrap());
+
+        for _ in 0..10 {
+            let mut record =
+                AccessRecord::new(log.clone(), &request(), "[::1]:80".parse().unwrap());
+            record.set_status(StatusCode::OK);
+        }
+        // pending entries are written when the log is closed
+        drop(log);
+        let lines = |i: usize| {
+            std::fs::read_to_string(rotated_path(&path, i))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        assert!(lines(0) > 0);
+        assert!(lines(1) > 0);
+        assert!(lines(2) > 0);
+        assert!(!rotated_path(&path, 3).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
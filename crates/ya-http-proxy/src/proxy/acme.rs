@@ -0,0 +1,932 @@
+//! Certificates obtained and renewed via the ACME protocol (RFC 8555)
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use futures::future::Either;
+use hyper::body::Bytes;
+use hyper::client::HttpConnector;
+use hyper::header::{self, HeaderMap};
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::sign::CertifiedKey;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::conf::{AcmeChallenge, AcmeConf, ClientConf};
+use crate::error::{AcmeError, ProxyError};
+use crate::proxy::challenge::Challenges;
+use crate::proxy::{client, Proxy};
+
+const ACCOUNT_KEY_FILE: &str = "account.key";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+const DEFAULT_RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+/// Delay between certificate expiry checks
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+/// Initial delay before retrying a failed renewal, doubled on consecutive failures
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 60;
+const JOSE_CONTENT_TYPE: &str = "application/jose+json";
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+/// Paths of the certificate and key obtained for the server name domains. Until the first
+/// certificate is issued, a placeholder is written in their place
+pub(crate) fn cert_paths(
+    conf: &AcmeConf,
+    server_name: &[String],
+) -> Result<(PathBuf, PathBuf), ProxyError> {
+    let storage_dir = match conf.storage_dir {
+        Some(ref dir) => dir,
+        None => return Err(ProxyError::Conf("ACME storage directory is not set".into())),
+    };
+    let domains = domains(server_name);
+    let dir = match domains.first() {
+        Some(domain) => storage_dir.join(domain),
+        None => {
+            return Err(ProxyError::Conf(
+                "ACME certificates require a domain server name".into(),
+            ))
+        }
+    };
+
+    let cert_path = dir.join(CERT_FILE);
+    let key_path = dir.join(KEY_FILE);
+    if !cert_path.exists() || !key_path.exists() {
+        write_placeholder(&dir, &domains).map_err(|e| ProxyError::conf(&dir, e))?;
+    }
+    Ok((cert_path, key_path))
+}
+
+/// Keeps the certificate of the proxy instance renewed, until the instance stops.
+/// Certificates stored in the same location are renewed by a single task
+pub(crate) fn watch(proxy: Proxy, renewing: Arc<Mutex<HashSet<PathBuf>>>) {
+    let cert_path = match proxy.conf.server.server_cert.server_cert_store_path {
+        Some(ref path) => path.clone(),
+        None => return,
+    };
+    if !renewing.lock().unwrap().insert(cert_path.clone()) {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let stopped = proxy.stopped();
+        futures::pin_mut!(stopped);
+        let mut retry = RETRY_INTERVAL;
+
+        loop {
+            let delay = match renew(&proxy, &cert_path).await {
+                Ok(_) => {
+                    retry = RETRY_INTERVAL;
+                    CHECK_INTERVAL
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Unable to renew the ACME certificate of proxy on {}: {}",
+                        proxy.conf.server.addresses(),
+                        e
+                    );
+                    let delay = retry;
+                    retry = (retry * 2).min(CHECK_INTERVAL);
+                    delay
+                }
+            };
+
+            let tick = tokio::time::sleep(delay);
+            futures::pin_mut!(tick);
+            if let Either::Right(_) = futures::future::select(tick, &mut stopped).await {
+                break;
+            }
+        }
+        renewing.lock().unwrap().remove(&cert_path);
+    });
+}
+
+/// Issues a new certificate when the current one is about to expire
+async fn renew(proxy: &Proxy, cert_path: &Path) -> Result<(), AcmeError> {
+    let conf = &proxy.conf.acme;
+    let renew_before = conf.renew_before.unwrap_or(DEFAULT_RENEW_BEFORE);
+    let renew_before =
+        chrono::Duration::from_std(renew_before).unwrap_or_else(|_| chrono::Duration::zero());
+    if let Some(not_after) = not_after(cert_path) {
+        if not_after - renew_before > Utc::now() {
+            return Ok(());
+        }
+    }
+
+    let dir = cert_path.parent().unwrap_or_else(|| Path::new("."));
+    let storage_dir = conf.storage_dir.as_deref().unwrap_or(dir);
+    let domains = domains(&proxy.conf.server.server_name);
+    log::info!("Requesting an ACME certificate for {}", domains.join(", "));
+
+    let key = account_key(storage_dir)?;
+    let mut acme = AcmeClient::connect(&proxy.conf.client, &conf.directory_url, key).await?;
+    acme.register(&conf.contact).await?;
+    let (key_pem, chain) = acme
+        .issue(&domains, conf.challenge, &proxy.challenges)
+        .await?;
+
+    store(dir, &key_pem, &chain)?;
+    let info = proxy
+        .reload_cert()
+        .await
+        .map_err(|e| AcmeError::Certificate(e.to_string()))?;
+    log::info!(
+        "ACME certificate for {} issued: {}",
+        domains.join(", "),
+        info.hash
+    );
+    Ok(())
+}
+
+/// Server names certificates can be issued for
+fn domains(server_name: &[String]) -> Vec<String> {
+    server_name
+        .iter()
+        .filter(|name| name.parse::<IpAddr>().is_err())
+        .map(|name| name.to_ascii_lowercase())
+        .collect()
+}
+
+/// Writes a self-signed certificate which has already expired, to be replaced right away
+fn write_placeholder(dir: &Path, domains: &[String]) -> Result<(), AcmeError> {
+    let mut params = rcgen::CertificateParams::new(domains.to_vec());
+    params.not_before = rcgen::date_time_ymd(1975, 1, 1);
+    params.not_after = rcgen::date_time_ymd(1975, 1, 2);
+    let cert = rcgen::Certificate::from_params(params).map_err(cert_error)?;
+    let cert_pem = cert.serialize_pem().map_err(cert_error)?;
+    store(dir, &cert.serialize_private_key_pem(), &cert_pem)
+}
+
+/// Replaces the key and the certificate. Both are written under temporary names before
+/// either is renamed into place, so that a failed write keeps the previous pair
+fn store(dir: &Path, key_pem: &str, cert_pem: &str) -> Result<(), AcmeError> {
+    fs::create_dir_all(dir)?;
+    let (key_path, cert_path) = (dir.join(KEY_FILE), dir.join(CERT_FILE));
+    let key_tmp = write_temp(&key_path, key_pem, true)?;
+    let cert_tmp = write_temp(&cert_path, cert_pem, false)?;
+    fs::rename(key_tmp, key_path)?;
+    fs::rename(cert_tmp, cert_path)?;
+    Ok(())
+}
+
+/// Writes the file under a temporary name first, so that it is never read partially written
+fn write_atomic(path: &Path, contents: &str, private: bool) -> io::Result<()> {
+    let tmp = write_temp(path, contents, private)?;
+    fs::rename(tmp, path)
+}
+
+/// Writes and syncs a temporary file next to the path. Private files are only readable
+/// by the owner
+fn write_temp(path: &Path, contents: &str, private: bool) -> io::Result<PathBuf> {
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    let mut file = options.open(&tmp)?;
+    io::Write::write_all(&mut file, contents.as_bytes())?;
+    file.sync_all()?;
+    Ok(tmp)
+}
+
+/// Reads or generates the ACME account key
+fn account_key(storage_dir: &Path) -> Result<EcdsaKeyPair, AcmeError> {
+    let path = storage_dir.join(ACCOUNT_KEY_FILE);
+    let key = match fs::read_to_string(&path) {
+        Ok(pem) => rcgen::KeyPair::from_pem(&pem).map_err(cert_error)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key =
+                rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).map_err(cert_error)?;
+            fs::create_dir_all(storage_dir)?;
+            write_atomic(&path, &key.serialize_pem(), true)?;
+            key
+        }
+        Err(e) => return Err(e.into()),
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.serialize_der())
+        .map_err(|e| AcmeError::Certificate(format!("invalid account key: {}", e)))
+}
+
+/// Expiry time of the first certificate in the PEM file
+fn not_after(path: &Path) -> Option<DateTime<Utc>> {
+    let file = fs::File::open(path).ok()?;
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(file)).ok()?;
+    parse_not_after(certs.first()?)
+}
+
+/// Reads the end of the validity period of a DER encoded X.509 certificate
+fn parse_not_after(der: &[u8]) -> Option<DateTime<Utc>> {
+    let (_, cert, _) = der_element(der)?;
+    let (_, mut tbs, _) = der_element(cert)?;
+    // optional explicit version
+    let (tag, _, rest) = der_element(tbs)?;
+    if tag == 0xa0 {
+        tbs = rest;
+    }
+    // serial number, signature algorithm and issuer
+    for _ in 0..3 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (_, validity, _) = der_element(tbs)?;
+    let (_, _, validity) = der_element(validity)?;
+    let (tag, time, _) = der_element(validity)?;
+
+    let format = match tag {
+        0x17 => "%y%m%d%H%M%SZ",
+        0x18 => "%Y%m%d%H%M%SZ",
+        _ => return None,
+    };
+    let time = NaiveDateTime::parse_from_str(std::str::from_utf8(time).ok()?, format).ok()?;
+    Some(Utc.from_utc_datetime(&time))
+}
+
+/// Splits DER input into the tag and the contents of its first element, and the remaining input
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&len, mut input) = input.split_first()?;
+    let len = if len < 0x80 {
+        len as usize
+    } else {
+        let n = (len & 0x7f) as usize;
+        if n == 0 || n > 4 || input.len() < n {
+            return None;
+        }
+        let (bytes, rest) = input.split_at(n);
+        input = rest;
+        bytes.iter().fold(0, |len, b| len << 8 | *b as usize)
+    };
+    if input.len() < len {
+        return None;
+    }
+    let (contents, rest) = input.split_at(len);
+    Some((tag, contents, rest))
+}
+
+/// Self-signed certificate proving the control of a domain to TLS-ALPN-01 validation (RFC 8737)
+fn validation_cert(domain: &str, key_authorization: &str) -> Result<Arc<CertifiedKey>, AcmeError> {
+    let digest = digest(&SHA256, key_authorization.as_bytes());
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_string()]);
+    params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(digest.as_ref())];
+    let cert = rcgen::Certificate::from_params(params).map_err(cert_error)?;
+    let der = cert.serialize_der().map_err(cert_error)?;
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let key = rustls::sign::any_supported_type(&key).map_err(cert_error)?;
+    Ok(Arc::new(CertifiedKey::new(
+        vec![rustls::Certificate(der)],
+        key,
+    )))
+}
+
+fn cert_error(e: impl ToString) -> AcmeError {
+    AcmeError::Certificate(e.to_string())
+}
+
+fn challenge_type(challenge: AcmeChallenge) -> &'static str {
+    match challenge {
+        AcmeChallenge::Http01 => "http-01",
+        AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+    }
+}
+
+fn base64url(data: impl AsRef<[u8]>) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// JSON Web Key of the account public key
+fn jwk(key: &EcdsaKeyPair) -> Value {
+    // uncompressed point: 0x04 | x | y
+    let point = key.public_key().as_ref();
+    json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": base64url(&point[1..33]),
+        "y": base64url(&point[33..65]),
+    })
+}
+
+/// JWK thumbprint (RFC 7638): digest of the required members in lexicographic order
+fn thumbprint(jwk: &Value) -> String {
+    let canonical = format!(
+        r#"{{"crv":{},"kty":{},"x":{},"y":{}}}"#,
+        jwk["crv"], jwk["kty"], jwk["x"], jwk["y"]
+    );
+    base64url(digest(&SHA256, canonical.as_bytes()))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+}
+
+struct Reply {
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Reply {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, AcmeError> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| AcmeError::Server(format!("invalid response: {}", e)))
+    }
+
+    fn location(&self) -> Result<String, AcmeError> {
+        self.headers
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(ToString::to_string)
+            .ok_or_else(|| AcmeError::Server("missing Location header".into()))
+    }
+}
+
+/// ACME account session, signing requests with ES256
+struct AcmeClient {
+    http: Client<HttpsConnector<HttpConnector>>,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    jwk: Value,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    async fn connect(
+        client_conf: &ClientConf,
+        directory_url: &str,
+        key: EcdsaKeyPair,
+    ) -> Result<Self, AcmeError> {
+        let http = client::build_tls(client_conf).map_err(|e| AcmeError::Request(e.to_string()))?;
+        let uri = directory_url
+            .parse()
+            .map_err(|e| AcmeError::Request(format!("invalid directory URL: {}", e)))?;
+        let response = http.get(uri).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            return Err(AcmeError::Server(format!(
+                "directory request failed: {}",
+                status
+            )));
+        }
+        let directory = serde_json::from_slice(&body)
+            .map_err(|e| AcmeError::Server(format!("invalid directory: {}", e)))?;
+
+        Ok(Self {
+            http,
+            directory,
+            jwk: jwk(&key),
+            key,
+            rng: SystemRandom::new(),
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    /// Registers the account or looks up the one already registered with the key
+    async fn register(&mut self, contact: &[String]) -> Result<(), AcmeError> {
+        let url = self.directory.new_account.clone();
+        let payload = json!({
+            "termsOfServiceAgreed": true,
+            "contact": contact,
+        });
+        let reply = self.post(&url, Some(&payload)).await?;
+        self.kid = Some(reply.location()?);
+        Ok(())
+    }
+
+    /// Orders a certificate for the domains, returning the private key and the certificate chain
+    async fn issue(
+        &mut self,
+        domains: &[String],
+        challenge: AcmeChallenge,
+        challenges: &Challenges,
+    ) -> Result<(String, String), AcmeError> {
+        let url = self.directory.new_order.clone();
+        let identifiers: Vec<Value> = domains
+            .iter()
+            .map(|d| json!({ "type": "dns", "value": d }))
+            .collect();
+        let reply = self
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = reply.location()?;
+        let order: Order = reply.json()?;
+
+        for authorization in order.authorizations.iter() {
+            self.authorize(authorization, challenge, challenges).await?;
+        }
+
+        let cert = rcgen::Certificate::from_params(rcgen::CertificateParams::new(domains.to_vec()))
+            .map_err(cert_error)?;
+        let csr = cert.serialize_request_der().map_err(cert_error)?;
+        self.post(&order.finalize, Some(&json!({ "csr": base64url(csr) })))
+            .await?;
+
+        let order: Order = self
+            .poll(&order_url, |o: &Order| {
+                !matches!(o.status.as_str(), "pending" | "ready" | "processing")
+            })
+            .await?;
+        let certificate = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(url)) => url,
+            (status, _) => {
+                return Err(AcmeError::Server(format!(
+                    "order finished with status '{}'",
+                    status
+                )))
+            }
+        };
+
+        let reply = self.post(&certificate, None).await?;
+        let chain = String::from_utf8(reply.body.to_vec())
+            .map_err(|e| AcmeError::Server(format!("invalid certificate chain: {}", e)))?;
+        Ok((cert.serialize_private_key_pem(), chain))
+    }
+
+    /// Completes the authorization of a domain, answering the challenge of the configured type
+    async fn authorize(
+        &mut self,
+        url: &str,
+        challenge: AcmeChallenge,
+        challenges: &Challenges,
+    ) -> Result<(), AcmeError> {
+        let authorization: Authorization = self.post(url, None).await?.json()?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let domain = authorization.identifier.value;
+        let kind = challenge_type(challenge);
+        let offered = match authorization
+            .challenges
+            .into_iter()
+            .find(|c| c.kind == kind)
+        {
+            Some(offered) => offered,
+            None => {
+                return Err(AcmeError::Server(format!(
+                    "{} challenge is not offered for '{}'",
+                    kind, domain
+                )))
+            }
+        };
+        let key_authorization = format!("{}.{}", offered.token, thumbprint(&self.jwk));
+
+        match challenge {
+            AcmeChallenge::Http01 => {
+                challenges.insert_http(offered.token.clone(), key_authorization);
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let cert = validation_cert(&domain, &key_authorization)?;
+                challenges.insert_tls_alpn(&domain, cert);
+            }
+        }
+        let result = self.validate(url, &offered.url, &domain).await;
+        match challenge {
+            AcmeChallenge::Http01 => challenges.remove_http(&offered.token),
+            AcmeChallenge::TlsAlpn01 => challenges.remove_tls_alpn(&domain),
+        }
+        result
+    }
+
+    async fn validate(
+        &mut self,
+        authorization_url: &str,
+        challenge_url: &str,
+        domain: &str,
+    ) -> Result<(), AcmeError> {
+        self.post(challenge_url, Some(&json!({}))).await?;
+        let authorization: Authorization = self
+            .poll(authorization_url, |a: &Authorization| a.status != "pending")
+            .await?;
+
+        match authorization.status.as_str() {
+            "valid" => Ok(()),
+            status => Err(AcmeError::Validation {
+                domain: domain.to_string(),
+                status: status.to_string(),
+            }),
+        }
+    }
+
+    /// Fetches the resource until it reaches a final state
+    async fn poll<T, F>(&mut self, url: &str, done: F) -> Result<T, AcmeError>
+    where
+        T: DeserializeOwned,
+        F: Fn(&T) -> bool,
+    {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource: T = self.post(url, None).await?.json()?;
+            if done(&resource) {
+                return Ok(resource);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(AcmeError::Timeout(url.to_string()))
+    }
+
+    /// Sends a signed request. Requests without a payload fetch the resource (POST-as-GET)
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Reply, AcmeError> {
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce().await?;
+            let body = self.sign(url, nonce, payload)?;
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(header::CONTENT_TYPE, JOSE_CONTENT_TYPE)
+                .body(Body::from(body))
+                .map_err(|e| AcmeError::Request(e.to_string()))?;
+
+            let response = self.http.request(request).await?;
+            let (parts, body) = response.into_parts();
+            self.nonce = replay_nonce(&parts.headers);
+            let body = hyper::body::to_bytes(body).await?;
+            if parts.status.is_success() {
+                return Ok(Reply {
+                    headers: parts.headers,
+                    body,
+                });
+            }
+
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            // nonces may expire; the response carries a fresh one
+            if problem["type"] == BAD_NONCE && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(AcmeError::Server(format!(
+                "{} responded with {}: {}",
+                url,
+                parts.status,
+                problem["detail"].as_str().unwrap_or_default()
+            )));
+        }
+    }
+
+    async fn nonce(&mut self) -> Result<String, AcmeError> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let request = Request::builder()
+            .method(Method::HEAD)
+            .uri(self.directory.new_nonce.as_str())
+            .body(Body::empty())
+            .map_err(|e| AcmeError::Request(e.to_string()))?;
+        let response = self.http.request(request).await?;
+        replay_nonce(response.headers())
+            .ok_or_else(|| AcmeError::Server("missing Replay-Nonce header".into()))
+    }
+
+    /// Serializes a flattened JWS of the payload
+    fn sign(&self, url: &str, nonce: String, payload: Option<&Value>) -> Result<String, AcmeError> {
+        let mut protected = json!({
+            "alg": "ES256",
+            "nonce": nonce,
+            "url": url,
+        });
+        match self.kid {
+            Some(ref kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = base64url(protected.to_string());
+        let payload = match payload {
+            Some(payload) => base64url(payload.to_string()),
+            None => String::new(),
+        };
+
+        let message = format!("{}.{}", protected, payload);
+        let signature = self
+            .key
+            .sign(&self.rng, message.as_bytes())
+            .map_err(|_| AcmeError::Certificate("unable to sign the request".into()))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": base64url(signature),
+        })
+        .to_string())
+    }
+}
+
+fn replay_nonce(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("replay-nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(ToString::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature;
+
+    use super::*;
+
+    #[test]
+    fn signed_request() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+        let public_key = key.public_key().as_ref().to_vec();
+
+        let acme = AcmeClient {
+            http: client::build_tls(&Default::default()).unwrap(),
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            jwk: jwk(&key),
+            key,
+            rng,
+            kid: None,
+            nonce: None,
+        };
+        let jws: Value = serde_json::from_str(
+            &acme
+                .sign("https://acme/new-account", "nonce".into(), Some(&json!({})))
+                .unwrap(),
+        )
+        .unwrap();
+
+        let protected = jws["protected"].as_str().unwrap();
+        let header: Value = serde_json::from_slice(
+            &base64::decode_config(protected, base64::URL_SAFE_NO_PAD).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(header["alg"], "ES256");
+        assert_eq!(header["nonce"], "nonce");
+        assert_eq!(header["jwk"], acme.jwk);
+
+        let message = format!("{}.{}", protected, jws["payload"].as_str().unwrap());
+        let sig =
+            base64::decode_config(jws["signature"].as_str().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, public_key)
+            .verify(message.as_bytes(), &sig)
+            .unwrap();
+    }
+
+    #[test]
+    fn placeholder_expired() {
+        let dir = std::env::temp_dir().join(format!("ya-http-proxy-acme-{}", std::process::id()));
+        let conf = AcmeConf {
+            storage_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let server_name = vec!["127.0.0.1".to_string(), "Example.com".to_string()];
+        let (cert_path, key_path) = cert_paths(&conf, &server_name).unwrap();
+        assert_eq!(cert_path, dir.join("example.com").join(CERT_FILE));
+        assert!(key_path.exists());
+
+        let not_after = not_after(&cert_path).unwrap();
+        assert_eq!(
+            not_after,
+            Utc.with_ymd_and_hms(1975, 1, 2, 0, 0, 0).unwrap()
+        );
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(cert_paths(&conf, &["127.0.0.1".to_string()]).is_err());
+        assert!(cert_paths(&AcmeConf::default(), &server_name).is_err());
+    }
+
+    #[test]
+    fn tls_alpn_validation_cert() {
+        let cert = validation_cert("example.com", "token.thumbprint").unwrap();
+        let der = &cert.cert[0].0;
+        // acmeIdentifier extension OID 1.3.6.1.5.5.7.1.31
+        let oid = [0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f];
+        assert!(der.windows(oid.len()).any(|w| w == oid));
+        assert!(parse_not_after(der).unwrap() > Utc::now());
+    }
+
+    const CHAIN: &str = "-----BEGIN CERTIFICATE-----\nchain\n-----END CERTIFICATE-----\n";
+
+    /// ACME server state: issued nonces and the progress of a single order
+    #[derive(Default)]
+    struct MockState {
+        issued: usize,
+        nonces: HashSet<String>,
+        bad_nonce_sent: bool,
+        validated: bool,
+        finalized: bool,
+    }
+
+    /// Serves a single order for `example.com` with an HTTP-01 challenge, checking that
+    /// requests are signed for their URL with an issued nonce
+    async fn mock_directory(challenges: Arc<Challenges>) -> String {
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Response, StatusCode};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockState::default()));
+
+        let service_base = base.clone();
+        let make_service = make_service_fn(move |_| {
+            let (base, state, challenges) =
+                (service_base.clone(), state.clone(), challenges.clone());
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let (base, state, challenges) =
+                        (base.clone(), state.clone(), challenges.clone());
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let method = req.method().clone();
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        let mut state = state.lock().unwrap();
+
+                        state.issued += 1;
+                        let nonce = format!("nonce-{}", state.issued);
+                        state.nonces.insert(nonce.clone());
+                        let reply = |status: StatusCode, body: String| {
+                            Response::builder()
+                                .status(status)
+                                .header("replay-nonce", nonce.as_str())
+                                .header(header::LOCATION, format!("{}/order/1", base))
+                                .body(Body::from(body))
+                                .unwrap()
+                        };
+
+                        if method == Method::GET && path == "/directory" {
+                            let directory = json!({
+                                "newNonce": format!("{}/nonce", base),
+                                "newAccount": format!("{}/account", base),
+                                "newOrder": format!("{}/order", base),
+                            });
+                            return Ok(reply(StatusCode::OK, directory.to_string()));
+                        }
+                        if method == Method::HEAD {
+                            return Ok(reply(StatusCode::OK, String::new()));
+                        }
+
+                        let jws: Value = serde_json::from_slice(&body).unwrap();
+                        let decode = |field: &str| {
+                            let data = jws[field].as_str().unwrap();
+                            base64::decode_config(data, base64::URL_SAFE_NO_PAD).unwrap()
+                        };
+                        let protected: Value =
+                            serde_json::from_slice(&decode("protected")).unwrap();
+                        assert_eq!(protected["url"], format!("{}{}", base, path));
+                        let used = protected["nonce"].as_str().unwrap();
+                        assert!(state.nonces.remove(used), "nonce reused");
+                        let payload = decode("payload");
+
+                        let order = |state: &MockState| {
+                            json!({
+                                "status": if state.finalized { "valid" } else { "pending" },
+                                "authorizations": [format!("{}/authz/1", base)],
+                                "finalize": format!("{}/finalize/1", base),
+                                "certificate": format!("{}/cert/1", base),
+                            })
+                            .to_string()
+                        };
+                        let response = match path.as_str() {
+                            "/account" if !state.bad_nonce_sent => {
+                                state.bad_nonce_sent = true;
+                                let problem = json!({ "type": BAD_NONCE, "detail": "stale" });
+                                reply(StatusCode::BAD_REQUEST, problem.to_string())
+                            }
+                            "/account" => {
+                                assert!(protected["jwk"].is_object());
+                                reply(StatusCode::CREATED, "{}".into())
+                            }
+                            "/order" | "/order/1" => reply(StatusCode::CREATED, order(&state)),
+                            "/authz/1" => {
+                                let authorization = json!({
+                                    "status": if state.validated { "valid" } else { "pending" },
+                                    "identifier": { "type": "dns", "value": "example.com" },
+                                    "challenges": [
+                                        { "type": "tls-alpn-01", "url": format!("{}/chall/2", base), "token": "alpn" },
+                                        { "type": "http-01", "url": format!("{}/chall/1", base), "token": "token" },
+                                    ],
+                                });
+                                reply(StatusCode::OK, authorization.to_string())
+                            }
+                            "/chall/1" => {
+                                let key_authorization = challenges
+                                    .http("/.well-known/acme-challenge/token")
+                                    .unwrap();
+                                assert!(key_authorization.starts_with("token."));
+                                state.validated = true;
+                                reply(StatusCode::OK, "{}".into())
+                            }
+                            "/finalize/1" => {
+                                assert!(state.validated);
+                                let payload: Value = serde_json::from_slice(&payload).unwrap();
+                                assert!(payload["csr"].is_string());
+                                state.finalized = true;
+                                reply(StatusCode::OK, order(&state))
+                            }
+                            "/cert/1" => reply(StatusCode::OK, CHAIN.into()),
+                            _ => reply(StatusCode::NOT_FOUND, "{}".into()),
+                        };
+                        Ok::<_, hyper::Error>(response)
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::from_tcp(listener)
+            .unwrap()
+            .serve(make_service);
+        tokio::spawn(server);
+        base
+    }
+
+    #[tokio::test]
+    async fn issue_with_mock_directory() {
+        let dir =
+            std::env::temp_dir().join(format!("ya-http-proxy-acme-issue-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let challenges = Arc::new(Challenges::default());
+        let base = mock_directory(challenges.clone()).await;
+
+        let key = account_key(&dir).unwrap();
+        let directory_url = format!("{}/directory", base);
+        let mut acme = AcmeClient::connect(&Default::default(), &directory_url, key)
+            .await
+            .unwrap();
+        acme.register(&["mailto:admin@example.com".to_string()])
+            .await
+            .unwrap();
+        assert!(acme.kid.is_some());
+
+        let (key_pem, chain) = acme
+            .issue(
+                &["example.com".to_string()],
+                AcmeChallenge::Http01,
+                &challenges,
+            )
+            .await
+            .unwrap();
+        assert!(key_pem.contains("PRIVATE KEY"));
+        assert_eq!(chain, CHAIN);
+        // challenge responses are withdrawn after validation
+        assert!(challenges
+            .http("/.well-known/acme-challenge/token")
+            .is_none());
+
+        // the account key is reused
+        let key = account_key(&dir).unwrap();
+        assert_eq!(jwk(&key), acme.jwk);
+
+        store(&dir, &key_pem, &chain).unwrap();
+        assert_eq!(fs::read_to_string(dir.join(KEY_FILE)).unwrap(), key_pem);
+        assert_eq!(fs::read_to_string(dir.join(CERT_FILE)).unwrap(), chain);
+        assert!(!dir.join(KEY_FILE).with_extension("tmp").exists());
+        assert!(!dir.join(CERT_FILE).with_extension("tmp").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
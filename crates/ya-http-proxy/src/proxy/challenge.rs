@@ -0,0 +1,54 @@
+//! Responses to pending ACME challenges, served by all proxy instances
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use rustls::sign::CertifiedKey;
+
+/// Protocol negotiated by TLS-ALPN-01 challenge validation connections
+pub(crate) const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+/// Path prefix of HTTP-01 challenge validation requests
+const HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+#[derive(Default)]
+pub(crate) struct Challenges {
+    /// Key authorizations by HTTP-01 challenge token
+    http: Mutex<HashMap<String, String>>,
+    /// Validation certificates by TLS-ALPN-01 challenge domain
+    tls_alpn: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl Challenges {
+    /// Key authorization served at the request path, if any
+    pub fn http(&self, path: &str) -> Option<String> {
+        let token = path.strip_prefix(HTTP_CHALLENGE_PATH)?;
+        self.http.lock().unwrap().get(token).cloned()
+    }
+
+    /// Validation certificate offered to connections negotiating `acme-tls/1`
+    pub fn tls_alpn(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+        let domain = domain.to_ascii_lowercase();
+        self.tls_alpn.lock().unwrap().get(&domain).cloned()
+    }
+
+    #[cfg(feature = "acme")]
+    pub fn insert_http(&self, token: String, key_authorization: String) {
+        self.http.lock().unwrap().insert(token, key_authorization);
+    }
+
+    #[cfg(feature = "acme")]
+    pub fn remove_http(&self, token: &str) {
+        self.http.lock().unwrap().remove(token);
+    }
+
+    #[cfg(feature = "acme")]
+    pub fn insert_tls_alpn(&self, domain: &str, key: Arc<CertifiedKey>) {
+        let domain = domain.to_ascii_lowercase();
+        self.tls_alpn.lock().unwrap().insert(domain, key);
+    }
+
+    #[cfg(feature = "acme")]
+    pub fn remove_tls_alpn(&self, domain: &str) {
+        let domain = domain.to_ascii_lowercase();
+        self.tls_alpn.lock().unwrap().remove(&domain);
+    }
+}
@@ -1,18 +1,86 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use std::{fs, io};
 
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::client::connect::{Connected, Connection};
 use hyper::client::{Builder, Client, HttpConnector};
+use hyper::service::Service;
+use hyper::Uri;
 use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
 
 use crate::conf::ClientConf;
 use crate::conf_builder_client;
-use crate::error::{Error, TlsError};
+use crate::error::{Error, ServiceError, TlsError};
+use ya_http_proxy_model::UpstreamProtocol;
 
-pub fn build(conf: &ClientConf) -> Client<HttpConnector> {
-    builder(conf).build_http()
+pub type UpstreamClient = Client<HttpsConnector<TimedConnector>>;
+
+/// Builds a client for connections to the service destination URL
+pub fn build_upstream(
+    conf: &ClientConf,
+    protocol: UpstreamProtocol,
+    to: &Uri,
+) -> Result<UpstreamClient, Error> {
+    let tls = to.scheme_str() == Some("https");
+    let connector = HttpsConnectorBuilder::new().with_tls_config(tls_conf(conf)?);
+    let mut client = builder(conf);
+
+    let https = match protocol {
+        UpstreamProtocol::Http1 => {
+            client.http2_only(false);
+            connector
+                .https_or_http()
+                .enable_http1()
+                .wrap_connector(TimedConnector::new())
+        }
+        UpstreamProtocol::H2c if !tls => {
+            client.http2_only(true);
+            connector
+                .https_or_http()
+                .enable_http2()
+                .wrap_connector(TimedConnector::new())
+        }
+        UpstreamProtocol::H2 if tls => {
+            client.http2_only(true);
+            connector
+                .https_only()
+                .enable_http2()
+                .wrap_connector(TimedConnector::new())
+        }
+        UpstreamProtocol::Auto => connector
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(TimedConnector::new()),
+        protocol => {
+            return Err(ServiceError::InvalidUpstream(format!(
+                "{:?} is not supported for '{}'",
+                protocol, to
+            ))
+            .into())
+        }
+    };
+
+    Ok(client.build(https))
 }
 
-#[allow(unused)]
 pub fn build_tls(conf: &ClientConf) -> Result<Client<HttpsConnector<HttpConnector>>, Error> {
+    let https = HttpsConnectorBuilder::new()
+        .with_tls_config(tls_conf(conf)?)
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build();
+
+    Ok(builder(conf).build(https))
+}
+
+fn tls_conf(conf: &ClientConf) -> Result<rustls::ClientConfig, Error> {
     let tls_conf = match conf.client_cert.client_ca_cert_store_path {
         Some(ref path) => {
             let file = fs::File::open(path).map_err(|e| {
@@ -37,15 +105,7 @@ pub fn build_tls(conf: &ClientConf) -> Result<Client<HttpsConnector<HttpConnecto
             .with_native_roots()
             .with_no_client_auth(),
     };
-
-    let https = HttpsConnectorBuilder::new()
-        .with_tls_config(tls_conf)
-        .https_or_http()
-        .enable_http1()
-        .enable_http2()
-        .build();
-
-    Ok(builder(conf).build(https))
+    Ok(tls_conf)
 }
 
 fn builder(conf: &ClientConf) -> Builder {
@@ -54,3 +114,98 @@ fn builder(conf: &ClientConf) -> Builder {
     conf_builder_client!(target, conf);
     builder
 }
+
+/// Connection establishment timing, set in upstream response extensions
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectTiming {
+    pub started: Instant,
+    pub duration: Duration,
+}
+
+/// TCP connector recording connection establishment timing
+#[derive(Clone)]
+pub struct TimedConnector(HttpConnector);
+
+impl TimedConnector {
+    fn new() -> Self {
+        let mut http = HttpConnector::new();
+        // schemes are verified by the TLS connector
+        http.enforce_http(false);
+        Self(http)
+    }
+}
+
+impl Service<Uri> for TimedConnector {
+    type Response = TimedStream;
+    type Error = <HttpConnector as Service<Uri>>::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let started = Instant::now();
+        let connect = self.0.call(uri);
+        async move {
+            let inner = connect.await?;
+            let timing = ConnectTiming {
+                started,
+                duration: started.elapsed(),
+            };
+            Ok(TimedStream { inner, timing })
+        }
+        .boxed()
+    }
+}
+
+pub struct TimedStream {
+    inner: TcpStream,
+    timing: ConnectTiming,
+}
+
+impl Connection for TimedStream {
+    fn connected(&self) -> Connected {
+        self.inner.connected().extra(self.timing)
+    }
+}
+
+impl AsyncRead for TimedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TimedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
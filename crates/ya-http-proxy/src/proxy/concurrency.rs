@@ -0,0 +1,172 @@
+//! Per-service limits of proxy worker threads handling requests at once
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+
+use ya_http_proxy_model as model;
+
+type Acquire = Pin<Box<dyn Future<Output = Result<OwnedSemaphorePermit, AcquireError>> + Send>>;
+
+/// Number of worker threads a service may occupy. Permits are granted in the order they
+/// were requested, so services sharing a proxy instance get fair shares of its threads
+#[derive(Debug)]
+pub(crate) struct Concurrency {
+    cpu_threads: usize,
+    limit: usize,
+    semaphore: Arc<Semaphore>,
+    throttled: AtomicU64,
+}
+
+impl Concurrency {
+    /// Limits the service to `cpu_threads`, up to the number of runtime worker threads
+    pub fn new(cpu_threads: usize, worker_threads: Option<usize>) -> Self {
+        let workers = worker_threads
+            .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1);
+        let limit = cpu_threads.clamp(1, workers.max(1));
+        Self {
+            cpu_threads,
+            limit,
+            semaphore: Arc::new(Semaphore::new(limit)),
+            throttled: Default::default(),
+        }
+    }
+
+    /// Number of threads the service may occupy at once
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn status(&self, service: String) -> model::ServiceConcurrency {
+        model::ServiceConcurrency {
+            service,
+            cpu_threads: self.cpu_threads,
+            limit: self.limit,
+            active: self.limit - self.semaphore.available_permits(),
+            throttled: self.throttled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Concurrency limit of the service handling a request, set once the service is known
+#[derive(Debug, Default)]
+pub(crate) struct Gate(Mutex<Option<Arc<Concurrency>>>);
+
+impl Gate {
+    pub fn set(&self, concurrency: Option<Arc<Concurrency>>) {
+        *self.0.lock().unwrap() = concurrency;
+    }
+
+    /// Polls the future only while holding a permit of the service concurrency limit
+    pub fn limit<F: Future>(self: &Arc<Self>, future: F) -> Limited<F> {
+        Limited {
+            inner: Box::pin(future),
+            gate: self.clone(),
+            acquire: None,
+        }
+    }
+
+    fn get(&self) -> Option<Arc<Concurrency>> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Future wrapper polling the inner future within the limits of a [`Gate`]
+pub(crate) struct Limited<F> {
+    inner: Pin<Box<F>>,
+    gate: Arc<Gate>,
+    acquire: Option<Acquire>,
+}
+
+impl<F: Future> Future for Limited<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let concurrency = match self.gate.get() {
+            Some(concurrency) => concurrency,
+            None => return self.inner.as_mut().poll(cx),
+        };
+
+        let permit = match self.acquire {
+            Some(ref mut acquire) => match acquire.as_mut().poll(cx) {
+                Poll::Ready(permit) => permit.ok(),
+                Poll::Pending => return Poll::Pending,
+            },
+            None => match concurrency.semaphore.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    concurrency.throttled.fetch_add(1, Ordering::Relaxed);
+                    let mut acquire: Acquire =
+                        Box::pin(concurrency.semaphore.clone().acquire_owned());
+                    match acquire.as_mut().poll(cx) {
+                        Poll::Ready(permit) => permit.ok(),
+                        Poll::Pending => {
+                            self.acquire = Some(acquire);
+                            return Poll::Pending;
+                        }
+                    }
+                }
+            },
+        };
+        self.acquire = None;
+
+        let poll = self.inner.as_mut().poll(cx);
+        drop(permit);
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{Concurrency, Gate};
+
+    #[test]
+    fn limit() {
+        assert_eq!(Concurrency::new(8, Some(2)).limit, 2);
+        assert_eq!(Concurrency::new(0, Some(2)).limit, 1);
+        let status = Concurrency::new(3, Some(4)).status("service".to_string());
+        assert_eq!((status.cpu_threads, status.limit, status.active), (3, 3, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn limited_polls() {
+        let concurrency = Arc::new(Concurrency::new(1, Some(4)));
+        let polling = Arc::new(AtomicUsize::new(0));
+        let max_polling = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..4)
+            .map(|_| {
+                let gate = Arc::new(Gate::default());
+                gate.set(Some(concurrency.clone()));
+                let polling = polling.clone();
+                let max_polling = max_polling.clone();
+                tokio::spawn(gate.limit(async move {
+                    for _ in 0..3 {
+                        let n = polling.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_polling.fetch_max(n, Ordering::SeqCst);
+                        // occupies the worker thread
+                        std::thread::sleep(Duration::from_millis(5));
+                        polling.fetch_sub(1, Ordering::SeqCst);
+                        tokio::task::yield_now().await;
+                    }
+                }))
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(max_polling.load(Ordering::SeqCst), 1);
+        let status = concurrency.status("service".to_string());
+        assert_eq!(status.active, 0);
+        assert!(status.throttled > 0);
+    }
+}
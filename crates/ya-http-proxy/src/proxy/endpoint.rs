@@ -0,0 +1,206 @@
+//! Path segment-aware service endpoint matching
+use ya_http_proxy_model::CreateService;
+
+/// Service endpoint, qualified by the server names the service is reachable at
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Route {
+    pub endpoint: String,
+    /// Lower-case server names. Services without any are reachable at every host
+    pub hosts: Vec<String>,
+}
+
+impl Route {
+    pub fn new(create: &CreateService) -> Self {
+        let mut hosts: Vec<_> = create
+            .server_name
+            .iter()
+            .map(|h| h.to_ascii_lowercase())
+            .collect();
+        hosts.sort();
+        hosts.dedup();
+
+        Self {
+            endpoint: from_path(create.from.path()),
+            hosts,
+        }
+    }
+
+    /// Checks whether requests could be routed ambiguously between the routes
+    pub fn overlaps(&self, other: &Route) -> bool {
+        overlaps(&self.endpoint, &other.endpoint) && server_names_overlap(&self.hosts, &other.hosts)
+    }
+
+    /// Checks whether the host is one of the route server names
+    pub fn serves(&self, host: &str) -> bool {
+        self.hosts.iter().any(|h| h == host)
+    }
+}
+
+/// Converts a service source path to an endpoint.
+pub fn from_path(path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        ["/", path].concat()
+    }
+}
+
+/// Normalizes a request path before routing and applying path rules.
+///
+/// Percent-encoded unreserved characters are decoded and the remaining escapes
+/// upper-cased, repeated slashes are merged and dot-segments removed. Paths with
+/// malformed escapes or escaped `/`, `\` and NUL characters are rejected.
+pub fn normalize(path: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            decoded.push(c);
+            continue;
+        }
+        let hex: String = chars.by_ref().take(2).collect();
+        if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        match u8::from_str_radix(&hex, 16).ok()? {
+            b'/' | b'\\' | 0 => return None,
+            b if b.is_ascii_alphanumeric() || b"-._~".contains(&b) => decoded.push(b as char),
+            b => decoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    let mut segments = Vec::new();
+    let mut trailing = false;
+    for segment in decoded.split('/') {
+        trailing = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = ["/", &segments.join("/")].concat();
+    if trailing && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// Checks whether the request `path` is routed to the service `endpoint`.
+///
+/// Matching is performed on whole path segments, i.e. `/api` matches `/api`
+/// and `/api/v1`, but not `/apiv2`.
+pub fn matches(path: &str, endpoint: &str) -> bool {
+    let endpoint = endpoint.trim_end_matches('/');
+    match path.strip_prefix(endpoint) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Checks whether requests for two service endpoints could be routed ambiguously.
+pub fn overlaps(left: &str, right: &str) -> bool {
+    matches(left, right) || matches(right, left)
+}
+
+/// Extracts the lower-case host name from a `Host` header or a URL authority,
+/// without the port and IPv6 address brackets.
+pub fn host(authority: &str) -> String {
+    let authority = authority.trim();
+    let host = match authority.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => authority
+            .rsplit_once(':')
+            .map(|(h, _)| h)
+            .unwrap_or(authority),
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Checks whether two sets of server names share a host.
+/// An empty set is treated as a wildcard.
+pub fn server_names_overlap(left: &[String], right: &[String]) -> bool {
+    if left.is_empty() || right.is_empty() {
+        return true;
+    }
+    left.iter()
+        .any(|l| right.iter().any(|r| l.eq_ignore_ascii_case(r)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_matching() {
+        assert!(matches("/api", "/api"));
+        assert!(matches("/api/", "/api"));
+        assert!(matches("/api/v1/resource", "/api"));
+        assert!(matches("/api/v1", "/api/"));
+        assert!(matches("/anything", "/"));
+        assert!(!matches("/apiv2", "/api"));
+        assert!(!matches("/ap", "/api"));
+    }
+
+    #[test]
+    fn path_normalization() {
+        assert_eq!(normalize("/").as_deref(), Some("/"));
+        assert_eq!(normalize("/api/v1/").as_deref(), Some("/api/v1/"));
+        assert_eq!(normalize("/%61pi").as_deref(), Some("/api"));
+        assert_eq!(normalize("/%41%7e%2d").as_deref(), Some("/A~-"));
+        assert_eq!(normalize("/api/../admin").as_deref(), Some("/admin"));
+        assert_eq!(normalize("/api/%2e%2E/admin").as_deref(), Some("/admin"));
+        assert_eq!(normalize("/api/./v1/.").as_deref(), Some("/api/v1/"));
+        assert_eq!(normalize("/../../admin").as_deref(), Some("/admin"));
+        assert_eq!(
+            normalize("//admin///users").as_deref(),
+            Some("/admin/users")
+        );
+        assert_eq!(normalize("/a%20b%3f").as_deref(), Some("/a%20b%3F"));
+        assert_eq!(normalize("/..").as_deref(), Some("/"));
+
+        assert!(normalize("/admin%2Fusers").is_none());
+        assert!(normalize("/admin%5c..%5cusers").is_none());
+        assert!(normalize("/admin%00").is_none());
+        assert!(normalize("/admin%2").is_none());
+        assert!(normalize("/admin%+f").is_none());
+        assert!(normalize("/admin%zz").is_none());
+    }
+
+    #[test]
+    fn endpoint_overlap() {
+        assert!(overlaps("/", "/api"));
+        assert!(overlaps("/api", "/api/"));
+        assert!(overlaps("/api", "/api/v2"));
+        assert!(overlaps("/api/v2", "/api"));
+        assert!(!overlaps("/api", "/apiv2"));
+        assert!(!overlaps("/api/v1", "/api/v2"));
+    }
+
+    #[test]
+    fn server_name_overlap() {
+        let names = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert!(server_names_overlap(&names(&[]), &names(&["a.com"])));
+        assert!(server_names_overlap(
+            &names(&["a.com", "1.2.3.4"]),
+            &names(&["A.com"])
+        ));
+        assert!(!server_names_overlap(
+            &names(&["a.com"]),
+            &names(&["b.com"])
+        ));
+    }
+
+    #[test]
+    fn host_names() {
+        assert_eq!(host("Example.com"), "example.com");
+        assert_eq!(host("example.com.:8443"), "example.com");
+        assert_eq!(host("1.2.3.4:80"), "1.2.3.4");
+        assert_eq!(host("[::1]:443"), "::1");
+        assert_eq!(host("[::1]"), "::1");
+    }
+}
@@ -0,0 +1,74 @@
+//! Entity tags of Management API resources, used to detect concurrent modifications
+use serde::Serialize;
+
+use crate::error::ManagementError;
+use crate::proxy::digest;
+use ya_http_proxy_model as model;
+
+/// Number of digest characters included in a tag
+const TAG_LEN: usize = 16;
+
+/// Computes the entity tag of a serialized resource
+pub(crate) fn of_json(json: &str) -> String {
+    format!("\"{}\"", &digest(json)[..TAG_LEN])
+}
+
+pub(crate) fn of<T: Serialize>(value: &T) -> String {
+    of_json(&serde_json::to_string(value).unwrap_or_default())
+}
+
+/// Computes the entity tag of a service definition. Upstream availability,
+/// user count and proxy errors are not a part of the definition
+pub(crate) fn service(service: &model::Service) -> String {
+    of(&model::Service {
+        upstream_available: None,
+        user_count: None,
+        proxy_error: None,
+        listener_error: None,
+        ..service.clone()
+    })
+}
+
+/// Checks the `If-Match` header value against the current entity tag.
+/// Requests without the header are always allowed
+pub(crate) fn check(if_match: Option<&str>, etag: Option<&str>) -> Result<(), ManagementError> {
+    let if_match = match if_match {
+        Some(if_match) => if_match,
+        None => return Ok(()),
+    };
+    let matches = etag
+        .map(|etag| {
+            if_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag == etag)
+        })
+        .unwrap_or(false);
+
+    if matches {
+        Ok(())
+    } else {
+        Err(ManagementError::PreconditionFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, of_json};
+
+    #[test]
+    fn if_match() {
+        let etag = of_json(r#"{"name":"service"}"#);
+        assert_eq!(etag.len(), 18);
+        assert_ne!(etag, of_json(r#"{"name":"other"}"#));
+
+        assert!(check(None, None).is_ok());
+        assert!(check(None, Some(&etag)).is_ok());
+        assert!(check(Some(&etag), Some(&etag)).is_ok());
+        assert!(check(Some(&format!("\"other\", {}", etag)), Some(&etag)).is_ok());
+        assert!(check(Some("*"), Some(&etag)).is_ok());
+        assert!(check(Some("*"), None).is_err());
+        assert!(check(Some("\"other\""), Some(&etag)).is_err());
+        assert!(check(Some(&format!("W/{}", etag)), Some(&etag)).is_err());
+    }
+}
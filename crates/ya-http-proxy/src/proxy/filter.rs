@@ -0,0 +1,687 @@
+//! Request and response transformations applied around request forwarding
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode, Uri};
+
+use crate::error::ServiceError;
+use crate::proxy::endpoint::Route;
+use crate::proxy::provider::AuthRequest;
+use crate::proxy::stream::ClientCert;
+use crate::proxy::{Access, Grant, Proxy, Usage};
+use ya_http_proxy_model as model;
+use ya_http_proxy_model::{Event, RevokeReason};
+
+pub use html::RewriteHtml;
+
+mod deny;
+mod html;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+/// Properties of the request being forwarded
+pub struct FilterContext {
+    /// Service name
+    pub service: String,
+    /// Remote address of the client
+    pub address: SocketAddr,
+    /// Name of the user the request is accounted to, once authorized
+    pub username: Option<String>,
+    /// Fingerprint of the user credentials, unless authorized by a credential provider
+    pub fingerprint: Option<String>,
+    pub(crate) route: Route,
+    pub(crate) proxy: Proxy,
+    /// Credentials usage of the authorized service user
+    pub(crate) usage: Option<Arc<Usage>>,
+}
+
+/// Inspects or modifies requests before they are forwarded
+pub trait RequestFilter: Debug + Send + Sync {
+    /// Returning a response stops processing and sends the response to the client
+    fn filter<'a>(
+        &'a self,
+        req: &'a mut Request<Body>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<(), Response<Body>>>;
+}
+
+/// Inspects or modifies upstream responses before they are sent to the client
+pub trait ResponseFilter: Debug + Send + Sync {
+    /// Invoked for responses received from the upstream
+    fn filter<'a>(
+        &'a self,
+        res: &'a mut Response<Body>,
+        ctx: &'a FilterContext,
+    ) -> BoxFuture<'a, ()>;
+}
+
+/// Filters applied by a proxy instance or a service, in order
+#[derive(Clone, Debug)]
+pub(crate) struct Filters {
+    pub request: Vec<Arc<dyn RequestFilter>>,
+    pub response: Vec<Arc<dyn ResponseFilter>>,
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Self {
+            request: vec![Arc::new(Authorization), Arc::new(ForwardedHeaders)],
+            response: Default::default(),
+        }
+    }
+}
+
+/// Creates filters configured for the service
+pub(crate) async fn from_service(create: &model::CreateService) -> Result<Filters, ServiceError> {
+    let mut response: Vec<Arc<dyn ResponseFilter>> = Vec::new();
+    if let Some(ref headers) = create.strip_response_headers {
+        response.push(Arc::new(StripHeaders::new(headers)?));
+    }
+    if create.rewrite_redirects == Some(true) {
+        response.push(Arc::new(RewriteRedirects::new(&create.from, &create.to)));
+    }
+    if create.rewrite_cookies == Some(true) {
+        response.push(Arc::new(RewriteCookies::new(&create.from, &create.to)));
+    }
+
+    let mut request: Vec<Arc<dyn RequestFilter>> = Vec::new();
+    if let Some(ref rules) = create.deny {
+        request.push(Arc::new(deny::Deny::new(rules)?));
+    }
+    if let Some(ref conf) = create.rewrite_html {
+        let rewrite = Arc::new(RewriteHtml::new(conf, &create.from, &create.to)?);
+        request.push(rewrite.clone());
+        response.push(rewrite);
+    }
+    request.extend(wasm_from_service(create).await?);
+
+    Ok(Filters { request, response })
+}
+
+async fn wasm_from_service(
+    create: &model::CreateService,
+) -> Result<Vec<Arc<dyn RequestFilter>>, ServiceError> {
+    let filters = match create.filters {
+        Some(ref filters) => filters,
+        None => return Ok(Vec::new()),
+    };
+
+    #[cfg(feature = "wasm")]
+    {
+        let mut loaded: Vec<Arc<dyn RequestFilter>> = Vec::with_capacity(filters.len());
+        for filter in filters {
+            loaded.push(Arc::new(wasm::Wasm::load(filter.clone()).await?));
+        }
+        Ok(loaded)
+    }
+    #[cfg(not(feature = "wasm"))]
+    {
+        if filters.is_empty() {
+            Ok(Vec::new())
+        } else {
+            Err(ServiceError::InvalidFilter(
+                "WASM support is not enabled".into(),
+            ))
+        }
+    }
+}
+
+/// Authorizes requests with service users and credential providers
+#[derive(Debug)]
+pub struct Authorization;
+
+impl Authorization {
+    async fn authorize(
+        &self,
+        req: &Request<Body>,
+        ctx: &mut FilterContext,
+    ) -> Result<(), Response<Body>> {
+        let headers = req.headers();
+        let (usage, username, fingerprint, provider, rate_limiter) = {
+            let state = ctx.proxy.state.read().await;
+            let service = match state.by_route.get(&ctx.route) {
+                Some(service) => service,
+                None => return Err(status(StatusCode::NOT_FOUND)),
+            };
+
+            // TODO: consider reading credentials from URL
+            // Extract credentials from header and authorize user
+            let api_key_header = match service.created_with.auth {
+                Some(ref auth) => auth.api_key_header(),
+                None => model::DEFAULT_API_KEY_HEADER,
+            };
+            let user = extract_access(headers)
+                .and_then(|a| service.access.get(&a))
+                .or_else(|| {
+                    extract_api_key(headers, api_key_header).and_then(|a| service.access.get(&a))
+                })
+                .or_else(|| {
+                    let cert = req.extensions().get::<ClientCert>()?;
+                    service.access.get(&Access::CertFingerprint(cert.0.clone()))
+                })
+                .and_then(|username| service.users.get(username));
+            let usage = user.map(|u| u.usage.clone());
+            let username = user.map(|u| u.username.clone());
+            let fingerprint = user.map(|u| u.fingerprint.clone());
+            (
+                usage,
+                username,
+                fingerprint,
+                service.provider.clone(),
+                service.rate_limiter.clone(),
+            )
+        };
+
+        let username = match (username, provider) {
+            (Some(username), _) => username,
+            (None, Some(provider)) => {
+                let auth_req = AuthRequest {
+                    service: &ctx.service,
+                    method: req.method(),
+                    path: req.uri().path(),
+                    headers,
+                    address: ctx.address,
+                };
+                match provider.authorize(auth_req).await {
+                    Some(username) => username,
+                    None => return Err(status(StatusCode::UNAUTHORIZED)),
+                }
+            }
+            (None, None) => return Err(status(StatusCode::UNAUTHORIZED)),
+        };
+
+        // rate limited requests do not count towards credentials usage limits
+        if let Some(limiter) = rate_limiter {
+            if let Err(retry_after) = limiter.acquire(&username) {
+                log::debug!(
+                    "[{}] rate limit exceeded by user '{}'",
+                    ctx.service,
+                    username
+                );
+                return Err(too_many_requests(retry_after));
+            }
+        }
+
+        // Check credentials usage limits
+        match usage.as_ref().map(|u| u.acquire(ctx.address)) {
+            None | Some(Grant::Allow) => (),
+            Some(Grant::Throttle) => {
+                log::debug!(
+                    "[{}] allowance exceeded by user '{}'",
+                    ctx.service,
+                    username
+                );
+                return Err(status(StatusCode::PAYMENT_REQUIRED));
+            }
+            Some(Grant::Foreign) => {
+                log::debug!(
+                    "[{}] user '{}' bound to another client",
+                    ctx.service,
+                    username
+                );
+                return Err(status(StatusCode::FORBIDDEN));
+            }
+            Some(Grant::Last(reason)) => revoke(ctx, &username, reason).await,
+            Some(Grant::Deny(reason)) => {
+                revoke(ctx, &username, reason).await;
+                return Err(match reason {
+                    RevokeReason::ByteLimit => status(StatusCode::PAYMENT_REQUIRED),
+                    _ => status(StatusCode::UNAUTHORIZED),
+                });
+            }
+        }
+
+        ctx.username = Some(username);
+        ctx.usage = usage;
+        ctx.fingerprint = fingerprint;
+        Ok(())
+    }
+}
+
+impl RequestFilter for Authorization {
+    fn filter<'a>(
+        &'a self,
+        req: &'a mut Request<Body>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<(), Response<Body>>> {
+        self.authorize(req, ctx).boxed()
+    }
+}
+
+/// Sets the `X-Forwarded-For` and `X-Forwarded-Host` headers
+#[derive(Debug)]
+pub struct ForwardedHeaders;
+
+impl RequestFilter for ForwardedHeaders {
+    fn filter<'a>(
+        &'a self,
+        req: &'a mut Request<Body>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<(), Response<Body>>> {
+        let headers = req.headers_mut();
+        // Domain name
+        let host = headers.get(header::HOST).cloned();
+
+        headers.insert(
+            HeaderName::from_static("x-forwarded-for"),
+            HeaderValue::try_from(ctx.address.ip().to_string()).unwrap(),
+        );
+
+        if let Some(host) = host {
+            headers.insert(HeaderName::from_static("x-forwarded-host"), host);
+        }
+
+        futures::future::ready(Ok(())).boxed()
+    }
+}
+
+/// Removes headers from upstream responses
+#[derive(Debug)]
+pub struct StripHeaders {
+    names: Vec<HeaderName>,
+    prefixes: Vec<String>,
+}
+
+impl StripHeaders {
+    /// Header names ending with `*` match a prefix
+    pub fn new(headers: &[String]) -> Result<Self, ServiceError> {
+        let mut names = Vec::new();
+        let mut prefixes = Vec::new();
+
+        for header in headers {
+            let name = header.strip_suffix('*').unwrap_or(header);
+            // validates the name and converts it to lowercase
+            let parsed = HeaderName::try_from(name).map_err(|_| {
+                ServiceError::InvalidFilter(format!("invalid header name: '{}'", header))
+            })?;
+
+            if name.len() == header.len() {
+                names.push(parsed);
+            } else {
+                prefixes.push(parsed.as_str().to_string());
+            }
+        }
+        Ok(Self { names, prefixes })
+    }
+
+    fn strip(&self, headers: &mut HeaderMap) {
+        for name in self.names.iter() {
+            headers.remove(name);
+        }
+        if self.prefixes.is_empty() {
+            return;
+        }
+
+        let matching: Vec<HeaderName> = headers
+            .keys()
+            .filter(|k| self.prefixes.iter().any(|p| k.as_str().starts_with(p)))
+            .cloned()
+            .collect();
+        for name in matching {
+            headers.remove(name);
+        }
+    }
+}
+
+impl ResponseFilter for StripHeaders {
+    fn filter<'a>(
+        &'a self,
+        res: &'a mut Response<Body>,
+        _ctx: &'a FilterContext,
+    ) -> BoxFuture<'a, ()> {
+        self.strip(res.headers_mut());
+        futures::future::ready(()).boxed()
+    }
+}
+
+/// Rewrites `Location` headers of redirects to the destination URL, e.g. with the service
+/// forwarding `/acme` to `http://127.0.0.1:8080/api`, `http://127.0.0.1:8080/api/login`
+/// and the root-relative `/api/login` are rewritten to `/acme/login`. Other locations
+/// are kept as is
+#[derive(Debug)]
+pub struct RewriteRedirects {
+    paths: PublicPath,
+    to: Uri,
+}
+
+impl RewriteRedirects {
+    pub fn new(from: &Uri, to: &Uri) -> Self {
+        Self {
+            paths: PublicPath::new(from, to),
+            to: to.clone(),
+        }
+    }
+
+    fn rewrite(&self, location: &str) -> Option<String> {
+        // root-relative locations refer to the destination origin
+        let root_relative = location.starts_with('/') && !location.starts_with("//");
+        let location = Uri::try_from(location).ok()?;
+        if !root_relative && !same_origin(&location, &self.to) {
+            return None;
+        }
+
+        let mut rewritten = self.paths.map(location.path())?;
+        if let Some(query) = location.query() {
+            rewritten.push('?');
+            rewritten.push_str(query);
+        }
+        Some(rewritten)
+    }
+}
+
+impl ResponseFilter for RewriteRedirects {
+    fn filter<'a>(
+        &'a self,
+        res: &'a mut Response<Body>,
+        ctx: &'a FilterContext,
+    ) -> BoxFuture<'a, ()> {
+        if res.status().is_redirection() {
+            let location = res.headers().get(header::LOCATION);
+            let rewritten = location
+                .and_then(|l| l.to_str().ok())
+                .and_then(|l| self.rewrite(l))
+                .and_then(|l| HeaderValue::try_from(l).ok());
+            if let Some(value) = rewritten {
+                log::debug!("[{}] redirect rewritten to {:?}", ctx.service, value);
+                res.headers_mut().insert(header::LOCATION, value);
+            }
+        }
+        futures::future::ready(()).boxed()
+    }
+}
+
+/// Rewrites `Set-Cookie` headers set for the destination URL, e.g. with the service
+/// forwarding `/acme` to `http://127.0.0.1:8080/api`, `Path=/api/session` is rewritten to
+/// `Path=/acme/session` and `Domain=127.0.0.1` is removed, making the cookie host-only
+#[derive(Debug)]
+pub struct RewriteCookies {
+    paths: PublicPath,
+    host: Option<String>,
+}
+
+impl RewriteCookies {
+    pub fn new(from: &Uri, to: &Uri) -> Self {
+        Self {
+            paths: PublicPath::new(from, to),
+            host: to.host().map(|h| h.to_ascii_lowercase()),
+        }
+    }
+
+    fn rewrite(&self, cookie: &str) -> Option<String> {
+        let mut parts = cookie.split(';');
+        let mut rewritten = vec![parts.next()?.trim().to_string()];
+        let mut changed = false;
+
+        for attr in parts.map(str::trim) {
+            let (name, value) = attr.split_once('=').unwrap_or((attr, ""));
+            let (name, value) = (name.trim(), value.trim());
+            if name.eq_ignore_ascii_case("path") {
+                if let Some(path) = self.paths.map(value) {
+                    rewritten.push(format!("{}={}", name, path));
+                    changed = true;
+                    continue;
+                }
+            } else if name.eq_ignore_ascii_case("domain") {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                if self.host.as_deref() == Some(domain.as_str()) {
+                    changed = true;
+                    continue;
+                }
+            }
+            rewritten.push(attr.to_string());
+        }
+        changed.then(|| rewritten.join("; "))
+    }
+}
+
+impl ResponseFilter for RewriteCookies {
+    fn filter<'a>(
+        &'a self,
+        res: &'a mut Response<Body>,
+        _ctx: &'a FilterContext,
+    ) -> BoxFuture<'a, ()> {
+        let headers = res.headers_mut();
+        if headers.contains_key(header::SET_COOKIE) {
+            let cookies: Vec<HeaderValue> = headers
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .map(|value| {
+                    value
+                        .to_str()
+                        .ok()
+                        .and_then(|c| self.rewrite(c))
+                        .and_then(|c| HeaderValue::try_from(c).ok())
+                        .unwrap_or_else(|| value.clone())
+                })
+                .collect();
+            headers.remove(header::SET_COOKIE);
+            for cookie in cookies {
+                headers.append(header::SET_COOKIE, cookie);
+            }
+        }
+        futures::future::ready(()).boxed()
+    }
+}
+
+/// Maps paths within the destination URL path to the service endpoint
+#[derive(Debug)]
+struct PublicPath {
+    from: String,
+    to: String,
+}
+
+impl PublicPath {
+    fn new(from: &Uri, to: &Uri) -> Self {
+        Self {
+            from: from.path().trim_end_matches('/').to_string(),
+            to: to.path().trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn map(&self, path: &str) -> Option<String> {
+        let rest = path.strip_prefix(self.to.as_str())?;
+        if !(rest.is_empty() || rest.starts_with('/')) {
+            return None;
+        }
+
+        let mut mapped = [self.from.as_str(), rest].concat();
+        if mapped.is_empty() {
+            mapped.push('/');
+        }
+        Some(mapped)
+    }
+}
+
+/// Compares schemes, hosts and ports, including the default ones
+fn same_origin(uri: &Uri, other: &Uri) -> bool {
+    fn port(uri: &Uri) -> Option<u16> {
+        uri.port_u16().or(match uri.scheme_str() {
+            Some("http") => Some(80),
+            Some("https") => Some(443),
+            _ => None,
+        })
+    }
+
+    match (uri.host(), other.host()) {
+        (Some(host), Some(other_host)) => {
+            uri.scheme() == other.scheme()
+                && host.eq_ignore_ascii_case(other_host)
+                && port(uri) == port(other)
+        }
+        _ => false,
+    }
+}
+
+async fn revoke(ctx: &FilterContext, username: &str, reason: RevokeReason) {
+    let service = {
+        let mut state = ctx.proxy.state.write().await;
+        state.revoke_user(&ctx.route, username)
+    };
+
+    if let Some(service) = service {
+        log::info!(
+            "[{}] credentials of user '{}' revoked: {:?}",
+            service,
+            username,
+            reason
+        );
+
+        ctx.proxy.emit(Event::UserRevoked {
+            service,
+            username: username.to_string(),
+            reason,
+        });
+    }
+}
+
+/// Builds an empty response with the status code
+pub(crate) fn status(code: StatusCode) -> Response<Body> {
+    let mut builder = Response::builder().status(code);
+
+    if code == StatusCode::UNAUTHORIZED {
+        builder = builder.header(header::WWW_AUTHENTICATE, "Basic realm=\"Service access\"");
+    }
+    builder.body(Body::empty()).unwrap()
+}
+
+/// Builds a `429 Too Many Requests` response, suggesting a retry delay in whole seconds
+fn too_many_requests(retry_after: Duration) -> Response<Body> {
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(header::RETRY_AFTER, secs.max(1))
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[inline]
+pub(super) fn extract_access(headers: &HeaderMap) -> Option<Access> {
+    let auth = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, value) = auth.split_once(' ')?;
+    let value = value.trim();
+
+    if scheme.eq_ignore_ascii_case("basic") {
+        Some(Access::basic(value))
+    } else if scheme.eq_ignore_ascii_case("bearer") {
+        Some(Access::bearer(value))
+    } else {
+        None
+    }
+}
+
+#[inline]
+fn extract_api_key(headers: &HeaderMap, header: &str) -> Option<Access> {
+    let key = headers.get(header)?.to_str().ok()?.trim();
+    (!key.is_empty()).then(|| Access::api_key(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_api_key, RewriteCookies, RewriteRedirects, StripHeaders};
+    use crate::proxy::Access;
+    use hyper::header::{HeaderValue, SERVER, SET_COOKIE};
+    use hyper::HeaderMap;
+
+    #[test]
+    fn api_key_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static(" key "));
+
+        assert_eq!(
+            extract_api_key(&headers, "X-Api-Key"),
+            Some(Access::api_key("key"))
+        );
+        assert_eq!(extract_api_key(&headers, "X-Token"), None);
+
+        headers.insert("x-api-key", HeaderValue::from_static(""));
+        assert_eq!(extract_api_key(&headers, "X-Api-Key"), None);
+    }
+
+    #[test]
+    fn strip_headers() {
+        let strip = StripHeaders::new(&["Server".to_string(), "X-Backend-*".to_string()]).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(SERVER, HeaderValue::from_static("nginx"));
+        headers.insert(SET_COOKIE, HeaderValue::from_static("id=1"));
+        headers.insert("x-backend-host", HeaderValue::from_static("10.0.0.1"));
+        headers.insert("x-backend-id", HeaderValue::from_static("1"));
+        strip.strip(&mut headers);
+
+        let names: Vec<_> = headers.keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, vec!["set-cookie"]);
+
+        assert!(StripHeaders::new(&["X Backend".to_string()]).is_err());
+    }
+
+    #[test]
+    fn rewrite_redirects() {
+        let rewrite = |from: &str, to: &str, location: &str| {
+            let filter = RewriteRedirects::new(&from.parse().unwrap(), &to.parse().unwrap());
+            filter.rewrite(location)
+        };
+        let to = "http://127.0.0.1:8080/api";
+
+        assert_eq!(
+            rewrite("/acme", to, "http://127.0.0.1:8080/api/login?next=%2F").as_deref(),
+            Some("/acme/login?next=%2F")
+        );
+        assert_eq!(
+            rewrite("/acme/", to, "HTTP://127.0.0.1:8080/api").as_deref(),
+            Some("/acme")
+        );
+        assert_eq!(
+            rewrite("/", "http://localhost/", "http://LOCALHOST:80/a").as_deref(),
+            Some("/a")
+        );
+        assert_eq!(
+            rewrite("/", to, "http://127.0.0.1:8080/api/").as_deref(),
+            Some("/")
+        );
+
+        // other origins and paths outside of the destination URL are kept
+        assert_eq!(rewrite("/acme", to, "https://127.0.0.1:8080/api/a"), None);
+        assert_eq!(rewrite("/acme", to, "http://example.com/api/a"), None);
+        assert_eq!(rewrite("/acme", to, "http://127.0.0.1:8080/apis"), None);
+        assert_eq!(rewrite("/acme", to, "/apis/login"), None);
+        assert_eq!(rewrite("/acme", to, "//127.0.0.1:8080/api/login"), None);
+        assert_eq!(rewrite("/acme", to, "login"), None);
+
+        // root-relative locations refer to the destination URL
+        assert_eq!(
+            rewrite("/acme", to, "/api/login?next=%2F").as_deref(),
+            Some("/acme/login?next=%2F")
+        );
+        assert_eq!(
+            rewrite("/", "http://localhost/", "/a").as_deref(),
+            Some("/a")
+        );
+    }
+
+    #[test]
+    fn rewrite_cookies() {
+        let filter = RewriteCookies::new(
+            &"/acme".parse().unwrap(),
+            &"http://Backend:8080/api".parse().unwrap(),
+        );
+
+        assert_eq!(
+            filter
+                .rewrite("sid=1; path=/api/session; Domain=.backend; HttpOnly")
+                .as_deref(),
+            Some("sid=1; path=/acme/session; HttpOnly")
+        );
+        assert_eq!(
+            filter.rewrite("sid=1;Path=/api;Secure").as_deref(),
+            Some("sid=1; Path=/acme; Secure")
+        );
+        // cookies for other paths and domains are kept
+        assert_eq!(filter.rewrite("sid=1; Path=/; Domain=example.com"), None);
+        assert_eq!(filter.rewrite("sid=1"), None);
+    }
+}
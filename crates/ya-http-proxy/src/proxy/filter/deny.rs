@@ -0,0 +1,155 @@
+//! Rejects requests matching service deny rules
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::header::HeaderName;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use regex::RegexSet;
+
+use crate::error::ServiceError;
+use crate::proxy::filter::{status, FilterContext, RequestFilter};
+use ya_http_proxy_model as model;
+
+#[derive(Debug)]
+pub struct Deny {
+    paths: Option<RegexSet>,
+    methods: Option<Vec<Method>>,
+    headers: Vec<HeaderName>,
+    max_header_size: Option<usize>,
+}
+
+impl Deny {
+    pub fn new(rules: &model::DenyRules) -> Result<Self, ServiceError> {
+        let invalid = |e: String| ServiceError::InvalidFilter(format!("deny rules: {}", e));
+
+        let paths = match rules.paths {
+            Some(ref paths) => Some(RegexSet::new(paths).map_err(|e| invalid(e.to_string()))?),
+            None => None,
+        };
+        let methods = match rules.methods {
+            Some(ref methods) => Some(
+                methods
+                    .iter()
+                    .map(|m| Method::try_from(m.to_uppercase().as_str()))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| invalid(e.to_string()))?,
+            ),
+            None => None,
+        };
+        let headers = rules
+            .headers
+            .iter()
+            .flatten()
+            .map(|h| HeaderName::try_from(h.as_str()))
+            .collect::<Result<_, _>>()
+            .map_err(|e| invalid(e.to_string()))?;
+
+        Ok(Self {
+            paths,
+            methods,
+            headers,
+            max_header_size: rules.max_header_size,
+        })
+    }
+
+    /// Returns the reason of rejecting the request
+    fn check(&self, req: &Request<Body>) -> Option<&'static str> {
+        if let Some(ref methods) = self.methods {
+            if !methods.contains(req.method()) {
+                return Some("method");
+            }
+        }
+        if let Some(ref paths) = self.paths {
+            if paths.is_match(req.uri().path()) {
+                return Some("path");
+            }
+        }
+
+        let headers = req.headers();
+        if self.headers.iter().any(|h| headers.contains_key(h)) {
+            return Some("header");
+        }
+        if let Some(max) = self.max_header_size {
+            let size: usize = headers
+                .iter()
+                .map(|(k, v)| k.as_str().len() + v.len())
+                .sum();
+            if size > max {
+                return Some("header size");
+            }
+        }
+        None
+    }
+}
+
+impl RequestFilter for Deny {
+    fn filter<'a>(
+        &'a self,
+        req: &'a mut Request<Body>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<(), Response<Body>>> {
+        async move {
+            if let Some(reason) = self.check(req) {
+                log::debug!("[{}] request denied by {} rule", ctx.service, reason);
+                ctx.proxy.stats.write().await.inc_blocked(&ctx.service);
+                return Err(status(StatusCode::FORBIDDEN));
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deny;
+    use hyper::{Body, Request};
+    use ya_http_proxy_model as model;
+
+    #[test]
+    fn deny_rules() {
+        let deny = Deny::new(&model::DenyRules {
+            paths: Some(vec!["^/admin".to_string(), r"\.php$".to_string()]),
+            methods: Some(vec!["get".to_string(), "POST".to_string()]),
+            headers: Some(vec!["X-Debug".to_string()]),
+            max_header_size: Some(64),
+        })
+        .unwrap();
+
+        let req = |method: &str, path: &str, header: (&str, &str)| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .header(header.0, header.1)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert_eq!(deny.check(&req("GET", "/api", ("accept", "*/*"))), None);
+        assert_eq!(
+            deny.check(&req("DELETE", "/api", ("accept", "*/*"))),
+            Some("method")
+        );
+        assert_eq!(
+            deny.check(&req("GET", "/admin/users", ("accept", "*/*"))),
+            Some("path")
+        );
+        assert_eq!(
+            deny.check(&req("GET", "/index.php", ("accept", "*/*"))),
+            Some("path")
+        );
+        assert_eq!(
+            deny.check(&req("GET", "/api", ("x-debug", "1"))),
+            Some("header")
+        );
+        assert_eq!(
+            deny.check(&req("GET", "/api", ("cookie", &"a".repeat(64)))),
+            Some("header size")
+        );
+
+        let invalid = model::DenyRules {
+            paths: Some(vec!["(".to_string()]),
+            ..Default::default()
+        };
+        assert!(Deny::new(&invalid).is_err());
+    }
+}
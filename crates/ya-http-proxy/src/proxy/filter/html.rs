@@ -0,0 +1,329 @@
+//! Rewrites HTML responses of services exposed under a sub-path
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+
+use crate::error::ServiceError;
+use crate::proxy::filter::{same_origin, FilterContext, PublicPath, RequestFilter, ResponseFilter};
+use ya_http_proxy_model as model;
+
+const DEFAULT_MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+/// Points `<base href>` at the service endpoint and applies string substitutions to HTML
+/// responses. Browsers navigating to pages are asked for uncompressed responses, since
+/// compressed ones are forwarded as is
+#[derive(Debug)]
+pub struct RewriteHtml {
+    base_href: bool,
+    paths: PublicPath,
+    to: Uri,
+    replace: Vec<(String, String)>,
+    max_body_size: usize,
+}
+
+impl RewriteHtml {
+    pub fn new(conf: &model::RewriteHtml, from: &Uri, to: &Uri) -> Result<Self, ServiceError> {
+        if conf.replace.iter().any(|s| s.from.is_empty()) {
+            return Err(ServiceError::InvalidFilter(
+                "HTML rewriting: empty substitution pattern".into(),
+            ));
+        }
+
+        Ok(Self {
+            base_href: conf.base_href,
+            paths: PublicPath::new(from, to),
+            to: to.clone(),
+            replace: conf
+                .replace
+                .iter()
+                .map(|s| (s.from.clone(), s.to.clone()))
+                .collect(),
+            max_body_size: conf.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+        })
+    }
+
+    fn applies(&self, res: &Response<Body>) -> bool {
+        let headers = res.headers();
+        let html = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim_start().to_ascii_lowercase().starts_with("text/html"))
+            .unwrap_or(false);
+        let encoded = headers
+            .get(header::CONTENT_ENCODING)
+            .map(|v| !v.as_bytes().eq_ignore_ascii_case(b"identity"))
+            .unwrap_or(false);
+
+        html && !encoded
+            && !matches!(
+                res.status(),
+                StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+            )
+    }
+
+    fn rewrite(&self, html: &str) -> String {
+        let mut html = match self.base_href {
+            true => self.rewrite_base(html),
+            false => html.to_string(),
+        };
+        for (from, to) in self.replace.iter() {
+            html = html.replace(from.as_str(), to);
+        }
+        html
+    }
+
+    /// Maps the `<base href>` within the destination URL to the service endpoint,
+    /// or inserts one pointing at the endpoint at the start of `<head>`
+    fn rewrite_base(&self, html: &str) -> String {
+        // ASCII lowercase keeps byte offsets
+        let lower = html.to_ascii_lowercase();
+
+        if let Some((start, end)) = find_tag(&lower, "base") {
+            let (value_start, value_end) = match attr_value(&lower[start..end], "href") {
+                Some((s, e)) => (start + s, start + e),
+                None => return html.to_string(),
+            };
+            return match self.map(&html[value_start..value_end]) {
+                Some(href) => [&html[..value_start], &href, &html[value_end..]].concat(),
+                None => html.to_string(),
+            };
+        }
+
+        match find_tag(&lower, "head") {
+            Some((_, end)) => {
+                let base = format!("<base href=\"{}/\">", self.paths.from);
+                [&html[..end + 1], &base, &html[end + 1..]].concat()
+            }
+            None => html.to_string(),
+        }
+    }
+
+    fn map(&self, href: &str) -> Option<String> {
+        let path = match href.parse::<Uri>() {
+            Ok(uri) if uri.host().is_some() && same_origin(&uri, &self.to) => {
+                uri.path().to_string()
+            }
+            _ if href.starts_with('/') && !href.starts_with("//") => href.to_string(),
+            _ => return None,
+        };
+        let mut mapped = self.paths.map(&path)?;
+        if path.ends_with('/') && !mapped.ends_with('/') {
+            mapped.push('/');
+        }
+        Some(mapped)
+    }
+}
+
+impl RequestFilter for RewriteHtml {
+    fn filter<'a>(
+        &'a self,
+        req: &'a mut Request<Body>,
+        _ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<(), Response<Body>>> {
+        let navigation = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_ascii_lowercase().contains("text/html"))
+            .unwrap_or(false);
+        if navigation {
+            req.headers_mut().insert(
+                header::ACCEPT_ENCODING,
+                HeaderValue::from_static("identity"),
+            );
+        }
+        futures::future::ok(()).boxed()
+    }
+}
+
+impl ResponseFilter for RewriteHtml {
+    fn filter<'a>(
+        &'a self,
+        res: &'a mut Response<Body>,
+        ctx: &'a FilterContext,
+    ) -> BoxFuture<'a, ()> {
+        async move {
+            if !self.applies(res) {
+                return;
+            }
+            let body = std::mem::take(res.body_mut());
+            let bytes = match read_limited(body, self.max_body_size).await {
+                Ok(bytes) => bytes,
+                Err(body) => {
+                    log::debug!("[{}] HTML response too large to rewrite", ctx.service);
+                    *res.body_mut() = body;
+                    return;
+                }
+            };
+            // responses to `HEAD` requests keep their `Content-Length`
+            if bytes.is_empty() {
+                return;
+            }
+
+            let rewritten = match std::str::from_utf8(&bytes) {
+                Ok(html) => self.rewrite(html),
+                Err(_) => {
+                    *res.body_mut() = Body::from(bytes);
+                    return;
+                }
+            };
+            res.headers_mut()
+                .insert(header::CONTENT_LENGTH, HeaderValue::from(rewritten.len()));
+            *res.body_mut() = Body::from(rewritten);
+        }
+        .boxed()
+    }
+}
+
+/// Reads the body up to the size limit. Larger bodies are returned for forwarding, unchanged
+async fn read_limited(mut body: Body, limit: usize) -> Result<Bytes, Body> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let head = futures::stream::iter(vec![Ok(Bytes::from(buf)), Err(e)]);
+                return Err(Body::wrap_stream(head));
+            }
+        };
+        if buf.len() + chunk.len() > limit {
+            let head = futures::stream::iter(vec![Ok(Bytes::from(buf)), Ok(chunk)]);
+            return Err(Body::wrap_stream(head.chain(body)));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.into())
+}
+
+/// Finds the start and the closing `>` of the first opening tag with the name
+fn find_tag(lower: &str, name: &str) -> Option<(usize, usize)> {
+    let open = format!("<{}", name);
+    let start = lower.match_indices(&open).map(|(i, _)| i).find(|&i| {
+        match lower.as_bytes().get(i + open.len()) {
+            Some(c) => *c == b'>' || *c == b'/' || c.is_ascii_whitespace(),
+            None => false,
+        }
+    })?;
+    let end = start + lower[start..].find('>')?;
+    Some((start, end))
+}
+
+/// Finds the value range of the attribute within a tag
+fn attr_value(tag: &str, name: &str) -> Option<(usize, usize)> {
+    let bytes = tag.as_bytes();
+    let mut offset = 0;
+
+    while let Some(i) = tag[offset..].find(name).map(|i| offset + i) {
+        offset = i + name.len();
+        // the name has to be a whole attribute name
+        if !bytes[i - 1].is_ascii_whitespace() {
+            continue;
+        }
+        let rest = &tag[offset..];
+        let value = rest.trim_start();
+        let value = match value.strip_prefix('=') {
+            Some(value) => value.trim_start(),
+            None => continue,
+        };
+        let start = tag.len() - value.len();
+
+        return match value.as_bytes().first() {
+            Some(q @ (b'"' | b'\'')) => {
+                let len = value[1..].find(*q as char)?;
+                Some((start + 1, start + 1 + len))
+            }
+            Some(_) => {
+                let len = value
+                    .find(|c: char| c.is_ascii_whitespace() || c == '>')
+                    .unwrap_or(value.len());
+                Some((start, start + len))
+            }
+            None => None,
+        };
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RewriteHtml;
+    use ya_http_proxy_model as model;
+
+    fn filter(from: &str, to: &str, conf: model::RewriteHtml) -> RewriteHtml {
+        RewriteHtml::new(&conf, &from.parse().unwrap(), &to.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn rewrite_base_href() {
+        let conf = model::RewriteHtml {
+            base_href: true,
+            ..Default::default()
+        };
+        let rewrite = filter("/acme", "http://127.0.0.1:8080/", conf.clone());
+
+        assert_eq!(
+            rewrite.rewrite("<html><HEAD lang=en><header></header></head></html>"),
+            "<html><HEAD lang=en><base href=\"/acme/\"><header></header></head></html>"
+        );
+        assert_eq!(
+            rewrite.rewrite("<head><Base target=_self HREF = '/app/'></head>"),
+            "<head><Base target=_self HREF = '/acme/app/'></head>"
+        );
+        assert_eq!(
+            rewrite.rewrite("<head><base href=http://127.0.0.1:8080/></head>"),
+            "<head><base href=/acme/></head>"
+        );
+        // relative and external base URLs are kept
+        let html = "<head><base href=\"https://cdn.example.com/\"></head>";
+        assert_eq!(rewrite.rewrite(html), html);
+        assert_eq!(rewrite.rewrite("<p>no head</p>"), "<p>no head</p>");
+
+        let rewrite = filter("/acme/", "http://127.0.0.1:8080/ui", conf);
+        assert_eq!(
+            rewrite.rewrite("<head><base href=\"/ui/\"></head>"),
+            "<head><base href=\"/acme/\"></head>"
+        );
+        assert_eq!(
+            rewrite.rewrite("<head><base data-href=\"/ui/\"></head>"),
+            "<head><base data-href=\"/ui/\"></head>"
+        );
+    }
+
+    #[test]
+    fn substitutions() {
+        let conf = model::RewriteHtml {
+            replace: vec![
+                model::Substitution {
+                    from: "=\"/static/".to_string(),
+                    to: "=\"/acme/static/".to_string(),
+                },
+                model::Substitution {
+                    from: "/acme/static/app.js".to_string(),
+                    to: "/acme/static/app.min.js".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+        let rewrite = filter("/acme", "http://127.0.0.1:8080", conf);
+        assert_eq!(
+            rewrite.rewrite("<script src=\"/static/app.js\"></script><img src=\"/static/a.png\">"),
+            "<script src=\"/acme/static/app.min.js\"></script><img src=\"/acme/static/a.png\">"
+        );
+
+        let conf = model::RewriteHtml {
+            replace: vec![model::Substitution {
+                from: String::new(),
+                to: "x".to_string(),
+            }],
+            ..Default::default()
+        };
+        assert!(RewriteHtml::new(
+            &conf,
+            &"/acme".parse().unwrap(),
+            &"http://127.0.0.1".parse().unwrap()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! Request policies implemented by sandboxed WASM modules
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use wasmtime::{Caller, Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::error::ServiceError;
+use crate::proxy::filter::{status, FilterContext, RequestFilter};
+use ya_http_proxy_model as model;
+
+const DEFAULT_FUEL: u64 = 10_000_000;
+const DEFAULT_MAX_MEMORY: u64 = 16 * 1024 * 1024;
+
+pub struct Wasm {
+    conf: model::WasmFilter,
+    module: Module,
+    linker: Arc<Linker<State>>,
+}
+
+/// Header modifications requested by the module
+struct State {
+    ops: Vec<HeaderOp>,
+    limits: StoreLimits,
+}
+
+enum HeaderOp {
+    Set(String, String),
+    Remove(String),
+}
+
+impl Wasm {
+    /// Compiles the module on a blocking thread
+    pub async fn load(conf: model::WasmFilter) -> Result<Self, ServiceError> {
+        let path = conf.path.clone();
+        tokio::task::spawn_blocking(move || Self::new(conf))
+            .await
+            .map_err(|e| ServiceError::InvalidFilter(format!("'{}': {}", path.display(), e)))?
+    }
+
+    fn new(conf: model::WasmFilter) -> Result<Self, ServiceError> {
+        let err = |e: anyhow::Error| {
+            ServiceError::InvalidFilter(format!("'{}': {}", conf.path.display(), e))
+        };
+
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(err)?;
+        let module = Module::from_file(&engine, &conf.path).map_err(err)?;
+
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap(
+                "env",
+                "set_header",
+                |mut caller: Caller<'_, State>,
+                 name: i32,
+                 name_len: i32,
+                 value: i32,
+                 value_len: i32| {
+                    let op = match (
+                        read_str(&mut caller, name, name_len),
+                        read_str(&mut caller, value, value_len),
+                    ) {
+                        (Some(name), Some(value)) => HeaderOp::Set(name, value),
+                        _ => return,
+                    };
+                    caller.data_mut().ops.push(op);
+                },
+            )
+            .map_err(err)?;
+        linker
+            .func_wrap(
+                "env",
+                "remove_header",
+                |mut caller: Caller<'_, State>, name: i32, name_len: i32| {
+                    if let Some(name) = read_str(&mut caller, name, name_len) {
+                        caller.data_mut().ops.push(HeaderOp::Remove(name));
+                    }
+                },
+            )
+            .map_err(err)?;
+
+        Ok(Self {
+            conf,
+            module,
+            linker: Arc::new(linker),
+        })
+    }
+
+    async fn apply(
+        &self,
+        req: &mut Request<Body>,
+        ctx: &mut FilterContext,
+    ) -> Result<(), Response<Body>> {
+        let request = model::WasmFilterRequest {
+            service: ctx.service.clone(),
+            method: req.method().to_string(),
+            path: req
+                .uri()
+                .path_and_query()
+                .map(|p| p.to_string())
+                .unwrap_or_default(),
+            headers: req
+                .headers()
+                .iter()
+                .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+                .collect(),
+            username: ctx.username.clone(),
+            remote_addr: ctx.address.to_string(),
+            config: self.conf.config.clone(),
+        };
+        let input = serde_json::to_vec(&request).map_err(|e| {
+            log::warn!("[{}] filter request error: {}", ctx.service, e);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+
+        let module = self.module.clone();
+        let linker = self.linker.clone();
+        let fuel = self.conf.fuel.unwrap_or(DEFAULT_FUEL);
+        let max_memory = self.conf.max_memory.unwrap_or(DEFAULT_MAX_MEMORY);
+
+        // module execution is CPU-bound
+        let result =
+            tokio::task::spawn_blocking(move || run(&module, &linker, fuel, max_memory, &input))
+                .await;
+
+        let (code, ops) = match result {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => {
+                log::warn!(
+                    "[{}] filter '{}' error: {}",
+                    ctx.service,
+                    self.conf.path.display(),
+                    e
+                );
+                return Err(status(StatusCode::INTERNAL_SERVER_ERROR));
+            }
+            Err(_) => return Err(status(StatusCode::INTERNAL_SERVER_ERROR)),
+        };
+
+        if code != 0 {
+            let code = u16::try_from(code)
+                .ok()
+                .and_then(|c| StatusCode::from_u16(c).ok())
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(status(code));
+        }
+
+        let headers = req.headers_mut();
+        for op in ops {
+            match op {
+                HeaderOp::Set(name, value) => {
+                    match (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+                        (Ok(name), Ok(value)) => {
+                            headers.insert(name, value);
+                        }
+                        _ => log::debug!("[{}] invalid filter header", ctx.service),
+                    }
+                }
+                HeaderOp::Remove(name) => {
+                    headers.remove(name.as_str());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RequestFilter for Wasm {
+    fn filter<'a>(
+        &'a self,
+        req: &'a mut Request<Body>,
+        ctx: &'a mut FilterContext,
+    ) -> BoxFuture<'a, Result<(), Response<Body>>> {
+        self.apply(req, ctx).boxed()
+    }
+}
+
+impl std::fmt::Debug for Wasm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Wasm").field("conf", &self.conf).finish()
+    }
+}
+
+/// Instantiates the module in a fresh store and calls `on_request`.
+/// Memory growth beyond `max_memory` bytes fails, running out of fuel traps
+fn run(
+    module: &Module,
+    linker: &Linker<State>,
+    fuel: u64,
+    max_memory: u64,
+    input: &[u8],
+) -> anyhow::Result<(i32, Vec<HeaderOp>)> {
+    let limits = StoreLimitsBuilder::new()
+        .memory_size(usize::try_from(max_memory).unwrap_or(usize::MAX))
+        .instances(1)
+        .build();
+    let state = State {
+        ops: Vec::new(),
+        limits,
+    };
+    let mut store = Store::new(module.engine(), state);
+    store.limiter(|state| &mut state.limits);
+    store.add_fuel(fuel)?;
+
+    let instance = linker.instantiate(&mut store, module)?;
+    let memory = instance
+        .get_memory(&mut store, "memory")
+        .ok_or_else(|| anyhow::anyhow!("missing 'memory' export"))?;
+    let alloc = instance.get_typed_func::<i32, i32, _>(&mut store, "alloc")?;
+    let on_request = instance.get_typed_func::<(i32, i32), i32, _>(&mut store, "on_request")?;
+
+    let len = i32::try_from(input.len())?;
+    let ptr = alloc.call(&mut store, len)?;
+    memory.write(&mut store, ptr as usize, input)?;
+    let code = on_request.call(&mut store, (ptr, len))?;
+
+    Ok((code, std::mem::take(&mut store.data_mut().ops)))
+}
+
+fn read_str(caller: &mut Caller<'_, State>, ptr: i32, len: i32) -> Option<String> {
+    let memory = caller.get_export("memory")?.into_memory()?;
+    let data = memory.data(&*caller);
+    let start = usize::try_from(ptr).ok()?;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+    let bytes = data.get(start..end)?;
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{run, Wasm, DEFAULT_FUEL, DEFAULT_MAX_MEMORY};
+    use ya_http_proxy_model as model;
+
+    /// Bump allocator and exported memory shared by test modules
+    const PRELUDE: &str = r#"
+        (import "env" "set_header" (func $set_header (param i32 i32 i32 i32)))
+        (import "env" "remove_header" (func $remove_header (param i32 i32)))
+        (memory (export "memory") 1)
+        (global $next (mut i32) (i32.const 1024))
+        (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+    "#;
+
+    async fn load(name: &str, on_request: &str, max_memory: Option<u64>) -> Wasm {
+        let wat = format!("(module {} {})", PRELUDE, on_request);
+        let path: PathBuf = std::env::temp_dir().join(format!(
+            "ya-http-proxy-filter-{}-{}.wasm",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+
+        let wasm = Wasm::load(model::WasmFilter {
+            path: path.clone(),
+            config: None,
+            fuel: Some(100_000),
+            max_memory,
+        })
+        .await
+        .unwrap();
+        std::fs::remove_file(path).unwrap();
+        wasm
+    }
+
+    fn call(wasm: &Wasm) -> anyhow::Result<(i32, Vec<super::HeaderOp>)> {
+        let fuel = wasm.conf.fuel.unwrap_or(DEFAULT_FUEL);
+        let max_memory = wasm.conf.max_memory.unwrap_or(DEFAULT_MAX_MEMORY);
+        run(&wasm.module, &wasm.linker, fuel, max_memory, b"{}")
+    }
+
+    #[tokio::test]
+    async fn headers_and_status() {
+        let wasm = load(
+            "headers",
+            r#"
+            (data (i32.const 0) "x-filteredyes")
+            (func (export "on_request") (param i32 i32) (result i32)
+                (call $set_header (i32.const 0) (i32.const 10) (i32.const 10) (i32.const 3))
+                (call $remove_header (i32.const 0) (i32.const 10))
+                (i32.const 0))
+            "#,
+            None,
+        )
+        .await;
+        let (code, ops) = call(&wasm).unwrap();
+        assert_eq!(code, 0);
+        assert!(matches!(
+            ops.as_slice(),
+            [super::HeaderOp::Set(n, v), super::HeaderOp::Remove(r)]
+                if n == "x-filtered" && v == "yes" && r == "x-filtered"
+        ));
+
+        let wasm = load(
+            "reject",
+            r#"(func (export "on_request") (param i32 i32) (result i32) (i32.const 403))"#,
+            None,
+        )
+        .await;
+        assert_eq!(call(&wasm).unwrap().0, 403);
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let wasm = load(
+            "spin",
+            r#"(func (export "on_request") (param i32 i32) (result i32) (loop (br 0)) (i32.const 0))"#,
+            None,
+        )
+        .await;
+        assert!(call(&wasm).is_err());
+
+        // returns the memory size in pages after trying to grow it by 4 MiB
+        let grow = r#"
+            (func (export "on_request") (param i32 i32) (result i32)
+                (drop (memory.grow (i32.const 64)))
+                (memory.size))
+            "#;
+        let wasm = load("grow", grow, None).await;
+        assert_eq!(call(&wasm).unwrap().0, 65);
+        let wasm = load("grow-limited", grow, Some(1024 * 1024)).await;
+        assert_eq!(call(&wasm).unwrap().0, 1);
+    }
+}
@@ -0,0 +1,33 @@
+//! Entry points of the fuzz targets in `fuzz/`, run with `cargo fuzz run <target>` from the
+//! crate directory. Not a stable API
+use hyper::{HeaderMap, Uri};
+
+use ya_http_proxy_model as model;
+
+/// Rewrites a request URI to the upstream URI of a service
+pub fn merge_path_and_query(req_uri: &mut Uri, from: Uri, to: Uri) -> Result<(), String> {
+    super::handler::merge_path_and_query(req_uri, from, to)
+}
+
+/// Decodes HTTP basic auth credentials
+pub fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    super::provider::basic_credentials(headers)
+}
+
+/// Returns whether the `Authorization` header yields a credentials lookup key
+pub fn extract_access(headers: &HeaderMap) -> bool {
+    super::filter::extract_access(headers).is_some()
+}
+
+/// Parses request bodies accepted by the Management API
+pub fn management_api_body(body: &[u8]) {
+    if let Ok(create) = serde_json::from_slice::<model::CreateService>(body) {
+        let _ = create.https_ports();
+        let _ = serde_json::to_vec(&create);
+    }
+    if let Ok(create) = serde_json::from_slice::<model::CreateUser>(body) {
+        let _ = serde_json::to_vec(&create);
+    }
+    let _ = serde_json::from_slice::<model::RotateCredentials>(body);
+    let _ = serde_json::from_slice::<model::Shutdown>(body);
+}
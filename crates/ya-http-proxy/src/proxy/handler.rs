@@ -1,104 +1,621 @@
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
 
-use hyper::client::HttpConnector;
-use hyper::header::{self, HeaderName, HeaderValue};
+use chrono::Utc;
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::client::ResponseFuture;
+use hyper::header::{HeaderValue, ALLOW, HOST};
 use hyper::http::uri::PathAndQuery;
 use hyper::http::Uri;
-use hyper::{Body, Client, HeaderMap, Request, Response, StatusCode};
-use tokio::sync::RwLock;
+use hyper::{Body, Method, Request, Response, StatusCode, Version};
+use tokio::sync::oneshot;
 
-use crate::proxy::{ProxyState, ProxyStats};
+use crate::proxy::access_log::AccessRecord;
+use crate::proxy::client::ConnectTiming;
+use crate::proxy::concurrency::Gate;
+use crate::proxy::filter::{status, FilterContext};
+use crate::proxy::journal::JournalRecord;
+use crate::proxy::streaming::Streaming;
+use crate::proxy::tap::TapRecord;
+use crate::proxy::transfer::Transfer;
+use crate::proxy::{endpoint, Proxy, ProxyState};
+use ya_http_proxy_model::{CreateService, Event, RequestTiming, UpstreamStats};
+
+/// Upstream error recorded when the response headers are not received in time
+const RESPONSE_TIMEOUT: &str = "response timeout";
+
+/// Methods allowed when not restricted by the service deny rules
+const DEFAULT_ALLOWED_METHODS: &[&str] =
+    &["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
 
 #[inline(always)]
 pub async fn forward_req(
+    req: Request<Body>,
+    proxy: Proxy,
+    address: SocketAddr,
+    gate: Arc<Gate>,
+) -> hyper::Result<Response<Body>> {
+    let mut access = proxy
+        .access_log
+        .clone()
+        .map(|log| AccessRecord::new(log, &req, address));
+    let result = handle_req(req, proxy, address, gate, &mut access).await;
+    // forwarded responses are logged once their body is sent, the remaining ones right away
+    if let (Some(access), Ok(res)) = (access.as_mut(), &result) {
+        access.set_status(res.status());
+    }
+    result
+}
+
+async fn handle_req(
     mut req: Request<Body>,
-    proxy_state: Arc<RwLock<ProxyState>>,
-    proxy_stats: Arc<RwLock<ProxyStats>>,
-    client: Client<HttpConnector>,
+    proxy: Proxy,
     address: SocketAddr,
+    gate: Arc<Gate>,
+    access: &mut Option<AccessRecord>,
 ) -> hyper::Result<Response<Body>> {
-    let path = req.uri().path();
-    let headers = req.headers();
-    let state = proxy_state.read().await;
+    let received = Instant::now();
+    let received_at = Utc::now();
+    let path = match normalize_path(&mut req) {
+        Some(path) => path,
+        None => {
+            log::debug!("invalid request path: {}", req.uri().path());
+            return response(StatusCode::BAD_REQUEST);
+        }
+    };
+    if let Some(key_authorization) = proxy.challenges.http(&path) {
+        return Ok(Response::new(Body::from(key_authorization)));
+    }
+    let state = proxy.state.read().await;
+
+    if req.method() == Method::OPTIONS && path == "*" {
+        return options_asterisk(&state);
+    }
 
     // Check whether the service is registered
-    let service = match state
-        .by_endpoint
-        .iter()
-        .find(|(e, _)| path.starts_with(e.as_str()))
-    {
-        Some((_, service)) => service,
+    let host = request_host(&req);
+    let (route, service) = match state.route(host.as_deref(), &path) {
+        Some(entry) => entry,
         None => return response(StatusCode::NOT_FOUND),
     };
+    // the rest of the request is handled within the service share of worker threads
+    gate.set(service.concurrency.clone());
 
-    // TODO: consider reading credentials from URL
-    // Extract credentials from header
-    let auth = match extract_basic_auth(headers) {
-        Ok(auth) => auth,
-        Err(_) => return response(StatusCode::UNAUTHORIZED),
+    let mut ctx = FilterContext {
+        service: service.created_with.name.clone(),
+        address,
+        username: None,
+        fingerprint: None,
+        route: route.clone(),
+        proxy: proxy.clone(),
+        usage: None,
     };
-    // Authorize user
-    if !service.access.contains(auth) {
-        return response(StatusCode::UNAUTHORIZED);
+    if let Some(access) = access.as_mut() {
+        access.set_service(&ctx.service);
     }
-
+    let client = service.client.clone();
+    let service_filters = service.filters.clone();
+    let policy = service.policy.clone();
+    let streaming = service.streaming.clone();
+    let tap = service.tap.clone();
+    let journal = service.journal.clone();
+    let upstream_down = service.upstream_down.clone();
+    let upstream = service.upstream.clone();
+    let metrics = service.metrics.clone();
+    let protocols = service.created_with.protocols.unwrap_or_default();
     let proxy_from = service.created_with.from.clone();
     let proxy_to = service.created_with.to.clone();
+    let timeouts = service.created_with.timeouts.as_ref();
+    let request_timeout = timeouts.and_then(|t| t.request_timeout);
+    let response_timeout = timeouts.and_then(|t| t.response_timeout);
+    let idle_timeout = timeouts.and_then(|t| t.response_idle_timeout);
+    let allowed_methods = match service.created_with.answer_options {
+        Some(true) => Some(allowed_methods(&service.created_with)),
+        _ => None,
+    };
     drop(state);
 
-    // Decode credentials
-    let decoded_auth = match decode_base64(auth) {
-        Ok(decoded_auth) => decoded_auth,
-        Err(_) => return response(StatusCode::FORBIDDEN),
-    };
-    let username = match extract_username(&decoded_auth) {
-        Ok(username) => username,
-        Err(_) => return response(StatusCode::FORBIDDEN),
-    };
+    let version = req.version();
+    if !protocols.accepts(version) {
+        log::debug!("[{}] {:?} request rejected", ctx.service, version);
+        return response(StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+    }
+    if let Some(policy) = policy {
+        if !policy.allows(address.ip()) {
+            log::debug!("[{}] address {} denied", ctx.service, address.ip());
+            proxy.stats.write().await.inc_blocked(&ctx.service);
+            return response(StatusCode::FORBIDDEN);
+        }
+    }
+    if let Some(methods) = allowed_methods.filter(|_| req.method() == Method::OPTIONS) {
+        return options(&methods);
+    }
 
-    // Domain name
-    let host = extract_host(headers);
+    let filters = proxy.filters();
+    let mut rejected = None;
+    for filter in filters.request.iter().chain(service_filters.request.iter()) {
+        if let Err(res) = filter.filter(&mut req, &mut ctx).await {
+            rejected = Some(res);
+            break;
+        }
+    }
+    if let Some(access) = access.as_mut() {
+        access.set_username(ctx.username.as_deref());
+    }
+    if let Some(res) = rejected {
+        return Ok(res);
+    }
+    let username = ctx.username.clone().unwrap_or_default();
 
     // Update request stats
     {
-        let mut stats = proxy_stats.write().await;
-        stats.inc(path, username);
+        let mut stats = proxy.stats.write().await;
+        stats.inc(&ctx.service, &path, &username);
+        stats.inc_protocol(&ctx.service, version);
+    }
+    let transfer = ctx.username.as_ref().map(|username| {
+        let usage = ctx.usage.clone();
+        Transfer::new(proxy.stats.clone(), usage, &ctx.service, &path, username)
+    });
+    if let Some(ref transfer) = transfer {
+        let body = std::mem::take(req.body_mut());
+        *req.body_mut() = transfer.request_body(body);
     }
 
-    log::debug!("[{}] {} -> {}", username, path, proxy_to);
-
-    // Write proxy headers
-    let headers = req.headers_mut();
-
-    headers.insert(
-        HeaderName::from_static("x-forwarded-for"),
-        HeaderValue::try_from(address.ip().to_string()).unwrap(),
-    );
-
-    if let Some(host) = host {
-        headers.insert(HeaderName::from_static("x-forwarded-host"), host);
+    match ctx.fingerprint {
+        Some(ref fingerprint) => log::debug!(
+            "[{}] {} -> {} (user '{}', fingerprint {})",
+            ctx.service,
+            path,
+            proxy_to,
+            username,
+            fingerprint
+        ),
+        None => log::debug!(
+            "[{}] {} -> {} (user '{}')",
+            ctx.service,
+            path,
+            proxy_to,
+            username
+        ),
     }
+    let method = req.method().clone();
 
     if let Err(e) = merge_path_and_query(req.uri_mut(), proxy_from, proxy_to) {
         log::warn!("Forwarded path error: {}", e);
         return response(StatusCode::INTERNAL_SERVER_ERROR);
     }
-    client.request(req).await
+    // the upstream protocol is determined by the service client: HTTP/1.1 requests are
+    // sent as HTTP/2 over HTTP/2 connections, along with their trailers
+    *req.version_mut() = Version::HTTP_11;
+
+    let started = Instant::now();
+    // the whole exchange, including the response body, is bound by the request timeout
+    let deadline = request_timeout.map(|timeout| received + timeout);
+    let sent = response_timeout.map(|_| {
+        let (body, sent) = notify_sent(std::mem::take(req.body_mut()));
+        *req.body_mut() = body;
+        sent
+    });
+    // the request body is first read here, so `100 Continue` is sent to clients
+    // expecting it only once the request is authorized and forwarded
+    let request = client.request(req);
+    let result = await_response(request, sent, response_timeout, deadline).await;
+    let ttfb = started.elapsed();
+    let timed_out = result.is_none();
+    let mut result = match result {
+        Some(result) => result,
+        None => {
+            log::warn!("[{}] upstream response timed out", ctx.service);
+            Ok(status(StatusCode::GATEWAY_TIMEOUT))
+        }
+    };
+    let failed = match result {
+        Ok(ref res) => res.status().is_server_error(),
+        Err(_) => true,
+    };
+    metrics.lock().unwrap().record(ttfb, failed);
+    record_upstream(&mut upstream.lock().unwrap(), &result, timed_out);
+    let streamed = match (&streaming, &result) {
+        (Some(streaming), Ok(res)) => streaming.matches(&path, res),
+        _ => false,
+    };
+
+    match result {
+        _ if timed_out => (),
+        Ok(_) if upstream_down.swap(false, Ordering::SeqCst) => {
+            log::info!("[{}] upstream recovered", ctx.service);
+            proxy.emit(Event::UpstreamRecovered {
+                service: ctx.service.clone(),
+            });
+        }
+        Err(ref e) if e.is_connect() && !upstream_down.swap(true, Ordering::SeqCst) => {
+            log::warn!("[{}] upstream unavailable: {}", ctx.service, e);
+            proxy.emit(Event::UpstreamUnavailable {
+                service: ctx.service.clone(),
+                reason: e.to_string(),
+            });
+        }
+        _ => (),
+    }
+
+    let journal = journal.map(|journal| {
+        let mut record = JournalRecord::new(journal, received_at, &path, ctx.username.as_deref());
+        if let Ok(ref res) = result {
+            record.set_status(res.status().as_u16());
+        }
+        record
+    });
+    let record = tap.map(|tap| {
+        let mut timing = RequestTiming {
+            received_at,
+            method: method.to_string(),
+            path,
+            username: ctx.username.clone(),
+            fingerprint: ctx.fingerprint.clone(),
+            status: None,
+            error: None,
+            queue: started.duration_since(received),
+            connect: None,
+            ttfb: None,
+            total: Default::default(),
+        };
+        match result {
+            Ok(ref res) if timed_out => {
+                timing.status = Some(res.status().as_u16());
+                timing.error = Some(RESPONSE_TIMEOUT.to_string());
+            }
+            Ok(ref res) => {
+                timing.status = Some(res.status().as_u16());
+                timing.ttfb = Some(ttfb);
+                // connections established for other requests are reused
+                timing.connect = res
+                    .extensions()
+                    .get::<ConnectTiming>()
+                    .filter(|c| c.started >= started)
+                    .map(|c| c.duration);
+            }
+            Err(ref e) => timing.error = Some(e.to_string()),
+        }
+        TapRecord::new(tap, received, timing)
+    });
+    if timed_out {
+        return result;
+    }
+
+    if let Ok(ref mut res) = result {
+        let response_filters = filters.response.iter();
+        for filter in response_filters.chain(service_filters.response.iter()) {
+            filter.filter(res, &ctx).await;
+        }
+        if method == Method::HEAD {
+            // keep the upstream `Content-Length`, but never send a body
+            *res.body_mut() = Body::empty();
+        } else {
+            if streamed {
+                Streaming::prepare(res);
+            }
+            // streamed responses are kept open for as long as chunks are sent
+            let timeout = BodyTimeout {
+                idle: idle_timeout,
+                deadline,
+                service: ctx.service.clone(),
+                upstream,
+            };
+            let timeout = Some(timeout).filter(|t| !streamed && t.is_set());
+            let access = access.take().map(|mut access| {
+                access.set_status(res.status());
+                access
+            });
+            pipe_body(res, timeout, record, journal, transfer, access);
+        }
+    }
+    result
 }
 
-#[inline]
-fn response(code: StatusCode) -> hyper::Result<Response<Body>> {
-    let mut builder = Response::builder().status(code);
+/// Host name the request is addressed to: the URL authority of HTTP/2 and absolute-form
+/// requests, or the `Host` header
+fn request_host(req: &Request<Body>) -> Option<String> {
+    match req.uri().authority() {
+        Some(authority) => Some(endpoint::host(authority.as_str())),
+        None => req
+            .headers()
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .map(endpoint::host),
+    }
+}
+
+/// Replaces the request path with its normalized form
+fn normalize_path(req: &mut Request<Body>) -> Option<String> {
+    let uri = req.uri();
+    if uri.path() == "*" {
+        return Some(uri.path().to_string());
+    }
+
+    let path = endpoint::normalize(uri.path())?;
+    if path != uri.path() {
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.clone(),
+        };
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).ok()?);
+        *req.uri_mut() = Uri::from_parts(parts).ok()?;
+    }
+    Some(path)
+}
+
+fn allowed_methods(create: &CreateService) -> Vec<String> {
+    match create.deny.as_ref().and_then(|d| d.methods.as_ref()) {
+        Some(methods) => methods.iter().map(|m| m.to_uppercase()).collect(),
+        None => DEFAULT_ALLOWED_METHODS
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+    }
+}
+
+/// Answers `OPTIONS *` with methods allowed by services answering `OPTIONS` requests
+fn options_asterisk(state: &ProxyState) -> hyper::Result<Response<Body>> {
+    let mut methods: Vec<String> = state
+        .by_route
+        .values()
+        .filter(|s| s.created_with.answer_options == Some(true))
+        .flat_map(|s| allowed_methods(&s.created_with))
+        .collect();
+    if methods.is_empty() {
+        return response(StatusCode::NOT_FOUND);
+    }
+    methods.sort();
+    methods.dedup();
+    options(&methods)
+}
+
+fn options(methods: &[String]) -> hyper::Result<Response<Body>> {
+    let mut res = status(StatusCode::NO_CONTENT);
+    if let Ok(allow) = HeaderValue::from_str(&methods.join(", ")) {
+        res.headers_mut().insert(ALLOW, allow);
+    }
+    Ok(res)
+}
+
+fn record_upstream(
+    stats: &mut UpstreamStats,
+    result: &hyper::Result<Response<Body>>,
+    timed_out: bool,
+) {
+    stats.requests += 1;
+    let error = match result {
+        _ if timed_out => {
+            stats.timeouts += 1;
+            RESPONSE_TIMEOUT.to_string()
+        }
+        Ok(res) if res.status().is_server_error() => {
+            stats.server_errors += 1;
+            res.status().to_string()
+        }
+        Ok(_) => return,
+        Err(e) => {
+            if e.is_connect() {
+                stats.connect_errors += 1;
+            } else if e.is_timeout() {
+                stats.timeouts += 1;
+            } else {
+                stats.other_errors += 1;
+            }
+            e.to_string()
+        }
+    };
+    stats.last_error = Some(error);
+    stats.last_error_at = Some(Utc::now());
+}
+
+fn timed_out(upstream: &Mutex<UpstreamStats>, error: &str) {
+    let mut upstream = upstream.lock().unwrap();
+    upstream.timeouts += 1;
+    upstream.last_error = Some(error.to_string());
+    upstream.last_error_at = Some(Utc::now());
+}
+
+/// Awaits the upstream response headers for at most `timeout` after the request body
+/// is sent, and until the request `deadline`. Returns `None` when timed out
+async fn await_response(
+    request: ResponseFuture,
+    sent: Option<oneshot::Receiver<()>>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+) -> Option<hyper::Result<Response<Body>>> {
+    let response = async {
+        tokio::pin!(request);
+        if let Some(sent) = sent {
+            // responses sent before the whole request body is received are not delayed
+            tokio::select! {
+                result = &mut request => return Some(result),
+                _ = sent => (),
+            }
+        }
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, request).await.ok(),
+            None => Some(request.await),
+        }
+    };
+    match deadline {
+        Some(at) => tokio::time::timeout_at(at.into(), response)
+            .await
+            .ok()
+            .flatten(),
+        None => response.await,
+    }
+}
+
+/// Wraps the request body to notify once it is sent. Requests without a body
+/// are sent along with their headers
+fn notify_sent(body: Body) -> (Body, oneshot::Receiver<()>) {
+    let (tx, rx) = oneshot::channel();
+    if body.is_end_stream() {
+        let _ = tx.send(());
+        return (body, rx);
+    }
+    let mut tx = Some(tx);
+    let body = body.chain(futures::stream::poll_fn(move |_| {
+        if let Some(tx) = tx.take() {
+            let _ = tx.send(());
+        }
+        Poll::Ready(None)
+    }));
+    (Body::wrap_stream(body), rx)
+}
+
+/// Upstream response body timeouts
+struct BodyTimeout {
+    /// Max wait time for the next body chunk
+    idle: Option<Duration>,
+    /// Time by which the whole body needs to be received
+    deadline: Option<Instant>,
+    service: String,
+    upstream: Arc<Mutex<UpstreamStats>>,
+}
+
+impl BodyTimeout {
+    fn is_set(&self) -> bool {
+        self.idle.is_some() || self.deadline.is_some()
+    }
+
+    /// Time by which the next body chunk needs to be received
+    fn next_chunk_at(&self) -> Option<Instant> {
+        let idle = self.idle.map(|timeout| Instant::now() + timeout);
+        match (idle, self.deadline) {
+            (Some(idle), Some(deadline)) => Some(idle.min(deadline)),
+            (idle, deadline) => idle.or(deadline),
+        }
+    }
+
+    fn elapsed(&self) {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                log::warn!("[{}] upstream response body timed out", self.service);
+                timed_out(&self.upstream, "request timeout");
+            }
+            _ => {
+                log::warn!(
+                    "[{}] upstream response body idle for {:?}",
+                    self.service,
+                    self.idle.unwrap_or_default()
+                );
+                timed_out(&self.upstream, "response body idle timeout");
+            }
+        }
+    }
+}
 
-    if code == StatusCode::UNAUTHORIZED {
-        builder = builder.header(header::WWW_AUTHENTICATE, "Basic realm=\"Service access\"");
+/// Forwards the upstream response body along with its trailers. Aborts the response
+/// when no body chunk is received within the idle timeout, or the body is not received
+/// by the request deadline. The request timing, journal
+/// entry, access log entry and transferred bytes are recorded once the body is sent
+/// or the client disconnects
+fn pipe_body(
+    res: &mut Response<Body>,
+    timeout: Option<BodyTimeout>,
+    record: Option<TapRecord>,
+    mut journal: Option<JournalRecord>,
+    transfer: Option<Transfer>,
+    mut access: Option<AccessRecord>,
+) {
+    if timeout.is_none()
+        && record.is_none()
+        && journal.is_none()
+        && transfer.is_none()
+        && access.is_none()
+    {
+        return;
     }
-    Ok(builder.body(Body::empty()).unwrap())
+
+    let mut body = std::mem::take(res.body_mut());
+    let (mut tx, piped) = Body::channel();
+    *res.body_mut() = piped;
+
+    tokio::spawn(async move {
+        let _record = record;
+        loop {
+            let data = async {
+                match timeout.as_ref().and_then(|t| Some((t, t.next_chunk_at()?))) {
+                    Some((timeout, at)) => tokio::time::timeout_at(at.into(), body.data())
+                        .await
+                        .map_err(|_| timeout),
+                    None => Ok(body.data().await),
+                }
+            };
+            let next = tokio::select! {
+                next = data => next,
+                // stop reading the upstream body once the client is gone
+                _ = closed(&mut tx) => return,
+            };
+            let next = match next {
+                Ok(next) => next,
+                Err(timeout) => {
+                    timeout.elapsed();
+                    return tx.abort();
+                }
+            };
+            match next {
+                Some(Ok(chunk)) => {
+                    let len = chunk.len();
+                    if let Some(ref transfer) = transfer {
+                        if !transfer.add(len) {
+                            log::debug!("response aborted, byte quota exceeded");
+                            return tx.abort();
+                        }
+                    }
+                    if tx.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                    if let Some(ref mut journal) = journal {
+                        journal.add_bytes(len);
+                    }
+                    if let Some(ref mut access) = access {
+                        access.add_bytes(len);
+                    }
+                }
+                Some(Err(_)) => return tx.abort(),
+                None => break,
+            }
+        }
+        // trailers are only exchanged over HTTP/2
+        let trailers = tokio::select! {
+            trailers = body.trailers() => trailers,
+            _ = closed(&mut tx) => return,
+        };
+        if let Ok(Some(trailers)) = trailers {
+            let _ = tx.send_trailers(trailers).await;
+        }
+    });
+}
+
+/// Resolves once the receiving end of the body is dropped, e.g. when the client disconnects
+async fn closed(tx: &mut hyper::body::Sender) {
+    futures::future::poll_fn(|cx| match tx.poll_ready(cx) {
+        Poll::Ready(Err(_)) => Poll::Ready(()),
+        _ => Poll::Pending,
+    })
+    .await
 }
 
 #[inline]
-fn merge_path_and_query(req_uri: &mut Uri, proxy_from: Uri, proxy_to: Uri) -> Result<(), String> {
+fn response(code: StatusCode) -> hyper::Result<Response<Body>> {
+    Ok(status(code))
+}
+
+#[inline]
+pub(super) fn merge_path_and_query(
+    req_uri: &mut Uri,
+    proxy_from: Uri,
+    proxy_to: Uri,
+) -> Result<(), String> {
     let from_parts = proxy_from.into_parts();
     let mut to_parts = proxy_to.into_parts();
 
@@ -168,40 +685,38 @@ fn extract_from<'a>(paq: &'a Option<&PathAndQuery>) -> &'a str {
     }
 }
 
-#[inline]
-fn decode_base64(string: &str) -> Result<String, ()> {
-    let decoded = base64::decode(string).map_err(|_| ())?;
-    String::from_utf8(decoded).map_err(|_| ())
-}
-
-#[inline]
-fn extract_host(headers: &HeaderMap) -> Option<HeaderValue> {
-    headers.get(header::HOST).cloned()
-}
-
-#[inline]
-fn extract_basic_auth(headers: &HeaderMap) -> Result<&str, ()> {
-    if let Some(Ok(auth)) = headers.get(header::AUTHORIZATION).map(|v| v.to_str()) {
-        if let Some(idx) = auth.find(' ') {
-            if auth[..idx].eq_ignore_ascii_case("basic") {
-                return Ok(auth[(idx + 1).min(auth.len())..].trim());
-            }
-        }
-    }
-    Err(())
-}
-
-#[inline]
-fn extract_username(decoded_auth: &str) -> Result<&str, ()> {
-    let mut split = decoded_auth.splitn(2, ':');
-    split.next().ok_or(())
-}
-
 #[cfg(test)]
 mod tests {
-    use super::merge_path_and_query;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::{allowed_methods, closed, merge_path_and_query, options, pipe_body, BodyTimeout};
+    use futures::StreamExt;
+    use hyper::body::HttpBody;
+    use hyper::header::ALLOW;
     use hyper::http::Uri;
+    use hyper::{Body, Response, StatusCode};
     use serde::de::StdError;
+    use ya_http_proxy_model::UpstreamStats;
+
+    /// Upstream server responding once the request body is received, after a delay
+    /// given in milliseconds by the request path
+    async fn delayed_upstream() -> std::net::SocketAddr {
+        use hyper::service::{make_service_fn, service_fn};
+
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|req: hyper::Request<Body>| async move {
+                let delay = req.uri().path()[1..].parse().unwrap_or(0);
+                hyper::body::to_bytes(req.into_body()).await?;
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                Ok::<_, hyper::Error>(Response::new(Body::from("done")))
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
 
     #[test]
     fn merge_uri_paths() -> anyhow::Result<()> {
@@ -315,4 +830,339 @@ mod tests {
 
         Ok(())
     }
+
+    /// Path merge invariants over generated endpoints, upstream URLs and requests
+    #[test]
+    fn merge_uri_invariants() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        const SEGMENTS: &[&str] = &["a", "api", "v1", "x-y_z.json"];
+        const REQUEST_SEGMENTS: &[&str] = &[
+            "a", "v1", "", ".", "..", "%2F", "%2e%2e", "~u", "@", ":8080", "evil.com", "a;b",
+        ];
+
+        fn path(rng: &mut StdRng, segments: &[&str], min: usize, max: usize) -> String {
+            (0..rng.gen_range(min..=max))
+                .map(|_| ["/", segments[rng.gen_range(0..segments.len())]].concat())
+                .collect()
+        }
+
+        let mut rng = StdRng::seed_from_u64(0x6d65_7267);
+        for _ in 0..10_000 {
+            let from = path(&mut rng, SEGMENTS, 0, 2);
+            let mut base = path(&mut rng, SEGMENTS, 0, 2);
+            if rng.gen_bool(0.5) {
+                base.push('/');
+            }
+            let rest = path(&mut rng, REQUEST_SEGMENTS, 1, 4);
+            let query = match rng.gen_range(0..3) {
+                0 => "",
+                1 => "?q=1",
+                _ => "?next=//evil.com/&a=%2F",
+            };
+            let origin = match rng.gen_bool(0.2) {
+                true => "http://evil.com:1",
+                false => "",
+            };
+
+            let from_uri: Uri = if from.is_empty() { "/" } else { &from }.parse().unwrap();
+            let to_uri: Uri = format!("http://127.0.0.1:5050{}", base).parse().unwrap();
+            let request = format!("{}{}{}{}", origin, from, rest, query);
+            let mut req_uri: Uri = request.parse().unwrap();
+
+            let result = merge_path_and_query(&mut req_uri, from_uri, to_uri.clone());
+            let ctx = format!("from '{}', to '{}', request '{}'", from, to_uri, request);
+            result.unwrap_or_else(|e| panic!("{}: {}", ctx, e));
+
+            // the request never selects a different upstream
+            assert_eq!(req_uri.scheme(), to_uri.scheme(), "{}", ctx);
+            assert_eq!(req_uri.authority(), to_uri.authority(), "{}", ctx);
+            // the upstream path is kept and followed by the remaining request path
+            let path = req_uri.path();
+            assert!(path.starts_with(base.trim_end_matches('/')), "{}", ctx);
+            assert!(path.ends_with(rest.trim_start_matches('/')), "{}", ctx);
+            assert_eq!(req_uri.query(), query.strip_prefix('?'), "{}", ctx);
+        }
+    }
+
+    #[test]
+    fn allowed_options() {
+        let mut create: ya_http_proxy_model::CreateService = serde_json::from_value(
+            serde_json::json!({"name": "svc", "bindHttp": "127.0.0.1:80", "from": "/", "to": "http://127.0.0.1:8080"}),
+        )
+        .unwrap();
+        assert_eq!(allowed_methods(&create).len(), 7);
+
+        create.deny =
+            serde_json::from_value(serde_json::json!({"methods": ["get", "head"]})).unwrap();
+        let res = options(&allowed_methods(&create)).unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()[ALLOW], "GET, HEAD");
+    }
+
+    #[tokio::test]
+    async fn idle_response_body() {
+        let (mut tx, body) = Body::channel();
+        let mut res = Response::new(body);
+        let upstream = Arc::new(Mutex::new(UpstreamStats::default()));
+        let idle = BodyTimeout {
+            idle: Some(Duration::from_millis(100)),
+            deadline: None,
+            service: "svc".to_string(),
+            upstream: upstream.clone(),
+        };
+        pipe_body(&mut res, Some(idle), None, None, None, None);
+
+        let mut body = res.into_body();
+        for chunk in ["data: 1", "data: 2"] {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            tx.send_data(chunk.into()).await.unwrap();
+            assert_eq!(body.next().await.unwrap().unwrap(), chunk);
+        }
+        assert!(body.next().await.unwrap().is_err());
+        assert!(body.next().await.is_none());
+        assert_eq!(upstream.lock().unwrap().timeouts, 1);
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn response_body_deadline() {
+        let (mut tx, body) = Body::channel();
+        let mut res = Response::new(body);
+        let upstream = Arc::new(Mutex::new(UpstreamStats::default()));
+        let timeout = BodyTimeout {
+            idle: Some(Duration::from_millis(100)),
+            deadline: Some(Instant::now() + Duration::from_millis(150)),
+            service: "svc".to_string(),
+            upstream: upstream.clone(),
+        };
+        pipe_body(&mut res, Some(timeout), None, None, None, None);
+
+        // chunks keep arriving within the idle timeout, yet the body is not complete in time
+        let mut body = res.into_body();
+        for chunk in ["data: 1", "data: 2"] {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            tx.send_data(chunk.into()).await.unwrap();
+            assert_eq!(body.next().await.unwrap().unwrap(), chunk);
+        }
+        assert!(body.next().await.unwrap().is_err());
+        let upstream = upstream.lock().unwrap();
+        assert_eq!(upstream.timeouts, 1);
+        assert_eq!(upstream.last_error.as_deref(), Some("request timeout"));
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn client_disconnect() {
+        let (mut tx, body) = Body::channel();
+        let mut res = Response::new(body);
+        let idle = BodyTimeout {
+            idle: Some(Duration::from_secs(60)),
+            deadline: None,
+            service: "svc".to_string(),
+            upstream: Default::default(),
+        };
+        pipe_body(&mut res, Some(idle), None, None, None, None);
+
+        tx.send_data("data: 1".into()).await.unwrap();
+        let mut body = res.into_body();
+        assert_eq!(body.next().await.unwrap().unwrap(), "data: 1");
+        // the upstream body is dropped along with the response body
+        drop(body);
+        tokio::time::timeout(Duration::from_secs(5), closed(&mut tx))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn response_trailers() {
+        let (mut tx, body) = Body::channel();
+        let mut res = Response::new(body);
+        let idle = BodyTimeout {
+            idle: Some(Duration::from_secs(5)),
+            deadline: None,
+            service: "svc".to_string(),
+            upstream: Default::default(),
+        };
+        pipe_body(&mut res, Some(idle), None, None, None, None);
+
+        let mut trailers = hyper::HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        tokio::spawn(async move {
+            tx.send_data("message".into()).await.unwrap();
+            tx.send_trailers(trailers).await.unwrap();
+        });
+
+        let mut body = res.into_body();
+        assert_eq!(body.data().await.unwrap().unwrap(), "message");
+        assert!(body.data().await.is_none());
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+    }
+
+    /// Upstream responses not received within the response timeout are answered
+    /// with `504 Gateway Timeout`, and still recorded in the request tap
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn response_timeout() {
+        use hyper::header::AUTHORIZATION;
+        use hyper::Request;
+
+        use crate::proxy::tests::{any_port, create_service, manager};
+        use ya_http_proxy_model as model;
+
+        let upstream = delayed_upstream().await;
+        let manager = manager();
+        let mut create = create_service("svc", "/", any_port());
+        create.to = format!("http://{}", upstream).parse().unwrap();
+        create.timeouts = Some(model::Timeouts {
+            request_timeout: None,
+            response_timeout: Some(Duration::from_millis(300)),
+            response_idle_timeout: None,
+        });
+        create.request_tap = Some(4);
+        let service: model::Service = manager.create_service(create, None).await.unwrap();
+        let addr = service.inner.bind_http.unwrap().to_vec()[0];
+        let proxy = manager.proxy("svc").await.unwrap();
+        let user = model::CreateUser::basic("user", "password");
+        proxy.add_user("svc", user, None).await.unwrap();
+
+        let client = hyper::Client::new();
+        let request = |path: &str, body: Body| {
+            Request::post(format!("http://{}{}", addr, path))
+                .header(
+                    AUTHORIZATION,
+                    format!("Basic {}", base64::encode("user:password")),
+                )
+                .body(body)
+                .unwrap()
+        };
+
+        let started = Instant::now();
+        let res = client
+            .request(request("/5000", Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // the response timeout starts once the request body is sent
+        let (mut tx, body) = Body::channel();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            tx.send_data("request".into()).await.unwrap();
+        });
+        let res = client.request(request("/50", body)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(hyper::body::to_bytes(res).await.unwrap(), "done");
+
+        let state = proxy.state.read().await;
+        let service = state.get_service("svc").unwrap();
+        let timings = service.tap.as_ref().unwrap().requests();
+        assert_eq!(timings.len(), 2);
+        assert_eq!(timings[1].status, Some(504));
+        assert_eq!(timings[1].error.as_deref(), Some("response timeout"));
+        assert_eq!(timings[0].status, Some(200));
+        let upstream = service.upstream.lock().unwrap().clone();
+        assert_eq!((upstream.requests, upstream.timeouts), (2, 1));
+        drop(state);
+
+        manager.stop(Some(Duration::from_secs(5))).await;
+    }
+
+    /// Filters added to a running proxy instance apply to the following requests
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn custom_filters() {
+        use futures::future::BoxFuture;
+        use futures::FutureExt;
+        use hyper::header::{HeaderValue, AUTHORIZATION};
+        use hyper::Request;
+
+        use crate::proxy::filter::{FilterContext, RequestFilter, ResponseFilter};
+        use crate::proxy::tests::{any_port, create_service, manager};
+        use ya_http_proxy_model as model;
+
+        /// Rejects requests carrying the `x-reject` header
+        #[derive(Debug)]
+        struct Reject;
+
+        impl RequestFilter for Reject {
+            fn filter<'a>(
+                &'a self,
+                req: &'a mut Request<Body>,
+                _ctx: &'a mut FilterContext,
+            ) -> BoxFuture<'a, Result<(), Response<Body>>> {
+                let rejected = req.headers().contains_key("x-reject");
+                async move {
+                    match rejected {
+                        true => {
+                            let mut res = Response::new(Body::empty());
+                            *res.status_mut() = StatusCode::IM_A_TEAPOT;
+                            Err(res)
+                        }
+                        false => Ok(()),
+                    }
+                }
+                .boxed()
+            }
+        }
+
+        /// Tags responses with the name of the service user
+        #[derive(Debug)]
+        struct Tag;
+
+        impl ResponseFilter for Tag {
+            fn filter<'a>(
+                &'a self,
+                res: &'a mut Response<Body>,
+                ctx: &'a FilterContext,
+            ) -> BoxFuture<'a, ()> {
+                let user = ctx.username.as_deref().unwrap_or_default();
+                let value = HeaderValue::from_str(user).unwrap();
+                res.headers_mut().insert("x-user", value);
+                futures::future::ready(()).boxed()
+            }
+        }
+
+        let upstream = delayed_upstream().await;
+        let manager = manager();
+        let mut create = create_service("svc", "/", any_port());
+        create.to = format!("http://{}", upstream).parse().unwrap();
+        let service: model::Service = manager.create_service(create, None).await.unwrap();
+        let addr = service.inner.bind_http.unwrap().to_vec()[0];
+        let proxy = manager.proxy("svc").await.unwrap();
+        let user = model::CreateUser::basic("user", "password");
+        proxy.add_user("svc", user, None).await.unwrap();
+        proxy.add_request_filter(Reject);
+        proxy.add_response_filter(Tag);
+
+        let client = hyper::Client::new();
+        let request = |reject: bool| {
+            let mut req = Request::get(format!("http://{}/", addr)).header(
+                AUTHORIZATION,
+                format!("Basic {}", base64::encode("user:password")),
+            );
+            if reject {
+                req = req.header("x-reject", "1");
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        let res = client.request(request(false)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-user"], "user");
+        assert_eq!(hyper::body::to_bytes(res).await.unwrap(), "done");
+
+        // rejected requests are neither forwarded nor passed to response filters
+        let res = client.request(request(true)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::IM_A_TEAPOT);
+        assert!(!res.headers().contains_key("x-user"));
+
+        let state = proxy.state.read().await;
+        let service = state.get_service("svc").unwrap();
+        assert_eq!(service.upstream.lock().unwrap().requests, 1);
+        drop(state);
+
+        manager.stop(Some(Duration::from_secs(5))).await;
+    }
 }
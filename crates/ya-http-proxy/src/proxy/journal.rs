@@ -0,0 +1,291 @@
+//! Binary journal of forwarded requests, exported to settle usage disputes
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, TimeZone, Utc};
+use sha3::{Digest, Sha3_256};
+
+use crate::error::ServiceError;
+use crate::proxy::rotation::RotatedFile;
+use ya_http_proxy_model as model;
+
+/// Journal file header
+const MAGIC: &[u8; 4] = b"YHJ1";
+const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 8;
+/// Number of path digest bytes stored in a record
+const PATH_HASH_LEN: usize = 8;
+/// Record size without the username: timestamp, status, bytes, path hash and username length
+const RECORD_LEN: usize = 8 + 2 + 8 + PATH_HASH_LEN + 1;
+
+/// Journal entry, as stored in a file
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    timestamp: i64,
+    status: u16,
+    bytes: u64,
+    path_hash: [u8; PATH_HASH_LEN],
+    username: String,
+}
+
+impl Entry {
+    fn new(received_at: DateTime<Utc>, path: &str, username: Option<&str>) -> Self {
+        let mut path_hash = [0u8; PATH_HASH_LEN];
+        path_hash.copy_from_slice(&Sha3_256::digest(path.as_bytes())[..PATH_HASH_LEN]);
+        Self {
+            timestamp: received_at.timestamp_millis(),
+            status: 0,
+            bytes: 0,
+            path_hash,
+            username: username.unwrap_or_default().to_string(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let username = self.username.as_bytes();
+        let username = &username[..username.len().min(u8::MAX as usize)];
+
+        let mut buf = Vec::with_capacity(RECORD_LEN + username.len());
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        buf.extend_from_slice(&self.status.to_le_bytes());
+        buf.extend_from_slice(&self.bytes.to_le_bytes());
+        buf.extend_from_slice(&self.path_hash);
+        buf.push(username.len() as u8);
+        buf.extend_from_slice(username);
+        buf
+    }
+
+    /// Decodes an entry from the beginning of `buf`, returning the number of bytes read.
+    /// Returns `None` for a truncated record
+    fn decode(buf: &[u8]) -> Option<(Self, usize)> {
+        let fixed = buf.get(..RECORD_LEN)?;
+        let len = RECORD_LEN + fixed[RECORD_LEN - 1] as usize;
+        let username = buf.get(RECORD_LEN..len)?;
+
+        let mut path_hash = [0u8; PATH_HASH_LEN];
+        path_hash.copy_from_slice(&fixed[18..18 + PATH_HASH_LEN]);
+        let entry = Self {
+            timestamp: i64::from_le_bytes(fixed[..8].try_into().ok()?),
+            status: u16::from_le_bytes(fixed[8..10].try_into().ok()?),
+            bytes: u64::from_le_bytes(fixed[10..18].try_into().ok()?),
+            path_hash,
+            username: String::from_utf8_lossy(username).into_owned(),
+        };
+        Some((entry, len))
+    }
+}
+
+impl From<Entry> for model::JournalEntry {
+    fn from(entry: Entry) -> Self {
+        Self {
+            timestamp: Utc
+                .timestamp_millis_opt(entry.timestamp)
+                .single()
+                .unwrap_or_default(),
+            username: Some(entry.username).filter(|u| !u.is_empty()),
+            path_hash: entry
+                .path_hash
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            bytes: entry.bytes,
+            status: Some(entry.status).filter(|s| *s != 0),
+        }
+    }
+}
+
+/// Service request journal, rotated by size
+#[derive(Debug)]
+pub(crate) struct Journal {
+    service: String,
+    file: Mutex<RotatedFile>,
+}
+
+impl Journal {
+    pub fn open(service: &str, conf: &model::RequestJournal) -> Result<Self, ServiceError> {
+        let invalid = |e: io::Error| {
+            ServiceError::InvalidJournal(format!("'{}': {}", conf.path.display(), e))
+        };
+        fs::create_dir_all(&conf.path).map_err(invalid)?;
+
+        let file = RotatedFile::open(
+            conf.path
+                .join(format!("{}.journal", model::file_name(service))),
+            MAGIC,
+            conf.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+            conf.max_files.unwrap_or(DEFAULT_MAX_FILES),
+        )
+        .map_err(invalid)?;
+
+        Ok(Self {
+            service: service.to_string(),
+            file: Mutex::new(file),
+        })
+    }
+
+    fn write(&self, entry: &Entry) -> io::Result<()> {
+        self.file.lock().unwrap().write(&entry.encode())
+    }
+
+    /// Reads entries of requests received in the `[from, to)` time range, oldest first
+    pub async fn export(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> io::Result<Vec<model::JournalEntry>> {
+        let from = from.map(|t| t.timestamp_millis()).unwrap_or(i64::MIN);
+        let to = to.map(|t| t.timestamp_millis()).unwrap_or(i64::MAX);
+        // files are read without holding up requests being journaled
+        let files = self.open_files()?;
+        tokio::task::spawn_blocking(move || read_entries(files, from, to))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Opens the journal files, oldest first. Opened files can be read after being rotated
+    fn open_files(&self) -> io::Result<Vec<File>> {
+        // prevent rotation while opening
+        let file = self.file.lock().unwrap();
+
+        let mut files = Vec::new();
+        for i in (0..=file.max_files()).rev() {
+            match File::open(file.path(i)) {
+                Ok(file) => files.push(file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(files)
+    }
+}
+
+fn read_entries(files: Vec<File>, from: i64, to: i64) -> io::Result<Vec<model::JournalEntry>> {
+    let mut entries = Vec::new();
+    for mut file in files {
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut records = match buf.strip_prefix(MAGIC.as_slice()) {
+            Some(records) => records,
+            None => continue,
+        };
+        while let Some((entry, len)) = Entry::decode(records) {
+            records = &records[len..];
+            if entry.timestamp >= from && entry.timestamp < to {
+                entries.push(entry.into());
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Journal entry of a forwarded request, written when dropped
+pub(crate) struct JournalRecord {
+    journal: Arc<Journal>,
+    entry: Entry,
+}
+
+impl JournalRecord {
+    pub fn new(
+        journal: Arc<Journal>,
+        received_at: DateTime<Utc>,
+        path: &str,
+        username: Option<&str>,
+    ) -> Self {
+        Self {
+            journal,
+            entry: Entry::new(received_at, path, username),
+        }
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.entry.status = status;
+    }
+
+    pub fn add_bytes(&mut self, bytes: usize) {
+        self.entry.bytes += bytes as u64;
+    }
+}
+
+impl Drop for JournalRecord {
+    fn drop(&mut self) {
+        if let Err(e) = self.journal.write(&self.entry) {
+            log::warn!(
+                "[{}] unable to write request journal: {}",
+                self.journal.service,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::{Entry, Journal, JournalRecord, MAGIC, RECORD_LEN};
+    use ya_http_proxy_model as model;
+
+    #[test]
+    fn entry_encoding() {
+        let received_at = Utc.timestamp_millis_opt(1_650_000_000_123).unwrap();
+        let mut entry = Entry::new(received_at, "/api/v1", Some("user"));
+        entry.status = 200;
+        entry.bytes = 1024;
+
+        let encoded = entry.encode();
+        assert_eq!(encoded.len(), RECORD_LEN + 4);
+        assert_eq!(
+            Entry::decode(&encoded),
+            Some((entry.clone(), encoded.len()))
+        );
+        assert!(Entry::decode(&encoded[..encoded.len() - 1]).is_none());
+
+        let exported = model::JournalEntry::from(entry);
+        assert_eq!(exported.timestamp, received_at);
+        assert_eq!(exported.username.as_deref(), Some("user"));
+        assert_eq!(exported.path_hash.len(), 16);
+        assert_eq!(exported.status, Some(200));
+    }
+
+    #[tokio::test]
+    async fn rotation_and_export() {
+        let dir =
+            std::env::temp_dir().join(format!("ya-http-proxy-journal-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let conf = model::RequestJournal {
+            path: dir.clone(),
+            max_file_size: Some((MAGIC.len() + 2 * (RECORD_LEN + 4)) as u64),
+            max_files: Some(1),
+        };
+        let journal = Arc::new(Journal::open("svc/1", &conf).unwrap());
+
+        let start = Utc.timestamp_millis_opt(1_650_000_000_000).unwrap();
+        for i in 0..5 {
+            let received_at = start + Duration::seconds(i);
+            let mut record = JournalRecord::new(journal.clone(), received_at, "/", Some("user"));
+            record.set_status(200);
+            record.add_bytes(i as usize);
+        }
+
+        // the oldest file was removed
+        let entries = journal.export(None, None).await.unwrap();
+        let bytes: Vec<_> = entries.iter().map(|e| e.bytes).collect();
+        assert_eq!(bytes, vec![2, 3, 4]);
+
+        let from = start + Duration::seconds(3);
+        let entries = journal
+            .export(Some(from), Some(from + Duration::seconds(1)))
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].bytes, 3);
+
+        drop(journal);
+        let journal = Journal::open("svc/1", &conf).unwrap();
+        assert_eq!(journal.export(None, None).await.unwrap().len(), 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
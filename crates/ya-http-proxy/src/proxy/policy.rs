@@ -0,0 +1,265 @@
+//! Client address access policies
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::ServiceError;
+use ya_http_proxy_model as model;
+
+/// Service access policy evaluated against the client address
+#[derive(Debug)]
+pub(crate) struct Policy {
+    allow: Option<Vec<Cidr>>,
+    deny: Vec<Cidr>,
+    countries: Option<Countries>,
+}
+
+impl Policy {
+    pub fn new(
+        policy: &model::AddressPolicy,
+        geoip_database: Option<&Path>,
+    ) -> Result<Self, ServiceError> {
+        let parse = |ranges: &Vec<String>| -> Result<Vec<Cidr>, ServiceError> {
+            ranges.iter().map(|r| r.parse()).collect()
+        };
+
+        let allow = match policy.allow {
+            Some(ref ranges) => Some(parse(ranges)?),
+            None => None,
+        };
+        let deny = match policy.deny {
+            Some(ref ranges) => parse(ranges)?,
+            None => Vec::new(),
+        };
+
+        let countries = if policy.allow_countries.is_some() || policy.deny_countries.is_some() {
+            let path = geoip_database.ok_or_else(|| {
+                ServiceError::InvalidPolicy("GeoIP database is not configured".into())
+            })?;
+            Some(Countries {
+                geoip: GeoIp::open(path)?,
+                allow: policy.allow_countries.as_ref().map(|c| upper(c)),
+                deny: policy.deny_countries.as_ref().map(|c| upper(c)),
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            allow,
+            deny,
+            countries,
+        })
+    }
+
+    /// Checks whether the client address is allowed to access the service.
+    /// Denied ranges and countries take precedence over allowed ones
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        if let Some(ref allow) = self.allow {
+            if !allow.iter().any(|c| c.contains(ip)) {
+                return false;
+            }
+        }
+        match self.countries {
+            Some(ref countries) => countries.allows(ip),
+            None => true,
+        }
+    }
+}
+
+/// Country rules resolved with a GeoIP database
+#[derive(Debug)]
+struct Countries {
+    geoip: GeoIp,
+    allow: Option<Vec<String>>,
+    deny: Option<Vec<String>>,
+}
+
+impl Countries {
+    fn allows(&self, ip: IpAddr) -> bool {
+        let country = self.geoip.country(ip);
+        let listed = |list: &Vec<String>| match country {
+            Some(ref country) => list.contains(country),
+            None => false,
+        };
+
+        if self.deny.as_ref().map(listed).unwrap_or(false) {
+            return false;
+        }
+        // addresses of unknown origin are not allowed
+        self.allow.as_ref().map(listed).unwrap_or(true)
+    }
+}
+
+#[cfg(feature = "geoip")]
+struct GeoIp(maxminddb::Reader<Vec<u8>>);
+
+#[cfg(feature = "geoip")]
+impl GeoIp {
+    fn open(path: &Path) -> Result<Self, ServiceError> {
+        let reader = maxminddb::Reader::open_readfile(path).map_err(|e| {
+            ServiceError::InvalidPolicy(format!("GeoIP database '{}': {}", path.display(), e))
+        })?;
+        Ok(Self(reader))
+    }
+
+    /// Returns the ISO code of the country the address is located in
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let country: maxminddb::geoip2::Country = self.0.lookup(ip).ok()?;
+        country.country?.iso_code.map(|c| c.to_uppercase())
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl std::fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("GeoIp").finish()
+    }
+}
+
+#[cfg(not(feature = "geoip"))]
+#[derive(Debug)]
+struct GeoIp;
+
+#[cfg(not(feature = "geoip"))]
+impl GeoIp {
+    fn open(_path: &Path) -> Result<Self, ServiceError> {
+        Err(ServiceError::InvalidPolicy(
+            "GeoIP support is not enabled".into(),
+        ))
+    }
+
+    fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+}
+
+/// IP address range in the CIDR notation. Single addresses are also accepted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ServiceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ServiceError::InvalidPolicy(format!("invalid address range: '{}'", s));
+
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = canonical(addr.trim().parse().map_err(|_| invalid())?);
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => max,
+        };
+
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses to IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo)))
+            }
+            _ => IpAddr::V6(v6),
+        },
+        ip => ip,
+    }
+}
+
+fn upper(codes: &[String]) -> Vec<String> {
+    codes.iter().map(|c| c.to_uppercase()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cidr, Policy};
+    use std::net::IpAddr;
+    use ya_http_proxy_model as model;
+
+    #[test]
+    fn cidr_ranges() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(!cidr.contains(ip("10.2.0.1")));
+        assert!(!cidr.contains(ip("::1")));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(ip("2001:db8:1::1")));
+        assert!(!cidr.contains(ip("2001:db9::1")));
+
+        let cidr: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(cidr.contains(ip("192.168.1.1")));
+
+        let cidr: Cidr = "192.168.1.1".parse().unwrap();
+        assert!(cidr.contains(ip("192.168.1.1")));
+        assert!(!cidr.contains(ip("192.168.1.2")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn address_policy() {
+        let policy = Policy::new(
+            &model::AddressPolicy {
+                allow: Some(vec!["10.0.0.0/8".to_string()]),
+                deny: Some(vec!["10.0.1.0/24".to_string()]),
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+
+        assert!(policy.allows("10.0.0.1".parse().unwrap()));
+        assert!(policy.allows("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!policy.allows("10.0.1.1".parse().unwrap()));
+        assert!(!policy.allows("192.168.0.1".parse().unwrap()));
+
+        let countries = model::AddressPolicy {
+            allow_countries: Some(vec!["PL".to_string()]),
+            ..Default::default()
+        };
+        assert!(Policy::new(&countries, None).is_err());
+    }
+}
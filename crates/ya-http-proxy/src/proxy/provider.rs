@@ -0,0 +1,195 @@
+//! Service user sources other than the Management API
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use hyper::header::{self, HeaderName};
+use hyper::{HeaderMap, Method};
+
+use crate::conf::ClientConf;
+use crate::error::ServiceError;
+use ya_http_proxy_model as model;
+
+mod external;
+mod htpasswd;
+mod jwt;
+#[cfg(feature = "ldap")]
+mod ldap;
+
+const CACHE_PRUNE_SIZE: usize = 1024;
+
+/// Request properties passed to credential providers
+#[derive(Clone, Copy)]
+pub struct AuthRequest<'a> {
+    pub service: &'a str,
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub headers: &'a HeaderMap,
+    pub address: SocketAddr,
+}
+
+/// Authorizes requests against an external user store
+pub trait CredentialProvider: Debug + Send + Sync {
+    /// Returns the name of the user to account the request to
+    fn authorize<'a>(&'a self, req: AuthRequest<'a>) -> BoxFuture<'a, Option<String>>;
+}
+
+/// Creates a credential provider for the service, if configured
+pub fn from_service(
+    create: &model::CreateService,
+    conf: &ClientConf,
+) -> Result<Option<Arc<dyn CredentialProvider>>, ServiceError> {
+    let auth = match create.auth {
+        Some(ref auth) => auth,
+        None => return Ok(None),
+    };
+    if HeaderName::from_bytes(auth.api_key_header().as_bytes()).is_err() {
+        return Err(ServiceError::InvalidAuth(format!(
+            "invalid API key header: {}",
+            auth.api_key_header()
+        )));
+    }
+
+    let provider: Arc<dyn CredentialProvider> = match (&auth.method, &auth.users) {
+        (model::AuthMethod::External, None) => {
+            let webhook = auth.webhook.clone().ok_or_else(|| {
+                ServiceError::InvalidAuth("missing authorization webhook configuration".into())
+            })?;
+            Arc::new(external::ExternalAuth::new(webhook, conf)?)
+        }
+        (model::AuthMethod::External, Some(_)) => {
+            return Err(ServiceError::InvalidAuth(
+                "user source cannot be combined with the authorization webhook".into(),
+            ))
+        }
+        (model::AuthMethod::Jwt, None) => {
+            let jwt = auth.jwt.as_ref().ok_or_else(|| {
+                ServiceError::InvalidAuth("missing JWT verification configuration".into())
+            })?;
+            Arc::new(jwt::JwtAuth::new(jwt, conf)?)
+        }
+        (model::AuthMethod::Jwt, Some(_)) => {
+            return Err(ServiceError::InvalidAuth(
+                "user source cannot be combined with JWT auth".into(),
+            ))
+        }
+        (
+            _,
+            Some(model::UserSource::Htpasswd {
+                path,
+                refresh_interval,
+            }),
+        ) => Arc::new(htpasswd::Htpasswd::new(path.clone(), *refresh_interval)?),
+        #[cfg(feature = "ldap")]
+        (
+            _,
+            Some(model::UserSource::Ldap {
+                url,
+                bind_dn,
+                cache_ttl,
+            }),
+        ) => Arc::new(ldap::Ldap::new(url.clone(), bind_dn.clone(), *cache_ttl)?),
+        #[cfg(not(feature = "ldap"))]
+        (_, Some(model::UserSource::Ldap { .. })) => {
+            return Err(ServiceError::InvalidAuth(
+                "LDAP support is not enabled".into(),
+            ))
+        }
+        (_, Some(_)) => return Err(ServiceError::InvalidAuth("unsupported user source".into())),
+        (_, None) => return Ok(None),
+    };
+
+    Ok(Some(provider))
+}
+
+/// Extracts and decodes HTTP basic auth credentials
+pub(crate) fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let auth = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, value) = auth.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = base64::decode(value.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Authorization verdict cache
+#[derive(Debug)]
+pub(crate) struct VerdictCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Verdict>>,
+}
+
+#[derive(Clone, Debug)]
+struct Verdict {
+    username: Option<String>,
+    expires_at: Instant,
+}
+
+impl VerdictCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    /// Returns a cached verdict: `Some(None)` stands for a denied request
+    pub fn get(&self, key: &str) -> Option<Option<String>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|v| v.expires_at > Instant::now())
+            .map(|v| v.username.clone())
+    }
+
+    pub fn insert(&self, key: String, username: Option<String>) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= CACHE_PRUNE_SIZE {
+            entries.retain(|_, v| v.expires_at > now);
+        }
+        entries.insert(
+            key,
+            Verdict {
+                username,
+                expires_at: now + self.ttl,
+            },
+        );
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::basic_credentials;
+    use hyper::header::{HeaderValue, AUTHORIZATION};
+    use hyper::HeaderMap;
+
+    #[test]
+    fn decode_basic_credentials() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(basic_credentials(&headers), None);
+
+        let encoded = base64::encode("user:pass:word");
+        let value = format!("Basic {}", encoded);
+        headers.insert(AUTHORIZATION, HeaderValue::try_from(value).unwrap());
+        assert_eq!(
+            basic_credentials(&headers),
+            Some(("user".to_string(), "pass:word".to_string()))
+        );
+
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer token"));
+        assert_eq!(basic_credentials(&headers), None);
+    }
+}
@@ -0,0 +1,214 @@
+//! Request authorization delegated to an external webhook
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::client::HttpConnector;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+
+use crate::conf::ClientConf;
+use crate::error::ServiceError;
+use crate::proxy::provider::{AuthRequest, CredentialProvider, VerdictCache};
+use crate::proxy::{client, digest};
+use ya_http_proxy_model as model;
+
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// User name assigned to requests when the webhook does not provide one
+const DEFAULT_USERNAME: &str = "external";
+
+pub struct ExternalAuth {
+    conf: model::AuthWebhook,
+    client: Client<HttpsConnector<HttpConnector>>,
+    cache: VerdictCache,
+}
+
+impl ExternalAuth {
+    pub fn new(conf: model::AuthWebhook, client_conf: &ClientConf) -> Result<Self, ServiceError> {
+        let client =
+            client::build_tls(client_conf).map_err(|e| ServiceError::InvalidAuth(e.to_string()))?;
+        let cache = VerdictCache::new(conf.cache_ttl.unwrap_or(DEFAULT_CACHE_TTL));
+
+        Ok(Self {
+            conf,
+            client,
+            cache,
+        })
+    }
+
+    async fn verify(&self, req: AuthRequest<'_>) -> Option<String> {
+        // requests without credentials are authorized by the webhook as well
+        let auth = match req.headers.get(header::AUTHORIZATION) {
+            Some(auth) => auth.to_str().ok()?,
+            None => "",
+        };
+        // verdicts may depend on the requested resource
+        let key = digest(&format!("{}\n{}\n{}", auth, req.method, req.path));
+
+        if let Some(verdict) = self.cache.get(&key) {
+            return verdict;
+        }
+
+        let request = model::ExternalAuthRequest {
+            service: req.service.to_string(),
+            method: req.method.to_string(),
+            path: req.path.to_string(),
+            remote_addr: req.address.to_string(),
+            headers: req
+                .headers
+                .iter()
+                .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+                .collect(),
+        };
+
+        let response = match self.request(&request).await {
+            Ok(response) => response,
+            Err(e) => {
+                log::warn!("[{}] authorization webhook error: {}", req.service, e);
+                return None;
+            }
+        };
+
+        let username = response.allow.then(|| {
+            response
+                .username
+                .unwrap_or_else(|| DEFAULT_USERNAME.to_string())
+        });
+        self.cache.insert(key, username.clone());
+        username
+    }
+
+    async fn request(
+        &self,
+        request: &model::ExternalAuthRequest,
+    ) -> Result<model::ExternalAuthResponse, String> {
+        let body = serde_json::to_vec(request).map_err(|e| e.to_string())?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.conf.url.clone())
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )
+            .body(Body::from(body))
+            .map_err(|e| e.to_string())?;
+
+        let timeout = self.conf.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        let fut = async {
+            let res = self.client.request(req).await.map_err(|e| e.to_string())?;
+            if !res.status().is_success() {
+                return Err(format!("status {}", res.status()));
+            }
+            let bytes = hyper::body::to_bytes(res.into_body())
+                .await
+                .map_err(|e| e.to_string())?;
+            serde_json::from_slice(bytes.as_ref()).map_err(|e| e.to_string())
+        };
+
+        tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| "timed out".to_string())?
+    }
+}
+
+impl CredentialProvider for ExternalAuth {
+    fn authorize<'a>(&'a self, req: AuthRequest<'a>) -> BoxFuture<'a, Option<String>> {
+        self.verify(req).boxed()
+    }
+}
+
+impl std::fmt::Debug for ExternalAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExternalAuth")
+            .field("conf", &self.conf)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use hyper::header::{HeaderValue, AUTHORIZATION};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, HeaderMap, Method, Request, Response, Server};
+
+    use super::ExternalAuth;
+    use crate::conf::ClientConf;
+    use crate::proxy::provider::AuthRequest;
+    use ya_http_proxy_model as model;
+
+    /// Webhook allowing `GET` requests and anonymous requests to `/public`
+    async fn webhook(calls: Arc<AtomicUsize>) -> SocketAddr {
+        let make_service = make_service_fn(move |_| {
+            let calls = calls.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let calls = calls.clone();
+                    async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        let req: model::ExternalAuthRequest =
+                            serde_json::from_slice(&body).unwrap();
+                        let allow = match req.headers.get("authorization") {
+                            Some(_) => req.method == "GET",
+                            None => req.path == "/public",
+                        };
+                        let res = model::ExternalAuthResponse {
+                            allow,
+                            username: Some("user".to_string()),
+                        };
+                        let body = serde_json::to_vec(&res).unwrap();
+                        Ok::<_, hyper::Error>(Response::new(Body::from(body)))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn webhook_verdicts() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let addr = webhook(calls.clone()).await;
+        let conf = model::AuthWebhook {
+            url: format!("http://{}/verify", addr).parse().unwrap(),
+            cache_ttl: None,
+            timeout: None,
+        };
+        let auth = ExternalAuth::new(conf, &ClientConf::default()).unwrap();
+
+        let mut credentials = HeaderMap::new();
+        credentials.insert(AUTHORIZATION, HeaderValue::from_static("Bearer token"));
+        let anonymous = HeaderMap::new();
+        let verify = |headers, method, path| {
+            auth.verify(AuthRequest {
+                service: "svc",
+                method,
+                path,
+                headers,
+                address: "127.0.0.1:10000".parse().unwrap(),
+            })
+        };
+
+        let user = Some("user".to_string());
+        assert_eq!(verify(&credentials, &Method::GET, "/a").await, user);
+        assert_eq!(verify(&credentials, &Method::GET, "/a").await, user);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // verdicts are cached per method and path
+        assert_eq!(verify(&credentials, &Method::DELETE, "/a").await, None);
+        assert_eq!(verify(&credentials, &Method::GET, "/b").await, user);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        assert_eq!(verify(&anonymous, &Method::GET, "/public").await, user);
+        assert_eq!(verify(&anonymous, &Method::GET, "/private").await, None);
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+}
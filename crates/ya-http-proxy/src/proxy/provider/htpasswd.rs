@@ -0,0 +1,266 @@
+//! Users sourced from an Apache `.htpasswd` file
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+use crate::error::ServiceError;
+use crate::proxy::digest;
+use crate::proxy::provider::{basic_credentials, AuthRequest, CredentialProvider, VerdictCache};
+
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const APR1_MAGIC: &str = "$apr1$";
+const SHA1_PREFIX: &str = "{SHA}";
+/// Alphabet of the crypt(3) Base64 encoding
+const CRYPT_BASE64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Verifies HTTP basic auth credentials against crypt(3) style hashes (bcrypt,
+/// SHA-256 / SHA-512 and MD5 crypt), and the Apache MD5 (`$apr1$`) and SHA-1 (`{SHA}`) ones.
+#[derive(Debug)]
+pub struct Htpasswd {
+    path: PathBuf,
+    refresh_interval: Duration,
+    state: Mutex<State>,
+    cache: VerdictCache,
+}
+
+#[derive(Debug)]
+struct State {
+    users: HashMap<String, String>,
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+}
+
+impl Htpasswd {
+    pub fn new(path: PathBuf, refresh_interval: Option<Duration>) -> Result<Self, ServiceError> {
+        let refresh_interval = refresh_interval.unwrap_or(DEFAULT_REFRESH_INTERVAL);
+        let (users, modified) = load(&path)?;
+
+        Ok(Self {
+            path,
+            refresh_interval,
+            state: Mutex::new(State {
+                users,
+                modified,
+                checked_at: Instant::now(),
+            }),
+            cache: VerdictCache::new(refresh_interval),
+        })
+    }
+
+    /// Reloads the file when modified, at most once per refresh interval.
+    /// The file is read on a blocking thread
+    async fn refresh(&self) {
+        let known = {
+            let mut state = self.state.lock().unwrap();
+            if state.checked_at.elapsed() < self.refresh_interval {
+                return;
+            }
+            state.checked_at = Instant::now();
+            state.modified
+        };
+
+        let path = self.path.clone();
+        let loaded = tokio::task::spawn_blocking(move || {
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            if modified.is_some() && modified == known {
+                return None;
+            }
+            Some(load(&path))
+        })
+        .await
+        .ok()
+        .flatten();
+
+        match loaded {
+            Some(Ok((users, modified))) => {
+                let mut state = self.state.lock().unwrap();
+                state.users = users;
+                state.modified = modified;
+                self.cache.clear();
+                log::info!("Reloaded users from '{}'", self.path.display());
+            }
+            Some(Err(e)) => log::warn!("{}", e),
+            None => (),
+        }
+    }
+
+    async fn verify(&self, req: AuthRequest<'_>) -> Option<String> {
+        let (username, password) = basic_credentials(req.headers)?;
+        self.refresh().await;
+
+        let key = digest(&format!("{}:{}", username, password));
+        if let Some(verdict) = self.cache.get(&key) {
+            return verdict;
+        }
+
+        let hash = {
+            let state = self.state.lock().unwrap();
+            state.users.get(&username)?.clone()
+        };
+
+        // hash verification is CPU-bound
+        let verified = tokio::task::spawn_blocking(move || verify(&password, &hash))
+            .await
+            .unwrap_or(false);
+
+        let verdict = if verified { Some(username) } else { None };
+        self.cache.insert(key, verdict.clone());
+        verdict
+    }
+}
+
+impl CredentialProvider for Htpasswd {
+    fn authorize<'a>(&'a self, req: AuthRequest<'a>) -> BoxFuture<'a, Option<String>> {
+        self.verify(req).boxed()
+    }
+}
+
+fn load(path: &Path) -> Result<(HashMap<String, String>, Option<SystemTime>), ServiceError> {
+    let err = |e: std::io::Error| {
+        ServiceError::InvalidAuth(format!("cannot read '{}': {}", path.display(), e))
+    };
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let contents = std::fs::read_to_string(path).map_err(err)?;
+    Ok((parse(&contents, path), modified))
+}
+
+/// Verifies the password against a supported hash
+fn verify(password: &str, hash: &str) -> bool {
+    if let Some(hash) = hash.strip_prefix(APR1_MAGIC) {
+        let salt = hash.split('$').next().unwrap_or_default();
+        return apr1(password.as_bytes(), salt) == hash;
+    }
+    if let Some(hash) = hash.strip_prefix(SHA1_PREFIX) {
+        return base64::encode(Sha1::digest(password.as_bytes())) == hash;
+    }
+    pwhash::unix::verify(password, hash)
+}
+
+/// MD5 crypt with the Apache magic string, returning `{salt}${checksum}`
+fn apr1(password: &[u8], salt: &str) -> String {
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alternate = Md5::new()
+        .chain(password)
+        .chain(salt)
+        .chain(password)
+        .finalize();
+    let mut digest = Md5::new().chain(password).chain(APR1_MAGIC).chain(salt);
+    for chunk in password.chunks(16) {
+        digest.update(&alternate[..chunk.len()]);
+    }
+    let mut len = password.len();
+    while len > 0 {
+        match len & 1 {
+            0 => digest.update(&password[..1]),
+            _ => digest.update([0u8]),
+        }
+        len >>= 1;
+    }
+    let mut hash = digest.finalize();
+
+    for round in 0..1000 {
+        let mut digest = Md5::new();
+        if round % 2 == 1 {
+            digest.update(password);
+        } else {
+            digest.update(hash);
+        }
+        if round % 3 != 0 {
+            digest.update(salt);
+        }
+        if round % 7 != 0 {
+            digest.update(password);
+        }
+        if round % 2 == 0 {
+            digest.update(password);
+        } else {
+            digest.update(hash);
+        }
+        hash = digest.finalize();
+    }
+
+    let mut checksum = String::with_capacity(22);
+    let mut encode = |mut value: u32, chars: usize| {
+        for _ in 0..chars {
+            checksum.push(CRYPT_BASE64[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        encode(
+            (hash[a] as u32) << 16 | (hash[b] as u32) << 8 | hash[c] as u32,
+            4,
+        );
+    }
+    encode(hash[11] as u32, 2);
+
+    format!("{}${}", String::from_utf8_lossy(salt), checksum)
+}
+
+fn parse(contents: &str, path: &Path) -> HashMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match line.split_once(':') {
+            Some((username, hash)) if hash.starts_with('$') || hash.starts_with(SHA1_PREFIX) => {
+                Some((username.to_string(), hash.to_string()))
+            }
+            Some((username, _)) => {
+                log::warn!(
+                    "'{}': unsupported password hash for user '{}'",
+                    path.display(),
+                    username
+                );
+                None
+            }
+            None => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, verify};
+    use std::path::Path;
+
+    #[test]
+    fn parse_htpasswd() {
+        let path = Path::new(".htpasswd");
+        let contents = r#"
+            # comment
+            bcrypt:$2y$05$c4WoMPo3SXsafkva.HHa6uXQZWr7oboPiC2bT/r6q1hB0xHcsm1zy
+            sha512:$6$rounds=1000$salt$hash
+            apr1:$apr1$salt$hash
+            sha1:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=
+            malformed
+        "#;
+
+        let users = parse(contents, path);
+        assert_eq!(users.len(), 4);
+        assert!(users.contains_key("bcrypt"));
+        assert!(users.contains_key("sha512"));
+        assert!(users.contains_key("apr1"));
+        assert!(users.contains_key("sha1"));
+    }
+
+    #[test]
+    fn verify_hashes() {
+        for (password, hash) in [
+            ("myPassword", "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/"),
+            ("password", "$apr1$abc$mehJE/UcwZsj.w5DYe.b5."),
+            ("password", "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g="),
+            ("password", "$1$5pZSV9va$azfrPr6af3Fc7dLblQXVa0"),
+        ] {
+            assert!(verify(password, hash), "{}", hash);
+            assert!(!verify("wrong", hash), "{}", hash);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Requests authorized with JSON Web Tokens
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::client::HttpConnector;
+use hyper::header;
+use hyper::{Body, Client, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::conf::ClientConf;
+use crate::error::ServiceError;
+use crate::proxy::client;
+use crate::proxy::provider::{AuthRequest, CredentialProvider};
+use ya_http_proxy_model as model;
+
+const DEFAULT_JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+/// Minimum time between key set fetches triggered by unknown key ids
+const JWKS_MIN_FETCH_INTERVAL: Duration = Duration::from_secs(10);
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Verifies bearer tokens and accounts requests to the token subject
+pub struct JwtAuth {
+    keys: Keys,
+    validation: Validation,
+}
+
+enum Keys {
+    Static(DecodingKey),
+    Jwks(Box<Jwks>),
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+impl JwtAuth {
+    pub fn new(conf: &model::JwtConf, client_conf: &ClientConf) -> Result<Self, ServiceError> {
+        let invalid = |e: jsonwebtoken::errors::Error| ServiceError::InvalidAuth(e.to_string());
+
+        let (keys, algorithm) = match conf.key {
+            model::JwtKey::Hs256 { ref key } => (
+                Keys::Static(DecodingKey::from_secret(key.expose().as_bytes())),
+                Algorithm::HS256,
+            ),
+            model::JwtKey::Rs256 { ref public_key } => (
+                Keys::Static(DecodingKey::from_rsa_pem(public_key.as_bytes()).map_err(invalid)?),
+                Algorithm::RS256,
+            ),
+            model::JwtKey::Jwks {
+                ref url,
+                refresh_interval,
+            } => (
+                Keys::Jwks(Box::new(Jwks::new(
+                    url.clone(),
+                    refresh_interval,
+                    client_conf,
+                )?)),
+                Algorithm::RS256,
+            ),
+            #[allow(unreachable_patterns)]
+            _ => return Err(ServiceError::InvalidAuth("unsupported JWT key".into())),
+        };
+
+        let mut validation = Validation::new(algorithm);
+        if !conf.audience.is_empty() {
+            validation.set_audience(&conf.audience);
+        }
+        if let Some(leeway) = conf.leeway {
+            validation.leeway = leeway.as_secs();
+        }
+
+        Ok(Self { keys, validation })
+    }
+
+    async fn verify(&self, req: AuthRequest<'_>) -> Option<String> {
+        let token = bearer_token(req)?;
+        let header = jsonwebtoken::decode_header(token).ok()?;
+
+        let result = match self.keys {
+            Keys::Static(ref key) => jsonwebtoken::decode::<Claims>(token, key, &self.validation),
+            Keys::Jwks(ref jwks) => {
+                let key = jwks.get(req.service, header.kid.as_deref()).await?;
+                jsonwebtoken::decode::<Claims>(token, &key, &self.validation)
+            }
+        };
+
+        match result {
+            Ok(data) if !data.claims.sub.is_empty() => Some(data.claims.sub),
+            Ok(_) => None,
+            Err(e) => {
+                log::debug!("[{}] invalid token: {}", req.service, e);
+                None
+            }
+        }
+    }
+}
+
+impl CredentialProvider for JwtAuth {
+    fn authorize<'a>(&'a self, req: AuthRequest<'a>) -> BoxFuture<'a, Option<String>> {
+        self.verify(req).boxed()
+    }
+}
+
+impl std::fmt::Debug for JwtAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtAuth")
+            .field("algorithms", &self.validation.algorithms)
+            .finish()
+    }
+}
+
+fn bearer_token<'a>(req: AuthRequest<'a>) -> Option<&'a str> {
+    let auth = req.headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, value) = auth.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| value.trim())
+}
+
+/// Keys of a JSON Web Key Set, fetched on demand
+struct Jwks {
+    url: Uri,
+    refresh_interval: Duration,
+    client: Client<HttpsConnector<HttpConnector>>,
+    state: Mutex<JwksState>,
+}
+
+#[derive(Default)]
+struct JwksState {
+    keys: HashMap<String, DecodingKey>,
+    fetched_at: Option<Instant>,
+}
+
+impl Jwks {
+    fn new(
+        url: Uri,
+        refresh_interval: Option<Duration>,
+        conf: &ClientConf,
+    ) -> Result<Self, ServiceError> {
+        let client =
+            client::build_tls(conf).map_err(|e| ServiceError::InvalidAuth(e.to_string()))?;
+        Ok(Self {
+            url,
+            refresh_interval: refresh_interval.unwrap_or(DEFAULT_JWKS_REFRESH_INTERVAL),
+            client,
+            state: Default::default(),
+        })
+    }
+
+    /// Returns the key with the given id, fetching the key set when stale or missing the key
+    async fn get(&self, service: &str, kid: Option<&str>) -> Option<DecodingKey> {
+        let kid = kid.unwrap_or_default();
+        let fetch = {
+            let mut state = self.state.lock().unwrap();
+            let elapsed = state.fetched_at.map(|t| t.elapsed());
+            let fetch = match elapsed {
+                None => true,
+                Some(elapsed) if elapsed >= self.refresh_interval => true,
+                Some(elapsed) => {
+                    !state.keys.contains_key(kid) && elapsed >= JWKS_MIN_FETCH_INTERVAL
+                }
+            };
+            if fetch {
+                // concurrent requests use the current keys meanwhile
+                state.fetched_at = Some(Instant::now());
+            }
+            fetch
+        };
+
+        if fetch {
+            match self.fetch().await {
+                Ok(keys) => self.state.lock().unwrap().keys = keys,
+                Err(e) => log::warn!(
+                    "[{}] unable to fetch JWKS from {}: {}",
+                    service,
+                    self.url,
+                    e
+                ),
+            }
+        }
+        self.state.lock().unwrap().keys.get(kid).cloned()
+    }
+
+    async fn fetch(&self) -> Result<HashMap<String, DecodingKey>, String> {
+        let req = Request::get(self.url.clone())
+            .body(Body::empty())
+            .map_err(|e| e.to_string())?;
+
+        let fut = async {
+            let res = self.client.request(req).await.map_err(|e| e.to_string())?;
+            if !res.status().is_success() {
+                return Err(format!("status {}", res.status()));
+            }
+            hyper::body::to_bytes(res.into_body())
+                .await
+                .map_err(|e| e.to_string())
+        };
+        let bytes = tokio::time::timeout(JWKS_TIMEOUT, fut)
+            .await
+            .map_err(|_| "timed out".to_string())??;
+
+        let set: JwkSet = serde_json::from_slice(bytes.as_ref()).map_err(|e| e.to_string())?;
+        Ok(set
+            .keys
+            .iter()
+            .filter_map(|jwk| {
+                let key = DecodingKey::from_jwk(jwk).ok()?;
+                Some((jwk.common.key_id.clone().unwrap_or_default(), key))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use hyper::header::{HeaderValue, AUTHORIZATION};
+    use hyper::{HeaderMap, Method};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde::Serialize;
+
+    use super::JwtAuth;
+    use crate::conf::ClientConf;
+    use crate::proxy::provider::AuthRequest;
+    use ya_http_proxy_model as model;
+
+    #[derive(Serialize)]
+    struct Claims<'a> {
+        sub: &'a str,
+        exp: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        aud: Option<&'a str>,
+    }
+
+    fn token(sub: &str, exp: i64, aud: Option<&str>, secret: &str) -> String {
+        let claims = Claims { sub, exp, aud };
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        jsonwebtoken::encode(&Header::default(), &claims, &key).unwrap()
+    }
+
+    async fn authorize(auth: &JwtAuth, token: &str) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::try_from(format!("Bearer {}", token)).unwrap();
+        headers.insert(AUTHORIZATION, value);
+        let address: SocketAddr = "127.0.0.1:10000".parse().unwrap();
+        let req = AuthRequest {
+            service: "service",
+            method: &Method::GET,
+            path: "/",
+            headers: &headers,
+            address,
+        };
+        auth.verify(req).await
+    }
+
+    #[tokio::test]
+    async fn hs256_tokens() {
+        let conf = model::JwtConf {
+            key: model::JwtKey::Hs256 {
+                key: "secret".to_string().into(),
+            },
+            audience: vec!["service".to_string()],
+            leeway: None,
+        };
+        let auth = JwtAuth::new(&conf, &ClientConf::default()).unwrap();
+        let exp = chrono::Utc::now().timestamp() + 60;
+
+        let valid = token("user", exp, Some("service"), "secret");
+        assert_eq!(authorize(&auth, &valid).await, Some("user".to_string()));
+
+        let forged = token("user", exp, Some("service"), "other");
+        assert_eq!(authorize(&auth, &forged).await, None);
+        let expired = token("user", exp - 3600, Some("service"), "secret");
+        assert_eq!(authorize(&auth, &expired).await, None);
+        let audience = token("user", exp, Some("other"), "secret");
+        assert_eq!(authorize(&auth, &audience).await, None);
+    }
+}
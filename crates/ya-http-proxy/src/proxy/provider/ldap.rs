@@ -0,0 +1,86 @@
+//! Users verified with an LDAP simple bind
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use ldap3::{dn_escape, LdapConnAsync, LdapConnSettings};
+
+use crate::error::ServiceError;
+use crate::proxy::digest;
+use crate::proxy::provider::{basic_credentials, AuthRequest, CredentialProvider, VerdictCache};
+
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+const CONN_TIMEOUT: Duration = Duration::from_secs(5);
+const USERNAME_PLACEHOLDER: &str = "{username}";
+
+/// Verifies HTTP basic auth credentials by binding to an LDAP server as the user
+#[derive(Debug)]
+pub struct Ldap {
+    url: String,
+    bind_dn: String,
+    cache: VerdictCache,
+}
+
+impl Ldap {
+    pub fn new(
+        url: String,
+        bind_dn: String,
+        cache_ttl: Option<Duration>,
+    ) -> Result<Self, ServiceError> {
+        if !bind_dn.contains(USERNAME_PLACEHOLDER) {
+            return Err(ServiceError::InvalidAuth(format!(
+                "LDAP bind DN template is missing the '{}' placeholder",
+                USERNAME_PLACEHOLDER
+            )));
+        }
+
+        Ok(Self {
+            url,
+            bind_dn,
+            cache: VerdictCache::new(cache_ttl.unwrap_or(DEFAULT_CACHE_TTL)),
+        })
+    }
+
+    async fn verify(&self, req: AuthRequest<'_>) -> Option<String> {
+        let (username, password) = basic_credentials(req.headers)?;
+        // an empty password would result in an unauthenticated bind
+        if password.is_empty() {
+            return None;
+        }
+
+        let key = digest(&format!("{}:{}", username, password));
+        if let Some(verdict) = self.cache.get(&key) {
+            return verdict;
+        }
+
+        let dn = self
+            .bind_dn
+            .replace(USERNAME_PLACEHOLDER, &dn_escape(username.as_str()));
+        match self.bind(&dn, &password).await {
+            Ok(()) => {
+                self.cache.insert(key, Some(username.clone()));
+                Some(username)
+            }
+            Err(e) => {
+                log::debug!("[{}] LDAP bind failed for '{}': {}", req.service, dn, e);
+                None
+            }
+        }
+    }
+
+    async fn bind(&self, dn: &str, password: &str) -> Result<(), ldap3::LdapError> {
+        let settings = LdapConnSettings::new().set_conn_timeout(CONN_TIMEOUT);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        ldap3::drive!(conn);
+
+        let result = ldap.simple_bind(dn, password).await?.success();
+        let _ = ldap.unbind().await;
+        result.map(|_| ())
+    }
+}
+
+impl CredentialProvider for Ldap {
+    fn authorize<'a>(&'a self, req: AuthRequest<'a>) -> BoxFuture<'a, Option<String>> {
+        self.verify(req).boxed()
+    }
+}
@@ -0,0 +1,228 @@
+//! Request rate limits, enforced with token buckets
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::ServiceError;
+use ya_http_proxy_model as model;
+
+/// Number of user buckets kept before full (idle) buckets are dropped
+const PRUNE_SIZE: usize = 1024;
+
+/// Service-wide and per-user rate limits of a service
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    service: Option<(model::RateLimit, Mutex<Bucket>)>,
+    user: Option<model::RateLimit>,
+    users: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter for the service, if configured
+    pub fn from_service(create: &model::CreateService) -> Result<Option<Self>, ServiceError> {
+        let service = create.rate_limit.clone();
+        let user = create.user.as_ref().and_then(|u| u.rate_limit.clone());
+
+        if service
+            .iter()
+            .chain(user.iter())
+            .any(|l| l.requests_per_second == 0)
+        {
+            return Err(ServiceError::InvalidRateLimit(
+                "requestsPerSecond must be greater than 0".into(),
+            ));
+        }
+        if service.is_none() && user.is_none() {
+            return Ok(None);
+        }
+
+        let now = Instant::now();
+        Ok(Some(Self {
+            service: service.map(|l| {
+                let bucket = Bucket::new(&l, now);
+                (l, Mutex::new(bucket))
+            }),
+            user,
+            users: Default::default(),
+        }))
+    }
+
+    /// Admits a request made by the user. Otherwise, returns the time after which
+    /// the request would be admitted
+    pub fn acquire(&self, username: &str) -> Result<(), Duration> {
+        self.acquire_at(username, Instant::now())
+    }
+
+    fn acquire_at(&self, username: &str, now: Instant) -> Result<(), Duration> {
+        let mut users = self.users.lock().unwrap();
+        let user = match self.user {
+            Some(ref limit) => {
+                if users.len() >= PRUNE_SIZE && !users.contains_key(username) {
+                    users.retain(|_, b| !b.refill(limit, now).is_full(limit));
+                }
+                let bucket = users
+                    .entry(username.to_string())
+                    .or_insert_with(|| Bucket::new(limit, now));
+                Some((limit, bucket.refill(limit, now)))
+            }
+            None => None,
+        };
+        let mut service = self
+            .service
+            .as_ref()
+            .map(|(limit, bucket)| (limit, bucket.lock().unwrap()));
+        if let Some((limit, ref mut bucket)) = service {
+            bucket.refill(limit, now);
+        }
+
+        // tokens are only taken when both limits admit the request
+        let wait = [
+            user.as_ref().map(|(l, b)| b.wait(l)),
+            service.as_ref().map(|(l, b)| b.wait(l)),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+        .unwrap_or_default();
+
+        if wait.is_zero() {
+            if let Some((_, bucket)) = user {
+                bucket.tokens -= 1.;
+            }
+            if let Some((_, ref mut bucket)) = service {
+                bucket.tokens -= 1.;
+            }
+            Ok(())
+        } else {
+            if let Some((_, bucket)) = user {
+                bucket.limited += 1;
+            }
+            if let Some((_, ref mut bucket)) = service {
+                bucket.limited += 1;
+            }
+            Err(wait)
+        }
+    }
+
+    /// State of the rate limit applied to the user
+    pub fn user_status(&self, username: &str) -> Option<model::RateLimitStatus> {
+        let limit = self.user.as_ref()?;
+        let mut users = self.users.lock().unwrap();
+        let (available, limited) = match users.get_mut(username) {
+            Some(bucket) => {
+                let bucket = bucket.refill(limit, Instant::now());
+                (bucket.tokens as u32, bucket.limited)
+            }
+            None => (limit.burst(), 0),
+        };
+
+        Some(model::RateLimitStatus {
+            requests_per_second: limit.requests_per_second,
+            burst: limit.burst(),
+            available,
+            limited,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limited: u64,
+}
+
+impl Bucket {
+    fn new(limit: &model::RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst() as f64,
+            updated: now,
+            limited: 0,
+        }
+    }
+
+    fn refill(&mut self, limit: &model::RateLimit, now: Instant) -> &mut Self {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let tokens = self.tokens + elapsed * limit.requests_per_second as f64;
+        self.tokens = tokens.min(limit.burst() as f64);
+        self.updated = now;
+        self
+    }
+
+    fn is_full(&self, limit: &model::RateLimit) -> bool {
+        self.tokens >= limit.burst() as f64
+    }
+
+    /// Time until a token is available
+    fn wait(&self, limit: &model::RateLimit) -> Duration {
+        if self.tokens >= 1. {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1. - self.tokens) / limit.requests_per_second as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimiter;
+    use ya_http_proxy_model as model;
+
+    fn limiter(service: Option<(u32, u32)>, user: Option<(u32, u32)>) -> RateLimiter {
+        let limit = |(requests_per_second, burst)| model::RateLimit {
+            requests_per_second,
+            burst: Some(burst),
+        };
+        let now = Instant::now();
+        RateLimiter {
+            service: service.map(|l| {
+                let limit = limit(l);
+                let bucket = super::Bucket::new(&limit, now);
+                (limit, bucket.into())
+            }),
+            user: user.map(limit),
+            users: Default::default(),
+        }
+    }
+
+    #[test]
+    fn user_rate_limit() {
+        let limiter = limiter(None, Some((2, 3)));
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.acquire_at("a", now).is_ok());
+        }
+        let wait = limiter.acquire_at("a", now).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+        // other users are limited separately
+        assert!(limiter.acquire_at("b", now).is_ok());
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.acquire_at("a", later).is_ok());
+        assert!(limiter.acquire_at("a", later).is_err());
+
+        let status = limiter.user_status("a").unwrap();
+        assert_eq!(status.burst, 3);
+        assert_eq!(status.limited, 2);
+        assert_eq!(limiter.user_status("c").unwrap().available, 3);
+    }
+
+    #[test]
+    fn service_rate_limit() {
+        let limiter = limiter(Some((1, 2)), Some((10, 10)));
+        let now = Instant::now();
+
+        assert!(limiter.acquire_at("a", now).is_ok());
+        assert!(limiter.acquire_at("b", now).is_ok());
+        assert_eq!(
+            limiter.acquire_at("a", now).unwrap_err(),
+            Duration::from_secs(1)
+        );
+        // requests rejected by the service limit do not count towards the user limit
+        let status = limiter.user_status("a").unwrap();
+        assert_eq!(status.available, 9);
+        assert!(limiter.user_status("x").is_some());
+    }
+}
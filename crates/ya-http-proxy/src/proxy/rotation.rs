@@ -0,0 +1,161 @@
+//! Append-only files rotated by size
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Current file, followed by rotated files `<path>.1` (the most recent) to `<path>.<max_files>`
+#[derive(Debug)]
+pub(crate) struct RotatedFile {
+    path: PathBuf,
+    /// Written at the beginning of each file, and verified when a file is reopened
+    header: &'static [u8],
+    max_file_size: u64,
+    max_files: usize,
+    /// Current file and its size. Reopened on the next write when not set
+    current: Option<(File, u64)>,
+}
+
+impl RotatedFile {
+    pub fn open(
+        path: PathBuf,
+        header: &'static [u8],
+        max_file_size: u64,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let mut file = Self {
+            path,
+            header,
+            max_file_size,
+            max_files,
+            current: None,
+        };
+        file.current = Some(file.open_current()?);
+        Ok(file)
+    }
+
+    pub fn max_files(&self) -> usize {
+        self.max_files
+    }
+
+    /// Path of the current (`0`) or a rotated file
+    pub fn path(&self, index: usize) -> PathBuf {
+        rotated_path(&self.path, index)
+    }
+
+    /// Appends a record, rotating the files first when the record would not fit
+    /// in the current one. Records are never split between files
+    pub fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        let header = self.header.len() as u64;
+        let full = match self.current {
+            Some((_, size)) => size > header && size + record.len() as u64 > self.max_file_size,
+            None => false,
+        };
+        if full {
+            // the current file is closed before rotation, and is not written to again
+            // when a new one cannot be opened
+            self.current = None;
+            self.rotate()?;
+        }
+
+        let (file, size) = match self.current {
+            Some(ref mut current) => current,
+            None => self.current.insert(self.open_current()?),
+        };
+        file.write_all(record)?;
+        *size += record.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.max_files == 0 {
+            return fs::remove_file(self.path(0));
+        }
+        for i in (0..self.max_files).rev() {
+            let path = self.path(i);
+            if path.exists() {
+                fs::rename(path, self.path(i + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn open_current(&self) -> io::Result<(File, u64)> {
+        let path = self.path(0);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let size = file.metadata()?.len();
+        if size == 0 {
+            file.write_all(self.header)?;
+            return Ok((file, self.header.len() as u64));
+        }
+        let mut header = vec![0u8; self.header.len()];
+        file.read_exact(&mut header)?;
+        if header != self.header {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("'{}' has an unknown format", path.display()),
+            ));
+        }
+        Ok((file, size))
+    }
+}
+
+/// Path of the current (`0`) or a rotated file
+pub(crate) fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    match index {
+        0 => path.to_path_buf(),
+        i => {
+            let mut path = OsString::from(path.as_os_str());
+            path.push(format!(".{}", i));
+            path.into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rotated_path, RotatedFile};
+
+    #[test]
+    fn rotation() {
+        let dir =
+            std::env::temp_dir().join(format!("ya-http-proxy-rotation-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("records");
+
+        let mut file = RotatedFile::open(path.clone(), b"HDR", 10, 2).unwrap();
+        for record in ["abc", "def", "ghi", "jkl", "mno"] {
+            file.write(record.as_bytes()).unwrap();
+        }
+        let read = |i: usize| std::fs::read_to_string(rotated_path(&path, i)).unwrap();
+        assert_eq!(read(0), "HDRmno");
+        assert_eq!(read(1), "HDRghijkl");
+        assert_eq!(read(2), "HDRabcdef");
+        assert!(!file.path(3).exists());
+
+        // rotated files cannot be renamed, records keep going to the current file
+        std::fs::remove_file(file.path(2)).unwrap();
+        std::fs::create_dir_all(file.path(2).join("dir")).unwrap();
+        file.write(b"pqr").unwrap();
+        assert!(file.write(b"stu").is_err());
+        file.write(b"vwx").unwrap();
+        assert_eq!(read(0), "HDRmnopqrvwx");
+        assert_eq!(read(1), "HDRghijkl");
+
+        std::fs::remove_dir_all(file.path(2)).unwrap();
+        file.write(b"yz").unwrap();
+        assert_eq!(read(0), "HDRyz");
+        assert_eq!(read(1), "HDRmnopqrvwx");
+        assert_eq!(read(2), "HDRghijkl");
+
+        std::fs::write(&path, "other").unwrap();
+        assert!(RotatedFile::open(path.clone(), b"HDR", 10, 2).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+//! Task statistics of a proxy runtime
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use ya_http_proxy_model as model;
+
+/// Connection and request task counters, shared by the proxy worker threads
+#[derive(Debug, Default)]
+pub(crate) struct RuntimeStats {
+    pub thread_name: String,
+    pub worker_threads: Option<usize>,
+    connections: AtomicUsize,
+    connections_total: AtomicUsize,
+    requests: AtomicUsize,
+    polls: AtomicU64,
+    poll_micros: AtomicU64,
+    max_poll_micros: AtomicU64,
+}
+
+impl RuntimeStats {
+    pub fn new(thread_name: String, worker_threads: Option<usize>) -> Self {
+        Self {
+            thread_name,
+            worker_threads,
+            ..Default::default()
+        }
+    }
+
+    /// Counts an open connection until the returned guard is dropped
+    pub fn connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    /// Measures the time spent polling a request handling future
+    pub fn instrument<F: Future>(self: &Arc<Self>, future: F) -> Instrumented<F> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        Instrumented {
+            inner: Box::pin(future),
+            stats: self.clone(),
+        }
+    }
+
+    pub fn snapshot(
+        &self,
+        addresses: model::Addresses,
+        services: Vec<String>,
+        concurrency: Vec<model::ServiceConcurrency>,
+    ) -> model::ProxyRuntime {
+        model::ProxyRuntime {
+            addresses,
+            services,
+            thread_name: self.thread_name.clone(),
+            worker_threads: self.worker_threads,
+            connections: self.connections.load(Ordering::Relaxed),
+            connections_total: self.connections_total.load(Ordering::Relaxed),
+            active_requests: self.requests.load(Ordering::Relaxed),
+            polls: self.polls.load(Ordering::Relaxed),
+            poll_time: Duration::from_micros(self.poll_micros.load(Ordering::Relaxed)),
+            max_poll_time: Duration::from_micros(self.max_poll_micros.load(Ordering::Relaxed)),
+            concurrency,
+        }
+    }
+
+    fn record_poll(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_poll_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct ConnectionGuard(Arc<RuntimeStats>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Future wrapper recording poll durations
+pub(crate) struct Instrumented<F> {
+    inner: Pin<Box<F>>,
+    stats: Arc<RuntimeStats>,
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let started = Instant::now();
+        let poll = self.inner.as_mut().poll(cx);
+        self.stats.record_poll(started.elapsed());
+        poll
+    }
+}
+
+impl<F> Drop for Instrumented<F> {
+    fn drop(&mut self) {
+        self.stats.requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::RuntimeStats;
+
+    #[tokio::test]
+    async fn runtime_stats() {
+        let stats = Arc::new(RuntimeStats::new("proxy".to_string(), Some(2)));
+        let connection = stats.connection();
+
+        let request = stats.instrument(async {
+            tokio::task::yield_now().await;
+            1
+        });
+        assert_eq!(
+            stats
+                .snapshot(Default::default(), vec![], vec![])
+                .active_requests,
+            1
+        );
+        assert_eq!(request.await, 1);
+        drop(connection);
+
+        let snapshot = stats.snapshot(Default::default(), vec!["service".to_string()], vec![]);
+        assert_eq!(snapshot.connections, 0);
+        assert_eq!(snapshot.connections_total, 1);
+        assert_eq!(snapshot.active_requests, 0);
+        assert_eq!(snapshot.polls, 2);
+        assert_eq!(snapshot.thread_name, "proxy");
+    }
+}
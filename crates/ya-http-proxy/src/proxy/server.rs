@@ -1,51 +1,102 @@
-use std::io::{Seek, SeekFrom};
-use std::path::Path;
-use std::sync::Arc;
-use std::{fs, io};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use futures::channel::mpsc::Sender;
 use futures::SinkExt;
 use hyper::server::accept::Accept;
 use hyper::server::{accept, Builder, Server};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::Semaphore;
 use tokio_rustls::TlsAcceptor;
 
 use crate::conf::ServerConf;
 use crate::conf_builder_server;
-use crate::error::{Error, TlsError};
+use crate::error::{Error, ProxyError, TlsError};
 use crate::proxy::stream::HttpStream;
+use crate::proxy::tls::ServerCert;
+use ya_http_proxy_model::Addresses;
 
+const DEFAULT_MAX_PENDING_CONNECTIONS: usize = 1024;
+const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const ACCEPT_ERROR_SLEEP: Duration = Duration::from_secs(1);
+const REBIND_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const REBIND_MAX_DELAY: Duration = Duration::from_secs(60);
+const REBIND_MAX_ATTEMPTS: usize = 10;
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// Serving state of a proxy, shared with its services
+#[derive(Clone, Debug, Default)]
+pub struct ProxyHealth {
+    /// Reason the proxy has stopped serving connections
+    failure: Arc<Mutex<Option<String>>>,
+    /// Errors of listeners being rebound, by listening address
+    listeners: Arc<Mutex<BTreeMap<SocketAddr, String>>>,
+}
+
+impl ProxyHealth {
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+
+    pub(crate) fn fail(&self, reason: impl ToString) {
+        self.failure.lock().unwrap().replace(reason.to_string());
+    }
+
+    /// Describes listeners not accepting connections, if any
+    pub fn listener_error(&self) -> Option<String> {
+        let listeners = self.listeners.lock().unwrap();
+        if listeners.is_empty() {
+            return None;
+        }
+        let errors: Vec<_> = listeners
+            .iter()
+            .map(|(addr, e)| format!("{}: {}", addr, e))
+            .collect();
+        Some(errors.join("; "))
+    }
+
+    fn listener_down(&self, addr: SocketAddr, reason: impl ToString) {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.insert(addr, reason.to_string());
+    }
+
+    fn listener_up(&self, addr: SocketAddr) {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.remove(&addr);
+    }
+}
+
+/// Binds the HTTP listeners. Addresses with port 0 are replaced in `conf`
+/// with the ones bound
 pub async fn listen_http(
-    conf: &ServerConf,
+    conf: &mut ServerConf,
+    health: ProxyHealth,
 ) -> Result<Option<Builder<impl Accept<Conn = HttpStream, Error = std::io::Error>>>, Error> {
     let addrs = match conf.bind_http.as_ref() {
         Some(addrs) => addrs.to_vec(),
         None => return Ok(None),
     };
 
-    let tcp_listener = TcpListener::bind(addrs.as_slice()).await?;
+    let tcp_listeners = bind(addrs.as_slice()).await?;
+    conf.bind_http = Some(local_addrs(&addrs, &tcp_listeners)?);
+    let permits = pending_permits(conf);
     let (tx, rx) = futures::channel::mpsc::channel(64);
 
-    tokio::task::spawn(async move {
-        loop {
-            match tcp_listener.accept().await {
-                Ok((stream, addr)) => {
-                    let mut tx = tx.clone();
-                    tokio::task::spawn(async move {
-                        let stream = HttpStream::plain(stream, addr);
-                        let _ = tx.send(Ok(stream)).await;
-                    });
-                }
-                // FIXME: handle network errors
-                Err(err) => match tcp_listener.local_addr() {
-                    Ok(_) => log::debug!("Client error: {}", err),
-                    Err(_) => {
-                        log::error!("Network error: {}", err);
-                        break;
-                    }
-                },
-            }
-        }
-    });
+    for tcp_listener in tcp_listeners {
+        spawn_accept_loop(
+            tcp_listener,
+            permits.clone(),
+            tx.clone(),
+            health.clone(),
+            sleep_on_errors(conf),
+            |socket, addr| futures::future::ready(Some(HttpStream::plain(socket, addr))),
+        );
+    }
 
     let acceptor = accept::from_stream(rx);
     let mut builder = Server::builder(acceptor);
@@ -54,118 +105,314 @@ pub async fn listen_http(
     Ok(Some(builder))
 }
 
+/// Binds the HTTPS listeners. Addresses with port 0 are replaced in `conf`
+/// with the ones bound
 pub async fn listen_https(
-    conf: &ServerConf,
+    conf: &mut ServerConf,
+    cert: Option<&Arc<ServerCert>>,
+    health: ProxyHealth,
+    handshake_timeouts: Arc<AtomicUsize>,
 ) -> Result<Option<Builder<impl Accept<Conn = HttpStream, Error = std::io::Error>>>, Error> {
     let addrs = match conf.bind_https.as_ref() {
         Some(addrs) => addrs.to_vec(),
         None => return Ok(None),
     };
 
-    let tls_conf = read_tls_conf(conf)?;
-    let tcp_listener = TcpListener::bind(addrs.as_slice()).await?;
+    let tls_conf = match cert {
+        Some(cert) => cert.tls_conf(),
+        None => return Err(TlsError::ServerCertStore("not loaded".to_string()).into()),
+    };
+    let tcp_listeners = bind(addrs.as_slice()).await?;
+    conf.bind_https = Some(local_addrs(&addrs, &tcp_listeners)?);
     let tls_acceptor = TlsAcceptor::from(tls_conf);
+    let handshake_timeout = conf
+        .tls_handshake_timeout
+        .unwrap_or(DEFAULT_TLS_HANDSHAKE_TIMEOUT);
+    let permits = pending_permits(conf);
     let (tx, rx) = futures::channel::mpsc::channel(64);
 
+    for tcp_listener in tcp_listeners {
+        let tls_acceptor = tls_acceptor.clone();
+        let handshake_timeouts = handshake_timeouts.clone();
+        spawn_accept_loop(
+            tcp_listener,
+            permits.clone(),
+            tx.clone(),
+            health.clone(),
+            sleep_on_errors(conf),
+            move |socket, addr| {
+                let handshake = tls_acceptor.accept(socket);
+                let handshake_timeouts = handshake_timeouts.clone();
+                async move {
+                    match tokio::time::timeout(handshake_timeout, handshake).await {
+                        Ok(Ok(stream)) => Some(HttpStream::tls(stream, addr)),
+                        Ok(Err(error)) => {
+                            log::warn!("TLS error from {}: {}", addr, error);
+                            None
+                        }
+                        Err(_) => {
+                            handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+                            log::debug!("TLS handshake with {} timed out", addr);
+                            None
+                        }
+                    }
+                }
+            },
+        );
+    }
+
+    let acceptor = accept::from_stream(rx);
+    let mut builder = Server::builder(acceptor);
+    conf_builder_server!(builder, conf);
+
+    Ok(Some(builder))
+}
+
+fn sleep_on_errors(conf: &ServerConf) -> bool {
+    conf.tcp_sleep_on_accept_errors.unwrap_or(false)
+}
+
+/// Errors of a listener no longer listening, e.g. shut down
+fn is_listener_error(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::InvalidInput
+}
+
+/// Errors concerning a single connection, which do not affect the listener
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+fn pending_permits(conf: &ServerConf) -> Arc<Semaphore> {
+    let max = conf
+        .max_pending_connections
+        .unwrap_or(DEFAULT_MAX_PENDING_CONNECTIONS)
+        .max(1);
+    Arc::new(Semaphore::new(max))
+}
+
+/// Accepts connections until the listener fails. Sockets are turned into streams by `prepare`
+/// in background tasks, each holding a permit until the stream is handed over to the server.
+/// Accepting pauses while all permits are in use, leaving further connections in the backlog.
+///
+/// Failed listeners are rebound, and reported in `health` in the meantime. When rebinding fails,
+/// the error is passed on to the server, which stops. Other errors are transient: unless caused
+/// by the client, accepting pauses when `sleep_on_errors` is set
+fn spawn_accept_loop<F, Fut>(
+    mut tcp_listener: TcpListener,
+    permits: Arc<Semaphore>,
+    mut tx: Sender<io::Result<HttpStream>>,
+    health: ProxyHealth,
+    sleep_on_errors: bool,
+    prepare: F,
+) where
+    F: Fn(TcpStream, SocketAddr) -> Fut + Send + 'static,
+    Fut: Future<Output = Option<HttpStream>> + Send + 'static,
+{
     tokio::task::spawn(async move {
+        let local_addr = match tcp_listener.local_addr() {
+            Ok(addr) => addr,
+            Err(err) => {
+                let _ = tx.send(Err(err)).await;
+                return;
+            }
+        };
+
         loop {
-            match tcp_listener.accept().await {
+            let permit = match permits.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+
+            let accepted = tcp_listener.accept().await;
+            match accepted {
                 Ok((socket, addr)) => {
-                    let tls_acceptor = tls_acceptor.clone();
+                    let stream = prepare(socket, addr);
                     let mut tx = tx.clone();
 
-                    // perform TLS handshakes in background
                     tokio::task::spawn(async move {
-                        match tls_acceptor.accept(socket).await {
-                            Ok(stream) => {
-                                let stream = HttpStream::tls(stream, addr);
-                                let _ = tx.send(Ok(stream)).await;
-                            }
-                            Err(error) => log::warn!("[{}] TLS error: {}", addr, error),
+                        if let Some(stream) = stream.await {
+                            let _ = tx.send(Ok(stream)).await;
                         }
+                        drop(permit);
                     });
                 }
-                // FIXME: handle network errors
-                Err(err) => match tcp_listener.local_addr() {
-                    Ok(_) => log::debug!("Client error: {}", err),
-                    Err(_) => {
-                        log::error!("Network error: {}", err);
-                        break;
+                Err(err) if is_connection_error(&err) => log::debug!("Client error: {}", err),
+                Err(err) if is_listener_error(&err) => {
+                    log::error!("Listener {} error: {}", local_addr, err);
+                    drop(permit);
+                    health.listener_down(local_addr, &err);
+                    // release the address before binding it again
+                    drop(tcp_listener);
+
+                    match rebind(local_addr).await {
+                        Ok(listener) => {
+                            log::info!("Listener {} rebound", local_addr);
+                            health.listener_up(local_addr);
+                            tcp_listener = listener;
+                        }
+                        Err(err) => {
+                            log::error!("Cannot rebind listener {}: {}", local_addr, err);
+                            let _ = tx.send(Err(err)).await;
+                            break;
+                        }
                     }
-                },
+                }
+                // e.g. the process or system limit of open files is reached
+                Err(err) if sleep_on_errors => {
+                    log::error!("Accept error: {}", err);
+                    drop(permit);
+                    tokio::time::sleep(ACCEPT_ERROR_SLEEP).await;
+                }
+                Err(err) => log::error!("Accept error: {}", err),
             }
         }
     });
+}
 
-    let acceptor = accept::from_stream(rx);
-    let mut builder = Server::builder(acceptor);
-    conf_builder_server!(builder, conf);
-
-    Ok(Some(builder))
+/// Binds the address again, retrying with exponential backoff
+async fn rebind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let mut delay = REBIND_INITIAL_DELAY;
+    let mut attempt = 1;
+    loop {
+        tokio::time::sleep(delay).await;
+        match listen(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(err) if attempt >= REBIND_MAX_ATTEMPTS => return Err(err),
+            Err(err) => log::warn!("Cannot rebind listener {}: {}", addr, err),
+        }
+        attempt += 1;
+        delay = (delay * 2).min(REBIND_MAX_DELAY);
+    }
 }
 
-fn read_tls_conf(conf: &ServerConf) -> Result<Arc<rustls::ServerConfig>, Error> {
-    let store = match conf.server_cert.server_cert_store_path.clone() {
-        Some(path) => read_cert_store(path)?,
-        None => return Err(TlsError::ServerCertStore("path not set".to_string()).into()),
-    };
-    let key = match conf.server_cert.server_key_path.clone() {
-        Some(path) => read_cert_key(path)?,
-        None => return Err(TlsError::ServerCertKey("path not set".to_string()).into()),
-    };
+/// Checks whether each of the addresses can be bound, without keeping the listeners
+pub(crate) fn check_bind(addrs: &[SocketAddr]) -> Result<(), ProxyError> {
+    for addr in addrs {
+        bind_socket(*addr).map_err(|e| ProxyError::bind(*addr, e))?;
+    }
+    Ok(())
+}
 
-    let mut cfg = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(store, key)
-        .map_err(|e| TlsError::Other(e.to_string()))?;
-
-    if conf.http1_only.unwrap_or(false) {
-        cfg.alpn_protocols = vec![b"http/1.1".to_vec()];
-    } else if conf.server_common.http2_only.unwrap_or(false) {
-        cfg.alpn_protocols = vec![b"h2".to_vec()];
-    } else {
-        cfg.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+/// Binds a listener to each of the addresses
+async fn bind(addrs: &[SocketAddr]) -> Result<Vec<TcpListener>, ProxyError> {
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let listener = listen(*addr).map_err(|e| ProxyError::bind(*addr, e))?;
+        listeners.push(listener);
     }
+    Ok(listeners)
+}
 
-    Ok(Arc::new(cfg))
+/// Addresses the listeners are bound to, with ports assigned in place of port 0
+fn local_addrs(addrs: &[SocketAddr], listeners: &[TcpListener]) -> Result<Addresses, ProxyError> {
+    let local_addrs = addrs.iter().zip(listeners).map(|(addr, listener)| {
+        listener
+            .local_addr()
+            .map_err(|e| ProxyError::bind(*addr, e))
+    });
+    Ok(Addresses::new(local_addrs.collect::<Result<Vec<_>, _>>()?))
 }
 
-fn read_cert_store(path: impl AsRef<Path>) -> Result<Vec<rustls::Certificate>, Error> {
-    let path = path.as_ref();
-    let file = fs::File::open(path).map_err(|e| {
-        TlsError::ServerCertStore(format!("cannot open '{}': {}", path.display(), e))
-    })?;
-    let mut reader = io::BufReader::new(file);
+fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    bind_socket(addr)?.listen(LISTEN_BACKLOG)
+}
 
-    let store = rustls_pemfile::certs(&mut reader).map_err(|e| {
-        TlsError::ServerCertStore(format!("error reading '{}': {}", path.display(), e))
-    })?;
-    Ok(store.into_iter().map(rustls::Certificate).collect())
+/// Binds a socket to the address. On Unix, the address may still be used by connections
+/// of a closed listener, lingering in `TIME_WAIT`
+fn bind_socket(addr: SocketAddr) -> io::Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    // on Windows, the option allows binding addresses in use
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    Ok(socket)
 }
 
-fn read_cert_key(path: impl AsRef<Path>) -> Result<rustls::PrivateKey, Error> {
-    let path = path.as_ref();
-    let file = fs::File::open(path)
-        .map_err(|e| TlsError::ServerCertKey(format!("cannot open '{}': {}", path.display(), e)))?;
-    let mut reader = io::BufReader::new(file);
+#[cfg(test)]
+mod tests {
+    use super::{check_bind, ProxyHealth};
+    use crate::error::ProxyError;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn listener_rebind() {
+        use std::os::unix::io::AsRawFd;
+        use std::sync::Arc;
+
+        use futures::StreamExt;
+        use tokio::net::TcpStream;
+        use tokio::sync::Semaphore;
+
+        use super::{listen, spawn_accept_loop, REBIND_INITIAL_DELAY};
+        use crate::proxy::stream::HttpStream;
+
+        let listener = listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.as_raw_fd();
+        let health = ProxyHealth::default();
+        let (tx, mut rx) = futures::channel::mpsc::channel(1);
+        spawn_accept_loop(
+            listener,
+            Arc::new(Semaphore::new(1)),
+            tx,
+            health.clone(),
+            false,
+            |socket, addr| futures::future::ready(Some(HttpStream::plain(socket, addr))),
+        );
 
-    let mut keys = rustls_pemfile::rsa_private_keys(&mut reader).map_err(|e| {
-        TlsError::ServerCertKey(format!("error reading '{}': {}", path.display(), e))
-    })?;
+        // accepting fails once the listening socket is shut down
+        assert_eq!(unsafe { libc::shutdown(fd, libc::SHUT_RDWR) }, 0);
+        tokio::time::sleep(REBIND_INITIAL_DELAY / 2).await;
+        assert!(health.listener_error().is_some());
 
-    if keys.is_empty() {
-        reader.seek(SeekFrom::Start(0))?;
-        keys = rustls_pemfile::pkcs8_private_keys(&mut reader).map_err(|e| {
-            TlsError::ServerCertKey(format!("error reading '{}': {}", path.display(), e))
-        })?;
+        tokio::time::sleep(REBIND_INITIAL_DELAY).await;
+        assert_eq!(health.listener_error(), None);
+        let _client = TcpStream::connect(addr).await.unwrap();
+        assert!(rx.next().await.unwrap().is_ok());
     }
 
-    if keys.is_empty() {
-        return Err(TlsError::ServerCertKey("missing server private key".to_string()).into());
-    } else if keys.len() > 1 {
-        return Err(TlsError::ServerCertKey("expected a single private key".to_string()).into());
+    #[test]
+    fn listener_health() {
+        let first = "127.0.0.1:10000".parse().unwrap();
+        let second = "127.0.0.1:10001".parse().unwrap();
+
+        let health = ProxyHealth::default();
+        assert_eq!(health.listener_error(), None);
+
+        health.listener_down(second, "network is unreachable");
+        health.listener_down(first, "network is unreachable");
+        assert_eq!(
+            health.listener_error().unwrap(),
+            "127.0.0.1:10000: network is unreachable; 127.0.0.1:10001: network is unreachable"
+        );
+
+        health.listener_up(first);
+        health.listener_up(second);
+        assert_eq!(health.listener_error(), None);
+        assert_eq!(health.failure(), None);
     }
 
-    Ok(rustls::PrivateKey(keys.remove(0)))
+    #[test]
+    fn bind_check() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        assert!(matches!(
+            check_bind(&[addr]),
+            Err(ProxyError::AddressInUse { addr: a }) if a == addr
+        ));
+        drop(listener);
+        assert!(check_bind(&[addr]).is_ok());
+    }
 }
@@ -8,6 +8,7 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use hyper::client::connect::{Connected, Connection};
+use sha3::{Digest, Sha3_256};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
 use tokio_rustls::server::TlsStream;
@@ -48,8 +49,21 @@ impl<T> HttpStreamKind<T> {
             Self::Tls { remote_addr, .. } => *remote_addr,
         }
     }
+
+    /// Fingerprint of the certificate presented by the client
+    pub fn client_cert(&self) -> Option<ClientCert> {
+        let cert = match self {
+            Self::Plain { .. } => return None,
+            Self::Tls { inner, .. } => inner.get_ref().1.peer_certificates()?.first()?,
+        };
+        Some(ClientCert(format!("{:x}", Sha3_256::digest(&cert.0))))
+    }
 }
 
+/// Hex-encoded Sha3_256 hash of the DER-encoded client certificate, added to request extensions
+#[derive(Clone, Debug)]
+pub struct ClientCert(pub String);
+
 impl<T: AsyncRead + AsyncWrite + Connection + Unpin> Connection for HttpStreamKind<T> {
     fn connected(&self) -> Connected {
         match self {
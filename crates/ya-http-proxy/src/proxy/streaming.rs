@@ -0,0 +1,85 @@
+//! Passthrough of streamed (e.g. Server-Sent Events) responses
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Response};
+use regex::RegexSet;
+
+use crate::error::ServiceError;
+use ya_http_proxy_model as model;
+
+const EVENT_STREAM: &str = "text/event-stream";
+const X_ACCEL_BUFFERING: &str = "x-accel-buffering";
+
+/// Recognizes streamed responses of a service
+#[derive(Debug)]
+pub(crate) struct Streaming {
+    paths: Option<RegexSet>,
+}
+
+impl Streaming {
+    pub fn new(streaming: &model::Streaming) -> Result<Self, ServiceError> {
+        let paths = match streaming.paths {
+            Some(ref paths) => Some(
+                RegexSet::new(paths)
+                    .map_err(|e| ServiceError::InvalidFilter(format!("streaming paths: {}", e)))?,
+            ),
+            None => None,
+        };
+        Ok(Self { paths })
+    }
+
+    /// Checks whether the response to a request for `path` is streamed
+    pub fn matches(&self, path: &str, res: &Response<Body>) -> bool {
+        let event_stream = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim_start().starts_with(EVENT_STREAM))
+            .unwrap_or(false);
+        event_stream || self.paths.iter().any(|p| p.is_match(path))
+    }
+
+    /// Disables caching and buffering of the response by clients and intermediaries
+    pub fn prepare(res: &mut Response<Body>) {
+        let headers = res.headers_mut();
+        headers
+            .entry(header::CACHE_CONTROL)
+            .or_insert(HeaderValue::from_static("no-cache"));
+        headers.insert(X_ACCEL_BUFFERING, HeaderValue::from_static("no"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header;
+    use hyper::{Body, Response};
+
+    use super::Streaming;
+    use ya_http_proxy_model as model;
+
+    #[test]
+    fn streamed_responses() {
+        let streaming = Streaming::new(&model::Streaming {
+            paths: Some(vec!["^/poll".to_string()]),
+        })
+        .unwrap();
+
+        let mut res = Response::new(Body::empty());
+        assert!(!streaming.matches("/events", &res));
+        assert!(streaming.matches("/poll/1", &res));
+
+        res.headers_mut().insert(
+            header::CONTENT_TYPE,
+            "text/event-stream; charset=utf-8".parse().unwrap(),
+        );
+        assert!(streaming.matches("/events", &res));
+
+        Streaming::prepare(&mut res);
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "no-cache");
+        assert_eq!(res.headers()["X-Accel-Buffering"], "no");
+
+        let invalid = model::Streaming {
+            paths: Some(vec!["(".to_string()]),
+        };
+        assert!(Streaming::new(&invalid).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Timings of recently forwarded requests
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use ya_http_proxy_model as model;
+
+/// Bounded log of request timings
+#[derive(Debug)]
+pub(crate) struct Tap {
+    capacity: usize,
+    requests: Mutex<VecDeque<model::RequestTiming>>,
+}
+
+impl Tap {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            requests: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns recorded timings, starting with the most recent request
+    pub fn requests(&self) -> Vec<model::RequestTiming> {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().rev().cloned().collect()
+    }
+
+    fn push(&self, timing: model::RequestTiming) {
+        let mut requests = self.requests.lock().unwrap();
+        while requests.len() >= self.capacity {
+            requests.pop_front();
+        }
+        requests.push_back(timing);
+    }
+}
+
+/// Request timing, recorded in the tap when dropped
+pub(crate) struct TapRecord {
+    tap: Arc<Tap>,
+    received: Instant,
+    pub timing: model::RequestTiming,
+}
+
+impl TapRecord {
+    pub fn new(tap: Arc<Tap>, received: Instant, timing: model::RequestTiming) -> Self {
+        Self {
+            tap,
+            received,
+            timing,
+        }
+    }
+}
+
+impl Drop for TapRecord {
+    fn drop(&mut self) {
+        let mut timing = self.timing.clone();
+        timing.total = self.received.elapsed();
+        self.tap.push(timing);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tap, TapRecord};
+    use chrono::Utc;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use ya_http_proxy_model as model;
+
+    #[test]
+    fn bounded_requests() {
+        let tap = Arc::new(Tap::new(2));
+        for path in ["/a", "/b", "/c"] {
+            let timing = model::RequestTiming {
+                received_at: Utc::now(),
+                method: "GET".to_string(),
+                path: path.to_string(),
+                username: None,
+                fingerprint: None,
+                status: None,
+                error: None,
+                queue: Duration::ZERO,
+                connect: None,
+                ttfb: None,
+                total: Duration::ZERO,
+            };
+            drop(TapRecord::new(tap.clone(), Instant::now(), timing));
+        }
+
+        let paths: Vec<_> = tap.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths, vec!["/c", "/b"]);
+    }
+}
@@ -0,0 +1,330 @@
+//! Server certificates of proxy instances, replaced when reloaded from their files
+use std::fs;
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use rustls::server::{ClientCertVerified, ClientCertVerifier, ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::DistinguishedNames;
+
+use crate::conf::ServerConf;
+use crate::error::{Error, TlsError};
+use crate::proxy::cert_digest;
+use crate::proxy::challenge::{Challenges, ACME_TLS_ALPN};
+use ya_http_proxy_model::ServerCertInfo;
+
+/// Certificate served by a proxy instance. Connections established before a reload
+/// keep the certificate they were offered
+pub(crate) struct ServerCert {
+    /// Certificate and key file paths
+    paths: RwLock<(PathBuf, PathBuf)>,
+    loaded: RwLock<(Arc<CertifiedKey>, DateTime<Utc>)>,
+    /// Modification times of the files last loaded
+    seen: Mutex<Option<(SystemTime, SystemTime)>>,
+    challenges: Arc<Challenges>,
+}
+
+impl ServerCert {
+    pub fn load(conf: &ServerConf, challenges: Arc<Challenges>) -> Result<Self, Error> {
+        let cert_path = match conf.server_cert.server_cert_store_path.clone() {
+            Some(path) => path,
+            None => return Err(TlsError::ServerCertStore("path not set".to_string()).into()),
+        };
+        let key_path = match conf.server_cert.server_key_path.clone() {
+            Some(path) => path,
+            None => return Err(TlsError::ServerCertKey("path not set".to_string()).into()),
+        };
+
+        let seen = modified(&cert_path, &key_path);
+        let (key, _) = read_certified_key(&cert_path, &key_path)?;
+        Ok(Self {
+            paths: RwLock::new((cert_path, key_path)),
+            loaded: RwLock::new((key, Utc::now())),
+            seen: Mutex::new(seen),
+            challenges,
+        })
+    }
+
+    /// Server configuration offering the current certificate
+    pub fn tls_conf(self: &Arc<Self>) -> Arc<rustls::ServerConfig> {
+        let mut cfg = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(Arc::new(AnyClientCert))
+            .with_cert_resolver(self.clone());
+
+        // accepted protocols are enforced per service
+        cfg.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        #[cfg(feature = "acme")]
+        cfg.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+        Arc::new(cfg)
+    }
+
+    /// Reads the certificate files again, returning the hash of the certificate file read.
+    /// The current certificate is kept on errors
+    pub fn reload(&self) -> Result<ServerCertInfo, Error> {
+        let (cert_path, key_path) = self.paths.read().unwrap().clone();
+        // files modified while being read are loaded again on the next check
+        let seen = modified(&cert_path, &key_path);
+        let (key, hash) = read_certified_key(&cert_path, &key_path)?;
+        let loaded_at = Utc::now();
+        *self.loaded.write().unwrap() = (key, loaded_at);
+        *self.seen.lock().unwrap() = seen;
+        Ok(ServerCertInfo { hash, loaded_at })
+    }
+
+    /// Reads a certificate from other files, served once installed
+    pub fn read(cert_path: PathBuf, key_path: PathBuf) -> Result<PendingCert, Error> {
+        let seen = modified(&cert_path, &key_path);
+        let (key, _) = read_certified_key(&cert_path, &key_path)?;
+        Ok(PendingCert {
+            paths: (cert_path, key_path),
+            key,
+            seen,
+        })
+    }
+
+    /// Serves a certificate read from other files
+    pub fn install(&self, pending: PendingCert) -> DateTime<Utc> {
+        let loaded_at = Utc::now();
+        *self.seen.lock().unwrap() = pending.seen;
+        *self.paths.write().unwrap() = pending.paths;
+        *self.loaded.write().unwrap() = (pending.key, loaded_at);
+        loaded_at
+    }
+
+    /// Certificate and key file paths of the certificate served
+    pub fn paths(&self) -> (PathBuf, PathBuf) {
+        self.paths.read().unwrap().clone()
+    }
+
+    /// Checks whether the certificate files were modified since they were last loaded
+    pub fn is_modified(&self) -> bool {
+        let (cert_path, key_path) = self.paths.read().unwrap().clone();
+        let current = modified(&cert_path, &key_path);
+        current.is_some() && current != *self.seen.lock().unwrap()
+    }
+}
+
+/// Certificate read by `ServerCert::read`, not served yet
+pub(crate) struct PendingCert {
+    paths: (PathBuf, PathBuf),
+    key: Arc<CertifiedKey>,
+    seen: Option<(SystemTime, SystemTime)>,
+}
+
+impl ResolvesServerCert for ServerCert {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        // TLS-ALPN-01 validation connections are only offered the challenge certificate
+        if let Some(mut protocols) = client_hello.alpn() {
+            if protocols.any(|p| p == ACME_TLS_ALPN) {
+                return self.challenges.tls_alpn(client_hello.server_name()?);
+            }
+        }
+        Some(self.loaded.read().unwrap().0.clone())
+    }
+}
+
+/// Requests an optional client certificate, identifying users by its fingerprint.
+/// Certificates are not validated against a trust anchor, while clients still prove
+/// the possession of the certificate key
+struct AnyClientCert;
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        Some(false)
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        Some(Vec::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some((modified(cert_path)?, modified(key_path)?))
+}
+
+/// Reads the certified key, along with the hash of the certificate file contents
+fn read_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<(Arc<CertifiedKey>, String), Error> {
+    let (store, contents) = read_cert_store(cert_path)?;
+    if store.is_empty() {
+        return Err(TlsError::ServerCertStore(format!(
+            "no certificates in '{}'",
+            cert_path.display()
+        ))
+        .into());
+    }
+    let key = read_cert_key(key_path)?;
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|e| TlsError::ServerCertKey(e.to_string()))?;
+    let key = Arc::new(CertifiedKey::new(store, key));
+    Ok((key, cert_digest(&contents)))
+}
+
+/// Reads certificates, along with the file contents they were parsed from
+fn read_cert_store(path: &Path) -> Result<(Vec<rustls::Certificate>, Vec<u8>), Error> {
+    let contents = fs::read(path).map_err(|e| {
+        TlsError::ServerCertStore(format!("cannot open '{}': {}", path.display(), e))
+    })?;
+
+    let store = rustls_pemfile::certs(&mut contents.as_slice()).map_err(|e| {
+        TlsError::ServerCertStore(format!("error reading '{}': {}", path.display(), e))
+    })?;
+    let store = store.into_iter().map(rustls::Certificate).collect();
+    Ok((store, contents))
+}
+
+fn read_cert_key(path: &Path) -> Result<rustls::PrivateKey, Error> {
+    let file = fs::File::open(path)
+        .map_err(|e| TlsError::ServerCertKey(format!("cannot open '{}': {}", path.display(), e)))?;
+    let mut reader = io::BufReader::new(file);
+
+    let mut keys = rustls_pemfile::rsa_private_keys(&mut reader).map_err(|e| {
+        TlsError::ServerCertKey(format!("error reading '{}': {}", path.display(), e))
+    })?;
+
+    if keys.is_empty() {
+        reader.seek(SeekFrom::Start(0))?;
+        keys = rustls_pemfile::pkcs8_private_keys(&mut reader).map_err(|e| {
+            TlsError::ServerCertKey(format!("error reading '{}': {}", path.display(), e))
+        })?;
+    }
+
+    if keys.is_empty() {
+        return Err(TlsError::ServerCertKey("missing server private key".to_string()).into());
+    } else if keys.len() > 1 {
+        return Err(TlsError::ServerCertKey("expected a single private key".to_string()).into());
+    }
+
+    Ok(rustls::PrivateKey(keys.remove(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use sha3::{Digest, Sha3_256};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::{read_cert_key, read_cert_store, ServerCert};
+    use crate::conf::ServerConf;
+    use crate::proxy::cert_hash;
+    use crate::proxy::stream::HttpStreamKind;
+
+    fn resources() -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/resources")
+    }
+
+    struct AnyServerCert;
+
+    impl rustls::client::ServerCertVerifier for AnyServerCert {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &rustls::Certificate,
+            _intermediates: &[rustls::Certificate],
+            _server_name: &rustls::ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        }
+    }
+
+    #[tokio::test]
+    async fn client_cert() {
+        let mut conf = ServerConf::default();
+        conf.server_cert.server_cert_store_path = Some(resources().join("server.cert"));
+        conf.server_cert.server_key_path = Some(resources().join("server.key"));
+        let cert = Arc::new(ServerCert::load(&conf, Default::default()).unwrap());
+        let acceptor = TlsAcceptor::from(cert.tls_conf());
+
+        let connect = |client_cert: bool| {
+            let builder = rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_custom_certificate_verifier(Arc::new(AnyServerCert));
+            let client_conf = match client_cert {
+                true => builder
+                    .with_single_cert(
+                        read_cert_store(&resources().join("server.cert")).unwrap().0,
+                        read_cert_key(&resources().join("server.key")).unwrap(),
+                    )
+                    .unwrap(),
+                false => builder.with_no_client_auth(),
+            };
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let name = rustls::ServerName::try_from("localhost").unwrap();
+            let connect = TlsConnector::from(Arc::new(client_conf)).connect(name, client);
+            let accept = acceptor.accept(server);
+            async move {
+                let (client, server) = futures::future::join(connect, accept).await;
+                let _client = client.unwrap();
+                HttpStreamKind::tls(server.unwrap(), "127.0.0.1:1".parse().unwrap()).client_cert()
+            }
+        };
+
+        let der = &read_cert_store(&resources().join("server.cert")).unwrap().0[0];
+        let fingerprint = format!("{:x}", Sha3_256::digest(&der.0));
+        assert_eq!(connect(true).await.unwrap().0, fingerprint);
+        // client certificates are optional
+        assert!(connect(false).await.is_none());
+    }
+
+    #[test]
+    fn reload() {
+        let resources = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/resources");
+        let dir = std::env::temp_dir().join(format!("ya-http-proxy-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("server.cert");
+        let key_path = dir.join("server.key");
+        fs::copy(resources.join("server.cert"), &cert_path).unwrap();
+        fs::copy(resources.join("server.key"), &key_path).unwrap();
+
+        let mut conf = ServerConf::default();
+        conf.server_cert.server_cert_store_path = Some(cert_path.clone());
+        conf.server_cert.server_key_path = Some(key_path.clone());
+        let cert = Arc::new(ServerCert::load(&conf, Default::default()).unwrap());
+        let _ = cert.tls_conf();
+        assert!(!cert.is_modified());
+
+        // a partially written certificate is not served
+        fs::write(&cert_path, "").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&cert_path)
+            .and_then(|f| f.set_modified(later))
+            .unwrap();
+        assert!(cert.is_modified());
+        assert!(cert.reload().is_err());
+        // checked again until reloaded
+        assert!(cert.is_modified());
+
+        fs::copy(resources.join("server.cert"), &cert_path).unwrap();
+        let loaded_at = cert.loaded.read().unwrap().1;
+        let info = cert.reload().unwrap();
+        assert!(info.loaded_at >= loaded_at);
+        assert_eq!(info.hash, cert_hash(&cert_path).unwrap());
+        assert!(!cert.is_modified());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
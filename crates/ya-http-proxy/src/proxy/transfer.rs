@@ -0,0 +1,151 @@
+//! Accounting of request and response body bytes
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use std::io;
+
+use futures::StreamExt;
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+use tokio::sync::RwLock;
+
+use crate::proxy::{ProxyStats, Usage};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Body bytes transferred by a user within a single request. Credentials usage is
+/// updated as bytes are transferred, proxy stats once the request and response are done
+#[derive(Clone)]
+pub(crate) struct Transfer {
+    inner: Arc<TransferInner>,
+}
+
+struct TransferInner {
+    stats: Arc<RwLock<ProxyStats>>,
+    usage: Option<Arc<Usage>>,
+    service: String,
+    endpoint: String,
+    username: String,
+    bytes: AtomicU64,
+}
+
+impl Transfer {
+    pub fn new(
+        stats: Arc<RwLock<ProxyStats>>,
+        usage: Option<Arc<Usage>>,
+        service: &str,
+        endpoint: &str,
+        username: &str,
+    ) -> Self {
+        Self {
+            inner: Arc::new(TransferInner {
+                stats,
+                usage,
+                service: service.to_string(),
+                endpoint: endpoint.to_string(),
+                username: username.to_string(),
+                bytes: Default::default(),
+            }),
+        }
+    }
+
+    /// Counts transferred bytes. Returns `false` once the credentials byte quota is
+    /// exceeded, in which case the transfer is aborted
+    pub fn add(&self, bytes: usize) -> bool {
+        let bytes = bytes as u64;
+        self.inner.bytes.fetch_add(bytes, Ordering::Relaxed);
+        match self.inner.usage {
+            Some(ref usage) => usage.add_bytes(bytes),
+            None => true,
+        }
+    }
+
+    /// Counts request body bytes as they are forwarded
+    pub fn request_body(&self, body: Body) -> Body {
+        // a wrapped body has no known length, which would make requests without a body chunked
+        if body.is_end_stream() {
+            return body;
+        }
+        let transfer = self.clone();
+        Body::wrap_stream(body.map(move |chunk| -> Result<Bytes, BoxError> {
+            let chunk = chunk?;
+            match transfer.add(chunk.len()) {
+                true => Ok(chunk),
+                false => Err(io::Error::other("byte quota exceeded").into()),
+            }
+        }))
+    }
+}
+
+impl Drop for TransferInner {
+    fn drop(&mut self) {
+        let bytes = *self.bytes.get_mut();
+        if bytes == 0 {
+            return;
+        }
+
+        if let Ok(mut stats) = self.stats.try_write() {
+            stats.add_bytes(&self.service, &self.endpoint, &self.username, bytes);
+            return;
+        }
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+        let stats = self.stats.clone();
+        let service = std::mem::take(&mut self.service);
+        let endpoint = std::mem::take(&mut self.endpoint);
+        let username = std::mem::take(&mut self.username);
+        handle.spawn(async move {
+            let mut stats = stats.write().await;
+            stats.add_bytes(&service, &endpoint, &username, bytes);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hyper::Body;
+    use tokio::sync::RwLock;
+
+    use super::Transfer;
+    use crate::proxy::{ProxyStats, Usage};
+    use ya_http_proxy_model::UserLimits;
+
+    #[tokio::test]
+    async fn body_bytes() {
+        let stats = Arc::new(RwLock::new(ProxyStats::default()));
+        let transfer = Transfer::new(stats.clone(), None, "svc", "/a", "user");
+
+        let body = transfer.request_body(Body::from("request"));
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "request");
+        assert!(transfer.add(8));
+        // empty bodies are kept as is
+        let body = transfer.request_body(Body::empty());
+        assert!(hyper::body::HttpBody::is_end_stream(&body));
+        drop(transfer);
+
+        let stats = stats.read().await;
+        assert_eq!(stats.user_bytes["user"], 15);
+        assert_eq!(stats.service_bytes["svc"], 15);
+    }
+
+    #[tokio::test]
+    async fn byte_quota() {
+        let stats = Arc::new(RwLock::new(ProxyStats::default()));
+        let usage = Usage::new(Some(UserLimits {
+            max_requests: None,
+            single_session: false,
+            max_bytes: Some(10),
+        }));
+        let transfer = Transfer::new(stats, Some(Arc::new(usage)), "svc", "/a", "user");
+
+        let body = transfer.request_body(Body::from("request"));
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "request");
+        let body = transfer.request_body(Body::from("request"));
+        assert!(hyper::body::to_bytes(body).await.is_err());
+        assert!(!transfer.add(1));
+    }
+}
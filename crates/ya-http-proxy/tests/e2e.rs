@@ -1,20 +1,25 @@
-#![allow(unused)]
-
-use std::collections::HashSet;
+//! Integration tests of the Management API and proxied requests.
+//!
+//! Every test runs its own Management API and upstream service on ephemeral ports. Services
+//! listen once created (`POST /services` returns after binding), so no test waits for
+//! servers to start.
 use std::env;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::time::Duration;
 
 use anyhow::Result;
 use awc::Connector;
-use hyper::http::{Method, Uri};
+use hyper::http::{Method, StatusCode, Uri};
 use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
 use serde::{Deserialize, Serialize};
 
 use ya_http_proxy::{Management, ProxyConf, ProxyManager};
 use ya_http_proxy_model as model;
-use ya_http_proxy_model::Addresses;
+
+const USERNAME: &str = "user1";
+const PASSWORD: &str = "password123";
 
 #[derive(Clone)]
 struct WebClient {
@@ -77,6 +82,29 @@ impl WebClient {
         self.request::<(), (), S>(Method::DELETE, uri, None).await
     }
 
+    /// Returns the response status and the `WWW-Authenticate` header value
+    pub async fn status<S>(&self, uri: S) -> Result<(StatusCode, Option<String>)>
+    where
+        S: AsRef<str>,
+    {
+        let url = format!("{}{}", self.url, uri.as_ref());
+        let mut req = self.inner.request(Method::GET, &url);
+        if let Some((username, password)) = self.credentials.as_ref() {
+            req = req.basic_auth(username, password);
+        }
+
+        let res = req
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        let authenticate = res
+            .headers()
+            .get("www-authenticate")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        Ok((res.status(), authenticate))
+    }
+
     async fn request<P, R, S>(&self, method: Method, uri: S, payload: Option<&P>) -> Result<R>
     where
         P: Serialize,
@@ -101,10 +129,149 @@ impl WebClient {
         if !res.status().is_success() {
             anyhow::bail!("{} {}", url, res.status().as_str());
         }
+        if res.status() == StatusCode::NO_CONTENT {
+            return Ok(serde_json::from_value(serde_json::Value::Null)?);
+        }
         Ok(res.json().await?)
     }
 }
 
+/// Management API and upstream service of a single test
+struct Harness {
+    client: WebClient,
+    upstream: SocketAddr,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl Harness {
+    async fn start() -> Result<Self> {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let mut management = Management::new(ProxyManager::new(default_proxy_conf()?));
+        management.bind("127.0.0.1:0".parse()?)?;
+        let management_url = format!("http://{}", management.local_addr()?);
+
+        let server = tokio::task::spawn(async move {
+            if let Err(e) = management.await {
+                panic!("Management API server error: {}", e);
+            }
+        });
+
+        let client = WebClient::new(management_url)?;
+        // the listener is bound, the first request is served when the server is ready
+        let status: model::Status = client.get("status").await?;
+        assert_eq!(status.api_version, model::API_VERSION);
+
+        let upstream = service::spawn("127.0.0.1:0".to_string()).await?;
+        Ok(Self {
+            client,
+            upstream,
+            server,
+        })
+    }
+
+    /// Creates a Basic auth service with a single user
+    async fn service(&self, name: &str, from: &str, to: &str) -> Result<Service> {
+        let create = create_service(name, from, &format!("http://{}{}", self.upstream, to))?;
+        let create = model::CreateService {
+            bind_http: Some(any_port().into()),
+            bind_https: Some(any_port().into()),
+            ..create
+        };
+
+        let service: model::Service = self.client.post("services", &create).await?;
+        assert_eq!(service.inner.name, name);
+        let http = bound(service.inner.bind_http.as_ref())?;
+        let https = bound(service.inner.bind_https.as_ref())?;
+
+        let user: model::User = self
+            .client
+            .post(
+                format!("services/{}/users", name),
+                &model::CreateUser::basic(USERNAME, PASSWORD),
+            )
+            .await?;
+        assert_eq!(user.username, USERNAME);
+
+        Ok(Service {
+            name: name.to_string(),
+            http: format!("http://localhost:{}", http.port()),
+            https: format!("https://localhost:{}", https.port()),
+        })
+    }
+
+    async fn shutdown(self) -> Result<()> {
+        for confirm in ["", model::SHUTDOWN_CONFIRMATION] {
+            let unconfirmed = model::Shutdown::new(confirm, None);
+            assert!(self
+                .client
+                .post::<_, (), _>("control/shutdown", &unconfirmed)
+                .await
+                .is_err());
+        }
+
+        let nonce = shutdown_nonce(&self.client).await?;
+        let shutdown = model::Shutdown::new(nonce, Some(Duration::from_secs(5)));
+        self.client
+            .post::<_, (), _>("control/shutdown", &shutdown)
+            .await?;
+        self.server.await?;
+        Ok(())
+    }
+}
+
+/// Service created by the harness
+struct Service {
+    name: String,
+    http: String,
+    https: String,
+}
+
+/// Runs the test on a single threaded runtime, as required by the web client
+fn run<F>(f: impl FnOnce(Harness) -> F) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let task_set = tokio::task::LocalSet::new();
+    task_set.block_on(&rt, async move {
+        let harness = Harness::start().await?;
+        let client = harness.client.clone();
+        let result = f(harness).await;
+
+        // the harness is consumed by the test, shut the server down on failure
+        if result.is_err() {
+            if let Ok(nonce) = shutdown_nonce(&client).await {
+                let shutdown = model::Shutdown::new(nonce, None);
+                let _ = client.post::<_, (), _>("control/shutdown", &shutdown).await;
+            }
+        }
+        result
+    })
+}
+
+/// Confirmation of shutdown requests, reported in the proxy status
+async fn shutdown_nonce(client: &WebClient) -> Result<String> {
+    let status: model::Status = client.get("status").await?;
+    status
+        .shutdown_nonce
+        .ok_or_else(|| anyhow::anyhow!("shutdown nonce not reported"))
+}
+
+/// Services bound to port 0 are assigned a free port by the system
+fn any_port() -> SocketAddr {
+    ([127, 0, 0, 1], 0).into()
+}
+
+/// Address the service is bound to
+fn bound(addrs: Option<&model::Addresses>) -> Result<SocketAddr> {
+    let addr = addrs.and_then(|addrs| addrs.to_vec().into_iter().next());
+    addr.ok_or_else(|| anyhow::anyhow!("service is not bound"))
+}
+
 fn default_proxy_conf() -> Result<ProxyConf> {
     let cert_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR")?)
         .join("tests")
@@ -113,7 +280,7 @@ fn default_proxy_conf() -> Result<ProxyConf> {
     let cert_key_path = cert_dir.join("server.key");
 
     let mut conf = ProxyConf::default();
-    conf.server.bind_http = Some(SocketAddr::from(([127, 0, 0, 1], 8080)).into());
+    conf.server.bind_http = Some(any_port().into());
     conf.server.bind_https = None;
     conf.server.server_cert.server_cert_store_path = Some(cert_store_path);
     conf.server.server_cert.server_key_path = Some(cert_key_path);
@@ -121,214 +288,236 @@ fn default_proxy_conf() -> Result<ProxyConf> {
     Ok(conf)
 }
 
-async fn e2e_requests(client: WebClient) -> anyhow::Result<()> {
-    let service_name = "test-service".to_string();
-    let service_https: SocketAddr = "127.0.0.1:8443".parse()?;
-    let service_http: SocketAddr = "127.0.0.1:8080".parse()?;
-
-    let service_endpoint = "/test".to_string();
-    let service_https_url = format!(
-        "https://localhost:{}{}",
-        service_https.port(),
-        service_endpoint
-    );
-    let service_http_url = format!(
-        "http://localhost:{}{}",
-        service_http.port(),
-        service_endpoint
-    );
-
-    let fwd_service_addr = service::spawn("127.0.0.1:0".to_string()).await?;
-    let fwd_service_url = format!("http://{}/resource", fwd_service_addr);
-
-    let user_name = "user1".to_string();
-    let password = "password123".to_string();
-    log::info!("[s] Creating a new service1");
-
-    let create_service = model::CreateService {
-        name: service_name.clone(),
+fn create_service(name: &str, from: &str, to: &str) -> Result<model::CreateService> {
+    Ok(model::CreateService {
+        name: name.to_string(),
         server_name: vec!["test-server".to_string()],
-        bind_https: Some(service_https.into()),
-        bind_http: Some(service_http.into()),
+        bind_https: None,
+        bind_http: None,
         cert: Default::default(),
         auth: Some(model::Auth {
             method: model::AuthMethod::Basic,
+            webhook: None,
+            users: None,
+            jwt: None,
+            api_key_header: None,
         }),
-        from: service_endpoint.parse()?,
-        to: fwd_service_url.parse()?,
+        from: from.parse()?,
+        to: to.parse()?,
         timeouts: None,
         user: None,
         cpu_threads: Some(2),
-    };
-    let create_user = model::CreateUser {
-        username: user_name.clone(),
-        password: password.clone(),
-    };
-    log::info!("[s] Creating a new service2");
-
-    tokio::time::sleep(Duration::from_millis(1000)).await;
-    let services_get: Vec<model::Service> = client.get("services").await.unwrap();
-    assert_eq!(0, services_get.len());
-
-    log::info!("[s] Creating a new service3");
-    tokio::time::sleep(Duration::from_millis(1000)).await;
-    let service_post: model::Service = client.post("services", &create_service).await.unwrap();
-    log::info!("[s] Created service: {:?}", service_post);
-    tokio::time::sleep(Duration::from_millis(1000)).await;
-    let service_get: model::Service = client
-        .get(format!("services/{}", service_name))
-        .await
-        .unwrap();
-    log::info!("[s] Retrieved service: {:?}", service_get);
-    tokio::time::sleep(Duration::from_millis(1000)).await;
-
-    let services_get: Vec<model::Service> = client.get("services").await?;
-    assert_eq!(1, services_get.len());
-
-    println!();
-
-    let users_get: Vec<model::User> = client
-        .get(format!("services/{}/users", service_name))
-        .await?;
-    assert_eq!(0, users_get.len());
-
-    println!("[s] [u] Creating a new user");
-    let user_post: model::User = client
-        .post(format!("services/{}/users", service_name), &create_user)
-        .await?;
-    println!("[s] [u] Created user: {:?}", user_post);
-    let user_get: model::User = client
-        .get(format!("services/{}/users/{}", service_name, user_name))
-        .await?;
-    println!("[s] [u] Retrieved user: {:?}", user_get);
-
-    let users_get: Vec<model::User> = client
-        .get(format!("services/{}/users", service_name))
-        .await?;
-    assert_eq!(1, users_get.len());
-
-    println!("[+] Requesting {}", service_http_url);
-    let service_client = WebClient::new_service(service_http_url, &user_name, &password)?;
-    let response: Result<String, _> = service_client.get("").await;
-    println!("[+] Response: {:?}", response);
-    response.expect("request failed");
-
-    println!("[+] Requesting {}", service_https_url);
-    let service_client = WebClient::new_service_tls(service_https_url, &user_name, &password)?;
-    let response: Result<String, _> = service_client.get("").await;
-    println!("[+] Response: {:?}", response);
-    response.expect("request failed");
-
-    let stats_get: model::UserStats = client
-        .get(format!(
-            "services/{}/users/{}/stats",
-            service_name, user_name
-        ))
-        .await?;
-    println!("[s] [u] User stats: {:?}", stats_get);
-
-    let ep_stats_get: model::UserEndpointStats = client
-        .get(format!(
-            "services/{}/users/{}/endpoints/stats",
-            service_name, user_name
-        ))
-        .await?;
-    println!("[s] [u] User endpoint stats: {:?}", ep_stats_get);
-
-    println!("[s] [u] Removing the user");
-    client
-        .delete(format!("services/{}/users/{}", service_name, user_name))
-        .await?;
-
-    let users_get: Vec<model::User> = client
-        .get(format!("services/{}/users", service_name))
-        .await?;
-    assert_eq!(0, users_get.len());
-
-    println!("[s] Removing the service");
-    client.delete(format!("services/{}", service_name)).await?;
-
-    let services_get: Vec<model::Service> = client.get("services").await?;
-    assert_eq!(0, services_get.len());
-
-    Ok(())
+        alerts: None,
+        protocols: None,
+        upstream_protocol: None,
+        filters: None,
+        request_tap: None,
+        strip_response_headers: None,
+        rewrite_redirects: None,
+        rewrite_cookies: None,
+        rewrite_html: None,
+        deny: None,
+        address_policy: None,
+        max_users: None,
+        server_tuning: None,
+        answer_options: None,
+        streaming: None,
+        journal: None,
+        rate_limit: None,
+    })
 }
 
-async fn e2e() -> anyhow::Result<()> {
-    let log_level = env::var("RUST_LOG").unwrap_or_else(|_| "info".into());
-    env::set_var("RUST_LOG", &log_level);
-    env_logger::init();
+#[test]
+fn service_lifecycle() -> Result<()> {
+    run(|harness| async move {
+        let client = harness.client.clone();
+        let services: Vec<model::Service> = client.get("services").await?;
+        assert!(services.is_empty());
+
+        let service = harness.service("lifecycle", "/test", "/resource").await?;
+        let services: Vec<model::Service> = client.get("services").await?;
+        assert_eq!(services.len(), 1);
+        let _: model::Service = client.get(format!("services/{}", service.name)).await?;
+
+        let users: Vec<model::User> = client
+            .get(format!("services/{}/users", service.name))
+            .await?;
+        assert_eq!(users.len(), 1);
+
+        let user_url = format!("{}services/{}/users/{}", client.url, service.name, USERNAME);
+        let res = client
+            .inner
+            .request(Method::GET, &user_url)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        let etag = res.headers().get("etag").expect("missing ETag").clone();
+        let etag = etag.to_str()?;
+
+        let res = client
+            .inner
+            .request(Method::DELETE, &user_url)
+            .insert_header(("If-Match", "\"stale\""))
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        let res = client
+            .inner
+            .request(Method::DELETE, &user_url)
+            .insert_header(("If-Match", etag))
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let users: Vec<model::User> = client
+            .get(format!("services/{}/users", service.name))
+            .await?;
+        assert!(users.is_empty());
+
+        client.delete(format!("services/{}", service.name)).await?;
+        let services: Vec<model::Service> = client.get("services").await?;
+        assert!(services.is_empty());
+
+        harness.shutdown().await
+    })
+}
 
-    let proxy_conf = default_proxy_conf()?;
-    let mut management = Management::new(ProxyManager::new(proxy_conf.clone()));
-    management.bind("127.0.0.1:9090".parse()?)?;
-    let management_url = format!("http://{}", management.local_addr()?);
+#[test]
+fn https_and_http_requests() -> Result<()> {
+    run(|harness| async move {
+        let service = harness.service("requests", "/test", "/resource").await?;
 
-    tokio::task::spawn(async move {
-        if let Err(e) = management.await {
-            panic!("Management API server error: {}", e);
-        }
-        println!("Management API server stopped");
-    });
+        let http = WebClient::new_service(service.http, USERNAME, PASSWORD)?;
+        let response: String = http.get("test").await?;
+        assert_eq!(response, "/resource");
 
-    let client = WebClient::new(management_url)?;
-    let result = e2e_requests(client.clone()).await;
-    //Do not call shutdown here, because it will stop application without returning marking test as succeeded, even if it fails
-    // let _ = client.post::<_, (), _>("control/shutdown", &()).await;
+        let https = WebClient::new_service_tls(service.https, USERNAME, PASSWORD)?;
+        let response: String = https.get("test").await?;
+        assert_eq!(response, "/resource");
 
-    result
+        harness.shutdown().await
+    })
 }
 
-#[cfg(feature = "tests-e2e")]
 #[test]
-fn test_e2e() -> anyhow::Result<()> {
-    let rt = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .thread_name("service")
-        .worker_threads(1)
-        .build()
-        .unwrap();
+fn auth_failures() -> Result<()> {
+    run(|harness| async move {
+        let service = harness.service("auth", "/test", "/resource").await?;
+
+        let anonymous = WebClient::new(service.http.clone())?;
+        let (status, authenticate) = anonymous.status("test").await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(authenticate.unwrap_or_default().starts_with("Basic"));
+
+        for (username, password) in [(USERNAME, "invalid"), ("unknown", PASSWORD)] {
+            let client = WebClient::new_service(service.http.clone(), username, password)?;
+            let (status, _) = client.status("test").await?;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
 
-    let task_set = tokio::task::LocalSet::new();
-    match task_set.block_on(&rt, e2e()) {
-        Ok(v) => Ok(v),
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            Err(e)
+        let https = WebClient::new_service_tls(service.https, USERNAME, "invalid")?;
+        let (status, _) = https.status("test").await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // rejected requests are not forwarded
+        let stats: model::ServiceStats = harness
+            .client
+            .get(format!("services/{}/stats", service.name))
+            .await?;
+        assert!(stats.upstreams.iter().all(|u| u.requests == 0));
+
+        harness.shutdown().await
+    })
+}
+
+#[test]
+fn path_merging() -> Result<()> {
+    run(|harness| async move {
+        let service = harness.service("paths", "/api", "/base").await?;
+        let client = WebClient::new_service(service.http, USERNAME, PASSWORD)?;
+
+        for (path, expected) in [
+            ("api", "/base"),
+            ("api/", "/base/"),
+            ("api/x/y", "/base/x/y"),
+            ("api/x?q=1&r=2", "/base/x?q=1&r=2"),
+        ] {
+            let forwarded: String = client.get(path).await?;
+            assert_eq!(forwarded, expected, "request path {}", path);
         }
-    }
+
+        harness.shutdown().await
+    })
+}
+
+#[test]
+fn stats() -> Result<()> {
+    run(|harness| async move {
+        let service = harness.service("stats", "/test", "/resource").await?;
+
+        let http = WebClient::new_service(service.http, USERNAME, PASSWORD)?;
+        let _: String = http.get("test").await?;
+        let https = WebClient::new_service_tls(service.https, USERNAME, PASSWORD)?;
+        let _: String = https.get("test").await?;
+
+        let client = &harness.client;
+        let user_stats: model::UserStats = client
+            .get(format!(
+                "services/{}/users/{}/stats",
+                service.name, USERNAME
+            ))
+            .await?;
+        assert_eq!(user_stats.requests, 2);
+
+        let endpoint_stats: model::UserEndpointStats = client
+            .get(format!(
+                "services/{}/users/{}/endpoints/stats",
+                service.name, USERNAME
+            ))
+            .await?;
+        assert!(!endpoint_stats.0.is_empty());
+
+        let service_stats: model::ServiceStats = client
+            .get(format!("services/{}/stats", service.name))
+            .await?;
+        assert_eq!(service_stats.users, 1);
+        assert_eq!(service_stats.upstreams.len(), 1);
+        assert_eq!(service_stats.upstreams[0].requests, 2);
+        assert!(service_stats.upstreams[0].available);
+
+        harness.shutdown().await
+    })
 }
 
 mod service {
     use std::net::SocketAddr;
 
-    use actix_web::{middleware, web, App, HttpResponse, HttpServer};
+    use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
     use futures::channel::oneshot;
 
-    async fn resource() -> Result<HttpResponse, actix_web::Error> {
-        Ok(HttpResponse::Ok().json("OK"))
+    /// Responds with the requested path and query
+    async fn echo(req: HttpRequest) -> Result<HttpResponse, actix_web::Error> {
+        Ok(HttpResponse::Ok().json(req.uri().to_string()))
     }
 
     pub async fn spawn(address: String) -> anyhow::Result<SocketAddr> {
         let (tx, rx) = oneshot::channel();
 
         std::thread::spawn(move || {
-            println!("[t] Starting target service ...");
-
             let rt = tokio::runtime::Builder::new_current_thread()
                 .enable_all()
-                .worker_threads(1)
                 .build()
                 .unwrap();
 
-            println!("[t] Target service started");
-
             let task_set = tokio::task::LocalSet::new();
             task_set.block_on(&rt, async move {
                 let server = HttpServer::new(move || {
                     App::new()
                         .wrap(middleware::Logger::default())
-                        .service(web::resource("/resource").route(web::get().to(resource)))
+                        .default_service(web::to(echo))
                 })
                 .workers(1)
                 .bind(address)
@@ -341,8 +530,6 @@ mod service {
                     .map_err(|e| anyhow::anyhow!(e.to_string()))?;
                 server.await.map_err(|e| anyhow::anyhow!(e.to_string()))?;
 
-                println!("[t] Target service stopped");
-
                 Ok::<_, anyhow::Error>(())
             })?;
 
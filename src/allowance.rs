@@ -0,0 +1,207 @@
+//! Usage paid for by requestors. The provider keeps per-service allowances in a control
+//! file in the data directory, which are pushed to the proxy. Users exceeding their
+//! allowance are throttled until it is raised
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use ya_http_proxy_client::model::UserAllowance;
+use ya_http_proxy_client::{Error, ManagementApi};
+
+use crate::state::service_file;
+
+const ALLOWANCE_DIR: &str = "allowances";
+const ALLOWANCE_EXT: &str = "json";
+
+/// Control file contents
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AllowanceFile {
+    /// Allowance of users not listed in `users`
+    #[serde(default)]
+    default: Option<UserAllowance>,
+    #[serde(default)]
+    users: HashMap<String, UserAllowance>,
+}
+
+impl AllowanceFile {
+    fn get(&self, username: &str) -> Option<UserAllowance> {
+        self.users.get(username).or(self.default.as_ref()).cloned()
+    }
+}
+
+/// Allowances of runtime users, as pushed to the proxy. Clones share the pushed state
+#[derive(Clone, Debug, Default)]
+pub struct Allowances {
+    path: Option<PathBuf>,
+    /// Also serializes concurrent synchronizations
+    applied: Rc<Mutex<HashMap<String, Option<UserAllowance>>>>,
+}
+
+impl Allowances {
+    pub fn new(data_dir: &Path, service: &str) -> Self {
+        Self {
+            path: Some(service_file(
+                data_dir,
+                ALLOWANCE_DIR,
+                service,
+                ALLOWANCE_EXT,
+            )),
+            applied: Default::default(),
+        }
+    }
+
+    /// Pushes allowance changes of the users to the proxy
+    pub async fn sync(&self, api: &ManagementApi, service_name: &str, usernames: Vec<String>) {
+        let mut applied = self.applied.lock().await;
+        let file = match self.read().await {
+            Some(file) => file,
+            None => return,
+        };
+
+        for (username, allowance) in pending(&mut applied, &file, usernames) {
+            let result = api
+                .set_user_allowance(service_name, &username, allowance.as_ref())
+                .await;
+            match result {
+                Ok(_) => log::info!("Allowance of user '{}': {:?}", username, allowance),
+                // revoked by the proxy
+                Err(Error::SendRequestError { code, .. }) if code.as_u16() == 404 => {}
+                Err(e) => {
+                    log::warn!("Unable to set the allowance of user '{}': {}", username, e);
+                    continue;
+                }
+            }
+            applied.insert(username, allowance);
+        }
+    }
+
+    /// Forgets the allowance pushed for a removed user
+    pub async fn forget(&self, username: &str) {
+        self.applied.lock().await.remove(username);
+    }
+
+    /// Reads the control file off the runtime thread
+    async fn read(&self) -> Option<AllowanceFile> {
+        let path = self.path.clone()?;
+        tokio::task::spawn_blocking(move || read_file(&path))
+            .await
+            .ok()?
+    }
+}
+
+/// Returns `None` when the control file cannot be read
+fn read_file(path: &Path) -> Option<AllowanceFile> {
+    match fs::read(path) {
+        Ok(contents) => match serde_json::from_slice(&contents) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                log::warn!("Invalid allowance file '{}': {}", path.display(), e);
+                None
+            }
+        },
+        // allowances are lifted once the file is removed
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some(Default::default()),
+        Err(e) => {
+            log::warn!("Unable to read '{}': {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Users with an allowance different from the one pushed to the proxy
+fn pending(
+    applied: &mut HashMap<String, Option<UserAllowance>>,
+    file: &AllowanceFile,
+    usernames: Vec<String>,
+) -> Vec<(String, Option<UserAllowance>)> {
+    applied.retain(|username, _| usernames.contains(username));
+    usernames
+        .into_iter()
+        .filter_map(|username| {
+            let allowance = file.get(&username);
+            let changed = match applied.get(&username) {
+                Some(applied) => *applied != allowance,
+                None => allowance.is_some(),
+            };
+            changed.then_some((username, allowance))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ya_http_proxy_client::model::UserAllowance;
+
+    use std::collections::HashMap;
+
+    use super::{pending, read_file, Allowances};
+
+    fn requests(max: usize) -> Option<UserAllowance> {
+        Some(UserAllowance {
+            max_requests: Some(max),
+            max_bytes: None,
+        })
+    }
+
+    #[test]
+    fn pending_allowances() {
+        let dir = std::env::temp_dir().join(format!(
+            "ya-runtime-http-auth-allowance-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let users = || vec!["a".to_string(), "b".to_string()];
+
+        let path = Allowances::new(&dir, "svc/1").path.unwrap();
+        assert_eq!(path, dir.join("allowances").join("svc_2f1.json"));
+        let mut applied = HashMap::new();
+        let file = read_file(&path).unwrap();
+        assert_eq!(pending(&mut applied, &file, users()), vec![]);
+
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(
+            &path,
+            r#"{"default": {"maxRequests": 10}, "users": {"b": {"maxRequests": 20}}}"#,
+        )
+        .unwrap();
+        let file = read_file(&path).unwrap();
+        let changed = pending(&mut applied, &file, users());
+        assert_eq!(
+            changed,
+            vec![
+                ("a".to_string(), requests(10)),
+                ("b".to_string(), requests(20))
+            ]
+        );
+        applied.extend(changed);
+        assert_eq!(pending(&mut applied, &file, users()), vec![]);
+
+        // partially written files are skipped
+        std::fs::write(&path, r#"{"default": {"#).unwrap();
+        assert!(read_file(&path).is_none());
+
+        std::fs::write(&path, r#"{"default": {"maxRequests": 15}}"#).unwrap();
+        let file = read_file(&path).unwrap();
+        assert_eq!(
+            pending(&mut applied, &file, users()),
+            vec![
+                ("a".to_string(), requests(15)),
+                ("b".to_string(), requests(15))
+            ]
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        let file = read_file(&path).unwrap();
+        assert_eq!(
+            pending(&mut applied, &file, vec!["a".to_string()]),
+            vec![("a".to_string(), None)]
+        );
+        assert!(!applied.contains_key("b"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+//! Removal of service users, finished by the next runtime instance when interrupted
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use ya_http_proxy_client::ManagementApi;
+
+use crate::state::service_file;
+use crate::MANAGEMENT_API_MAX_CONCURRENT_REQUESTS;
+
+const TOMBSTONE_DIR: &str = "tombstones";
+const TOMBSTONE_EXT: &str = "json";
+const DELETE_ATTEMPTS: u32 = 3;
+const DELETE_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Users that could not be removed when an activity stopped
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tombstone {
+    pub service: String,
+    pub users: Vec<String>,
+}
+
+impl Tombstone {
+    fn path(data_dir: &Path, service: &str) -> PathBuf {
+        service_file(data_dir, TOMBSTONE_DIR, service, TOMBSTONE_EXT)
+    }
+
+    /// Records the users, along with users recorded by earlier runtime instances
+    pub fn save(&self, data_dir: &Path) -> std::io::Result<()> {
+        let mut users = Self::load(data_dir, &self.service)
+            .map(|t| t.users)
+            .unwrap_or_default();
+        for user in self.users.iter() {
+            if !users.contains(user) {
+                users.push(user.clone());
+            }
+        }
+        self.write(data_dir, users)
+    }
+
+    /// Drops removed users from the recorded ones. The file is removed once no users are left
+    fn forget(&self, data_dir: &Path, removed: &[String]) -> std::io::Result<()> {
+        let mut users = Self::load(data_dir, &self.service)
+            .map(|t| t.users)
+            .unwrap_or_default();
+        users.retain(|user| !removed.contains(user));
+        if users.is_empty() {
+            return match std::fs::remove_file(Self::path(data_dir, &self.service)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        self.write(data_dir, users)
+    }
+
+    fn write(&self, data_dir: &Path, users: Vec<String>) -> std::io::Result<()> {
+        let path = Self::path(data_dir, &self.service);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tombstone = Self {
+            service: self.service.clone(),
+            users,
+        };
+        std::fs::write(path, serde_json::to_vec(&tombstone)?)
+    }
+
+    fn load(data_dir: &Path, service: &str) -> Option<Self> {
+        Self::read(&Self::path(data_dir, service))
+    }
+
+    fn read(path: &Path) -> Option<Self> {
+        let contents = std::fs::read(path).ok()?;
+        match serde_json::from_slice(&contents) {
+            Ok(tombstone) => Some(tombstone),
+            Err(e) => {
+                log::warn!("Invalid tombstone file '{}': {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn load_all(data_dir: &Path) -> Vec<Self> {
+        let entries = match std::fs::read_dir(data_dir.join(TOMBSTONE_DIR)) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().map(|e| e == TOMBSTONE_EXT).unwrap_or(false))
+            .filter_map(|p| Self::read(&p))
+            .collect()
+    }
+}
+
+/// Deletes service users, retrying failed deletions with backoff.
+/// Returns names of users that could not be removed
+pub async fn delete_users(
+    api: &ManagementApi,
+    service_name: &str,
+    usernames: Vec<String>,
+) -> Vec<String> {
+    let mut pending = usernames;
+    let mut backoff = DELETE_BACKOFF;
+
+    for attempt in 1..=DELETE_ATTEMPTS {
+        pending = futures::stream::iter(pending)
+            .map(|username| async move {
+                let result = api.delete_user(service_name, &username).await;
+                (username, result)
+            })
+            .buffer_unordered(MANAGEMENT_API_MAX_CONCURRENT_REQUESTS)
+            .filter_map(|(username, result)| async move {
+                match result {
+                    Ok(_) => None,
+                    // removed along with the service or by a previous attempt
+                    Err(e) if e.status().map(|s| s.as_u16()) == Some(404) => None,
+                    Err(e) => {
+                        log::warn!(
+                            "Failed to remove user '{}' (attempt {}/{}): {}",
+                            username,
+                            attempt,
+                            DELETE_ATTEMPTS,
+                            e
+                        );
+                        Some(username)
+                    }
+                }
+            })
+            .collect()
+            .await;
+
+        if pending.is_empty() || attempt == DELETE_ATTEMPTS {
+            break;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+    pending
+}
+
+/// Removes users left behind by previous runtime instances
+pub async fn finish(api: &ManagementApi, data_dir: &Path) {
+    for tombstone in Tombstone::load_all(data_dir) {
+        log::info!(
+            "Removing {} users of service '{}' left by a previous activity",
+            tombstone.users.len(),
+            tombstone.service
+        );
+
+        let pending = delete_users(api, &tombstone.service, tombstone.users.clone()).await;
+        // users recorded in the meantime by other runtime instances are kept
+        let removed: Vec<_> = tombstone
+            .users
+            .iter()
+            .filter(|user| !pending.contains(user))
+            .cloned()
+            .collect();
+        if let Err(e) = tombstone.forget(data_dir, &removed) {
+            log::warn!(
+                "Unable to update the tombstone of service '{}': {}",
+                tombstone.service,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tombstone;
+
+    #[test]
+    fn tombstone_files() {
+        let dir = std::env::temp_dir().join(format!("ya-runtime-http-auth-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(Tombstone::load_all(&dir).is_empty());
+
+        let tombstone = Tombstone {
+            service: "svc/1".to_string(),
+            users: vec!["a".to_string(), "b".to_string()],
+        };
+        tombstone.save(&dir).unwrap();
+        assert!(dir.join("tombstones").join("svc_2f1.json").exists());
+
+        let loaded = Tombstone::load_all(&dir);
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].service, "svc/1");
+        assert_eq!(loaded[0].users, tombstone.users);
+
+        // users still pending removal are kept
+        let later = Tombstone {
+            service: "svc/1".to_string(),
+            users: vec!["b".to_string(), "c".to_string()],
+        };
+        later.save(&dir).unwrap();
+        let users = |dir| Tombstone::load_all(dir).remove(0).users;
+        assert_eq!(users(&dir), vec!["a", "b", "c"]);
+
+        loaded[0].forget(&dir, &["a".to_string()]).unwrap();
+        assert_eq!(users(&dir), vec!["b", "c"]);
+        later.forget(&dir, &later.users).unwrap();
+        assert!(Tombstone::load_all(&dir).is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,20 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
 use derive_more::From;
 use futures::TryFutureExt;
 use serde::Serialize;
 use structopt::StructOpt;
 use strum::VariantNames;
 
-use ya_http_proxy_client::model::{AuthMethod, CreateUser, PubService, User, UserEndpointStats};
+use ya_http_proxy_client::model::{
+    deser, AuthMethod, CreateUser, Credentials, PubService, RotateCredentials, Secret,
+    ServiceUsage, TimeRange, User, UserCredentials, UserEndpointStats, UserLimits,
+    UserLimitsStatus,
+};
+use ya_http_proxy_client::Error as ApiError;
 use ya_runtime_sdk::error::Error as SdkError;
+use ya_runtime_sdk::serialize::json;
 
 use crate::HttpAuth;
 
@@ -20,18 +29,24 @@ pub enum RuntimeCommand {
 impl RuntimeCommand {
     pub fn new(args: Vec<String>) -> Result<Self, SdkError> {
         let args = std::iter::once("run".to_string()).chain(args.into_iter());
-        Self::from_iter_safe(args).map_err(SdkError::from_string)
+        Self::from_iter_safe(args)
+            .map_err(|e| CommandError::new(CommandErrorKind::InvalidCommand, e.message).into())
     }
 
     pub async fn execute(
         self,
         service_name: String,
+        correlation_id: String,
         rt: &mut HttpAuth,
     ) -> Result<RuntimeCommandOutput, SdkError> {
-        match self {
-            Self::User(cmd) => cmd.execute(service_name, rt).await.map(Into::into),
+        let result = match self {
+            Self::User(cmd) => cmd
+                .execute(service_name, correlation_id, rt)
+                .await
+                .map(Into::into),
             Self::Service(cmd) => cmd.execute(service_name, rt).await.map(Into::into),
-        }
+        };
+        result.map_err(Into::into)
     }
 }
 
@@ -42,18 +57,151 @@ pub enum RuntimeCommandOutput {
     Service(ServiceCommandOutput),
 }
 
+/// Command error reported to the requestor as JSON
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandError {
+    pub kind: CommandErrorKind,
+    /// Management API response status
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Management API error code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(
+        with = "deser::duration::opt_ms",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub retry_after: Option<Duration>,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<&'static str>,
+}
+
+impl CommandError {
+    pub fn new(kind: CommandErrorKind, message: impl ToString) -> Self {
+        Self {
+            kind,
+            status: None,
+            code: None,
+            retry_after: None,
+            message: message.to_string(),
+            hint: kind.hint(),
+        }
+    }
+}
+
+impl From<ApiError> for CommandError {
+    fn from(e: ApiError) -> Self {
+        let status = e.status();
+        let code = e.error_code().map(ToString::to_string);
+        let hint = code.as_deref().and_then(code_hint);
+        let retry_after = e.retry_after();
+        let kind = match e {
+            ApiError::SendRequestError { code, .. } => match code.as_u16() {
+                404 => CommandErrorKind::NotFound,
+                409 => CommandErrorKind::Conflict,
+                401 | 403 => CommandErrorKind::Unauthorized,
+                400..=499 => CommandErrorKind::Rejected,
+                503 => CommandErrorKind::ProxyUnavailable,
+                _ => CommandErrorKind::ProxyError,
+            },
+            ApiError::ConnectionError { .. } => CommandErrorKind::ProxyUnavailable,
+            ApiError::IncompatibleVersion { .. } => CommandErrorKind::IncompatibleProxy,
+            _ => CommandErrorKind::ProxyError,
+        };
+        let message = match e {
+            ApiError::SendRequestError { msg, .. } => msg,
+            e => e.to_string(),
+        };
+
+        let error = Self::new(kind, message);
+        Self {
+            status: status.map(|s| s.as_u16()),
+            code,
+            retry_after,
+            hint: hint.or(error.hint),
+            ..error
+        }
+    }
+}
+
+/// Actionable hints for Management API error codes
+fn code_hint(code: &str) -> Option<&'static str> {
+    match code {
+        "tlsServerCert" | "tlsServerKey" => {
+            Some("check the certificate paths in the service definition")
+        }
+        "tlsClientCert" => Some("check the client certificate store of the proxy"),
+        "tls" => Some("check the TLS configuration of the service"),
+        "addressInUse" | "addressOwned" | "bind" => {
+            Some("bind the service to addresses not used by other services")
+        }
+        "resourceLimit" => Some("raise the process limits of the proxy or retry later"),
+        _ => None,
+    }
+}
+
+impl From<CommandError> for SdkError {
+    fn from(e: CommandError) -> Self {
+        match json::to_string(&e) {
+            Ok(s) => SdkError::from_string(s),
+            Err(_) => SdkError::from_string(e.message),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CommandErrorKind {
+    InvalidCommand,
+    ServiceNotRunning,
+    NotFound,
+    Conflict,
+    Unauthorized,
+    Rejected,
+    ProxyUnavailable,
+    IncompatibleProxy,
+    ProxyError,
+}
+
+impl CommandErrorKind {
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::InvalidCommand => Some("check the command syntax"),
+            Self::ServiceNotRunning => Some("start the activity before running commands"),
+            Self::NotFound => Some("list users to verify the user name"),
+            Self::Conflict => Some("the user or credentials already exist"),
+            Self::Unauthorized => Some("the proxy was started by a different runtime instance"),
+            Self::ProxyUnavailable => Some("the proxy is not running; retry later"),
+            Self::IncompatibleProxy => Some("update the runtime and proxy to matching versions"),
+            Self::Rejected | Self::ProxyError => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 pub enum UserCommand {
     Add {
         username: String,
-        password: String,
+        /// Generated by the proxy when omitted
+        password: Option<Secret<String>>,
         #[structopt(
             long, short,
             possible_values = AuthMethod::VARIANTS,
             default_value = AuthMethod::Basic.into(),
         )]
         auth: AuthMethod,
+        /// Revoke credentials after the given number of requests
+        #[structopt(long)]
+        max_requests: Option<usize>,
+        /// Bind credentials to the IP address of the first client
+        #[structopt(long)]
+        single_session: bool,
+        /// Revoke credentials after transferring the given number of body bytes
+        #[structopt(long)]
+        max_bytes: Option<u64>,
     },
     Remove {
         username: String,
@@ -68,6 +216,10 @@ pub enum UserCommand {
     Stats {
         username: String,
     },
+    /// Request and byte quota and session binding of user credentials
+    Limits {
+        username: String,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, From)]
@@ -75,72 +227,143 @@ pub enum UserCommand {
 pub enum UserCommandOutput {
     None,
     User(User),
+    Credentials(UserCredentials),
     Users(Vec<User>),
     Stats(UserEndpointStats),
+    Limits(UserLimitsStatus),
 }
 
 impl UserCommand {
     pub async fn execute(
         self,
         service_name: String,
+        correlation_id: String,
         rt: &mut HttpAuth,
-    ) -> Result<UserCommandOutput, SdkError> {
+    ) -> Result<UserCommandOutput, CommandError> {
+        let api = rt.api.with_correlation_id(correlation_id);
         match self {
             Self::Add {
                 username,
-                password,
-                auth: _,
+                password: Some(password),
+                auth,
+                max_requests,
+                single_session,
+                max_bytes,
             } => {
-                let user = rt
-                    .api
-                    .create_user(&service_name, &CreateUser { username, password })
-                    .map_err(SdkError::from_string)
+                let credentials = Credentials::new(auth.clone(), password.into_inner())
+                    .ok_or_else(|| {
+                        let method: &'static str = auth.into();
+                        CommandError::new(
+                            CommandErrorKind::InvalidCommand,
+                            format!("{} auth does not use user credentials", method),
+                        )
+                    })?;
+                let create = CreateUser {
+                    username,
+                    credentials,
+                    limits: user_limits(max_requests, single_session, max_bytes),
+                };
+                let user = api
+                    .create_user(&service_name, &create)
+                    .map_err(CommandError::from)
                     .await?;
-                rt.users.insert(user.username.clone(), user.clone());
+                rt.users.insert(user.clone());
+                rt.sync_allowances().await;
 
                 Ok(user.into())
             }
+            Self::Add {
+                username,
+                password: None,
+                auth,
+                max_requests,
+                single_session,
+                max_bytes,
+            } => {
+                let rotate = RotateCredentials {
+                    method: Some(auth),
+                    limits: user_limits(max_requests, single_session, max_bytes),
+                };
+                let credentials = api
+                    .rotate_credentials(&service_name, &username, &rotate)
+                    .map_err(CommandError::from)
+                    .await?;
+                rt.users.insert(User::from(credentials.clone()));
+                rt.sync_allowances().await;
+
+                Ok(credentials.into())
+            }
             Self::Remove { username, auth: _ } => {
-                rt.api
-                    .delete_user(&service_name, &username)
-                    .map_err(SdkError::from_string)
+                api.delete_user(&service_name, &username)
+                    .map_err(CommandError::from)
                     .await?;
                 rt.users.remove(&username);
+                rt.allowances.forget(&username).await;
 
                 Ok(().into())
             }
             Self::List => {
-                let users = rt
-                    .api
+                let users = api
                     .get_users(&service_name)
-                    .map_err(SdkError::from_string)
+                    .map_err(CommandError::from)
                     .await?;
 
                 Ok(users.into())
             }
             Self::Stats { username } => {
-                let stats = rt
-                    .api
+                let stats = api
                     .get_endpoint_user_stats(&service_name, &username)
-                    .map_err(SdkError::from_string)
+                    .map_err(CommandError::from)
                     .await?;
 
                 Ok(stats.into())
             }
+            Self::Limits { username } => {
+                let limits = api
+                    .get_user_limits(&service_name, &username)
+                    .map_err(CommandError::from)
+                    .await?;
+
+                Ok(limits.into())
+            }
         }
     }
 }
 
+fn user_limits(
+    max_requests: Option<usize>,
+    single_session: bool,
+    max_bytes: Option<u64>,
+) -> Option<UserLimits> {
+    if max_requests.is_none() && !single_session && max_bytes.is_none() {
+        return None;
+    }
+    Some(UserLimits {
+        max_requests,
+        single_session,
+        max_bytes,
+    })
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 pub enum ServiceCommand {
     Info {},
+    /// Requests and response sizes per user, in the RFC 3339 time range
+    Usage {
+        #[structopt(long)]
+        from: Option<DateTime<Utc>>,
+        #[structopt(long)]
+        to: Option<DateTime<Utc>>,
+    },
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, Serialize, From)]
 #[serde(untagged)]
 pub enum ServiceCommandOutput {
     Service(PubService),
+    Usage(ServiceUsage),
 }
 
 impl ServiceCommand {
@@ -148,18 +371,27 @@ impl ServiceCommand {
         self,
         service_name: String,
         rt: &mut HttpAuth,
-    ) -> Result<ServiceCommandOutput, SdkError> {
+    ) -> Result<ServiceCommandOutput, CommandError> {
         match self {
             Self::Info {} => {
                 let service: PubService = rt
                     .api
                     .get_service(&service_name)
-                    .map_err(SdkError::from_string)
+                    .map_err(CommandError::from)
                     .await?
                     .into();
 
                 Ok(service.into())
             }
+            Self::Usage { from, to } => {
+                let usage = rt
+                    .api
+                    .get_usage(&service_name, &TimeRange { from, to })
+                    .map_err(CommandError::from)
+                    .await?;
+
+                Ok(usage.into())
+            }
         }
     }
 }
@@ -1,12 +1,13 @@
+use anyhow::Context as _;
 use regex::Regex;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{read_dir, File};
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use ya_http_proxy_client::model::CreateService;
+use ya_http_proxy_client::model::{Addresses, CreateService};
 use ya_runtime_sdk::serialize::{json, toml, yaml};
 use ya_runtime_sdk::Context;
 
@@ -24,28 +25,175 @@ pub struct ServiceConf {
 }
 
 impl ServiceConf {
+    /// Prefixes the service name with the activity ID, so that services created
+    /// by concurrent activities do not collide in a shared proxy
+    pub fn namespaced(mut self, activity_id: Option<&str>) -> Self {
+        if let Some(activity_id) = activity_id {
+            self.inner.name = format!("{}-{}", activity_id, self.inner.name);
+        }
+        self
+    }
+
     pub fn offer_properties(&self, prefix: &str) -> anyhow::Result<json::Value> {
         let re = Regex::new(r"[^A-Za-z0-9-_.]+").unwrap();
+        let template = Template::new(self)?;
         let mut map = json::Map::new();
 
         for (key, value) in self.offer_properties.iter() {
-            let value = match sanitize_value(value)? {
+            let value = template
+                .expand(value)
+                .with_context(|| format!("offer property '{}'", key))?;
+            let value = match sanitize_value(&value)? {
                 Some(value) => value,
                 None => continue,
             };
 
-            let lower = key.to_ascii_lowercase();
-            let post_re = re.replace(&lower, "");
-            let post_dedup = post_re
-                .replace('_', "-")
-                .replace("--", "-")
-                .replace("..", ".");
-            let trimmed = post_dedup.trim_end_matches('.');
-            map.insert([prefix, ".", trimmed].concat(), value);
+            let key = sanitize_key(&re, key);
+            map.insert([prefix, ".", &key].concat(), value);
         }
 
         Ok(json::Value::Object(map))
     }
+
+    /// Lists offer property keys altered when building offer properties
+    pub fn unsanitized_offer_keys(&self) -> Vec<&str> {
+        let re = Regex::new(r"[^A-Za-z0-9-_.]+").unwrap();
+        let mut keys: Vec<&str> = self
+            .offer_properties
+            .keys()
+            .filter(|key| {
+                let sanitized = sanitize_key(&re, key);
+                sanitized.is_empty() || sanitized != **key
+            })
+            .map(String::as_str)
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+}
+
+/// Expands `{{field.path}}` references to service definition values in offer properties
+struct Template {
+    re: Regex,
+    values: json::Value,
+}
+
+impl Template {
+    fn new(service: &ServiceConf) -> anyhow::Result<Self> {
+        let mut values = json::to_value(&service.inner)?;
+        let ports = |addrs: &Option<Addresses>| -> json::Value {
+            let mut ports: Vec<u16> = match addrs {
+                Some(addrs) => addrs.ports().into_iter().collect(),
+                None => return json::Value::Null,
+            };
+            ports.sort_unstable();
+            match ports.as_slice() {
+                [port] => json::Value::from(*port),
+                _ => json::Value::from(ports),
+            }
+        };
+
+        if let Some(map) = values.as_object_mut() {
+            map.insert(
+                "ports".to_string(),
+                json::json!({
+                    "https": ports(&service.inner.bind_https),
+                    "http": ports(&service.inner.bind_http),
+                }),
+            );
+        }
+
+        Ok(Self {
+            re: Regex::new(r"\{\{\s*([A-Za-z0-9_.-]+)\s*\}\}").unwrap(),
+            values,
+        })
+    }
+
+    fn expand(&self, value: &json::Value) -> anyhow::Result<json::Value> {
+        let value = match value {
+            json::Value::String(s) => self.expand_str(s)?,
+            json::Value::Array(vec) => json::Value::Array(
+                vec.iter()
+                    .map(|v| self.expand(v))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            json::Value::Object(map) => json::Value::Object(
+                map.iter()
+                    .map(|(k, v)| Ok((k.clone(), self.expand(v)?)))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            v => v.clone(),
+        };
+        Ok(value)
+    }
+
+    /// A string consisting of a single reference is replaced with the referenced value,
+    /// otherwise references are substituted with their string representation
+    fn expand_str(&self, s: &str) -> anyhow::Result<json::Value> {
+        let mut result = String::new();
+        let mut last = 0;
+
+        for captures in self.re.captures_iter(s) {
+            let whole = captures.get(0).unwrap();
+            let value = self.resolve(&captures[1])?;
+            if whole.start() == 0 && whole.end() == s.len() {
+                return Ok(value.clone());
+            }
+
+            result.push_str(&s[last..whole.start()]);
+            match value {
+                json::Value::String(v) => result.push_str(v),
+                v => result.push_str(&v.to_string()),
+            }
+            last = whole.end();
+        }
+
+        result.push_str(&s[last..]);
+        Ok(json::Value::String(result))
+    }
+
+    fn resolve(&self, path: &str) -> anyhow::Result<&json::Value> {
+        let value = path
+            .split('.')
+            .try_fold(&self.values, |value, key| match value {
+                json::Value::Object(map) => map.get(key),
+                json::Value::Array(vec) => key.parse::<usize>().ok().and_then(|i| vec.get(i)),
+                _ => None,
+            });
+        match value {
+            Some(json::Value::Null) | None => anyhow::bail!("unknown reference '{}'", path),
+            Some(value) => Ok(value),
+        }
+    }
+}
+
+fn sanitize_key(re: &Regex, key: &str) -> String {
+    let lower = key.to_ascii_lowercase();
+    let post_re = re.replace(&lower, "");
+    let post_dedup = post_re
+        .replace('_', "-")
+        .replace("--", "-")
+        .replace("..", ".");
+    post_dedup.trim_end_matches('.').to_string()
+}
+
+/// Service configuration file contents. Files may define a single service,
+/// an array of services or a `services` array (e.g. `[[services]]` in TOML)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ServiceFile {
+    Many(Vec<ServiceConf>),
+    Table { services: Vec<ServiceConf> },
+    One(Box<ServiceConf>),
+}
+
+impl ServiceFile {
+    fn into_services(self) -> Vec<ServiceConf> {
+        match self {
+            Self::Many(services) | Self::Table { services } => services,
+            Self::One(service) => vec![*service],
+        }
+    }
 }
 
 fn sanitize_value(value: &json::Value) -> anyhow::Result<Option<json::Value>> {
@@ -70,7 +218,26 @@ fn sanitize_value(value: &json::Value) -> anyhow::Result<Option<json::Value>> {
 }
 
 pub fn lookup(ctx: &mut Context<HttpAuthRuntime>) -> Option<ServiceConf> {
-    let mut paths: Vec<_> = ctx.conf.service_lookup_dirs.clone();
+    let runtime_name = ctx.env.runtime_name().unwrap();
+    lookup_by_name(
+        &ctx.conf.service_lookup_dirs,
+        ctx.conf.service_lookup_recursive,
+        &runtime_name,
+    )
+}
+
+/// Finds the service definition in `lookup_dirs` and the default service directories.
+/// Lookup directories may contain glob patterns. Directories are searched in order,
+/// files within a directory in lexicographical order, followed by its subdirectories
+/// when `recursive` is set
+pub fn lookup_by_name(lookup_dirs: &[PathBuf], recursive: bool, name: &str) -> Option<ServiceConf> {
+    let (path, service) = find(service_dirs(lookup_dirs), recursive, name)?;
+    log::info!("Using service '{}' defined in {}", name, path.display());
+    Some(service)
+}
+
+fn service_dirs(lookup_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut paths: Vec<_> = lookup_dirs.iter().flat_map(|p| expand(p)).collect();
 
     let port_paths = vec![
         // parent directory of this binary
@@ -95,22 +262,76 @@ pub fn lookup(ctx: &mut Context<HttpAuthRuntime>) -> Option<ServiceConf> {
             .join(SERVICES_SUBDIRECTORY)
     }));
 
-    find(paths, ctx)
+    paths
 }
 
-fn find(paths: Vec<PathBuf>, ctx: &mut Context<HttpAuthRuntime>) -> Option<ServiceConf> {
-    let runtime_name = ctx.env.runtime_name().unwrap();
+/// Expands a glob pattern into matching directories, sorted by path
+fn expand(path: &Path) -> Vec<PathBuf> {
+    let pattern = path.to_string_lossy();
+    if !pattern.contains(&['*', '?', '['][..]) {
+        return vec![path.to_path_buf()];
+    }
+
+    match glob::glob(&pattern) {
+        Ok(paths) => {
+            let mut paths: Vec<_> = paths.flatten().filter(|p| p.is_dir()).collect();
+            paths.sort();
+            paths
+        }
+        Err(e) => {
+            log::warn!("Invalid service lookup pattern '{}': {}", pattern, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Lists files in `dir` sorted by path. Subdirectories are listed after files
+fn dir_files(dir: &Path, recursive: bool) -> Vec<PathBuf> {
+    list_files(dir, recursive, &mut HashSet::new())
+}
+
+/// Lists files of directories not visited yet, so that symlink loops are not followed
+fn list_files(dir: &Path, recursive: bool, visited: &mut HashSet<PathBuf>) -> Vec<PathBuf> {
+    let canonical = match dir.canonicalize() {
+        Ok(canonical) => canonical,
+        Err(_) => return Vec::new(),
+    };
+    if !visited.insert(canonical) {
+        return Vec::new();
+    }
+    let mut entries: Vec<_> = match read_dir(dir) {
+        Ok(entries) => entries.filter_map(|r| r.ok().map(|e| e.path())).collect(),
+        Err(_) => return Vec::new(),
+    };
+    entries.sort();
+
+    let (dirs, mut files): (Vec<_>, Vec<_>) = entries.into_iter().partition(|p| p.is_dir());
+    if recursive {
+        for dir in dirs {
+            files.extend(list_files(&dir, recursive, visited));
+        }
+    }
+    files
+}
 
+fn find(
+    paths: Vec<PathBuf>,
+    recursive: bool,
+    runtime_name: &str,
+) -> Option<(PathBuf, ServiceConf)> {
     paths
         .into_iter()
-        .filter_map(|p| read_dir(p).ok())
-        .flatten()
-        .filter_map(|r| r.ok().map(|e| e.path()))
-        .filter_map(|p| read_file(p).ok())
-        .find(|conf: &ServiceConf| conf.inner.name == runtime_name)
+        .flat_map(|p| dir_files(&p, recursive))
+        .filter_map(|p| read_file(&p).ok().map(|f: ServiceFile| (p, f)))
+        .flat_map(|(p, f)| {
+            f.into_services()
+                .into_iter()
+                .map(move |conf| (p.clone(), conf))
+        })
+        .find(|(_, conf)| conf.inner.name == runtime_name)
 }
 
-fn read_file<T: DeserializeOwned>(path: PathBuf) -> anyhow::Result<T> {
+fn read_file<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
     let ext = match path.extension() {
         Some(ext) => ext.to_string_lossy().to_lowercase(),
         _ => anyhow::bail!("missing file extension"),
@@ -137,7 +358,8 @@ fn read_file<T: DeserializeOwned>(path: PathBuf) -> anyhow::Result<T> {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::ServiceConf;
+    use crate::config::{expand, find, ServiceConf, ServiceFile};
+    use std::fs;
     use ya_runtime_sdk::serialize;
 
     #[test]
@@ -176,4 +398,141 @@ mod tests {
 
         assert_eq!(properties, expected);
     }
+
+    #[test]
+    fn service_namespace() {
+        let json = serialize::json::json!({
+            "name": "service_1",
+            "bind": "127.0.0.1:443",
+            "from": "/",
+            "to": "http://127.0.0.1:8444",
+        });
+
+        let service: ServiceConf =
+            serialize::json::from_value(json).expect("failed to deserialize service");
+
+        assert_eq!(service.clone().namespaced(None).inner.name, "service_1");
+        assert_eq!(
+            service.namespaced(Some("a1b2")).inner.name,
+            "a1b2-service_1"
+        );
+    }
+
+    #[test]
+    fn multi_service_file() {
+        let names = |file: ServiceFile| -> Vec<String> {
+            file.into_services()
+                .into_iter()
+                .map(|s| s.inner.name)
+                .collect()
+        };
+
+        let json = serialize::json::json!([
+            { "name": "service_1", "from": "/", "to": "http://127.0.0.1:8444" },
+            { "name": "service_2", "from": "/", "to": "http://127.0.0.1:8445" },
+        ]);
+        let file: ServiceFile = serialize::json::from_value(json).unwrap();
+        assert_eq!(names(file), vec!["service_1", "service_2"]);
+
+        let toml = r#"
+            [[services]]
+            name = "service_1"
+            from = "/"
+            to = "http://127.0.0.1:8444"
+
+            [[services]]
+            name = "service_2"
+            from = "/"
+            to = "http://127.0.0.1:8445"
+        "#;
+        let file: ServiceFile = serialize::toml::from_str(toml).unwrap();
+        assert_eq!(names(file), vec!["service_1", "service_2"]);
+
+        let json = serialize::json::json!({
+            "name": "service_1", "from": "/", "to": "http://127.0.0.1:8444"
+        });
+        let file: ServiceFile = serialize::json::from_value(json).unwrap();
+        assert_eq!(names(file), vec!["service_1"]);
+    }
+
+    #[test]
+    fn recursive_glob_lookup() {
+        let root = std::env::temp_dir().join(format!("http-auth-lookup-{}", std::process::id()));
+        let write = |path: &str, to: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let json = serialize::json::json!({ "name": "service_1", "from": "/", "to": to });
+            fs::write(path, json.to_string()).unwrap();
+        };
+        write("pkg-a/nested/services/b.json", "http://127.0.0.1:1001/");
+        write("pkg-a/nested/services/a.json", "http://127.0.0.1:1002/");
+        write("pkg-b/services/z.json", "http://127.0.0.1:1003/");
+        write("pkg-b/services/deep/a.json", "http://127.0.0.1:1004/");
+
+        let found = |dirs: Vec<_>, recursive: bool| {
+            find(dirs, recursive, "service_1").map(|(_, s)| s.inner.to.to_string())
+        };
+
+        let dirs = expand(&root.join("pkg-*"));
+        assert_eq!(dirs, vec![root.join("pkg-a"), root.join("pkg-b")]);
+        assert_eq!(found(dirs.clone(), false), None);
+        assert_eq!(
+            found(dirs, true),
+            Some("http://127.0.0.1:1002/".to_string())
+        );
+
+        // symlinked directories are listed once
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(root.join("pkg-b"), root.join("pkg-b/services/deep/loop"))
+            .unwrap();
+        let dirs = expand(&root.join("pkg-b"));
+        assert_eq!(
+            found(dirs, true),
+            Some("http://127.0.0.1:1003/".to_string())
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn offer_property_templates() {
+        let json = serialize::json::json!({
+            "name": "service_1",
+            "bindHttps": ["0.0.0.0:8443", "[::]:8443"],
+            "from": "/",
+            "to": "http://127.0.0.1:8444",
+            "cert": {
+                "hash": "abcd",
+                "path": "/tmp/server.cert",
+                "keyPath": "/tmp/server.key"
+            },
+            "offerProperties": {
+                "port": "{{ports.https}}",
+                "url": "https://{{ serverName.0 }}:{{ports.https}}{{from}}",
+                "cert": { "hash": "{{cert.hash}}" },
+                "name": "{{name}}",
+                "serverName": ["{{serverName.0}}"]
+            }
+        });
+
+        let mut service: ServiceConf = serialize::json::from_value(json).unwrap();
+        service.inner.server_name = vec!["acme.com".to_string()];
+
+        let properties = service.offer_properties("meta").unwrap();
+        assert_eq!(
+            properties,
+            serialize::json::json!({
+                "meta.port": 8443,
+                "meta.url": "https://acme.com:8443/",
+                "meta.cert": "{\"hash\":\"abcd\"}",
+                "meta.name": "service_1",
+                "meta.servername": ["acme.com"]
+            })
+        );
+
+        service
+            .offer_properties
+            .insert("http".to_string(), "{{ports.http}}".into());
+        assert!(service.offer_properties("meta").is_err());
+    }
 }
@@ -0,0 +1,93 @@
+//! Discovery of the proxy instance owning the data directory
+use std::path::Path;
+
+use ya_http_proxy_client::model::Ready;
+
+/// Whether the proxy refuses to start when another instance owns the data directory.
+/// Otherwise, proxy starts are serialized with a lock file
+pub const EXCLUSIVE: bool = cfg!(any(target_os = "linux", windows));
+
+/// Returns the descriptor of the running proxy instance
+pub fn discover(data_dir: &Path) -> Option<Ready> {
+    platform::discover(data_dir)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_ready_file(data_dir: &Path) -> Option<Ready> {
+    let path = data_dir.join(ya_http_proxy_client::model::READY_FILE_NAME);
+    serde_json::from_slice(&std::fs::read(path).ok()?).ok()
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+    use nix::unistd::geteuid;
+
+    use ya_http_proxy_client::model::{Ready, INSTANCE_SOCKET_NAME};
+
+    const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// Reads the descriptor served by the instance socket. The Management API token
+    /// is only sent to a proxy run by the same user, as the serving process itself
+    pub fn discover(data_dir: &Path) -> Option<Ready> {
+        let mut stream = UnixStream::connect(data_dir.join(INSTANCE_SOCKET_NAME)).ok()?;
+        let peer = getsockopt(stream.as_raw_fd(), PeerCredentials).ok()?;
+        if peer.uid() != geteuid().as_raw() {
+            log::warn!(
+                "Ignoring the proxy instance of user {} in '{}'",
+                peer.uid(),
+                data_dir.display()
+            );
+            return None;
+        }
+        stream.set_read_timeout(Some(READ_TIMEOUT)).ok()?;
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).ok()?;
+        let ready: Ready = serde_json::from_slice(&buf).ok()?;
+        (ready.pid == peer.pid() as u32).then_some(ready)
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::path::Path;
+
+    use winapi::shared::winerror::WAIT_TIMEOUT;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::OpenProcess;
+    use winapi::um::synchapi::WaitForSingleObject;
+    use winapi::um::winnt::SYNCHRONIZE;
+
+    use ya_http_proxy_client::model::Ready;
+
+    /// Reads the ready file while the process which wrote it is running
+    pub fn discover(data_dir: &Path) -> Option<Ready> {
+        let ready = super::read_ready_file(data_dir)?;
+        let handle = unsafe { OpenProcess(SYNCHRONIZE, 0, ready.pid) };
+        if handle.is_null() {
+            return None;
+        }
+        let running = unsafe { WaitForSingleObject(handle, 0) } == WAIT_TIMEOUT;
+        unsafe { CloseHandle(handle) };
+        running.then_some(ready)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod platform {
+    use std::path::Path;
+
+    use ya_http_proxy_client::model::Ready;
+
+    /// The ready file may be stale
+    pub fn discover(data_dir: &Path) -> Option<Ready> {
+        super::read_ready_file(data_dir)
+    }
+}
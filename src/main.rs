@@ -1,11 +1,9 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::time::Duration;
 
 use futures::future::{AbortHandle, Abortable};
-use futures::{FutureExt, StreamExt};
-use http::StatusCode;
+use futures::FutureExt;
 use serde::{Deserialize, Serialize};
 use serde_default::DefaultFromSerde;
 use structopt::StructOpt;
@@ -17,24 +15,44 @@ use ya_runtime_sdk::serialize::json;
 use ya_runtime_sdk::*;
 
 use ya_http_proxy_client::{
-    model::{CreateService, GlobalStats, Service, User},
-    Error, ManagementApi,
+    model::{self, deser, Service},
+    Error, ManagementApi, RecentRequests, RequestSummary, WireLog, ENV_MANAGEMENT_API_URL,
 };
 
-use crate::command::RuntimeCommand;
+use crate::allowance::Allowances;
+use crate::command::{CommandError, CommandErrorKind, RuntimeCommand};
+use crate::state::UserState;
 
+mod allowance;
+mod cleanup;
 mod command;
 mod config;
+mod instance;
 mod lock;
 mod proxy;
+mod state;
+mod usage;
+mod validate;
 
 type RuntimeCli = <HttpAuthRuntime as RuntimeDef>::Cli;
 
 pub const PROPERTY_PREFIX: &str = "golem.runtime.http-auth";
-const COUNTER_NAME: &str = "http-auth.requests";
-const COUNTER_PUBLISH_INTERVAL: Duration = Duration::from_secs(2);
+const DEFAULT_COUNTER_NAME: &str = "http-auth.requests";
+const DEFAULT_COUNTER_PUBLISH_INTERVAL: Duration = Duration::from_secs(2);
+/// Data directory subdirectory of named runtime instances
+const INSTANCES_DIR: &str = "instances";
+const HEALTH_STATE_NAME: &str = "http-auth.health";
+/// Request and response body bytes transferred by service users
+const BYTES_COUNTER_NAME: &str = "http-auth.bytes";
+/// Usage of the proxy process, shared by all activities using the proxy
+const PROXY_CPU_COUNTER_NAME: &str = "http-auth.proxy-cpu-sec";
+const PROXY_RSS_COUNTER_NAME: &str = "http-auth.proxy-rss-bytes";
 
 const MANAGEMENT_API_MAX_CONCURRENT_REQUESTS: usize = 3;
+/// Number of recent Management API requests to assess the API health with
+const MANAGEMENT_API_METRICS_WINDOW: usize = 20;
+const MANAGEMENT_API_SLOW_RESPONSE: Duration = Duration::from_secs(1);
+const MANAGEMENT_API_MAX_FAILURE_RATE: f64 = 0.2;
 
 #[derive(RuntimeDef)]
 #[cli(HttpAuthCli)]
@@ -43,14 +61,15 @@ pub struct HttpAuthRuntime {
     http_auth: Rc<RwLock<HttpAuth>>,
 }
 
-impl From<ManagementApi> for HttpAuthRuntime {
-    fn from(api: ManagementApi) -> Self {
+impl HttpAuthRuntime {
+    fn new(api: ManagementApi, api_metrics: RecentRequests) -> Self {
         let http_auth = Rc::new(RwLock::new(HttpAuth {
-            api,
+            api: api.with_metrics(api_metrics.clone()),
+            api_metrics,
             handle: Default::default(),
             service: Default::default(),
             users: Default::default(),
-            global_stats: Default::default(),
+            allowances: Default::default(),
         }));
         Self { http_auth }
     }
@@ -58,47 +77,69 @@ impl From<ManagementApi> for HttpAuthRuntime {
 
 pub struct HttpAuth {
     api: ManagementApi,
+    api_metrics: RecentRequests,
     handle: Option<AbortHandle>,
     service: Option<Service>,
-    users: HashMap<String, User>,
-    global_stats: GlobalStats,
+    users: UserState,
+    allowances: Allowances,
 }
 
 impl HttpAuth {
-    pub async fn count_requests(&self) -> usize {
+    /// Returns the number of service requests and transferred bytes
+    pub async fn count_usage(&self) -> Option<(usize, u64)> {
+        let service_name = &self.service.as_ref()?.inner.name;
+        match self.api.get_service_stats(service_name).await {
+            Ok(stats) => Some((stats.requests, stats.bytes)),
+            Err(e) => {
+                log::warn!("Unable to retrieve service stats: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Pushes allowance changes of runtime users to the proxy
+    pub async fn sync_allowances(&self) {
         let service_name = match self.service {
             Some(ref service) => &service.inner.name,
-            None => return 0,
+            None => return,
         };
+        let usernames = self.users.usernames();
+        self.allowances
+            .sync(&self.api, service_name, usernames)
+            .await;
+    }
 
-        futures::stream::iter(self.users.keys())
-            .map(|username| self.api.get_user_stats(service_name, username))
-            .buffer_unordered(MANAGEMENT_API_MAX_CONCURRENT_REQUESTS)
-            .filter_map(|r| async move { r.ok() })
-            .fold(0, |mut acc, stats| async move {
-                acc += stats.requests;
-                acc
-            })
-            .await
+    /// Returns whether the service was removed
+    pub async fn delete_service(&self) -> bool {
+        let service_name = match self.service {
+            Some(ref service) => &service.inner.name,
+            None => return false,
+        };
+
+        match self.api.delete_service(service_name).await {
+            Ok(_) => true,
+            Err(e) => {
+                log::error!("Failed to remove service '{}': {}", service_name, e);
+                false
+            }
+        }
     }
 
-    pub async fn delete_users(&self) {
+    /// Returns names of users that could not be removed
+    pub async fn delete_users(&self) -> Vec<String> {
         let service_name = match self.service {
             Some(ref service) => &service.inner.name,
-            None => return,
+            None => return Vec::new(),
         };
 
         let total = self.users.len();
-        let failed = futures::stream::iter(self.users.keys())
-            .map(|username| self.api.delete_user(service_name, username))
-            .buffer_unordered(MANAGEMENT_API_MAX_CONCURRENT_REQUESTS)
-            .filter_map(|result| async move { result.err() })
-            .count()
-            .await;
+        let usernames = self.users.usernames();
+        let failed = cleanup::delete_users(&self.api, service_name, usernames).await;
 
-        if failed > 0 {
-            log::error!("Failed to remove {} out of {} users", failed, total);
+        if !failed.is_empty() {
+            log::error!("Failed to remove {} out of {} users", failed.len(), total);
         }
+        failed
     }
 }
 
@@ -111,6 +152,13 @@ pub struct HttpAuthEnv {
 #[structopt(rename_all = "kebab-case")]
 pub struct HttpAuthCli {
     name: String,
+    /// Activity ID to namespace the proxy service with
+    #[structopt(long, env = "YAGNA_ACTIVITY_ID")]
+    activity_id: Option<String>,
+    /// Name of an independent runtime and proxy instance. Namespaces the data directory,
+    /// which holds the proxy lock, status and log files
+    #[structopt(long, env = "YA_HTTP_AUTH_INSTANCE")]
+    instance_name: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, DefaultFromSerde)]
@@ -120,8 +168,37 @@ pub struct HttpAuthConf {
     pub data_dir: PathBuf,
     #[serde(default)]
     pub management_api_url: Option<String>,
+    /// Logs Management API requests and responses, with credentials redacted
+    #[serde(default)]
+    pub management_api_wire_log: bool,
     #[serde(default)]
     pub service_lookup_dirs: Vec<PathBuf>,
+    /// Searches subdirectories of service lookup directories
+    #[serde(default)]
+    pub service_lookup_recursive: bool,
+    #[serde(default = "default_counter_name")]
+    pub counter_name: String,
+    #[serde(
+        default = "default_counter_publish_interval",
+        with = "deser::duration::ms"
+    )]
+    pub counter_publish_interval: Duration,
+}
+
+impl HttpAuthConf {
+    /// Whether the Management API address is discovered from a spawned proxy
+    /// instead of being set explicitly
+    pub fn discover_management_api(&self) -> bool {
+        self.management_api_url.is_none() && std::env::var(ENV_MANAGEMENT_API_URL).is_err()
+    }
+}
+
+/// Data directory of a named instance
+fn instance_dir(data_dir: &Path, instance_name: Option<&str>) -> PathBuf {
+    match instance_name {
+        Some(name) => data_dir.join(INSTANCES_DIR).join(name),
+        None => data_dir.to_path_buf(),
+    }
 }
 
 fn default_data_dir() -> PathBuf {
@@ -133,6 +210,14 @@ fn default_data_dir() -> PathBuf {
     .join(crate_name)
 }
 
+fn default_counter_name() -> String {
+    DEFAULT_COUNTER_NAME.to_string()
+}
+
+fn default_counter_publish_interval() -> Duration {
+    DEFAULT_COUNTER_PUBLISH_INTERVAL
+}
+
 impl Env<RuntimeCli> for HttpAuthEnv {
     fn runtime_name(&self) -> Option<String> {
         self.runtime_name.clone()
@@ -147,8 +232,20 @@ impl Env<RuntimeCli> for HttpAuthEnv {
 
 impl Runtime for HttpAuthRuntime {
     fn deploy<'a>(&mut self, ctx: &mut Context<Self>) -> OutputResponse<'a> {
-        if config::lookup(ctx).is_none() {
-            return SdkError::response("Config file not found");
+        let service = match config::lookup(ctx) {
+            Some(service) => service,
+            None => return SdkError::response("Config file not found"),
+        };
+
+        let report = validate::validate(&service, false);
+        for check in report.warnings() {
+            log::warn!("Service configuration: {}: {}", check.name, check.message);
+        }
+        if !report.is_valid() {
+            return SdkError::response(format!(
+                "Invalid service configuration: {}",
+                report.summary()
+            ));
         }
 
         if std::fs::create_dir_all(&ctx.conf.data_dir).is_err() {
@@ -166,50 +263,111 @@ impl Runtime for HttpAuthRuntime {
             Some(emitter) => emitter,
             None => return SdkError::response("Not running in server mode"),
         };
+        let activity_id = ctx.cli.runtime.activity_id.clone();
         let service = match config::lookup(ctx) {
-            Some(service) => service,
+            Some(service) => service.namespaced(activity_id.as_deref()),
             None => return SdkError::response("Config file not found"),
         };
 
         let data_dir = ctx.conf.data_dir.clone();
+        let discover = ctx.conf.discover_management_api();
+        let counter_name = ctx.conf.counter_name.clone();
+        let interval = ctx.conf.counter_publish_interval;
         let http_auth = self.http_auth.clone();
         async move {
-            let api = {
+            let (api, api_metrics) = {
                 let inner = http_auth.read().await;
-                inner.api.clone()
+                (inner.api.clone(), inner.api_metrics.clone())
             };
 
-            proxy::spawn(api.clone(), data_dir).await?;
-            let service = try_create_service(api.clone(), service.inner.clone()).await?;
+            let api = proxy::spawn(api, data_dir.clone(), discover).await?;
+            cleanup::finish(&api, &data_dir).await;
+            let service = try_create_service(&api, &service.inner).await?;
+            let service_name = service.inner.name.clone();
             let (h, reg) = AbortHandle::new_pair();
+            // users left by a runtime or a proxy that did not stop cleanly
+            let mut users = UserState::load(&data_dir, &service_name);
+            let reconciliation = state::reconcile(&api, &service_name, &mut users)
+                .await
+                .map_err(CommandError::from)?;
             {
                 let mut inner = http_auth.write().await;
+                inner.api = api.clone();
                 inner.service.replace(service);
+                inner.users = users;
+                inner.allowances = Allowances::new(&data_dir, &service_name);
                 inner.handle.replace(h);
             }
+            if !reconciliation.is_empty() {
+                log::warn!("Reconciled service users: {:?}", reconciliation);
+            }
 
             tokio::task::spawn_local(Abortable::new(
                 async move {
+                    let mut health = Health::Ready;
+                    let mut proxy_pid = None;
+                    let mut proxy_cpu = usage::CpuMeter::default();
                     loop {
-                        let total_req = {
-                            let inner = http_auth.read().await;
-                            inner.count_requests().await
-                        };
-
-                        if let Ok(stats) = api.get_global_stats().await {
-                            http_auth.write().await.global_stats = stats;
+                        let current =
+                            Health::check(&api, &service_name, &api_metrics.summary()).await;
+                        if current != health {
+                            log::warn!("Proxy health changed: {:?} -> {:?}", health, current);
+                            emit_state(HEALTH_STATE_NAME.to_string(), emitter.clone(), &current)
+                                .await;
+                            health = current;
                         }
 
-                        emit_counter(COUNTER_NAME.to_string(), emitter.clone(), total_req as f64)
-                            .await;
+                        // skip publishing stale counters
+                        if health != Health::Unavailable {
+                            let usage = {
+                                let inner = http_auth.read().await;
+                                inner.count_usage().await
+                            };
+                            if let Some(usage) = usage {
+                                emit_usage(&counter_name, &emitter, usage).await;
+                            }
+                            // the proxy is called without holding the runtime lock
+                            let (allowances, usernames) = {
+                                let inner = http_auth.read().await;
+                                (inner.allowances.clone(), inner.users.usernames())
+                            };
+                            allowances.sync(&api, &service_name, usernames).await;
+
+                            if usage::SUPPORTED {
+                                // the proxy process may have been restarted
+                                let mut usage = proxy_pid.and_then(usage::sample);
+                                if usage.is_none() {
+                                    proxy_pid = api.get_status().await.ok().and_then(|s| s.pid);
+                                    usage = proxy_pid.and_then(usage::sample);
+                                }
+                                if let (Some(pid), Some(usage)) = (proxy_pid, usage) {
+                                    let cpu = proxy_cpu.update(pid, usage.cpu);
+                                    emit_counter(
+                                        PROXY_CPU_COUNTER_NAME.to_string(),
+                                        emitter.clone(),
+                                        cpu.as_secs_f64(),
+                                    )
+                                    .await;
+                                    emit_counter(
+                                        PROXY_RSS_COUNTER_NAME.to_string(),
+                                        emitter.clone(),
+                                        usage.rss as f64,
+                                    )
+                                    .await;
+                                }
+                            }
+                        }
 
-                        tokio::time::sleep(COUNTER_PUBLISH_INTERVAL).await;
+                        tokio::time::sleep(interval).await;
                     }
                 },
                 reg,
             ));
 
-            Ok(None)
+            if reconciliation.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(serde_json::to_value(reconciliation)?))
         }
         .boxed_local()
     }
@@ -220,18 +378,44 @@ impl Runtime for HttpAuthRuntime {
             None => return SdkError::response("Not running in server mode"),
         };
 
+        let counter_name = ctx.conf.counter_name.clone();
+        let data_dir = ctx.conf.data_dir.clone();
+        // namespaced services are never reused by other activities
+        let namespaced = ctx.cli.runtime.activity_id.is_some();
         let inner = self.http_auth.clone();
         async move {
-            let inner = inner.read().await;
+            let mut inner = inner.write().await;
             if let Some(handle) = &inner.handle {
                 handle.abort();
             };
 
-            let total_req = inner.count_requests().await;
-            inner.delete_users().await;
+            let usage = inner.count_usage().await;
+            let mut failed = inner.delete_users().await;
+            // users that could not be removed are recorded in a tombstone
+            inner.users.clear();
+            // users are removed along with the service
+            if namespaced && inner.delete_service().await {
+                failed.clear();
+            }
+            if let (Some(service), false) = (&inner.service, failed.is_empty()) {
+                let tombstone = cleanup::Tombstone {
+                    service: service.inner.name.clone(),
+                    users: failed.clone(),
+                };
+                if let Err(e) = tombstone.save(&data_dir) {
+                    log::error!("Unable to save the tombstone file: {}", e);
+                }
+            }
             drop(inner);
 
-            emit_counter(COUNTER_NAME.to_string(), emitter.clone(), total_req as f64).await;
+            if let Some(usage) = usage {
+                emit_usage(&counter_name, &emitter, usage).await;
+            }
+            if !failed.is_empty() {
+                failed.sort();
+                let message = format!("Unable to remove users: {}", failed.join(", "));
+                return Err(CommandError::new(CommandErrorKind::ProxyError, message).into());
+            }
             Ok(())
         }
         .boxed_local()
@@ -244,17 +428,24 @@ impl Runtime for HttpAuthRuntime {
         ctx: &mut Context<Self>,
     ) -> ProcessIdResponse<'a> {
         let http_auth = self.http_auth.clone();
+        let activity_id = ctx.cli.runtime.activity_id.clone();
 
-        ctx.command(|_| async move {
+        ctx.command(|run_ctx| async move {
+            // attributes changes made by the proxy to this command
+            let correlation_id =
+                format!("{}/{}", activity_id.as_deref().unwrap_or("-"), run_ctx.id());
             let mut http_auth = http_auth.write().await;
             let service_name = http_auth
                 .service
                 .as_ref()
                 .map(|s| s.inner.name.clone())
-                .ok_or_else(|| SdkError::from_string("Service not running"))?;
+                .ok_or_else(|| {
+                    CommandError::new(CommandErrorKind::ServiceNotRunning, "Service not running")
+                })?;
 
             let cmd = RuntimeCommand::new(cmd.args)?;
-            cmd.execute(service_name, &mut http_auth).await
+            cmd.execute(service_name, correlation_id, &mut http_auth)
+                .await
         })
     }
 
@@ -298,6 +489,11 @@ impl Runtime for HttpAuthRuntime {
 
     fn test<'a>(&mut self, ctx: &mut Context<Self>) -> EmptyResponse<'a> {
         let offer = self.offer(ctx);
+        let discover = ctx.conf.discover_management_api();
+        let data_dir = instance_dir(
+            &std::env::temp_dir(),
+            ctx.cli.runtime.instance_name.as_deref(),
+        );
         let inner = self.http_auth.clone();
 
         async move {
@@ -305,8 +501,9 @@ impl Runtime for HttpAuthRuntime {
 
             let inner = inner.read().await;
             let api = inner.api.clone();
-            proxy::spawn(api, std::env::temp_dir())
+            proxy::spawn(api, data_dir, discover)
                 .await
+                .map(|_| ())
                 .map_err(Into::into)
         }
         .boxed_local()
@@ -315,14 +512,36 @@ impl Runtime for HttpAuthRuntime {
 
 #[actix_rt::main]
 async fn main() -> anyhow::Result<()> {
+    if let Some(cli) = validate::ConfigCli::from_env() {
+        return cli.run();
+    }
+
     build::<HttpAuthRuntime, _, _, _>(HttpAuthEnv::default(), move |ctx| {
+        let instance_name = ctx.cli.runtime.instance_name.clone();
+        let checked = instance_name
+            .as_deref()
+            .map(model::check_instance_name)
+            .transpose();
+        ctx.conf.data_dir = instance_dir(&ctx.conf.data_dir, instance_name.as_deref());
+
         let api_url = ctx.conf.management_api_url.clone();
+        let wire_log = ctx.conf.management_api_wire_log;
+        let data_dir = ctx.conf.data_dir.clone();
         async move {
-            Ok(HttpAuthRuntime::from(if let Some(url) = api_url {
+            checked.map_err(anyhow::Error::msg)?;
+            let api = if let Some(url) = api_url {
                 ManagementApi::try_from_url(&url)?
             } else {
                 ManagementApi::try_default()?
-            }))
+            };
+            let api = if wire_log {
+                api.with_wire_log(WireLog::default())
+            } else {
+                api
+            };
+            let token = proxy::management_token(&data_dir)?;
+            let api_metrics = RecentRequests::new(MANAGEMENT_API_METRICS_WINDOW);
+            Ok(HttpAuthRuntime::new(api.with_token(token), api_metrics))
         }
     })
     .await
@@ -337,23 +556,80 @@ async fn emit_counter(counter_name: String, mut emitter: EventEmitter, value: f6
         .await;
 }
 
+/// Publishes the request and byte counters
+async fn emit_usage(counter_name: &str, emitter: &EventEmitter, (requests, bytes): (usize, u64)) {
+    emit_counter(counter_name.to_string(), emitter.clone(), requests as f64).await;
+    emit_counter(
+        BYTES_COUNTER_NAME.to_string(),
+        emitter.clone(),
+        bytes as f64,
+    )
+    .await;
+}
+
+async fn emit_state(state_name: String, mut emitter: EventEmitter, value: &Health) {
+    let value = match json::to_vec(value) {
+        Ok(value) => value,
+        Err(e) => return log::error!("Unable to serialize runtime state: {}", e),
+    };
+    emitter
+        .state(RuntimeState {
+            name: state_name,
+            value,
+        })
+        .await;
+}
+
+/// Proxy health reported to the event stream
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum Health {
+    /// Proxy and upstream server are reachable
+    Ready,
+    /// Proxy is running but the upstream server refuses connections
+    /// or a proxy listener is being rebound
+    Degraded,
+    /// Proxy Management API responds slowly or fails intermittently
+    Unstable,
+    /// Proxy Management API is unreachable, the service is gone
+    /// or the proxy has stopped accepting connections
+    Unavailable,
+}
+
+impl Health {
+    async fn check(api: &ManagementApi, service_name: &str, recent: &RequestSummary) -> Self {
+        match api.get_service(service_name).await {
+            Ok(service) if service.proxy_error.is_some() => Self::Unavailable,
+            Ok(service) if service.listener_error.is_some() => Self::Degraded,
+            Ok(service) if service.upstream_available == Some(false) => Self::Degraded,
+            Ok(_) if recent.failure_rate() > MANAGEMENT_API_MAX_FAILURE_RATE => Self::Unstable,
+            Ok(_) if recent.mean_duration > MANAGEMENT_API_SLOW_RESPONSE => Self::Unstable,
+            Ok(_) => Self::Ready,
+            Err(_) => Self::Unavailable,
+        }
+    }
+}
+
+/// Creates the service, or takes over an identical service left by a previous runtime
 async fn try_create_service(
-    api: ManagementApi,
-    create_service: CreateService,
-) -> anyhow::Result<Service> {
-    match api.create_service(&create_service).await {
-        Err(
-            err @ Error::SendRequestError {
-                code: StatusCode::CONFLICT,
-                ..
-            },
-        ) => {
-            let service = api.get_service(create_service.name.as_str()).await?;
-            if service.inner != create_service {
-                anyhow::bail!(err);
+    api: &ManagementApi,
+    create_service: &model::CreateService,
+) -> Result<Service, CommandError> {
+    // the service name identifies retried creation requests
+    let result = api
+        .create_service_idempotent(create_service, &create_service.name)
+        .await;
+    match result {
+        Err(err @ Error::SendRequestError { code, .. }) if code.as_u16() == 409 => {
+            let service = api
+                .get_service(&create_service.name)
+                .await
+                .map_err(CommandError::from)?;
+            if service.inner != *create_service {
+                return Err(err.into());
             }
             Ok(service)
         }
-        result => result.map_err(Into::into),
+        result => result.map_err(CommandError::from),
     }
 }
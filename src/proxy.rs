@@ -1,33 +1,91 @@
 use std::env::consts::EXE_SUFFIX;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::time::{Duration, Instant};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
 
 use is_executable::IsExecutable;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 
+use ya_http_proxy_client::model::{Ready, READY_FILE_NAME};
 use ya_http_proxy_client::{Error, ManagementApi};
 
+use crate::instance;
 use crate::lock::{with_lock_ext, LockFile};
 
 const TIMEOUT: Duration = Duration::from_secs(3);
 const SLEEP: Duration = Duration::from_millis(500);
 
-pub async fn spawn(api: ManagementApi, data_dir: PathBuf) -> anyhow::Result<()> {
+const ENV_PROXY_MANAGEMENT_TOKEN: &str = "YA_HTTP_PROXY_MANAGEMENT_TOKEN";
+const TOKEN_FILE_NAME: &str = "management.token";
+const DISCOVERY_ADDR: &str = "127.0.0.1:0";
+const TOKEN_LEN: usize = 48;
+
+/// Records why the last proxy launch did not result in a running proxy
+const LAUNCH_FILE_NAME: &str = "ya-http-proxy.launch";
+const STDERR_FILE_NAME: &str = "ya-http-proxy.stderr";
+/// Failed launches are retried once this long has passed since the failure
+const LAUNCH_BACKOFF: Duration = Duration::from_secs(1);
+const LAUNCH_WATCH_INTERVAL: Duration = Duration::from_millis(50);
+/// Number of trailing stderr bytes included in launch errors
+const STDERR_EXCERPT_LEN: usize = 1024;
+
+/// Starts the proxy if it's not running yet and returns a Management API client connected to it.
+///
+/// With `discover` set, the proxy binds its Management API to a random local port
+/// and publishes it in a ready file in the data directory.
+pub async fn spawn(
+    mut api: ManagementApi,
+    data_dir: PathBuf,
+    discover: bool,
+) -> anyhow::Result<ManagementApi> {
     let started = Instant::now();
     let lock_path = with_lock_ext(data_dir.join(env!("CARGO_PKG_NAME")));
+    let ready_path = data_dir.join(READY_FILE_NAME);
+    let launch = Launch::new(&data_dir);
     let mut lock = LockFile::new(&lock_path);
     let mut state = ProxyState::Unknown;
 
     loop {
         if Instant::now() - started >= TIMEOUT {
-            anyhow::bail!("proxy timed out after {}s", TIMEOUT.as_secs_f32());
+            let mut msg = format!("proxy timed out after {}s", TIMEOUT.as_secs_f32());
+            match state {
+                ProxyState::AwaitStart => msg.push_str(&launch.stderr_excerpt()),
+                ProxyState::Start => {
+                    if let Some(failure) = launch.failure() {
+                        msg = format!("{}, the last launch failed: {}", msg, failure);
+                    }
+                }
+                _ => (),
+            }
+            anyhow::bail!(msg);
+        }
+
+        let ready = match discover {
+            true => instance::discover(&data_dir),
+            false => None,
+        };
+        if let Some(discovered) = ready.as_ref().and_then(|r| discovered_api(&api, r)) {
+            api = discovered;
         }
 
+        // without a running instance there is no proxy to connect to
+        let awaiting_ready = discover && ready.is_none();
+
         state = match std::mem::replace(&mut state, ProxyState::Poisoned) {
+            ProxyState::Unknown if awaiting_ready => {
+                if lock.is_locked() {
+                    ProxyState::AwaitLock
+                } else {
+                    ProxyState::Lock
+                }
+            }
             ProxyState::Unknown => match api.get_services().await {
                 Ok(_) => ProxyState::Running,
                 Err(err) => match err {
-                    Error::SendRequestError { .. } => {
+                    Error::SendRequestError { .. } | Error::ConnectionError { .. } => {
                         if lock.is_locked() {
                             ProxyState::AwaitLock
                         } else {
@@ -37,8 +95,9 @@ pub async fn spawn(api: ManagementApi, data_dir: PathBuf) -> anyhow::Result<()>
                     err => anyhow::bail!(err),
                 },
             },
+            // concurrently started proxies are resolved by the proxy itself
             ProxyState::Lock => {
-                if lock.lock().is_ok() {
+                if instance::EXCLUSIVE || lock.lock().is_ok() {
                     ProxyState::Start
                 } else {
                     ProxyState::AwaitLock
@@ -52,6 +111,11 @@ pub async fn spawn(api: ManagementApi, data_dir: PathBuf) -> anyhow::Result<()>
                     ProxyState::Unknown
                 }
             }
+            ProxyState::Start if launch.backoff().is_some() => {
+                let remaining = launch.backoff().unwrap_or_default();
+                tokio::time::sleep(remaining.min(SLEEP)).await;
+                ProxyState::Start
+            }
             ProxyState::Start => {
                 let exe_path = std::env::current_exe()?;
                 let exe_dir = exe_path
@@ -60,10 +124,11 @@ pub async fn spawn(api: ManagementApi, data_dir: PathBuf) -> anyhow::Result<()>
 
                 let path = exe_dir.join(format!("ya-http-proxy{EXE_SUFFIX}"));
                 if !path.is_file() {
-                    anyhow::bail!("unable to find proxy binary");
+                    anyhow::bail!("proxy binary not found at '{}'", path.display());
                 } else if !path.is_executable() {
-                    anyhow::bail!("unable to execute proxy");
+                    anyhow::bail!("proxy binary '{}' is not executable", path.display());
                 }
+                launch.reset();
 
                 let mut command = Command::new(path);
                 command
@@ -72,15 +137,37 @@ pub async fn spawn(api: ManagementApi, data_dir: PathBuf) -> anyhow::Result<()>
                     .current_dir(std::env::current_dir()?)
                     .stdin(Stdio::null())
                     .stdout(Stdio::null())
-                    .stderr(Stdio::null());
+                    .stderr(launch.stderr())
+                    .arg("--status-dir")
+                    .arg(&data_dir);
+                if discover {
+                    // the ready file is stale when the proxy is not running
+                    let _ = fs::remove_file(&ready_path);
+                    command.arg("--management-addr").arg(DISCOVERY_ADDR);
+                }
+                if let Some(token) = api.token() {
+                    command.env(ENV_PROXY_MANAGEMENT_TOKEN, token);
+                }
+                if api.wire_log().is_some() {
+                    command.arg("--wire-log");
+                }
 
-                spawn_detached_command(command)?;
+                spawn_detached_command(command, launch.path.clone())?;
                 ProxyState::AwaitStart
             }
+            ProxyState::AwaitStart if launch.path.is_file() => {
+                anyhow::bail!(launch.failure().unwrap_or_default())
+            }
+            ProxyState::AwaitStart if awaiting_ready => {
+                tokio::time::sleep(SLEEP).await;
+                ProxyState::AwaitStart
+            }
+            // the ready file is written once the Management API is bound
+            ProxyState::AwaitStart if discover => ProxyState::Running,
             ProxyState::AwaitStart => match api.get_services().await {
                 Ok(_) => ProxyState::Running,
                 Err(err) => match err {
-                    Error::SendRequestError { .. } => {
+                    Error::SendRequestError { .. } | Error::ConnectionError { .. } => {
                         tokio::time::sleep(SLEEP).await;
                         ProxyState::AwaitStart
                     }
@@ -92,10 +179,128 @@ pub async fn spawn(api: ManagementApi, data_dir: PathBuf) -> anyhow::Result<()>
         };
     }
 
-    Ok(())
+    Ok(api)
+}
+
+fn discovered_api(api: &ManagementApi, ready: &Ready) -> Option<ManagementApi> {
+    api.with_url(&ready.management_url).ok()
 }
 
-fn spawn_detached_command(mut command: Command) -> anyhow::Result<()> {
+/// Reads the Management API token shared by all runtimes using the data directory.
+/// The token is generated on first use.
+pub fn management_token(data_dir: &Path) -> anyhow::Result<String> {
+    let path = data_dir.join(TOKEN_FILE_NAME);
+    if let Ok(token) = fs::read_to_string(&path) {
+        return Ok(token.trim().to_string());
+    }
+
+    fs::create_dir_all(data_dir)?;
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LEN)
+        .map(char::from)
+        .collect();
+
+    // write to a temporary file first, so that concurrent runtimes never read a partial token
+    let tmp_path = data_dir.join(format!("{}.{}", TOKEN_FILE_NAME, std::process::id()));
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&tmp_path)?.write_all(token.as_bytes())?;
+
+    let result = fs::hard_link(&tmp_path, &path);
+    let _ = fs::remove_file(&tmp_path);
+
+    match result {
+        Ok(_) => Ok(token),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            Ok(fs::read_to_string(&path)?.trim().to_string())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Proxy launch diagnostics, kept in the data directory
+struct Launch {
+    path: PathBuf,
+    stderr_path: PathBuf,
+}
+
+impl Launch {
+    fn new(data_dir: &Path) -> Self {
+        Self {
+            path: data_dir.join(LAUNCH_FILE_NAME),
+            stderr_path: data_dir.join(STDERR_FILE_NAME),
+        }
+    }
+
+    fn reset(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+
+    /// Standard error output of the launched proxy
+    fn stderr(&self) -> Stdio {
+        match File::create(&self.stderr_path) {
+            Ok(file) => Stdio::from(file),
+            Err(_) => Stdio::null(),
+        }
+    }
+
+    fn stderr_excerpt(&self) -> String {
+        let stderr = match fs::read(&self.stderr_path) {
+            Ok(stderr) => stderr,
+            Err(_) => return String::new(),
+        };
+        let excerpt = &stderr[stderr.len().saturating_sub(STDERR_EXCERPT_LEN)..];
+        let excerpt = String::from_utf8_lossy(excerpt);
+        match excerpt.trim() {
+            "" => String::new(),
+            excerpt => format!("; stderr ('{}'): {}", self.stderr_path.display(), excerpt),
+        }
+    }
+
+    fn failure(&self) -> Option<String> {
+        let reason = fs::read_to_string(&self.path).ok()?;
+        Some(format!("{}{}", reason.trim(), self.stderr_excerpt()))
+    }
+
+    /// Time left until a launch that failed less than `LAUNCH_BACKOFF` ago can be retried
+    fn backoff(&self) -> Option<Duration> {
+        let modified = fs::metadata(&self.path).ok()?.modified().ok()?;
+        let elapsed = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        LAUNCH_BACKOFF.checked_sub(elapsed)
+    }
+}
+
+/// Records the failure of a launched proxy process exiting within the start timeout.
+/// The process is reaped once it exits
+fn watch_launch(mut child: Child, launch_path: &Path) {
+    let started = Instant::now();
+    while started.elapsed() < TIMEOUT {
+        match child.try_wait() {
+            // another instance owns the data directory
+            Ok(Some(status)) if status.success() => return,
+            Ok(Some(status)) => {
+                let reason = format!("proxy started, then exited ({})", status);
+                let _ = fs::write(launch_path, reason);
+                return;
+            }
+            Ok(None) => std::thread::sleep(LAUNCH_WATCH_INTERVAL),
+            Err(_) => return,
+        }
+    }
+    let _ = child.wait();
+}
+
+/// Spawns the command in a new session (process group on Windows), watching its launch
+/// in a background thread
+fn spawn_detached_command(mut command: Command, launch_path: PathBuf) -> anyhow::Result<()> {
     #[cfg(windows)]
     {
         use std::os::windows::process::CommandExt;
@@ -104,27 +309,26 @@ fn spawn_detached_command(mut command: Command) -> anyhow::Result<()> {
         const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
 
         command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
-        let _child = command.spawn()?;
     }
     #[cfg(unix)]
     {
-        use nix::sys::wait::waitpid;
-        use nix::unistd::{fork, setsid, ForkResult};
-        use std::process::exit;
+        use std::os::unix::process::CommandExt;
 
-        match unsafe { fork().expect("failed to fork the process") } {
-            ForkResult::Parent { child } => {
-                let _ = waitpid(Some(child), None);
-            }
-            ForkResult::Child => {
-                if setsid().is_err() {
-                    exit(166);
-                }
-                let result = command.spawn();
-                exit(if result.is_ok() { 0 } else { 167 });
-            }
+        // runs in the forked process, where only async-signal-safe calls are allowed
+        unsafe {
+            command.pre_exec(|| {
+                nix::unistd::setsid()?;
+                Ok(())
+            });
         }
     }
+
+    let child = command.spawn().map_err(|e| {
+        let reason = format!("unable to execute proxy: {}", e);
+        let _ = fs::write(&launch_path, &reason);
+        anyhow::anyhow!(reason)
+    })?;
+    std::thread::spawn(move || watch_launch(child, &launch_path));
     Ok(())
 }
 
@@ -137,3 +341,119 @@ enum ProxyState {
     Running,
     Poisoned,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::time::{Duration, SystemTime};
+
+    use super::{spawn_detached_command, watch_launch, Launch, LAUNCH_BACKOFF};
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ya-runtime-http-auth-launch-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn launch_backoff() {
+        let dir = data_dir("backoff");
+        let launch = Launch::new(&dir);
+        assert!(launch.backoff().is_none());
+        assert!(launch.failure().is_none());
+
+        fs::write(&launch.path, "proxy started, then exited (exit status: 1)").unwrap();
+        fs::write(&launch.stderr_path, "address in use\n").unwrap();
+        assert!(launch.backoff().unwrap() <= LAUNCH_BACKOFF);
+        let failure = launch.failure().unwrap();
+        assert!(failure.starts_with("proxy started, then exited (exit status: 1); stderr"));
+        assert!(failure.ends_with(": address in use"));
+
+        // retried once the backoff has elapsed
+        let earlier = SystemTime::now() - LAUNCH_BACKOFF - Duration::from_secs(1);
+        fs::File::options()
+            .write(true)
+            .open(&launch.path)
+            .and_then(|f| f.set_modified(earlier))
+            .unwrap();
+        assert!(launch.backoff().is_none());
+
+        launch.reset();
+        assert!(launch.failure().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn launch_watch() {
+        let dir = data_dir("watch");
+        let launch = Launch::new(&dir);
+        let sh = |script: &str| {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(script);
+            command
+        };
+
+        // another instance owns the data directory
+        watch_launch(sh("exit 0").spawn().unwrap(), &launch.path);
+        assert!(launch.failure().is_none());
+
+        watch_launch(sh("exit 3").spawn().unwrap(), &launch.path);
+        assert_eq!(
+            launch.failure().unwrap(),
+            "proxy started, then exited (exit status: 3)"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn detached_spawn() {
+        use nix::unistd::{getsid, Pid};
+
+        let dir = data_dir("detached");
+        let launch = Launch::new(&dir);
+        let pid_path = dir.join("pid");
+
+        // the process leads a new session
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!(
+            "echo $$ > {}; sleep 0.2; exit 2",
+            pid_path.display()
+        ));
+        spawn_detached_command(command, launch.path.clone()).unwrap();
+
+        let mut pid = None;
+        while pid.is_none() {
+            std::thread::sleep(Duration::from_millis(10));
+            let contents = fs::read_to_string(&pid_path).unwrap_or_default();
+            pid = contents.trim().parse().ok().map(Pid::from_raw);
+        }
+        let pid = pid.unwrap();
+        assert_eq!(getsid(Some(pid)).unwrap(), pid);
+
+        // the exit is recorded by the watching thread
+        while !launch.path.is_file() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            launch.failure().unwrap(),
+            "proxy started, then exited (exit status: 2)"
+        );
+
+        let missing = Command::new(dir.join("missing"));
+        assert!(spawn_detached_command(missing, launch.path.clone()).is_err());
+        assert!(launch
+            .failure()
+            .unwrap()
+            .starts_with("unable to execute proxy: "));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+//! Users created by the runtime, persisted in the data directory so that a restarted
+//! runtime can reconcile them with the proxy and remove them on stop
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use ya_http_proxy_client::model::{self, User};
+use ya_http_proxy_client::{Error, ManagementApi};
+
+const STATE_DIR: &str = "users";
+const STATE_EXT: &str = "json";
+
+/// Path of a per-service file in a data directory subdirectory
+pub fn service_file(data_dir: &Path, dir: &str, service: &str, ext: &str) -> PathBuf {
+    let name = format!("{}.{}", model::file_name(service), ext);
+    data_dir.join(dir).join(name)
+}
+
+/// Service users created by the runtime. User descriptors carry no credentials.
+/// Saved on every change once loaded for a service
+#[derive(Debug, Default)]
+pub struct UserState {
+    path: Option<PathBuf>,
+    users: HashMap<String, User>,
+}
+
+impl UserState {
+    pub fn load(data_dir: &Path, service: &str) -> Self {
+        let path = service_file(data_dir, STATE_DIR, service, STATE_EXT);
+        let users: Vec<User> = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                log::warn!("Invalid user state file '{}': {}", path.display(), e);
+                Default::default()
+            }),
+            Err(_) => Default::default(),
+        };
+        Self {
+            path: Some(path),
+            users: users.into_iter().map(|u| (u.username.clone(), u)).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        self.users.keys().cloned().collect()
+    }
+
+    pub fn insert(&mut self, user: User) {
+        self.users.insert(user.username.clone(), user);
+        self.save();
+    }
+
+    pub fn remove(&mut self, username: &str) {
+        if self.users.remove(username).is_some() {
+            self.save();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.users.clear();
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Unable to remove '{}': {}", path.display(), e);
+            }
+        }
+    }
+
+    fn save(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(e) = self.write(path) {
+                log::warn!("Unable to save '{}': {}", path.display(), e);
+            }
+        }
+    }
+
+    fn write(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut users: Vec<_> = self.users.values().collect();
+        users.sort_by(|l, r| l.username.cmp(&r.username));
+
+        // never leave a partially written file behind
+        let tmp_path = path.with_extension(format!("{}.tmp", STATE_EXT));
+        fs::write(&tmp_path, serde_json::to_vec(&users)?)?;
+        fs::rename(tmp_path, path)
+    }
+}
+
+/// Differences between runtime and proxy users
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Diff {
+    /// Known to the runtime only
+    missing: Vec<String>,
+    /// Known to the proxy only
+    unknown: Vec<String>,
+    /// Using a different auth method
+    mismatched: Vec<String>,
+}
+
+fn diff(runtime: &HashMap<String, User>, proxy: &[User]) -> Diff {
+    let proxy: HashMap<_, _> = proxy.iter().map(|u| (u.username.as_str(), u)).collect();
+    let mut diff = Diff::default();
+
+    for (username, user) in runtime {
+        match proxy.get(username.as_str()) {
+            Some(u) if u.method != user.method => diff.mismatched.push(username.clone()),
+            Some(_) => {}
+            None => diff.missing.push(username.clone()),
+        }
+    }
+    for username in proxy.keys() {
+        if !runtime.contains_key(*username) {
+            diff.unknown.push(username.to_string());
+        }
+    }
+
+    diff.missing.sort();
+    diff.unknown.sort();
+    diff.mismatched.sort();
+    diff
+}
+
+/// Proxy descriptors of the users recorded by the runtime. The proxy state prevails
+fn recorded(runtime: &HashMap<String, User>, proxy: Vec<User>) -> HashMap<String, User> {
+    proxy
+        .into_iter()
+        .filter(|u| runtime.contains_key(&u.username))
+        .map(|u| (u.username.clone(), u))
+        .collect()
+}
+
+/// Outcome of reconciling runtime and proxy users
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reconciliation {
+    /// Users recorded by a previous runtime, taken over by the current one
+    pub adopted: Vec<String>,
+    /// Users that could not be reconciled
+    pub discrepancies: Vec<String>,
+}
+
+impl Reconciliation {
+    pub fn is_empty(&self) -> bool {
+        self.adopted.is_empty() && self.discrepancies.is_empty()
+    }
+}
+
+/// Brings runtime users in line with service users known to the proxy.
+/// Users recorded by a previous runtime are adopted, users lost by the proxy
+/// cannot be recreated without credentials and are reported. Proxy users not
+/// recorded by this runtime belong to someone else and are left alone
+pub async fn reconcile(
+    api: &ManagementApi,
+    service_name: &str,
+    users: &mut UserState,
+) -> Result<Reconciliation, Error> {
+    let proxy_users = api.get_users(service_name).await?;
+    let diff = diff(&users.users, &proxy_users);
+    let mut result = Reconciliation::default();
+
+    for username in diff.missing {
+        result
+            .discrepancies
+            .push(format!("{}: removed from the proxy", username));
+    }
+    for username in diff.mismatched {
+        let runtime: &'static str = users.users[&username].method.clone().into();
+        let proxy = proxy_users.iter().find(|u| u.username == username);
+        let proxy: &'static str = proxy.map(|u| u.method.clone()).unwrap_or_default().into();
+        result.discrepancies.push(format!(
+            "{}: {} auth in the runtime, {} auth in the proxy",
+            username, runtime, proxy
+        ));
+    }
+    if !diff.unknown.is_empty() {
+        log::info!(
+            "Ignoring users not created by the runtime: {:?}",
+            diff.unknown
+        );
+    }
+
+    users.users = recorded(&users.users, proxy_users);
+    result.adopted = users.usernames();
+    result.adopted.sort();
+    users.save();
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::Utc;
+    use ya_http_proxy_client::model::{AuthMethod, User};
+
+    use super::{diff, recorded, UserState};
+
+    fn user(username: &str, method: AuthMethod) -> User {
+        User {
+            username: username.to_string(),
+            method,
+            limits: None,
+            fingerprint: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn user_diff() {
+        let runtime: HashMap<_, _> = [
+            user("kept", AuthMethod::Basic),
+            user("lost", AuthMethod::Basic),
+            user("changed", AuthMethod::Basic),
+        ]
+        .into_iter()
+        .map(|u| (u.username.clone(), u))
+        .collect();
+        let proxy = vec![
+            user("kept", AuthMethod::Basic),
+            user("changed", AuthMethod::Bearer),
+            user("other", AuthMethod::ApiKey),
+        ];
+
+        let diff = diff(&runtime, &proxy);
+        assert_eq!(diff.missing, vec!["lost".to_string()]);
+        assert_eq!(diff.mismatched, vec!["changed".to_string()]);
+        assert_eq!(diff.unknown, vec!["other".to_string()]);
+
+        let mut adopted: Vec<_> = recorded(&runtime, proxy)
+            .into_values()
+            .map(|u| (u.username, u.method))
+            .collect();
+        adopted.sort_by(|l, r| l.0.cmp(&r.0));
+        assert_eq!(
+            adopted,
+            vec![
+                ("changed".to_string(), AuthMethod::Bearer),
+                ("kept".to_string(), AuthMethod::Basic)
+            ]
+        );
+    }
+
+    #[test]
+    fn user_state_file() {
+        let dir =
+            std::env::temp_dir().join(format!("ya-runtime-http-auth-state-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut state = UserState::load(&dir, "svc/1");
+        assert_eq!(state.len(), 0);
+        state.insert(user("a", AuthMethod::Basic));
+        state.insert(user("b", AuthMethod::Bearer));
+        state.remove("a");
+
+        let loaded = UserState::load(&dir, "svc/1");
+        assert_eq!(loaded.usernames(), vec!["b".to_string()]);
+        assert_eq!(loaded.users["b"], state.users["b"]);
+
+        state.clear();
+        assert_eq!(UserState::load(&dir, "svc/1").len(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
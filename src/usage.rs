@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+/// Unit of CPU times reported in `/proc` (`USER_HZ`)
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const CLOCK_TICKS_PER_SEC: u64 = 100;
+
+/// Resource usage of the proxy process
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProcessUsage {
+    /// Total CPU time consumed by the process
+    pub cpu: Duration,
+    /// Resident set size in bytes
+    pub rss: u64,
+}
+
+/// Whether process usage can be sampled on this platform
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// Samples resource usage of a local process. Only supported on Linux.
+#[cfg(target_os = "linux")]
+pub fn sample(pid: u32) -> Option<ProcessUsage> {
+    let proc_dir = std::path::Path::new("/proc").join(pid.to_string());
+    let stat = std::fs::read_to_string(proc_dir.join("stat")).ok()?;
+    let status = std::fs::read_to_string(proc_dir.join("status")).ok()?;
+
+    Some(ProcessUsage {
+        cpu: parse_stat(&stat)?,
+        rss: parse_rss(&status)?,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sample(_pid: u32) -> Option<ProcessUsage> {
+    None
+}
+
+/// CPU time consumed by the proxy process while an activity is running, including
+/// the proxy processes restarted in the meantime
+#[derive(Debug, Default)]
+pub struct CpuMeter {
+    /// Current process, its CPU time when first sampled and the latest one
+    process: Option<(u32, Duration, Duration)>,
+    /// CPU time consumed by the previous processes
+    previous: Duration,
+}
+
+impl CpuMeter {
+    /// Registers a sample of a process, returning the CPU time consumed since the first sample
+    pub fn update(&mut self, pid: u32, cpu: Duration) -> Duration {
+        let (start, last) = match self.process {
+            Some((current, start, _)) if current == pid => (start, cpu),
+            Some((_, start, last)) => {
+                // the restarted process has been running for the activity only
+                self.previous += last.saturating_sub(start);
+                (Duration::ZERO, cpu)
+            }
+            None => (cpu, cpu),
+        };
+        self.process = Some((pid, start, last));
+        self.previous + last.saturating_sub(start)
+    }
+}
+
+/// Parses user and system CPU time of all process threads from `/proc/<pid>/stat`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_stat(stat: &str) -> Option<Duration> {
+    // the command name may contain spaces and parentheses; fields following it start
+    // with the process state (3rd field), while `utime` and `stime` are the 14th and 15th
+    let (_, fields) = stat.rsplit_once(')')?;
+    let mut fields = fields.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    let ticks = utime.checked_add(stime)?;
+    Some(Duration::from_millis(
+        ticks.checked_mul(1000)? / CLOCK_TICKS_PER_SEC,
+    ))
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let mut fields = line["VmRSS:".len()..].split_whitespace();
+    let value: u64 = fields.next()?.parse().ok()?;
+    match fields.next() {
+        Some("kB") => value.checked_mul(1024),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_rss, parse_stat, CpuMeter};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_proc_files() {
+        let stat = "4242 (ya-http (proxy)) S 1 4242 4242 0 -1 4194560 2171 0 0 0 250 125 0 0 \
+                    20 0 9 0 1234 123456789 2500 18446744073709551615\n";
+        assert_eq!(parse_stat(stat), Some(Duration::from_millis(3750)));
+        assert_eq!(parse_stat("4242 (ya-http-proxy) S 1"), None);
+        assert_eq!(parse_stat(""), None);
+
+        let status = "Name:\tya-http-proxy\nVmPeak:\t  20000 kB\nVmRSS:\t   10240 kB\n";
+        assert_eq!(parse_rss(status), Some(10240 * 1024));
+        assert_eq!(parse_rss("Name:\tkthreadd\n"), None);
+    }
+
+    #[test]
+    fn cpu_meter() {
+        let secs = Duration::from_secs;
+        let mut meter = CpuMeter::default();
+        // time consumed before the activity started is not included
+        assert_eq!(meter.update(1, secs(100)), secs(0));
+        assert_eq!(meter.update(1, secs(103)), secs(3));
+        assert_eq!(meter.update(1, secs(105)), secs(5));
+        // restarted proxy
+        assert_eq!(meter.update(2, secs(1)), secs(6));
+        assert_eq!(meter.update(2, secs(4)), secs(9));
+    }
+}
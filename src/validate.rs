@@ -0,0 +1,314 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Serialize;
+use structopt::StructOpt;
+use tokio::net::TcpSocket;
+use ya_runtime_sdk::serialize::json;
+
+use crate::config::{self, ServiceConf};
+use crate::PROPERTY_PREFIX;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Runtime commands executed outside of the ExeUnit Supervisor
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub enum ConfigCli {
+    /// Service configuration commands
+    Config(ConfigCommand),
+}
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub enum ConfigCommand {
+    /// Validates the service configuration and prints a report
+    Validate {
+        /// Service name
+        name: String,
+        /// Check whether the service URL accepts connections
+        #[structopt(long)]
+        probe: bool,
+        /// Additional directory to look up service configuration files in
+        #[structopt(long)]
+        lookup_dir: Vec<PathBuf>,
+        /// Search subdirectories of lookup directories
+        #[structopt(long)]
+        recursive: bool,
+    },
+}
+
+impl ConfigCli {
+    /// Parses the command line when invoked with the `config` command
+    pub fn from_env() -> Option<Self> {
+        let args: Vec<String> = std::env::args().collect();
+        if args.get(1).map(String::as_str) != Some("config") {
+            return None;
+        }
+        Some(Self::from_iter(args))
+    }
+
+    pub fn run(self) -> anyhow::Result<()> {
+        let Self::Config(ConfigCommand::Validate {
+            name,
+            probe,
+            lookup_dir,
+            recursive,
+        }) = self;
+
+        let service = config::lookup_by_name(&lookup_dir, recursive, &name)
+            .ok_or_else(|| anyhow::anyhow!("Config file not found for service '{}'", name))?;
+        let report = validate(&service, probe);
+        println!("{}", json::to_string_pretty(&report)?);
+
+        if !report.is_valid() {
+            anyhow::bail!("Invalid service configuration: {}", report.summary());
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of a single check
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Check {
+    pub name: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Service configuration diagnostics
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub service: String,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.checks.iter().all(|c| c.severity != Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Check> {
+        self.checks
+            .iter()
+            .filter(|c| c.severity == Severity::Warning)
+    }
+
+    /// Joins messages of failed checks
+    pub fn summary(&self) -> String {
+        self.checks
+            .iter()
+            .filter(|c| c.severity == Severity::Error)
+            .map(|c| format!("{}: {}", c.name, c.message))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn push(&mut self, name: &'static str, severity: Severity, message: impl ToString) {
+        self.checks.push(Check {
+            name,
+            severity,
+            message: message.to_string(),
+        });
+    }
+}
+
+/// Checks the service configuration. With `probe` set, connects to the service URL
+pub fn validate(service: &ServiceConf, probe: bool) -> Report {
+    let mut report = Report {
+        service: service.inner.name.clone(),
+        checks: Vec::new(),
+    };
+
+    check_cert(service, &mut report);
+    check_bind(service, &mut report);
+    check_offer_properties(service, &mut report);
+    if probe {
+        check_upstream(service, &mut report);
+    }
+    report
+}
+
+fn check_cert(service: &ServiceConf, report: &mut Report) {
+    const NAME: &str = "cert";
+
+    let cert = match service.inner.cert {
+        Some(ref cert) => cert,
+        None if service.inner.bind_https.is_some() => {
+            return report.push(
+                NAME,
+                Severity::Warning,
+                "HTTPS enabled without a certificate, the proxy default will be used",
+            );
+        }
+        None => return report.push(NAME, Severity::Ok, "not configured"),
+    };
+    if cert.acme {
+        return report.push(NAME, Severity::Ok, "obtained by the proxy via ACME");
+    }
+
+    let missing: Vec<String> = [&cert.path, &cert.key_path]
+        .into_iter()
+        .filter(|p| !p.is_file())
+        .map(|p| p.display().to_string())
+        .collect();
+
+    if missing.is_empty() {
+        report.push(NAME, Severity::Ok, "certificate and key files found");
+    } else {
+        report.push(
+            NAME,
+            Severity::Error,
+            format!("missing files: {}", missing.join(", ")),
+        );
+    }
+}
+
+fn check_bind(service: &ServiceConf, report: &mut Report) {
+    const NAME: &str = "bind";
+
+    let addrs: Vec<SocketAddr> = [&service.inner.bind_https, &service.inner.bind_http]
+        .into_iter()
+        .flatten()
+        .flat_map(|a| a.to_vec())
+        .collect();
+    if addrs.is_empty() {
+        return report.push(NAME, Severity::Ok, "using proxy default addresses");
+    }
+
+    for addr in addrs {
+        match bind(addr) {
+            Ok(_) => report.push(NAME, Severity::Ok, format!("{} is free", addr)),
+            // services share listeners of a running proxy
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => report.push(
+                NAME,
+                Severity::Warning,
+                format!("{} is in use, possibly by a running proxy", addr),
+            ),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => report.push(
+                NAME,
+                Severity::Warning,
+                format!("{} requires elevated privileges", addr),
+            ),
+            Err(e) => report.push(NAME, Severity::Error, format!("{}: {}", addr, e)),
+        }
+    }
+}
+
+/// Binds a socket the way the proxy does, reusing addresses of closed connections
+fn bind(addr: SocketAddr) -> io::Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    Ok(socket)
+}
+
+fn check_offer_properties(service: &ServiceConf, report: &mut Report) {
+    const NAME: &str = "offerProperties";
+
+    if let Err(e) = service.offer_properties(PROPERTY_PREFIX) {
+        return report.push(NAME, Severity::Error, e);
+    }
+
+    let keys = service.unsanitized_offer_keys();
+    if keys.is_empty() {
+        report.push(NAME, Severity::Ok, "valid");
+    } else {
+        report.push(
+            NAME,
+            Severity::Warning,
+            format!("keys will be renamed or dropped: {}", keys.join(", ")),
+        );
+    }
+}
+
+fn check_upstream(service: &ServiceConf, report: &mut Report) {
+    const NAME: &str = "upstream";
+
+    let to = &service.inner.to;
+    let host = match to.host() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => return report.push(NAME, Severity::Error, format!("{} has no host", to)),
+    };
+    let port = to.port_u16().unwrap_or_else(|| {
+        if to.scheme_str() == Some("https") {
+            443
+        } else {
+            80
+        }
+    });
+
+    let addrs = match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => return report.push(NAME, Severity::Error, format!("{}: {}", host, e)),
+    };
+
+    let mut error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, PROBE_TIMEOUT) {
+            Ok(_) => return report.push(NAME, Severity::Ok, format!("{} is reachable", to)),
+            Err(e) => error = Some(e),
+        }
+    }
+
+    let message = match error {
+        Some(e) => format!("{} is unreachable: {}", to, e),
+        None => format!("{} did not resolve to any address", host),
+    };
+    report.push(NAME, Severity::Error, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, Severity};
+    use crate::config::ServiceConf;
+    use ya_runtime_sdk::serialize;
+
+    #[test]
+    fn validate_service() {
+        let json = serialize::json::json!({
+            "name": "service_1",
+            "from": "/",
+            "to": "http://127.0.0.1:8444",
+            "cert": {
+                "path": "/nonexistent/server.cert",
+                "keyPath": "/nonexistent/server.key"
+            },
+            "offerProperties": {
+                "valid-key": 1,
+                "Invalid Key": 2
+            }
+        });
+        let service: ServiceConf = serialize::json::from_value(json).unwrap();
+
+        let report = validate(&service, false);
+        assert!(!report.is_valid());
+
+        let severity = |name: &str| {
+            report
+                .checks
+                .iter()
+                .find(|c| c.name == name)
+                .map(|c| c.severity)
+        };
+        assert_eq!(severity("cert"), Some(Severity::Error));
+        assert_eq!(severity("offerProperties"), Some(Severity::Warning));
+        assert_eq!(severity("upstream"), None);
+    }
+}